@@ -0,0 +1,146 @@
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Fields, Type};
+
+// Implements VertexLayout for a struct with named fields of f32, i32 or u32, arrays of up to
+// four of them or arrays of up to four of those arrays, which are matrices read column by
+// column. Fields take consecutive locations from 0, #[vertex(location = N)] moves a field and
+// the ones after it. The impl names the trait as crate::ceaser::reflect, which only resolves
+// inside oberon itself, #[vertex(reflect = path::to::reflect)] on the struct points it elsewhere
+#[proc_macro_derive(VertexLayout, attributes(vertex))]
+pub fn derive_vertex_layout(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match vertex_layout(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn vertex_layout(input: &DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let name = &input.ident;
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    name,
+                    "VertexLayout needs a struct with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(syn::Error::new_spanned(
+                name,
+                "VertexLayout can only be derived for structs",
+            ))
+        }
+    };
+
+    let mut reflect: syn::Path = syn::parse_quote!(crate::ceaser::reflect);
+    for attr in input.attrs.iter().filter(|a| a.path().is_ident("vertex")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("reflect") {
+                reflect = meta.value()?.parse()?;
+                Ok(())
+            } else {
+                Err(meta.error("expected `reflect = path`"))
+            }
+        })?;
+    }
+
+    let mut location = 0;
+    let mut descriptions = Vec::new();
+    for field in fields {
+        let ident = field.ident.as_ref().expect("named fields have names");
+        for attr in field.attrs.iter().filter(|a| a.path().is_ident("vertex")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("location") {
+                    location = meta.value()?.parse::<syn::LitInt>()?.base10_parse()?;
+                    Ok(())
+                } else {
+                    Err(meta.error("expected `location = N`"))
+                }
+            })?;
+        }
+        let (format, columns) = format(&field.ty).ok_or_else(|| {
+            syn::Error::new_spanned(
+                &field.ty,
+                "unsupported vertex attribute type, expected f32, i32 or u32, an array of up \
+                 to four of them or an array of up to four such arrays",
+            )
+        })?;
+        let format = syn::Ident::new(&format, Span::call_site());
+        let field_name = ident.to_string();
+        descriptions.push(quote! {
+            #reflect::VertexField {
+                name: #field_name,
+                location: #location,
+                offset: ::std::mem::offset_of!(Self, #ident) as u32,
+                format: ::ash::vk::Format::#format,
+                columns: #columns,
+            }
+        });
+        location += columns;
+    }
+
+    let type_name = name.to_string();
+    let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics #reflect::VertexLayout for #name #type_generics
+        #where_clause
+        {
+            const TYPE_NAME: &'static str = #type_name;
+
+            fn fields() -> Vec<#reflect::VertexField> {
+                vec![#(#descriptions),*]
+            }
+        }
+    })
+}
+
+// The vk::Format name of one column and the number of columns
+fn format(ty: &Type) -> Option<(String, u32)> {
+    if let Some(component) = scalar(ty) {
+        return Some((vector_format(component, 1), 1));
+    }
+    let (element, length) = array(ty)?;
+    if let Some(component) = scalar(element) {
+        return Some((vector_format(component, length), 1));
+    }
+    let (column, rows) = array(element)?;
+    Some((vector_format(scalar(column)?, rows), length))
+}
+
+fn scalar(ty: &Type) -> Option<&'static str> {
+    let Type::Path(path) = ty else {
+        return None;
+    };
+    match path.path.get_ident()?.to_string().as_str() {
+        "f32" => Some("SFLOAT"),
+        "i32" => Some("SINT"),
+        "u32" => Some("UINT"),
+        _ => None,
+    }
+}
+
+// Element type and length of an array of one to four elements
+fn array(ty: &Type) -> Option<(&Type, u32)> {
+    let Type::Array(array) = ty else {
+        return None;
+    };
+    let syn::Expr::Lit(syn::ExprLit {
+        lit: syn::Lit::Int(length),
+        ..
+    }) = &array.len
+    else {
+        return None;
+    };
+    let length = length.base10_parse().ok()?;
+    (1..=4).contains(&length).then_some((&*array.elem, length))
+}
+
+fn vector_format(component: &str, count: u32) -> String {
+    let channels: String = ["R32", "G32", "B32", "A32"][..count as usize].concat();
+    format!("{}_{}", channels, component)
+}
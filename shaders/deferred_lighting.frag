@@ -0,0 +1,49 @@
+#version 450
+#extension GL_GOOGLE_include_directive : require
+
+layout (location=0) in vec2 uv;
+
+layout (location=0) out vec4 out_color;
+
+// Written by gbuffer.frag in the previous subpass
+layout (input_attachment_index=0, set=0, binding=0) uniform subpassInput gbuffer_albedo;
+layout (input_attachment_index=1, set=0, binding=1) uniform subpassInput gbuffer_normal;
+layout (input_attachment_index=2, set=0, binding=2) uniform subpassInput gbuffer_material;
+layout (input_attachment_index=3, set=0, binding=3) uniform subpassInput gbuffer_depth;
+
+#include "lighting.glsl"
+
+layout (push_constant) uniform PushConstants {
+	mat4 inverse_view_projection;
+	vec4 camera_position;
+	float near;
+	float far;
+	uint ambient_occlusion;
+} pc;
+
+void main() {
+  float depth = subpassLoad(gbuffer_depth).r;
+  // Nothing was drawn here, keep the clear colour
+  if (depth >= 1.0) {
+    discard;
+  }
+  vec4 world = pc.inverse_view_projection * vec4(uv * 2.0 - 1.0, depth, 1.0);
+  vec4 material = subpassLoad(gbuffer_material);
+
+  Surface surface;
+  surface.position = world.xyz / world.w;
+  surface.view_depth = pc.near * pc.far / (pc.far - depth * (pc.far - pc.near));
+  surface.normal = normalize(subpassLoad(gbuffer_normal).xyz);
+  surface.colour = subpassLoad(gbuffer_albedo).rgb;
+  surface.metallic = material.r;
+  surface.roughness = material.g;
+
+  float occlusion = 1.0;
+  if (pc.ambient_occlusion != 0) {
+    occlusion = texelFetch(ambient_occlusion, ivec2(gl_FragCoord.xy), 0).r;
+  }
+  vec3 L = shade(surface, pc.camera_position.xyz, material.b > 0.5, occlusion);
+
+  // Linear radiance, exposure and tonemapping happen in shaders/post.frag
+  out_color = vec4(L, 1.0);
+}
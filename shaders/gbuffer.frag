@@ -0,0 +1,39 @@
+#version 450
+
+// Surface attributes for the deferred lighting pass, formats are in ceaser/deferred.rs
+layout (location=0) out vec4 out_albedo;
+layout (location=1) out vec4 out_normal;
+layout (location=2) out vec4 out_material;
+
+layout (location=0) in vec3 colour_in;
+layout (location=1) in vec3 normal;
+layout (location=2) in vec3 worldpos;
+layout (location=3) in vec3 camera_coordinates;
+layout (location=4) in float metallic;
+layout (location=5) in float roughness;
+layout (location=6) in float view_depth;
+layout (location=7) in vec2 uv;
+layout (location=8) in vec4 tangent;
+
+// Per model, white and (0, 0, 1) when the model has no textures
+layout (set=2, binding=0) uniform sampler2D albedo_map;
+layout (set=3, binding=0) uniform sampler2D normal_map;
+
+// Same block as shader.frag, the pipelines share their layout
+layout (push_constant) uniform PushConstants {
+	uint receives_shadows;
+	uint ambient_occlusion;
+} pc;
+
+void main() {
+  // MikkTSpace: interpolated vectors are used as they are, only the result is normalized
+  vec3 bitangent = tangent.w * cross(normal, tangent.xyz);
+  vec3 tangent_space_normal = texture(normal_map, uv).xyz * 2.0 - 1.0;
+  vec3 n = normalize(tangent_space_normal.x * tangent.xyz +
+                     tangent_space_normal.y * bitangent +
+                     tangent_space_normal.z * normal);
+
+  out_albedo = vec4(colour_in * texture(albedo_map, uv).rgb, 1.0);
+  out_normal = vec4(n, 0.0);
+  out_material = vec4(metallic, roughness, pc.receives_shadows != 0 ? 1.0 : 0.0, 0.0);
+}
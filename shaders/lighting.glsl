@@ -0,0 +1,248 @@
+// Lights, shadows and the environment in descriptor set 1, and the BRDF shading with them.
+// Shared by the forward pass in shader.frag and the deferred lighting pass.
+
+// The w component of a light's direction or position holds its first shadow map layer, or -1.
+// For point lights, the w component of the second vec4 is the influence radius
+readonly layout (set=1, binding=0) buffer StorageBufferObject {
+	float num_directional;
+	float num_point;
+	vec4 data[];
+} sbo;
+
+// Keep in sync with ceaser/shadow.rs
+const int CASCADE_COUNT = 4;
+const int MAX_SHADOW_MAPS = 8;
+const int MAX_POINT_SHADOW_MAPS = 24;
+
+layout (set=1, binding=1) uniform ShadowUniforms {
+	mat4 light_matrices[MAX_SHADOW_MAPS];
+	mat4 point_matrices[MAX_POINT_SHADOW_MAPS];
+	vec4 cascade_splits;
+} shadows;
+
+layout (set=1, binding=2) uniform sampler2DArrayShadow shadow_maps;
+// Six layers per point light, one per cube face in the order +x, -x, +y, -y, +z, -z
+layout (set=1, binding=3) uniform sampler2DArrayShadow point_shadow_maps;
+
+// Environment lighting, see ceaser/ibl.rs
+const float SPECULAR_MIP_LEVELS = 5.0;
+layout (set=1, binding=4) uniform samplerCube irradiance_map;
+layout (set=1, binding=5) uniform samplerCube specular_map;
+layout (set=1, binding=6) uniform sampler2D brdf_lut;
+// Screen-space ambient occlusion at full resolution, see ceaser/ssao.rs
+layout (set=1, binding=7) uniform sampler2D ambient_occlusion;
+
+// Point lights per view space cluster, filled by shaders/light_culling.comp
+const uint CLUSTERS_X = 16;
+const uint CLUSTERS_Y = 9;
+const uint CLUSTERS_Z = 24;
+const uint MAX_LIGHTS_PER_CLUSTER = 128;
+readonly layout (set=1, binding=8) buffer Clusters {
+	vec2 tile_size;
+	float slice_scale;
+	float slice_bias;
+	uint lights[];
+} clusters;
+
+const float PI = 3.14159265358979323846264;
+
+struct DirectionalLight {
+  vec3 direction_to_light;
+  vec3 irradiance;
+};
+
+struct PointLight{
+	vec3 position;
+	vec3 luminous_flux;
+};
+
+// Everything the BRDF needs to know about the shaded point
+struct Surface {
+  vec3 position;
+  // Distance along the camera's view direction, picks the shadow cascade
+  float view_depth;
+  vec3 normal;
+  vec3 colour;
+  float metallic;
+  float roughness;
+};
+
+float distribution(vec3 normal,vec3 halfvector,float roughness2){
+	float NdotH=dot(halfvector,normal);
+	if (NdotH>0){
+		float r=roughness2*roughness2;
+		return r / (PI* (1 + NdotH*NdotH*(r-1))*(1 + NdotH*NdotH*(r-1)));
+	}else{
+		return 0.0;
+	}
+}
+
+float geometry(vec3 light, vec3 normal, vec3 view, float roughness2) {
+  float NdotL = abs(dot(normal, light));
+  float NdotV = abs(dot(normal, view));
+  return 0.5 / max(0.01, mix(2 * NdotL * NdotV, NdotL + NdotV, roughness2));
+}
+
+vec3 compute_radiance(vec3 irradiance, vec3 light_direction, Surface surface,
+                      vec3 camera_direction) {
+  vec3 normal = surface.normal;
+  float metallic = surface.metallic;
+  float NdotL = max(dot(normal, light_direction), 0);
+
+  vec3 irradiance_on_surface = irradiance * NdotL;
+
+  float roughness2 = surface.roughness * surface.roughness;
+
+  vec3 F0 = mix(vec3(0.03), surface.colour, vec3(metallic));
+  vec3 reflected_irradiance =
+      (F0 + (1 - F0) * (1 - NdotL) * (1 - NdotL) * (1 - NdotL) * (1 - NdotL) *
+                (1 - NdotL)) *
+      irradiance_on_surface;
+  vec3 refracted_irradiance = irradiance_on_surface - reflected_irradiance;
+  vec3 refracted_not_absorbed_irradiance =
+      refracted_irradiance * (1 - metallic);
+
+  vec3 halfvector = normalize(0.5 * (camera_direction + light_direction));
+  float NdotH = max(dot(normal, halfvector), 0);
+  vec3 F = (F0 + (1 - F0) * (1 - NdotH) * (1 - NdotH) * (1 - NdotH) *
+                     (1 - NdotH) * (1 - NdotH));
+  vec3 relevant_reflection =
+      reflected_irradiance * F *
+      geometry(light_direction, normal, camera_direction, roughness2) *
+      distribution(normal, halfvector, roughness2);
+
+  return refracted_not_absorbed_irradiance * surface.colour / PI +
+         relevant_reflection;
+}
+
+// Light from the environment, with the same F0 and roughness as compute_radiance
+vec3 compute_ambient(Surface surface, vec3 camera_direction) {
+  vec3 normal = surface.normal;
+  float roughness = surface.roughness;
+  float NdotV = max(dot(normal, camera_direction), 0);
+  vec3 F0 = mix(vec3(0.03), surface.colour, vec3(surface.metallic));
+  vec3 F = F0 + (max(vec3(1 - roughness), F0) - F0) * pow(1 - NdotV, 5);
+
+  vec3 diffuse = texture(irradiance_map, normal).rgb * surface.colour * (1 - F) *
+                 (1 - surface.metallic);
+
+  vec3 reflected = reflect(-camera_direction, normal);
+  vec3 prefiltered =
+      textureLod(specular_map, reflected, roughness * (SPECULAR_MIP_LEVELS - 1)).rgb;
+  vec2 brdf = texture(brdf_lut, vec2(NdotV, roughness)).rg;
+  vec3 specular = prefiltered * (F0 * brdf.x + brdf.y);
+
+  return diffuse + specular;
+}
+
+// Fraction of light reaching the surface, 3x3 PCF in the cascade covering it
+float shadow_factor(int first_layer, Surface surface) {
+  int cascade = 0;
+  while (cascade < CASCADE_COUNT && surface.view_depth > shadows.cascade_splits[cascade]) {
+    cascade++;
+  }
+  if (cascade == CASCADE_COUNT) {
+    return 1.0;
+  }
+  int layer = first_layer + cascade;
+  vec4 light_space = shadows.light_matrices[layer] * vec4(surface.position, 1.0);
+  vec3 ndc = light_space.xyz / light_space.w;
+  if (ndc.z > 1.0) {
+    return 1.0;
+  }
+  vec2 uv = ndc.xy * 0.5 + 0.5;
+  vec2 texel = 1.0 / vec2(textureSize(shadow_maps, 0).xy);
+  float lit = 0.0;
+  for (int x = -1; x <= 1; x++) {
+    for (int y = -1; y <= 1; y++) {
+      lit += texture(shadow_maps, vec4(uv + vec2(x, y) * texel, layer, ndc.z));
+    }
+  }
+  return lit / 9.0;
+}
+
+// Fraction of a point light reaching the surface, 3x3 PCF in the cube face facing it
+float point_shadow_factor(int first_layer, vec3 light_position, Surface surface) {
+  vec3 v = surface.position - light_position;
+  vec3 a = abs(v);
+  int face;
+  if (a.x >= a.y && a.x >= a.z) {
+    face = v.x > 0.0 ? 0 : 1;
+  } else if (a.y >= a.z) {
+    face = v.y > 0.0 ? 2 : 3;
+  } else {
+    face = v.z > 0.0 ? 4 : 5;
+  }
+  int layer = first_layer + face;
+  vec4 light_space = shadows.point_matrices[layer] * vec4(surface.position, 1.0);
+  vec3 ndc = light_space.xyz / light_space.w;
+  if (ndc.z > 1.0) {
+    return 1.0;
+  }
+  vec2 uv = ndc.xy * 0.5 + 0.5;
+  vec2 texel = 1.0 / vec2(textureSize(point_shadow_maps, 0).xy);
+  float lit = 0.0;
+  for (int x = -1; x <= 1; x++) {
+    for (int y = -1; y <= 1; y++) {
+      lit += texture(point_shadow_maps, vec4(uv + vec2(x, y) * texel, layer, ndc.z));
+    }
+  }
+  return lit / 9.0;
+}
+
+// Index of the first entry of the cluster containing the fragment, the light count
+uint cluster_start(float view_depth) {
+  uvec2 tile = min(uvec2(gl_FragCoord.xy / clusters.tile_size), uvec2(CLUSTERS_X, CLUSTERS_Y) - 1u);
+  float slice = log(view_depth) * clusters.slice_scale + clusters.slice_bias;
+  uint z = uint(clamp(slice, 0.0, float(CLUSTERS_Z - 1u)));
+  uint cluster = tile.x + CLUSTERS_X * (tile.y + CLUSTERS_Y * z);
+  return cluster * (MAX_LIGHTS_PER_CLUSTER + 1u);
+}
+
+// Outgoing radiance towards the camera from the directional lights, the point lights in the
+// fragment's cluster and the environment. `occlusion` only darkens the environment, the lights
+// have their shadow maps
+vec3 shade(Surface surface, vec3 camera_position, bool receives_shadows, float occlusion) {
+  vec3 L = vec3(0);
+  vec3 direction_to_camera = normalize(camera_position - surface.position);
+
+  int number_directional = int(sbo.num_directional);
+
+  for (int i = 0; i < number_directional; i++) {
+    vec4 data1 = sbo.data[2 * i];
+    vec3 data2 = sbo.data[2 * i + 1].xyz;
+    DirectionalLight dlight = DirectionalLight(normalize(data1.xyz), data2);
+
+    float shadow = 1.0;
+    if (receives_shadows && data1.w >= 0.0) {
+      shadow = shadow_factor(int(data1.w), surface);
+    }
+    L += shadow * compute_radiance(dlight.irradiance, dlight.direction_to_light,
+                                   surface, direction_to_camera);
+  }
+
+  uint first = cluster_start(surface.view_depth);
+  uint count = clusters.lights[first];
+  for (uint j = 0u; j < count; j++) {
+    int i = int(clusters.lights[first + 1u + j]);
+    vec4 data1 = sbo.data[2 * i + 2 * number_directional];
+    vec4 data2 = sbo.data[2 * i + 1 + 2 * number_directional];
+    PointLight light = PointLight(data1.xyz, data2.xyz);
+    vec3 direction_to_light = normalize(light.position - surface.position);
+    float d = length(surface.position - light.position);
+    // Fades out towards the influence radius, so the light does not end at cluster edges
+    float ratio = d / data2.w;
+    float window = clamp(1.0 - ratio * ratio * ratio * ratio, 0.0, 1.0);
+    vec3 irradiance = light.luminous_flux / (4 * PI * d * d) * window * window;
+
+    float shadow = 1.0;
+    if (receives_shadows && data1.w >= 0.0) {
+      shadow = point_shadow_factor(int(data1.w), light.position, surface);
+    }
+    L += shadow * compute_radiance(irradiance, direction_to_light, surface,
+                                   direction_to_camera);
+  }
+
+  L += occlusion * compute_ambient(surface, direction_to_camera);
+  return L;
+}
@@ -0,0 +1,122 @@
+#version 450
+
+layout (location = 0) in vec2 uv;
+
+layout (location = 0) out vec4 out_color;
+
+layout (set = 0, binding = 0) uniform sampler2D hdr_color;
+
+// Written by shaders/exposure_average.comp
+layout (std430, set = 0, binding = 1) readonly buffer ExposureState {
+  float luminance;
+  float exposure;
+} metered;
+
+// Level 0 of the bloom chain, see shaders/bloom_*.comp
+layout (set = 0, binding = 2) uniform sampler2D bloom;
+
+// Values have to match post::Tonemapper
+const uint REINHARD = 0;
+const uint ACES = 1;
+const uint AGX = 2;
+const uint UNCHARTED2 = 3;
+
+layout (push_constant) uniform PushConstants {
+  float exposure;
+  uint tonemapper;
+  uint encode_srgb;
+  // When set, exposure is only a compensation on top of the metered exposure
+  uint auto_exposure;
+  // 0 means the bloom chain was not rendered this frame
+  float bloom_intensity;
+} pc;
+
+vec3 reinhard(vec3 x) { return x / (1.0 + x); }
+
+// Stephen Hill's fit of the ACES reference rendering and output transforms for sRGB displays
+vec3 aces(vec3 x) {
+  const mat3 input_matrix = mat3(0.59719, 0.35458, 0.04823,
+                                 0.07600, 0.90834, 0.01566,
+                                 0.02840, 0.13383, 0.83777);
+  const mat3 output_matrix = mat3(1.60475, -0.53108, -0.07367,
+                                  -0.10208, 1.10813, -0.00605,
+                                  -0.00327, -0.07276, 1.07602);
+  x = x * input_matrix;
+  vec3 a = x * (x + 0.0245786) - 0.000090537;
+  vec3 b = x * (0.983729 * x + 0.4329510) + 0.238081;
+  return (a / b) * output_matrix;
+}
+
+// Polynomial approximation of the AgX base contrast curve
+vec3 agx_contrast(vec3 x) {
+  vec3 x2 = x * x;
+  vec3 x4 = x2 * x2;
+  return 15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x +
+         0.4298 * x2 + 0.1191 * x - 0.00232;
+}
+
+vec3 agx(vec3 x) {
+  const mat3 inset = mat3(0.842479062253094, 0.0423282422610123, 0.0423756549057051,
+                          0.0784335999999992, 0.878468636469772, 0.0784336,
+                          0.0792237451477643, 0.0791661274605434, 0.879142973793104);
+  const mat3 outset = mat3(1.19687900512017, -0.0528968517574562, -0.0529716355144438,
+                           -0.0980208811401368, 1.15190312990417, -0.0980434501171241,
+                           -0.0990297440797205, -0.0989611768448433, 1.15107367264116);
+  const float min_ev = -12.47393;
+  const float max_ev = 4.026069;
+  x = inset * x;
+  x = clamp(log2(max(x, vec3(1e-10))), min_ev, max_ev);
+  x = (x - min_ev) / (max_ev - min_ev);
+  x = outset * agx_contrast(x);
+  // The curve produces display encoded values, the sRGB encoding happens afterwards
+  return pow(max(x, vec3(0.0)), vec3(2.2));
+}
+
+// John Hable's filmic curve with its usual exposure bias and linear white point
+vec3 hable(vec3 x) {
+  const float A = 0.15;
+  const float B = 0.50;
+  const float C = 0.10;
+  const float D = 0.20;
+  const float E = 0.02;
+  const float F = 0.30;
+  return ((x * (A * x + C * B) + D * E) / (x * (A * x + B) + D * F)) - E / F;
+}
+
+vec3 uncharted2(vec3 x) {
+  const float white_point = 11.2;
+  return hable(2.0 * x) / hable(vec3(white_point));
+}
+
+vec3 linear_to_srgb(vec3 c) {
+  vec3 low = 12.92 * c;
+  vec3 high = 1.055 * pow(c, vec3(1.0 / 2.4)) - 0.055;
+  return mix(high, low, lessThanEqual(c, vec3(0.0031308)));
+}
+
+void main() {
+  float exposure = pc.exposure;
+  if (pc.auto_exposure != 0) {
+    exposure *= metered.exposure;
+  }
+  vec3 radiance = texture(hdr_color, uv).rgb;
+  if (pc.bloom_intensity > 0.0) {
+    radiance = mix(radiance, texture(bloom, uv).rgb, pc.bloom_intensity);
+  }
+  radiance *= exposure;
+  vec3 mapped;
+  if (pc.tonemapper == ACES) {
+    mapped = aces(radiance);
+  } else if (pc.tonemapper == AGX) {
+    mapped = agx(radiance);
+  } else if (pc.tonemapper == UNCHARTED2) {
+    mapped = uncharted2(radiance);
+  } else {
+    mapped = reinhard(radiance);
+  }
+  mapped = clamp(mapped, 0.0, 1.0);
+  if (pc.encode_srgb != 0) {
+    mapped = linear_to_srgb(mapped);
+  }
+  out_color = vec4(mapped, 1.0);
+}
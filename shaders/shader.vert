@@ -7,6 +7,8 @@ layout (location = 6) in mat4 inverse_model_matrix;
 layout (location = 10) in vec3 color;
 layout (location = 11) in float metallic_in;
 layout (location = 12) in float roughness_in;
+layout (location = 13) in vec2 uv_in;
+layout (location = 14) in vec4 tangent_in;
 
 
 layout (set = 0, binding = 0) uniform UniformBufferObject {
@@ -20,6 +22,9 @@ layout (location = 2) out vec4 worldpos;
 layout (location = 3) out vec3 camera_coordinates;
 layout (location = 4) out float metallic;
 layout (location = 5) out float roughness;
+layout (location = 6) out float view_depth;
+layout (location = 7) out vec2 uv;
+layout (location = 8) out vec4 out_tangent;
 
 void main() {
   worldpos = model_matrix * vec4(position, 1.0);
@@ -27,6 +32,7 @@ void main() {
                 vec4(position, 1.0);
   f_color = vec4(color, 1.0);
   out_normal = transpose(mat3(inverse_model_matrix)) * normal;
+  out_tangent = vec4(mat3(model_matrix) * tangent_in.xyz, tangent_in.w);
   camera_coordinates =
       -ubo.view_matrix[3][0] * vec3(ubo.view_matrix[0][0],
                                     ubo.view_matrix[1][0],
@@ -35,6 +41,8 @@ void main() {
                                    ubo.view_matrix[2][1]) -
       ubo.view_matrix[3][2] * vec3(ubo.view_matrix[0][2], ubo.view_matrix[1][2],
                                    ubo.view_matrix[2][2]);
+  view_depth = (ubo.view_matrix * worldpos).z;
   metallic = metallic_in;
   roughness = roughness_in;
-}
\ No newline at end of file
+  uv = uv_in;
+}
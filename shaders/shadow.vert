@@ -0,0 +1,12 @@
+#version 450
+
+layout (location = 0) in vec3 position;
+layout (location = 2) in mat4 model_matrix;
+
+layout (push_constant) uniform PushConstants {
+  mat4 light_matrix;
+} pc;
+
+void main() {
+  gl_Position = pc.light_matrix * model_matrix * vec4(position, 1.0);
+}
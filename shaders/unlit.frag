@@ -0,0 +1,22 @@
+#version 450
+
+layout (location=0) out vec4 out_color;
+
+layout (location=0) in vec3 colour_in;
+layout (location=7) in vec2 uv;
+
+// Per model, white when the model has no texture
+layout (set=2, binding=0) uniform sampler2D albedo_map;
+
+// The flags are the same as in shader.frag, the tint is the material's parameter block
+layout (push_constant) uniform PushConstants {
+	uint receives_shadows;
+	uint ambient_occlusion;
+	vec4 tint;
+} pc;
+
+void main() {
+  vec4 albedo = texture(albedo_map, uv);
+  // Taken as linear radiance like the lit shaders' output, so exposure still applies
+  out_color = vec4(colour_in * albedo.rgb * pc.tint.rgb, albedo.a * pc.tint.a);
+}
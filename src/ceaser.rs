@@ -1,187 +1,465 @@
-use ash::vk::{self, CommandBuffer, DescriptorPool, DescriptorSet};
+use ash::vk::{self, DescriptorPool};
 use command_buffer::create_command_buffers;
 use gpu_allocator::{
     vulkan::{Allocator, AllocatorCreateDesc}
 };
-use nalgebra as na;
 use std::mem::ManuallyDrop;
 use winit::window::Window;
 
-use crate::hamlet::{InstanceData, Model, VertexData};
+use crate::hamlet::{light::LightManager, InstanceData, Model, Position, VertexData};
 
-use self::buffer::Buffer;
+use self::batch::BatchState;
+use self::camera::Camera;
+use self::material::Material;
+use self::pipeline::ScenePass;
+use self::reflect::VertexLayout;
 
+pub mod attachment;
+pub mod batch;
+pub mod bloom;
+pub mod cluster;
+pub mod culling;
 pub mod buffer;
 pub mod command_buffer;
+pub mod deferred;
+pub mod deletion_queue;
 pub mod device;
+pub mod exposure;
+pub mod frame;
+pub mod ibl;
 pub mod instance;
 pub mod logical;
+pub mod material;
 pub mod pipeline;
+pub mod pipeline_cache;
+pub mod post;
 pub mod queue;
+pub mod reflect;
 pub mod render_pass;
+pub mod shaders;
+pub mod shadow;
+pub mod ssao;
+pub mod offscreen;
 pub mod surface;
 pub mod swap_chain;
+pub mod texture;
+pub mod transfer;
 pub mod camera;
 
-pub struct Ceaser {
-    pub window: winit::window::Window,
+pub struct CeaserBuilder {
+    device_preference: device::DevicePreference,
+    frames_in_flight: usize,
+    msaa_samples: vk::SampleCountFlags,
+    tonemapper: post::Tonemapper,
+    bloom_intensity: f32,
+    bloom_threshold: f32,
+    ssao_radius: f32,
+    ssao_samples: u32,
+    render_path: deferred::RenderPath,
+    batched: bool,
+    pipeline_cache_dir: Option<std::path::PathBuf>,
+    shader_dir: Option<std::path::PathBuf>,
+}
+
+impl Default for CeaserBuilder {
+    fn default() -> Self {
+        CeaserBuilder {
+            device_preference: device::DevicePreference::Auto,
+            frames_in_flight: frame::DEFAULT_FRAMES_IN_FLIGHT,
+            msaa_samples: vk::SampleCountFlags::TYPE_4,
+            tonemapper: post::Tonemapper::Reinhard,
+            bloom_intensity: 0.04,
+            bloom_threshold: 0.0,
+            ssao_radius: 0.5,
+            ssao_samples: 16,
+            render_path: deferred::RenderPath::Forward,
+            batched: false,
+            pipeline_cache_dir: pipeline_cache::default_directory(),
+            shader_dir: None,
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl CeaserBuilder {
+    // OBERON_DEVICE still takes precedence, so a machine can be pinned without a rebuild
+    pub fn device(mut self, preference: device::DevicePreference) -> CeaserBuilder {
+        self.device_preference = preference;
+        self
+    }
+    pub fn frames_in_flight(mut self, frames: usize) -> CeaserBuilder {
+        self.frames_in_flight = frames.max(1);
+        self
+    }
+    // Clamped to what the device supports once it is chosen
+    pub fn msaa(mut self, samples: vk::SampleCountFlags) -> CeaserBuilder {
+        self.msaa_samples = samples;
+        self
+    }
+    pub fn tonemapper(mut self, tonemapper: post::Tonemapper) -> CeaserBuilder {
+        self.tonemapper = tonemapper;
+        self
+    }
+    // 0 turns bloom off
+    pub fn bloom_intensity(mut self, intensity: f32) -> CeaserBuilder {
+        self.bloom_intensity = intensity.clamp(0.0, 1.0);
+        self
+    }
+    // In scene radiance, 0 lets everything bloom
+    pub fn bloom_threshold(mut self, threshold: f32) -> CeaserBuilder {
+        self.bloom_threshold = threshold.max(0.0);
+        self
+    }
+    // In world units, how far away geometry still occludes
+    pub fn ssao_radius(mut self, radius: f32) -> CeaserBuilder {
+        self.ssao_radius = radius.max(0.01);
+        self
+    }
+    // 0 turns ambient occlusion off
+    pub fn ssao_samples(mut self, samples: u32) -> CeaserBuilder {
+        self.ssao_samples = samples.min(ssao::MAX_SSAO_SAMPLES);
+        self
+    }
+    // Can also be switched later through Ceaser::render_path
+    pub fn render_path(mut self, render_path: deferred::RenderPath) -> CeaserBuilder {
+        self.render_path = render_path;
+        self
+    }
+    // Draw the scene from shared buffers with multi-draw indirect, see batch::SceneBatches.
+    // Ignored on devices without drawIndirectFirstInstance
+    pub fn batched(mut self, batched: bool) -> CeaserBuilder {
+        self.batched = batched;
+        self
+    }
+    // Where compiled pipelines are kept between runs, None keeps them in memory only
+    pub fn pipeline_cache_dir(mut self, dir: Option<std::path::PathBuf>) -> CeaserBuilder {
+        self.pipeline_cache_dir = dir;
+        self
+    }
+    // Development mode: watches the GLSL in this directory and rebuilds the pipelines from it
+    // whenever it changes and Ceaser::reload_shaders is called, see shaders::ShaderLibrary
+    pub fn hot_reload(mut self, shader_dir: Option<std::path::PathBuf>) -> CeaserBuilder {
+        self.shader_dir = shader_dir;
+        self
+    }
+    // The vertex and instance types are those of every model the renderer draws, see Ceaser
+    pub fn build<V: VertexLayout + Position + Copy, I: VertexLayout>(
+        self,
+        window: Window,
+    ) -> Result<Ceaser<V, I>, Box<dyn std::error::Error>> {
+        Ceaser::init(self, Some(window), None)
+    }
+    pub fn build_headless<V: VertexLayout + Position + Copy, I: VertexLayout>(
+        self,
+        width: u32,
+        height: u32,
+    ) -> Result<Ceaser<V, I>, Box<dyn std::error::Error>> {
+        Ceaser::init(self, None, Some(vk::Extent2D { width, height }))
+    }
+}
+
+// Draws models of one vertex and one instance type, their layouts become the vertex input of
+// every scene pipeline. Another instance type has to start with the model matrix, which the
+// culling and shadow shaders read, and give the material shaders the inputs they declare
+pub struct Ceaser<V = VertexData, I = InstanceData> {
+    pub window: Option<winit::window::Window>,
     pub entry: ash::Entry,
     pub instance: ash::Instance,
     pub debug: ManuallyDrop<instance::Debug>,
-    pub surfaces: ManuallyDrop<surface::Surface>,
+    pub surfaces: Option<ManuallyDrop<surface::Surface>>,
     pub device: device::Device,
     pub queue_families: queue::QueueFamilies,
     pub queues: queue::Queues,
     pub logical_device: ash::Device,
-    pub swapchain: swap_chain::Swapchain,
+    pub swapchain: Option<swap_chain::Swapchain>,
+    pub offscreen: Option<offscreen::Offscreen>,
+    // Draws the scene into hdr_target, post then tonemaps it into the swapchain or offscreen image
     pub render_pass: vk::RenderPass,
+    pub hdr_target: post::HdrTarget,
+    pub auto_exposure: exposure::AutoExposure,
+    pub bloom: bloom::Bloom,
+    pub post: post::PostProcess,
+    // Depth prepass and occlusion, recorded before the scene pass which reads the result
+    pub ssao: ssao::Ssao,
+    // The layout every material's pipelines share
     pub pipeline: pipeline::Pipeline,
+    pub materials: material::Materials,
+    // Every scene pipeline goes through it, saved to disk when the renderer is dropped
+    pub pipeline_cache: pipeline_cache::PipelineCache,
+    pub shaders: shaders::ShaderLibrary,
+    // Only in development mode
+    shader_watcher: Option<shaders::ShaderWatcher>,
+    pub light_culling: cluster::LightCulling,
+    // Decides which instances Model::draw draws, the shadow passes draw all of them
+    pub frustum_culling: culling::FrustumCulling,
+    pub batches: batch::SceneBatches,
+    pub render_path: deferred::RenderPath,
+    // Only used with RenderPath::Deferred, draws into the single sampled HDR color image and
+    // ignores msaa_samples
+    pub deferred: deferred::Deferred,
     pub pools: queue::Pools,
-    pub command_buffers: Vec<CommandBuffer>,
-    pub allocator: Allocator,
-    pub models: Vec<Model<VertexData, InstanceData>>,
-    pub uniform_buffer: Buffer,
+    pub shadow_maps: shadow::ShadowMaps,
+    pub uploader: transfer::Uploader,
+    pub textures: texture::TextureStorage,
+    pub environment: ibl::Environment,
+    // Dropped by hand, it has to release its memory blocks before the device goes away
+    pub allocator: ManuallyDrop<Allocator>,
+    pub deletion_queue: deletion_queue::DeletionQueue,
+    pub models: Vec<Model<V, I>>,
+    pub lights: LightManager,
     pub descriptor_pool: DescriptorPool,
-    pub descriptor_sets_camera: Vec<vk::DescriptorSet>, 
-    pub descriptor_sets_light: Vec<vk::DescriptorSet>, 
-    pub light_buffer: Buffer,
+    pub frames: Vec<frame::FrameContext>,
+    pub current_frame: usize,
+    pub framebuffer_resized: bool,
+    pub msaa_samples: vk::SampleCountFlags,
 }
 
 impl Ceaser {
+    pub fn builder() -> CeaserBuilder {
+        CeaserBuilder::default()
+    }
+
+    #[allow(dead_code)]
     pub fn new(window: Window) -> Result<Ceaser, Box<dyn std::error::Error>> {
+        Self::builder().build(window)
+    }
+
+    #[allow(dead_code)]
+    pub fn new_headless(width: u32, height: u32) -> Result<Ceaser, Box<dyn std::error::Error>> {
+        Self::builder().build_headless(width, height)
+    }
+}
+
+impl<V: VertexLayout + Position + Copy, I: VertexLayout> Ceaser<V, I> {
+    fn init(
+        settings: CeaserBuilder,
+        window: Option<Window>,
+        headless_extent: Option<vk::Extent2D>,
+    ) -> Result<Ceaser<V, I>, Box<dyn std::error::Error>> {
         let entry = unsafe { ash::Entry::load()? };
         let layer_names = vec!["VK_LAYER_KHRONOS_validation"];
-        let instance = instance::init_instance(&entry, &layer_names, &window)?;
+        let instance = instance::init_instance(&entry, &layer_names, window.as_ref())?;
         let debug = instance::Debug::new(&entry, &instance)?;
-        let surfaces = surface::Surface::new(&window, &entry, &instance)?;
-        let device = device::Device::new(&instance)?;
+        let surfaces = match &window {
+            Some(window) => Some(surface::Surface::new(window, &entry, &instance)?),
+            None => None,
+        };
+        let device_preference =
+            device::DevicePreference::from_env().unwrap_or(settings.device_preference);
+        let device = device::Device::new(&instance, surfaces.as_ref(), &device_preference)?;
         let queue_families =
-            queue::QueueFamilies::new(&instance, device.physical_device, &surfaces)?;
+            queue::QueueFamilies::new(&instance, device.physical_device, surfaces.as_ref())?;
+        let device_extension_names = if surfaces.is_some() {
+            vec![ash::extensions::khr::Swapchain::name()]
+        } else {
+            vec![]
+        };
+        let supported_features =
+            unsafe { instance.get_physical_device_features(device.physical_device) };
+        // Only what batched drawing and wireframe materials use, when the device has it
+        let features = vk::PhysicalDeviceFeatures::builder()
+            .multi_draw_indirect(supported_features.multi_draw_indirect == vk::TRUE)
+            .draw_indirect_first_instance(supported_features.draw_indirect_first_instance == vk::TRUE)
+            .fill_mode_non_solid(supported_features.fill_mode_non_solid == vk::TRUE)
+            .build();
         let (logical_device, queues) = logical::init_device_and_queues(
             &instance,
             device.physical_device,
             &queue_families,
             &layer_names,
+            &device_extension_names,
+            &features,
         )?;
         let mut allocator = Allocator::new(&AllocatorCreateDesc {
             instance: instance.clone(),
             device: logical_device.clone(),
-            physical_device: device.physical_device.clone(),
+            physical_device: device.physical_device,
             debug_settings: Default::default(),
             buffer_device_address: false,
         })?;
 
-        let mut swapchain = swap_chain::Swapchain::new(
-            &instance,
-            device.physical_device,
+        let pipeline_cache = pipeline_cache::PipelineCache::new(
+            &logical_device,
+            &device.physical_device_properties,
+            settings.pipeline_cache_dir.as_deref(),
+        )?;
+        let shaders = shaders::ShaderLibrary::new();
+        let shader_watcher = settings.shader_dir.map(shaders::ShaderWatcher::new);
+        let msaa_samples = device.supported_sample_count(settings.msaa_samples);
+        let render_pass = render_pass::init_render_pass(
+            &logical_device,
+            post::HDR_FORMAT,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            msaa_samples,
+        )?;
+        let (mut swapchain, mut offscreen) = match &surfaces {
+            Some(surfaces) => {
+                let swapchain = swap_chain::Swapchain::new(
+                    &instance,
+                    device.physical_device,
+                    &logical_device,
+                    surfaces,
+                    &queue_families,
+                    &queues,
+                    window_extent(window.as_ref().unwrap()),
+                )?;
+                (Some(swapchain), None)
+            }
+            None => {
+                let offscreen = offscreen::Offscreen::new(
+                    &logical_device,
+                    &mut allocator,
+                    &queue_families,
+                    headless_extent.expect("headless renderer needs an extent"),
+                )?;
+                (None, Some(offscreen))
+            }
+        };
+        let (extent, format, final_layout) = match (&swapchain, &offscreen) {
+            (Some(swapchain), _) => (
+                swapchain.extent,
+                swapchain.surface_format.format,
+                vk::ImageLayout::PRESENT_SRC_KHR,
+            ),
+            (None, Some(offscreen)) => (
+                offscreen.extent,
+                offscreen::OFFSCREEN_FORMAT,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            ),
+            (None, None) => unreachable!(),
+        };
+        let hdr_target = post::HdrTarget::new(
             &logical_device,
-            &surfaces,
+            &mut allocator,
             &queue_families,
-            &queues,
+            extent,
+            msaa_samples,
+            render_pass,
+        )?;
+        let auto_exposure = exposure::AutoExposure::new(
+            &logical_device,
             &mut allocator,
+            &hdr_target,
+            pipeline_cache.cache,
+            &shaders,
         )?;
-
-        let render_pass = render_pass::init_render_pass(
+        let mut bloom = bloom::Bloom::new(
+            &logical_device,
+            &mut allocator,
+            &hdr_target,
+            extent,
+            pipeline_cache.cache,
+            &shaders,
+        )?;
+        bloom.intensity = settings.bloom_intensity;
+        bloom.threshold = settings.bloom_threshold;
+        let mut post = post::PostProcess::new(
             &logical_device,
-            surfaces
-                .get_formats(device.physical_device)?
-                .first()
-                .unwrap()
-                .format,
+            format,
+            final_layout,
+            &hdr_target,
+            &auto_exposure,
+            &bloom,
+            pipeline_cache.cache,
+            &shaders,
         )?;
+        post.tonemapper = settings.tonemapper;
+        if let Some(swapchain) = &mut swapchain {
+            swapchain.create_framebuffers(&logical_device, post.render_pass)?;
+        }
+        if let Some(offscreen) = &mut offscreen {
+            offscreen.create_framebuffer(&logical_device, post.render_pass)?;
+        }
 
-        swapchain.create_framebuffers(&logical_device, render_pass)?;
-
-        let pipeline = pipeline::Pipeline::new(&logical_device, &swapchain, &render_pass)?;
+        let pipeline = pipeline::Pipeline::new::<V, I>(&logical_device, &shaders)?;
+        let light_culling =
+            cluster::LightCulling::new(&logical_device, pipeline_cache.cache, &shaders)?;
+        let frustum_culling = culling::FrustumCulling::new(
+            &logical_device,
+            settings.frames_in_flight,
+            pipeline_cache.cache,
+            &shaders,
+        )?;
+        let batches =
+            batch::SceneBatches::new(settings.frames_in_flight, settings.batched, &features);
+        let deferred = deferred::Deferred::new(
+            &logical_device,
+            &mut allocator,
+            &queue_families,
+            &hdr_target,
+            extent,
+            &pipeline,
+            pipeline_cache.cache,
+            &shaders,
+        )?;
+        let mut materials =
+            material::Materials::new(render_pass, msaa_samples, deferred.render_pass, &features);
+        let built_in = [
+            (material::PBR, Material::pbr()),
+            (material::TRANSPARENT, Material::transparent()),
+            (material::UNLIT, Material::unlit([1.0; 4])),
+            (material::WIREFRAME, Material::wireframe([1.0; 4])),
+        ];
+        for (index, m) in built_in {
+            let added = materials.add(&logical_device, &pipeline, pipeline_cache.cache, &shaders, m)?;
+            debug_assert_eq!(added, index);
+        }
 
         let pools = queue::Pools::new(&logical_device, &queue_families)?;
-
-        let command_buffers =
-            create_command_buffers(&logical_device, &pools, swapchain.amount_of_images as usize)?;
-
-        let mut uniform_buffer = Buffer::new(
+        let uploader = transfer::Uploader::new(&logical_device, &queue_families, &queues, &pools)?;
+        let shadow_maps = shadow::ShadowMaps::new(
             &logical_device,
             &mut allocator,
-            128,
-            vk::BufferUsageFlags::UNIFORM_BUFFER,
-            gpu_allocator::MemoryLocation::CpuToGpu,
+            &queue_families,
+            &uploader,
+            pipeline_cache.cache,
+            &shaders,
+            &pipeline.vertex_bindings,
         )?;
-        let camera_transforms: [[[f32; 4]; 4]; 2] = [
-            na::Matrix4::identity().into(),
-            na::Matrix4::identity().into(),
-        ];
-        uniform_buffer.fill(&logical_device, &mut allocator, &camera_transforms)?;
-        
-        let mut light_buffer = Buffer::new(
+        let textures = texture::TextureStorage::new(
             &logical_device,
             &mut allocator,
-            8,
-            vk::BufferUsageFlags::STORAGE_BUFFER,
-            gpu_allocator::MemoryLocation::CpuToGpu,
+            &uploader,
+            pipeline.descriptor_set_layouts[2],
         )?;
-        light_buffer.fill(&logical_device, &mut allocator, &[0.,0.])?;
-
-        let pool_sizes = [
-            vk::DescriptorPoolSize {
-                ty: vk::DescriptorType::UNIFORM_BUFFER,
-                descriptor_count: swapchain.amount_of_images,
-            },
-            vk::DescriptorPoolSize {
-                ty: vk::DescriptorType::STORAGE_BUFFER,
-                descriptor_count: swapchain.amount_of_images,
-            },
-        ];
-        let descriptor_pool_info = vk::DescriptorPoolCreateInfo::builder()
-            .max_sets(2 * swapchain.amount_of_images) //
-            .pool_sizes(&pool_sizes);
-        let descriptor_pool =
-            unsafe { logical_device.create_descriptor_pool(&descriptor_pool_info, None) }?;
-
-        let desc_layouts_camera =
-            vec![pipeline.descriptor_set_layouts[0]; swapchain.amount_of_images as usize];
-        let descriptor_set_allocate_info_camera = vk::DescriptorSetAllocateInfo::builder()
-            .descriptor_pool(descriptor_pool)
-            .set_layouts(&desc_layouts_camera);
-        let descriptor_sets_camera = unsafe {
-            logical_device.allocate_descriptor_sets(&descriptor_set_allocate_info_camera)
-        }?;
-
-        for descset in &descriptor_sets_camera {
-            let buffer_infos = [vk::DescriptorBufferInfo {
-                buffer: uniform_buffer.buffer,
-                offset: 0,
-                range: 128,
-            }];
-            let desc_sets_write = [vk::WriteDescriptorSet::builder()
-                .dst_set(*descset)
-                .dst_binding(0)
-                .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
-                .buffer_info(&buffer_infos)
-                .build()];
-            unsafe { logical_device.update_descriptor_sets(&desc_sets_write, &[]) };
-        }
-        let desc_layouts_light =
-            vec![pipeline.descriptor_set_layouts[1]; swapchain.amount_of_images as usize];
-        let descriptor_set_allocate_info_light = vk::DescriptorSetAllocateInfo::builder()
-            .descriptor_pool(descriptor_pool)
-            .set_layouts(&desc_layouts_light);
-        let descriptor_sets_light = unsafe {
-            logical_device.allocate_descriptor_sets(&descriptor_set_allocate_info_light)
-        }?;
-
-        for descset in &descriptor_sets_light {
-            let buffer_infos = [vk::DescriptorBufferInfo {
-                buffer: light_buffer.buffer,
-                offset: 0,
-                range: 8,
-            }];
-            let desc_sets_write = [vk::WriteDescriptorSet::builder()
-                .dst_set(*descset)
-                .dst_binding(0)
-                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
-                .buffer_info(&buffer_infos)
-                .build()];
-            unsafe { logical_device.update_descriptor_sets(&desc_sets_write, &[]) };
+        let environment = ibl::Environment::black(
+            &logical_device,
+            &mut allocator,
+            &uploader,
+            pipeline_cache.cache,
+            &shaders,
+        )?;
+        let mut ssao = ssao::Ssao::new(
+            &logical_device,
+            &mut allocator,
+            &queue_families,
+            &uploader,
+            extent,
+            pipeline_cache.cache,
+            &shaders,
+            &pipeline.vertex_bindings,
+        )?;
+        ssao.radius = settings.ssao_radius;
+        ssao.sample_count = settings.ssao_samples.max(1);
+        ssao.enabled = settings.ssao_samples > 0;
+
+        let frames_in_flight = settings.frames_in_flight;
+        let descriptor_pool = frame::create_descriptor_pool(&logical_device, frames_in_flight as u32)?;
+        let command_buffers = create_command_buffers(&logical_device, &pools, frames_in_flight)?;
+        let mut frames = Vec::with_capacity(frames_in_flight);
+        for command_buffer in command_buffers {
+            let frame = frame::FrameContext::new(
+                &logical_device,
+                &mut allocator,
+                command_buffer,
+                descriptor_pool,
+                &pipeline,
+                &light_culling,
+                &shadow_maps,
+                &environment,
+            )?;
+            frame.write_ambient_occlusion(&logical_device, &ssao);
+            frames.push(frame);
         }
 
         Ok(Self {
@@ -189,115 +467,930 @@ impl Ceaser {
             entry,
             instance,
             debug: std::mem::ManuallyDrop::new(debug),
-            surfaces: std::mem::ManuallyDrop::new(surfaces),
+            surfaces: surfaces.map(std::mem::ManuallyDrop::new),
             device,
             queue_families,
             queues,
             logical_device,
             swapchain,
+            offscreen,
             render_pass,
+            hdr_target,
+            auto_exposure,
+            bloom,
+            post,
+            ssao,
             pipeline,
+            materials,
+            pipeline_cache,
+            shaders,
+            shader_watcher,
+            light_culling,
+            frustum_culling,
+            batches,
+            render_path: settings.render_path,
+            deferred,
             pools,
-            command_buffers,
-            allocator,
+            shadow_maps,
+            uploader,
+            textures,
+            environment,
+            allocator: ManuallyDrop::new(allocator),
+            deletion_queue: deletion_queue::DeletionQueue::new(frames_in_flight),
             models: vec![],
-            uniform_buffer,
+            lights: LightManager::default(),
             descriptor_pool,
-            descriptor_sets_camera,
-            descriptor_sets_light,
-            light_buffer
+            frames,
+            current_frame: 0,
+            framebuffer_resized: false,
+            msaa_samples,
         })
     }
 
-    pub fn update_commandbuffer(&mut self, index: usize) -> Result<(), vk::Result> {
-        let commandbuffer = self.command_buffers[index];
+    // Returns the index to put into Model::material. The built in ones are in material.rs
+    #[allow(dead_code)]
+    pub fn add_material(&mut self, material: Material) -> Result<usize, Box<dyn std::error::Error>> {
+        self.materials.add(
+            &self.logical_device,
+            &self.pipeline,
+            self.pipeline_cache.cache,
+            &self.shaders,
+            material,
+        )
+    }
+
+    pub fn set_models(&mut self, models: Vec<Model<V, I>>) {
+        for mut m in std::mem::replace(&mut self.models, models) {
+            m.retire(&mut self.deletion_queue);
+        }
+    }
+
+    // PNG or JPEG albedo, returns the index to put into Model::albedo_texture
+    pub fn load_texture<P: AsRef<std::path::Path>>(
+        &mut self,
+        path: P,
+    ) -> Result<usize, Box<dyn std::error::Error>> {
+        self.textures.load(
+            &self.logical_device,
+            &mut self.allocator,
+            &self.uploader,
+            path,
+            vk::Format::R8G8B8A8_SRGB,
+        )
+    }
+
+    // Tangent space normal map with +y up, for Model::normal_texture
+    pub fn load_normal_map<P: AsRef<std::path::Path>>(
+        &mut self,
+        path: P,
+    ) -> Result<usize, Box<dyn std::error::Error>> {
+        self.textures.load(
+            &self.logical_device,
+            &mut self.allocator,
+            &self.uploader,
+            path,
+            vk::Format::R8G8B8A8_UNORM,
+        )
+    }
+
+    // Equirectangular .hdr image used as ambient light, replacing the previous one
+    pub fn set_environment<P: AsRef<std::path::Path>>(
+        &mut self,
+        path: P,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let environment = ibl::Environment::load(
+            &self.logical_device,
+            &mut self.allocator,
+            &self.uploader,
+            self.pipeline_cache.cache,
+            &self.shaders,
+            path,
+        )?;
+        unsafe {
+            self.logical_device.device_wait_idle()?;
+        }
+        for frame in &self.frames {
+            frame.write_environment(&self.logical_device, &environment);
+        }
+        let mut old = std::mem::replace(&mut self.environment, environment);
+        unsafe { old.cleanup(&self.logical_device, &mut self.allocator) }
+    }
+
+    pub fn extent(&self) -> vk::Extent2D {
+        match (&self.swapchain, &self.offscreen) {
+            (Some(swapchain), _) => swapchain.extent,
+            (None, Some(offscreen)) => offscreen.extent,
+            (None, None) => unreachable!(),
+        }
+    }
+
+    fn framebuffer(&self, index: usize) -> vk::Framebuffer {
+        match (&self.swapchain, &self.offscreen) {
+            (Some(swapchain), _) => swapchain.framebuffers[index],
+            (None, Some(offscreen)) => offscreen.framebuffer,
+            (None, None) => unreachable!(),
+        }
+    }
+
+    pub fn update_commandbuffer(
+        &mut self,
+        frame_index: usize,
+        image_index: usize,
+    ) -> Result<(), vk::Result> {
+        let frame = &self.frames[frame_index];
+        let commandbuffer = frame.command_buffer;
+        let extent = self.extent();
         let commandbuffer_begininfo = vk::CommandBufferBeginInfo::builder();
         unsafe {
             self.logical_device
                 .begin_command_buffer(commandbuffer, &commandbuffer_begininfo)?;
-        }
-        let clearvalues = [
-            vk::ClearValue {
-                color: vk::ClearColorValue {
-                    float32: [0.0, 0.0, 0.08, 1.0],
-                },
-            },
-            vk::ClearValue {
-                depth_stencil: vk::ClearDepthStencilValue {
-                    depth: 1.0,
-                    stencil: 0,
-                },
-            },
-        ];
-        let renderpass_begininfo = vk::RenderPassBeginInfo::builder()
-            .render_pass(self.render_pass)
-            .framebuffer(self.swapchain.framebuffers[index])
-            .render_area(vk::Rect2D {
-                offset: vk::Offset2D { x: 0, y: 0 },
-                extent: self.swapchain.extent,
-            })
-            .clear_values(&clearvalues);
-        unsafe {
-            self.logical_device.cmd_begin_render_pass(
+            let shadow_passes = [
+                (&self.shadow_maps.directional, &frame.shadow_views.directional),
+                (&self.shadow_maps.point, &frame.shadow_views.point),
+            ];
+            for (target, matrices) in shadow_passes {
+                for (layer, light_matrix) in matrices.iter().enumerate() {
+                    self.shadow_maps.begin(
+                        &self.logical_device,
+                        commandbuffer,
+                        target,
+                        layer,
+                        light_matrix,
+                    );
+                    for m in self.models.iter().filter(|m| m.casts_shadows) {
+                        m.draw_all_instances(&self.logical_device, commandbuffer, frame_index);
+                    }
+                    self.shadow_maps.end(&self.logical_device, commandbuffer);
+                }
+            }
+            let cull_targets = if self.batches.active() {
+                self.batches.cull_targets(&self.models, frame_index)
+            } else {
+                culling::model_targets(&self.models, frame_index)
+            };
+            self.frustum_culling.record(
+                &self.logical_device,
+                commandbuffer,
+                &cull_targets,
+                frame_index,
+            )?;
+            self.light_culling.record(
+                &self.logical_device,
                 commandbuffer,
-                &renderpass_begininfo,
-                vk::SubpassContents::INLINE,
+                frame.descriptor_set_culling,
+                extent,
             );
-            self.logical_device.cmd_bind_pipeline(
+            if self.ssao.enabled {
+                self.ssao.record(&self.logical_device, commandbuffer, |commandbuffer| {
+                    self.draw_geometry(commandbuffer, frame_index)
+                });
+            }
+        }
+        // Where no model was drawn
+        let clear_color = [0.0, 0.0, 0.08, 1.0];
+        match self.render_path {
+            deferred::RenderPath::Forward => {
+                let clearvalues = [
+                    vk::ClearValue {
+                        color: vk::ClearColorValue {
+                            float32: clear_color,
+                        },
+                    },
+                    vk::ClearValue {
+                        depth_stencil: vk::ClearDepthStencilValue {
+                            depth: 1.0,
+                            stencil: 0,
+                        },
+                    },
+                ];
+                let renderpass_begininfo = vk::RenderPassBeginInfo::builder()
+                    .render_pass(self.render_pass)
+                    .framebuffer(self.hdr_target.framebuffer)
+                    .render_area(vk::Rect2D {
+                        offset: vk::Offset2D { x: 0, y: 0 },
+                        extent,
+                    })
+                    .clear_values(&clearvalues);
+                let viewports = [vk::Viewport {
+                    x: 0.,
+                    y: 0.,
+                    width: extent.width as f32,
+                    height: extent.height as f32,
+                    min_depth: 0.,
+                    max_depth: 1.,
+                }];
+                let scissors = [vk::Rect2D {
+                    offset: vk::Offset2D { x: 0, y: 0 },
+                    extent,
+                }];
+                unsafe {
+                    self.logical_device.cmd_begin_render_pass(
+                        commandbuffer,
+                        &renderpass_begininfo,
+                        vk::SubpassContents::INLINE,
+                    );
+                    self.logical_device
+                        .cmd_set_viewport(commandbuffer, 0, &viewports);
+                    self.logical_device
+                        .cmd_set_scissor(commandbuffer, 0, &scissors);
+                }
+                self.draw_models(commandbuffer, frame_index, ScenePass::Forward);
+            }
+            deferred::RenderPath::Deferred => {
+                self.deferred
+                    .begin(&self.logical_device, commandbuffer, extent, clear_color);
+                self.draw_models(commandbuffer, frame_index, ScenePass::GBuffer);
+                self.deferred.light(
+                    &self.logical_device,
+                    commandbuffer,
+                    frame.descriptor_set_light,
+                    self.ssao.enabled,
+                );
+                self.draw_models(commandbuffer, frame_index, ScenePass::AfterLighting);
+            }
+        }
+        unsafe {
+            self.logical_device.cmd_end_render_pass(commandbuffer);
+            if self.post.auto_exposure() {
+                self.auto_exposure
+                    .record(&self.logical_device, commandbuffer, extent);
+            }
+            if self.bloom.intensity > 0.0 {
+                self.bloom.record(&self.logical_device, commandbuffer);
+            }
+            self.post.draw(
+                &self.logical_device,
                 commandbuffer,
-                vk::PipelineBindPoint::GRAPHICS,
-                self.pipeline.pipeline,
+                self.framebuffer(image_index),
+                extent,
+                self.bloom.intensity,
             );
+            if let Some(offscreen) = &self.offscreen {
+                offscreen.record_readback(&self.logical_device, commandbuffer);
+            }
+            self.logical_device.end_command_buffer(commandbuffer)?;
+        }
+        Ok(())
+    }
 
+    // Draws the models whose material goes into this pass, sorted so that opaque models come
+    // before blended ones and each material's pipeline is bound once
+    fn draw_models(&self, commandbuffer: vk::CommandBuffer, frame_index: usize, pass: ScenePass) {
+        let frame = &self.frames[frame_index];
+        let ambient_occlusion = self.ssao.enabled as u32;
+        let push_constant_stages = self.pipeline.push_constant_range.map(|r| r.stage_flags);
+        // Indices into either the batches, which are sorted already, or the models
+        let batched = self.batches.active();
+        let mut draws: Vec<(BatchState, usize)> = if batched {
+            self.batches
+                .batches
+                .iter()
+                .enumerate()
+                .map(|(i, batch)| (batch.state, i))
+                .collect()
+        } else {
+            let mut draws: Vec<_> = self
+                .models
+                .iter()
+                .enumerate()
+                .map(|(i, m)| (BatchState::of(m, &self.materials), i))
+                .collect();
+            draws.sort_by_key(|&(state, _)| state);
+            draws
+        };
+        draws.retain(|(state, _)| {
+            pass == ScenePass::Forward || self.materials.get(state.material).deferred_pass() == pass
+        });
+        unsafe {
             self.logical_device.cmd_bind_descriptor_sets(
                 commandbuffer,
                 vk::PipelineBindPoint::GRAPHICS,
                 self.pipeline.layout,
                 0,
-                &[
-                    self.descriptor_sets_camera[index],
-                    self.descriptor_sets_light[index],
-                ],
+                &[frame.descriptor_set_camera, frame.descriptor_set_light],
                 &[],
             );
+            let mut bound_material = None;
+            for (state, index) in draws {
+                if bound_material != Some(state.material) {
+                    self.logical_device.cmd_bind_pipeline(
+                        commandbuffer,
+                        vk::PipelineBindPoint::GRAPHICS,
+                        self.materials.pipeline(state.material, self.render_path),
+                    );
+                    let parameters = self.materials.get(state.material).parameter_bytes();
+                    match push_constant_stages {
+                        Some(stages) if !parameters.is_empty() => {
+                            self.logical_device.cmd_push_constants(
+                                commandbuffer,
+                                self.pipeline.layout,
+                                stages,
+                                material::PARAMETER_OFFSET,
+                                &parameters,
+                            )
+                        }
+                        _ => {}
+                    }
+                    bound_material = Some(state.material);
+                }
+                if let Some(stages) = push_constant_stages {
+                    let flags = [state.receives_shadows as u32, ambient_occlusion];
+                    self.logical_device.cmd_push_constants(
+                        commandbuffer,
+                        self.pipeline.layout,
+                        stages,
+                        0,
+                        std::slice::from_raw_parts(flags.as_ptr() as *const u8, 8),
+                    );
+                }
+                self.logical_device.cmd_bind_descriptor_sets(
+                    commandbuffer,
+                    vk::PipelineBindPoint::GRAPHICS,
+                    self.pipeline.layout,
+                    2,
+                    &[
+                        self.textures.get(state.albedo_texture).descriptor_set,
+                        self.textures.get(state.normal_texture).descriptor_set,
+                    ],
+                    &[],
+                );
+                if batched {
+                    let batch = &self.batches.batches[index];
+                    self.batches.draw(
+                        &self.logical_device,
+                        commandbuffer,
+                        frame_index,
+                        batch.first_draw,
+                        batch.draw_count,
+                    );
+                } else {
+                    self.models[index].draw(&self.logical_device, commandbuffer, frame_index);
+                }
+            }
+        }
+    }
 
+    // Every model's culled instances without binding anything per model, for depth only passes
+    fn draw_geometry(&self, commandbuffer: vk::CommandBuffer, frame_index: usize) {
+        if self.batches.active() {
+            self.batches.draw(
+                &self.logical_device,
+                commandbuffer,
+                frame_index,
+                0,
+                self.batches.draw_count(),
+            );
+        } else {
             for m in &self.models {
-                m.draw(&self.logical_device, commandbuffer);
+                m.draw(&self.logical_device, commandbuffer, frame_index);
             }
-            self.logical_device.cmd_end_render_pass(commandbuffer);
-            self.logical_device.end_command_buffer(commandbuffer)?;
+        }
+    }
+
+    // Returns Ok(false) while there is nothing to render into, e.g. while the window is minimized
+    pub fn recreate_swapchain(&mut self) -> Result<bool, Box<dyn std::error::Error>> {
+        let window = self.window.as_ref().ok_or("headless renderer has no swapchain")?;
+        let window_extent = window_extent(window);
+        if window_extent.width == 0 || window_extent.height == 0 {
+            return Ok(false);
+        }
+        let swapchain = self.swapchain.as_mut().unwrap();
+        unsafe {
+            self.logical_device.device_wait_idle()?;
+            swapchain.recreate(
+                self.device.physical_device,
+                &self.logical_device,
+                self.surfaces.as_ref().unwrap(),
+                &self.queue_families,
+                self.post.render_pass,
+                window_extent,
+            )?;
+            self.recreate_hdr_target()?;
+        }
+        self.framebuffer_resized = false;
+        Ok(true)
+    }
+
+    // Matches the HDR target to the current extent and sample count. The caller has to make
+    // sure the device is idle.
+    unsafe fn recreate_hdr_target(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.hdr_target
+            .destroy(&self.logical_device, &mut self.allocator);
+        let extent = self.extent();
+        self.hdr_target = post::HdrTarget::new(
+            &self.logical_device,
+            &mut self.allocator,
+            &self.queue_families,
+            extent,
+            self.msaa_samples,
+            self.render_pass,
+        )?;
+        self.auto_exposure
+            .write_input(&self.logical_device, &self.hdr_target);
+        self.bloom.resize(
+            &self.logical_device,
+            &mut self.allocator,
+            &self.hdr_target,
+            extent,
+        )?;
+        self.post
+            .write_input(&self.logical_device, &self.hdr_target, &self.bloom);
+        self.ssao.resize(
+            &self.logical_device,
+            &mut self.allocator,
+            &self.queue_families,
+            &self.uploader,
+            extent,
+        )?;
+        for frame in &self.frames {
+            frame.write_ambient_occlusion(&self.logical_device, &self.ssao);
+        }
+        self.deferred.resize(
+            &self.logical_device,
+            &mut self.allocator,
+            &self.queue_families,
+            &self.hdr_target,
+            extent,
+        )?;
+        Ok(())
+    }
+
+    // Rebuilds the scene render pass, pipeline and HDR target for a new sample count. Returns the
+    // count actually in use, which is lower than requested if the device does not support it.
+    // The old pass and pipelines stay in use if the new ones cannot be built
+    pub fn set_msaa(
+        &mut self,
+        samples: vk::SampleCountFlags,
+    ) -> Result<vk::SampleCountFlags, Box<dyn std::error::Error>> {
+        let samples = self.device.supported_sample_count(samples);
+        if samples == self.msaa_samples {
+            return Ok(samples);
+        }
+        unsafe { self.logical_device.device_wait_idle()? };
+        let render_pass = render_pass::init_render_pass(
+            &self.logical_device,
+            post::HDR_FORMAT,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            samples,
+        )?;
+        unsafe {
+            // Only replaces the pipelines once all of them are built
+            if let Err(e) = self.materials.set_forward_pass(
+                &self.logical_device,
+                &self.pipeline,
+                self.pipeline_cache.cache,
+                &self.shaders,
+                render_pass,
+                samples,
+            ) {
+                self.logical_device.destroy_render_pass(render_pass, None);
+                return Err(e);
+            }
+            let old = std::mem::replace(&mut self.render_pass, render_pass);
+            self.logical_device.destroy_render_pass(old, None);
+        }
+        self.msaa_samples = samples;
+        unsafe { self.recreate_hdr_target()? };
+        Ok(samples)
+    }
+
+    // Swaps in the pipelines built from shaders changed on disk, call it between frames. Returns
+    // the shaders that were reloaded. Whatever does not compile or build keeps running as
+    // before, the error lists all of it
+    pub fn reload_shaders(&mut self) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let Some(watcher) = &mut self.shader_watcher else {
+            return Ok(Vec::new());
+        };
+        let changed = watcher.changed_files();
+        if changed.is_empty() {
+            return Ok(Vec::new());
+        }
+        let reload = self.shaders.reload(&watcher.directory, &changed);
+        let mut errors = reload.errors;
+        if !reload.reloaded.is_empty() {
+            let uses = |names: &[&str]| reload.reloaded.iter().any(|r| names.contains(r));
+            unsafe {
+                self.logical_device.device_wait_idle()?;
+                for index in 0..self.materials.count() {
+                    if !uses(&self.materials.get(index).shaders().collect::<Vec<_>>()) {
+                        continue;
+                    }
+                    if let Err(e) = self.materials.rebuild(
+                        &self.logical_device,
+                        &self.pipeline,
+                        self.pipeline_cache.cache,
+                        &self.shaders,
+                        index,
+                    ) {
+                        errors.push(format!("could not rebuild the pipelines of {}", e));
+                    }
+                }
+                if uses(&deferred::LIGHTING_SHADERS) {
+                    if let Err(e) = self.deferred.rebuild_lighting_pipeline(
+                        &self.logical_device,
+                        &self.pipeline,
+                        self.pipeline_cache.cache,
+                        &self.shaders,
+                    ) {
+                        errors.push(format!(
+                            "could not rebuild the deferred lighting pipeline: {}",
+                            e
+                        ));
+                    }
+                }
+                if uses(&shadow::SHADERS) {
+                    if let Err(e) = self.shadow_maps.rebuild_pipeline(
+                        &self.logical_device,
+                        self.pipeline_cache.cache,
+                        &self.shaders,
+                        &self.pipeline.vertex_bindings,
+                    ) {
+                        errors.push(format!("could not rebuild the shadow map pipeline: {}", e));
+                    }
+                    if let Err(e) = self.ssao.rebuild_depth_pipeline(
+                        &self.logical_device,
+                        self.pipeline_cache.cache,
+                        &self.shaders,
+                        &self.pipeline.vertex_bindings,
+                    ) {
+                        errors.push(format!("could not rebuild the SSAO depth pipeline: {}", e));
+                    }
+                }
+                if uses(&ssao::SHADERS) {
+                    if let Err(e) = self.ssao.rebuild_pipelines(
+                        &self.logical_device,
+                        self.pipeline_cache.cache,
+                        &self.shaders,
+                    ) {
+                        errors.push(format!("could not rebuild the SSAO pipelines: {}", e));
+                    }
+                }
+                if uses(&post::SHADERS) {
+                    if let Err(e) = self.post.rebuild_pipeline(
+                        &self.logical_device,
+                        self.pipeline_cache.cache,
+                        &self.shaders,
+                    ) {
+                        errors.push(format!(
+                            "could not rebuild the post processing pipeline: {}",
+                            e
+                        ));
+                    }
+                }
+                if uses(&bloom::SHADERS) {
+                    if let Err(e) = self.bloom.rebuild_pipelines(
+                        &self.logical_device,
+                        self.pipeline_cache.cache,
+                        &self.shaders,
+                    ) {
+                        errors.push(format!("could not rebuild the bloom pipelines: {}", e));
+                    }
+                }
+                if uses(&exposure::SHADERS) {
+                    if let Err(e) = self.auto_exposure.rebuild_pipelines(
+                        &self.logical_device,
+                        self.pipeline_cache.cache,
+                        &self.shaders,
+                    ) {
+                        errors.push(format!(
+                            "could not rebuild the auto exposure pipelines: {}",
+                            e
+                        ));
+                    }
+                }
+                if uses(&culling::SHADERS) {
+                    if let Err(e) = self.frustum_culling.rebuild_pipeline(
+                        &self.logical_device,
+                        self.pipeline_cache.cache,
+                        &self.shaders,
+                    ) {
+                        errors.push(format!(
+                            "could not rebuild the frustum culling pipeline: {}",
+                            e
+                        ));
+                    }
+                }
+                if uses(&cluster::SHADERS) {
+                    if let Err(e) = self.light_culling.rebuild_pipeline(
+                        &self.logical_device,
+                        self.pipeline_cache.cache,
+                        &self.shaders,
+                    ) {
+                        errors.push(format!(
+                            "could not rebuild the light culling pipeline: {}",
+                            e
+                        ));
+                    }
+                }
+            }
+        }
+        if errors.is_empty() {
+            Ok(reload.reloaded.iter().map(|name| name.to_string()).collect())
+        } else {
+            Err(errors.join("\n").into())
+        }
+    }
+
+    // Waits until the GPU is done with the next frame slot and uploads everything the CPU
+    // writes per frame into that slot's own buffers
+    fn begin_frame(&mut self, camera: &Camera) -> Result<usize, Box<dyn std::error::Error>> {
+        let frame_index = self.current_frame;
+        let frame = &mut self.frames[frame_index];
+        unsafe {
+            self.logical_device
+                .wait_for_fences(&[frame.may_begin_drawing], true, u64::MAX)?;
+        }
+        unsafe {
+            self.deletion_queue
+                .collect(&self.logical_device, &mut self.allocator)?;
+        }
+        camera.update_buffer(&mut frame.uniform_buffer);
+        self.post.set_exposure(camera);
+        self.ssao.set_camera(camera);
+        self.deferred.set_camera(camera);
+        self.light_culling.set_camera(camera);
+        self.frustum_culling.set_camera(camera);
+        frame.shadow_views = self
+            .lights
+            .update_shadow_buffer(camera, &mut frame.shadow_buffer)?;
+        self.lights.update_buffer(
+            &self.logical_device,
+            &mut self.allocator,
+            &mut self.deletion_queue,
+            camera,
+            &mut frame.light_buffer,
+            &mut [frame.descriptor_set_light, frame.descriptor_set_culling],
+        )?;
+        for m in &mut self.models {
+            if m.bounding_sphere.is_none() {
+                m.update_bounding_sphere();
+                self.batches.invalidate_meshes();
+            }
+            m.update_instancebuffer(
+                &self.logical_device,
+                &mut self.allocator,
+                &mut self.deletion_queue,
+                frame_index,
+            )?;
+        }
+        if self.batches.active() {
+            self.batches.update(
+                &self.logical_device,
+                &mut self.allocator,
+                &self.uploader,
+                &mut self.deletion_queue,
+                &self.models,
+                &self.materials,
+                frame_index,
+            )?;
+        }
+        Ok(frame_index)
+    }
+
+    pub fn render_frame(&mut self, camera: &mut Camera) -> Result<(), Box<dyn std::error::Error>> {
+        if self.framebuffer_resized {
+            if !self.recreate_swapchain()? {
+                return Ok(());
+            }
+            let extent = self.extent();
+            camera.set_aspect(extent.width as f32 / extent.height as f32);
+        }
+
+        let swapchain = self
+            .swapchain
+            .as_ref()
+            .ok_or("headless renderer has no swapchain, use render_offscreen")?;
+        let frame = &self.frames[self.current_frame];
+        let (image_available, rendering_finished, may_begin_drawing) = (
+            frame.image_available,
+            frame.rendering_finished,
+            frame.may_begin_drawing,
+        );
+        unsafe {
+            self.logical_device
+                .wait_for_fences(&[may_begin_drawing], true, u64::MAX)?;
+        }
+        let image_index = match unsafe {
+            swapchain.swapchain_loader.acquire_next_image(
+                swapchain.swapchain,
+                u64::MAX,
+                image_available,
+                vk::Fence::null(),
+            )
+        } {
+            // A suboptimal image is still presentable, the rebuild happens after presenting it
+            Ok((image_index, suboptimal)) => {
+                self.framebuffer_resized |= suboptimal;
+                image_index
+            }
+            Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => {
+                self.framebuffer_resized = true;
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let frame_index = self.begin_frame(camera)?;
+        self.update_commandbuffer(frame_index, image_index as usize)?;
+
+        let semaphores_available = [image_available];
+        let waiting_stages = [vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT];
+        let semaphores_finished = [rendering_finished];
+        let command_buffers = [self.frames[frame_index].command_buffer];
+        let submit_info = [vk::SubmitInfo::builder()
+            .wait_semaphores(&semaphores_available)
+            .wait_dst_stage_mask(&waiting_stages)
+            .command_buffers(&command_buffers)
+            .signal_semaphores(&semaphores_finished)
+            .build()];
+        unsafe {
+            // Only reset once work is guaranteed to be submitted, otherwise the next wait hangs
+            self.logical_device.reset_fences(&[may_begin_drawing])?;
+            self.logical_device.queue_submit(
+                self.queues.graphics_queue,
+                &submit_info,
+                may_begin_drawing,
+            )?;
+        }
+        self.deletion_queue.frame_submitted();
+        self.current_frame = (self.current_frame + 1) % self.frames.len();
+
+        let swapchain = self.swapchain.as_ref().unwrap();
+        let swapchains = [swapchain.swapchain];
+        let indices = [image_index];
+        let present_info = vk::PresentInfoKHR::builder()
+            .wait_semaphores(&semaphores_finished)
+            .swapchains(&swapchains)
+            .image_indices(&indices);
+        match unsafe {
+            swapchain
+                .swapchain_loader
+                .queue_present(self.queues.graphics_queue, &present_info)
+        } {
+            Ok(suboptimal) => self.framebuffer_resized |= suboptimal,
+            Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => self.framebuffer_resized = true,
+            Err(e) => return Err(e.into()),
         }
         Ok(())
     }
+
+    pub fn render_offscreen(&mut self, camera: &Camera) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        if self.offscreen.is_none() {
+            return Err("renderer was not created with new_headless".into());
+        }
+        let frame_index = self.begin_frame(camera)?;
+        self.update_commandbuffer(frame_index, 0)?;
+        let fence = self.frames[frame_index].may_begin_drawing;
+        let command_buffers = [self.frames[frame_index].command_buffer];
+        let submit_info = [vk::SubmitInfo::builder()
+            .command_buffers(&command_buffers)
+            .build()];
+        unsafe {
+            self.logical_device.reset_fences(&[fence])?;
+            self.logical_device
+                .queue_submit(self.queues.graphics_queue, &submit_info, fence)?;
+            self.deletion_queue.frame_submitted();
+            self.logical_device
+                .wait_for_fences(&[fence], true, u64::MAX)?;
+        }
+        self.current_frame = (self.current_frame + 1) % self.frames.len();
+        Ok(self.offscreen.as_ref().unwrap().read_pixels())
+    }
 }
 
-impl Drop for Ceaser {
+impl<V, I> Drop for Ceaser<V, I> {
     fn drop(&mut self) {
         unsafe {
             self.logical_device
                 .device_wait_idle()
                 .expect("something wrong while waiting");
-            for m in &self.models {
-                if let Some(_vb) = &m.vertexbuffer {
-                    //Do later
-                    println!("Remove Buffer vb")
-                }
-                if let Some(_ixb) = &m.indexbuffer {
-                    println!("Remove Buffer ixb")
-                }
-                if let Some(_ib) = &m.instancebuffer {
-                    println!("Remove Buffer ib")
-                }
+            // Losing the cache only costs compile time on the next launch
+            if let Err(e) = self.pipeline_cache.save(&self.logical_device) {
+                eprintln!("could not save the pipeline cache: {}", e);
             }
+            for m in &mut self.models {
+                m.cleanup(&self.logical_device, &mut self.allocator)
+                    .expect("freeing model buffers");
+            }
+            self.deletion_queue
+                .cleanup(&self.logical_device, &mut self.allocator)
+                .expect("freeing retired buffers");
+            for frame in &mut self.frames {
+                frame
+                    .cleanup(&self.logical_device, &mut self.allocator)
+                    .expect("freeing frame resources");
+            }
+            self.logical_device
+                .destroy_descriptor_pool(self.descriptor_pool, None);
+            self.textures
+                .cleanup(&self.logical_device, &mut self.allocator)
+                .expect("freeing textures");
+            self.environment
+                .cleanup(&self.logical_device, &mut self.allocator)
+                .expect("freeing the environment");
+            self.uploader.cleanup(&self.logical_device);
             self.pools.cleanup(&self.logical_device);
+            self.shadow_maps
+                .cleanup(&self.logical_device, &mut self.allocator);
+            self.deferred
+                .cleanup(&self.logical_device, &mut self.allocator);
+            self.light_culling.cleanup(&self.logical_device);
+            self.frustum_culling.cleanup(&self.logical_device);
+            self.batches
+                .cleanup(&self.logical_device, &mut self.allocator)
+                .expect("freeing batch buffers");
+            self.materials.cleanup(&self.logical_device);
             self.pipeline.cleanup(&self.logical_device);
+            self.pipeline_cache.cleanup(&self.logical_device);
             self.logical_device
                 .destroy_render_pass(self.render_pass, None);
-            self.swapchain.cleanup(&self.logical_device);
+            self.post.cleanup(&self.logical_device);
+            self.auto_exposure
+                .cleanup(&self.logical_device, &mut self.allocator)
+                .expect("freeing auto exposure buffers");
+            self.bloom
+                .cleanup(&self.logical_device, &mut self.allocator)
+                .expect("freeing the bloom chain");
+            self.hdr_target
+                .destroy(&self.logical_device, &mut self.allocator);
+            self.ssao
+                .cleanup(&self.logical_device, &mut self.allocator)
+                .expect("freeing ambient occlusion");
+            if let Some(swapchain) = &mut self.swapchain {
+                swapchain.cleanup(&self.logical_device);
+            }
+            if let Some(offscreen) = &mut self.offscreen {
+                offscreen.cleanup(&self.logical_device, &mut self.allocator);
+            }
+            ManuallyDrop::drop(&mut self.allocator);
             self.logical_device.destroy_device(None);
-            std::mem::ManuallyDrop::drop(&mut self.surfaces);
+            if let Some(surfaces) = &mut self.surfaces {
+                std::mem::ManuallyDrop::drop(surfaces);
+            }
             std::mem::ManuallyDrop::drop(&mut self.debug);
             self.instance.destroy_instance(None)
         };
     }
 }
+
+fn window_extent(window: &Window) -> vk::Extent2D {
+    let size = window.inner_size();
+    vk::Extent2D {
+        width: size.width,
+        height: size.height,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra as na;
+    use oberon_derive::VertexLayout;
+
+    // InstanceData with an attribute no built-in shader reads, which changes the stride
+    #[derive(VertexLayout)]
+    #[repr(C)]
+    struct TaggedInstance {
+        #[vertex(location = 2)]
+        model_matrix: [[f32; 4]; 4],
+        inverse_model_matrix: [[f32; 4]; 4],
+        color: [f32; 3],
+        metallic: f32,
+        roughness: f32,
+        tag: [f32; 4],
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn renders_a_custom_instance_layout() {
+        let (width, height) = (64, 64);
+        let mut ceaser: Ceaser<VertexData, TaggedInstance> =
+            Ceaser::builder().build_headless(width, height).unwrap();
+        let mut sphere = Model::sphere(3);
+        sphere.material = material::UNLIT;
+        let model_matrix = na::Matrix4::new_scaling(0.5);
+        sphere.insert_visibly(TaggedInstance {
+            model_matrix: model_matrix.into(),
+            inverse_model_matrix: model_matrix.try_inverse().unwrap().into(),
+            color: [1.0, 0.0, 0.0],
+            metallic: 0.0,
+            roughness: 1.0,
+            tag: [0.0, 1.0, 2.0, 3.0],
+        });
+        ceaser.set_models(vec![sphere]);
+
+        // Undoes the sunny 16 default, so the unlit color comes out close to itself
+        let camera = Camera::builder()
+            .aspect(width as f32 / height as f32)
+            .exposure_compensation(15.0)
+            .build();
+        let pixels = ceaser.render_offscreen(&camera).unwrap();
+        let pixel = |x: u32, y: u32| {
+            let i = 4 * (y * width + x) as usize;
+            [pixels[i], pixels[i + 1], pixels[i + 2]]
+        };
+        let [r, g, b] = pixel(width / 2, height / 2);
+        assert!(r > 128 && g < 32 && b < 32, "centre is {:?}", [r, g, b]);
+        assert_eq!(pixel(0, 0)[0], 0, "the background is not black");
+    }
+}
@@ -0,0 +1,82 @@
+use ash::vk;
+
+// A device-local image with a single view, used for render targets
+pub struct Attachment {
+    pub image: vk::Image,
+    pub allocation: gpu_allocator::vulkan::Allocation,
+    pub imageview: vk::ImageView,
+}
+
+impl Attachment {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+        queuefamilies: &[u32],
+        extent: vk::Extent2D,
+        format: vk::Format,
+        usage: vk::ImageUsageFlags,
+        samples: vk::SampleCountFlags,
+        name: &str,
+    ) -> Result<Attachment, Box<dyn std::error::Error>> {
+        let image_info = vk::ImageCreateInfo::builder()
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(format)
+            .extent(vk::Extent3D {
+                width: extent.width,
+                height: extent.height,
+                depth: 1,
+            })
+            .mip_levels(1)
+            .array_layers(1)
+            .samples(samples)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(usage)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .queue_family_indices(queuefamilies);
+        let image = unsafe { logical_device.create_image(&image_info, None) }?;
+        let requirements = unsafe { logical_device.get_image_memory_requirements(image) };
+        let allocation = allocator.allocate(&gpu_allocator::vulkan::AllocationCreateDesc {
+            name,
+            requirements,
+            location: gpu_allocator::MemoryLocation::GpuOnly,
+            linear: false,
+        })?;
+        unsafe { logical_device.bind_image_memory(image, allocation.memory(), allocation.offset()) }?;
+
+        let aspect_mask = if usage.contains(vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT) {
+            vk::ImageAspectFlags::DEPTH
+        } else {
+            vk::ImageAspectFlags::COLOR
+        };
+        let subresource_range = vk::ImageSubresourceRange::builder()
+            .aspect_mask(aspect_mask)
+            .base_mip_level(0)
+            .level_count(1)
+            .base_array_layer(0)
+            .layer_count(1);
+        let imageview_create_info = vk::ImageViewCreateInfo::builder()
+            .image(image)
+            .view_type(vk::ImageViewType::TYPE_2D)
+            .format(format)
+            .subresource_range(*subresource_range);
+        let imageview = unsafe { logical_device.create_image_view(&imageview_create_info, None) }?;
+        Ok(Attachment {
+            image,
+            allocation,
+            imageview,
+        })
+    }
+
+    pub unsafe fn destroy(
+        &mut self,
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+    ) {
+        logical_device.destroy_image_view(self.imageview, None);
+        logical_device.destroy_image(self.image, None);
+        allocator
+            .free(std::mem::take(&mut self.allocation))
+            .expect("freeing an attachment");
+    }
+}
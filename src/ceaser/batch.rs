@@ -0,0 +1,331 @@
+use ash::vk;
+
+use crate::ceaser::{
+    buffer::Buffer, culling::CullTarget, deletion_queue::DeletionQueue, material::Materials,
+    transfer::Uploader,
+};
+use crate::hamlet::Model;
+
+const DRAW_COMMAND_SIZE: u64 = std::mem::size_of::<vk::DrawIndexedIndirectCommand>() as u64;
+
+// Models that agree on all of this end up in the same batch. Sorted by it, opaque models come
+// first and models of the same material are next to each other
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BatchState {
+    pub blended: bool,
+    pub material: usize,
+    pub albedo_texture: usize,
+    pub normal_texture: usize,
+    pub receives_shadows: bool,
+}
+
+impl BatchState {
+    pub fn of<V, I>(model: &Model<V, I>, materials: &Materials) -> BatchState {
+        BatchState {
+            blended: materials.get(model.material).state.blended,
+            material: model.material,
+            albedo_texture: model.albedo_texture,
+            normal_texture: model.normal_texture,
+            receives_shadows: model.receives_shadows,
+        }
+    }
+}
+
+// A run of draw commands in the indirect buffer, drawn with one call
+pub struct Batch {
+    pub state: BatchState,
+    pub first_draw: u32,
+    pub draw_count: u32,
+}
+
+struct Draw {
+    model: usize,
+    first_instance: u32,
+}
+
+// Packs every mesh into one vertex and one index buffer and every visible instance into one
+// instance buffer, so the scene draws with one cmd_draw_indexed_indirect per batch instead of
+// binding buffers model by model. With the default textures that is a single call. Works with
+// any vertex and instance type, as long as every call sees the same ones
+pub struct SceneBatches {
+    pub enabled: bool,
+    // Needs drawIndirectFirstInstance, without it the draws cannot share the instance buffer
+    pub supported: bool,
+    // Without multiDrawIndirect every draw command is its own call, still sharing the buffers
+    multi_draw: bool,
+    vertexbuffer: Option<Buffer>,
+    indexbuffer: Option<Buffer>,
+    // Per model, its vertex and index count when the shared buffers were built, and where its
+    // mesh starts in them
+    mesh_sizes: Vec<(usize, usize)>,
+    mesh_offsets: Vec<(i32, u32)>,
+    // Rebuilt every frame, sorted by BatchState
+    draws: Vec<Draw>,
+    pub batches: Vec<Batch>,
+    // Per frame in flight, filled by the culling pass
+    visiblebuffers: Vec<Option<Buffer>>,
+    indirectbuffers: Vec<Option<Buffer>>,
+}
+
+impl SceneBatches {
+    pub fn new(
+        frames_in_flight: usize,
+        enabled: bool,
+        features: &vk::PhysicalDeviceFeatures,
+    ) -> SceneBatches {
+        SceneBatches {
+            enabled,
+            supported: features.draw_indirect_first_instance == vk::TRUE,
+            multi_draw: features.multi_draw_indirect == vk::TRUE,
+            vertexbuffer: None,
+            indexbuffer: None,
+            mesh_sizes: Vec::new(),
+            mesh_offsets: Vec::new(),
+            draws: Vec::new(),
+            batches: Vec::new(),
+            visiblebuffers: (0..frames_in_flight).map(|_| None).collect(),
+            indirectbuffers: (0..frames_in_flight).map(|_| None).collect(),
+        }
+    }
+
+    pub fn active(&self) -> bool {
+        self.enabled && self.supported
+    }
+
+    // For meshes edited without changing their vertex or index count
+    pub fn invalidate_meshes(&mut self) {
+        self.mesh_sizes.clear();
+    }
+
+    // After the models' instance buffers are updated for the frame. Meshes are copied again
+    // when a model's vertex or index count changes or after invalidate_meshes
+    #[allow(clippy::too_many_arguments)]
+    pub fn update<V: Copy, I>(
+        &mut self,
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+        uploader: &Uploader,
+        deletion_queue: &mut DeletionQueue,
+        models: &[Model<V, I>],
+        materials: &Materials,
+        frame_index: usize,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mesh_sizes: Vec<_> = models
+            .iter()
+            .map(|m| (m.vertexdata.len(), m.indexdata.len()))
+            .collect();
+        if mesh_sizes != self.mesh_sizes {
+            self.upload_meshes(logical_device, allocator, uploader, deletion_queue, models)?;
+            self.mesh_sizes = mesh_sizes;
+        }
+
+        // A draw the culling pass has no instance buffer for would read a command nobody wrote
+        let mut order: Vec<usize> = (0..models.len())
+            .filter(|&i| {
+                let m = &models[i];
+                m.first_invisible > 0
+                    && !m.indexdata.is_empty()
+                    && m.instancebuffers.get(frame_index).is_some_and(Option::is_some)
+            })
+            .collect();
+        order.sort_by_key(|&i| BatchState::of(&models[i], materials));
+        self.draws.clear();
+        self.batches.clear();
+        let mut instance_count = 0;
+        for model in order {
+            let state = BatchState::of(&models[model], materials);
+            match self.batches.last_mut() {
+                Some(batch) if batch.state == state => batch.draw_count += 1,
+                _ => self.batches.push(Batch {
+                    state,
+                    first_draw: self.draws.len() as u32,
+                    draw_count: 1,
+                }),
+            }
+            self.draws.push(Draw {
+                model,
+                first_instance: instance_count,
+            });
+            instance_count += models[model].first_invisible as u32;
+        }
+
+        // Only the GPU writes these, so they are grown without copying anything over
+        let sizes = [
+            (
+                &mut self.visiblebuffers[frame_index],
+                instance_count as u64 * std::mem::size_of::<I>() as u64,
+                vk::BufferUsageFlags::VERTEX_BUFFER | vk::BufferUsageFlags::STORAGE_BUFFER,
+            ),
+            (
+                &mut self.indirectbuffers[frame_index],
+                self.draws.len() as u64 * DRAW_COMMAND_SIZE,
+                vk::BufferUsageFlags::INDIRECT_BUFFER
+                    | vk::BufferUsageFlags::STORAGE_BUFFER
+                    | vk::BufferUsageFlags::TRANSFER_DST,
+            ),
+        ];
+        for (slot, bytes, usage) in sizes {
+            let size = slot.as_ref().map_or(0, |buffer| buffer.size_in_bytes);
+            if size < bytes {
+                let buffer = Buffer::new(
+                    logical_device,
+                    allocator,
+                    bytes,
+                    usage,
+                    gpu_allocator::MemoryLocation::GpuOnly,
+                )?;
+                if let Some(old) = slot.replace(buffer) {
+                    deletion_queue.retire_buffer(old);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn upload_meshes<V: Copy, I>(
+        &mut self,
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+        uploader: &Uploader,
+        deletion_queue: &mut DeletionQueue,
+        models: &[Model<V, I>],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut vertexdata: Vec<V> = Vec::new();
+        let mut indexdata: Vec<u32> = Vec::new();
+        self.mesh_offsets.clear();
+        for m in models {
+            self.mesh_offsets
+                .push((vertexdata.len() as i32, indexdata.len() as u32));
+            vertexdata.extend_from_slice(&m.vertexdata);
+            indexdata.extend_from_slice(&m.indexdata);
+        }
+        for old in self.vertexbuffer.take().into_iter().chain(self.indexbuffer.take()) {
+            deletion_queue.retire_buffer(old);
+        }
+        if !indexdata.is_empty() {
+            self.vertexbuffer = Some(uploader.upload(
+                logical_device,
+                allocator,
+                &vertexdata,
+                vk::BufferUsageFlags::VERTEX_BUFFER,
+            )?);
+            self.indexbuffer = Some(uploader.upload(
+                logical_device,
+                allocator,
+                &indexdata,
+                vk::BufferUsageFlags::INDEX_BUFFER,
+            )?);
+        }
+        Ok(())
+    }
+
+    // The culling pass writes each draw's instances into its range of the visible buffer and its
+    // command, so every draw needs a target
+    pub fn cull_targets<'a, V, I>(
+        &'a self,
+        models: &'a [Model<V, I>],
+        frame_index: usize,
+    ) -> Vec<CullTarget<'a, V, I>> {
+        let (Some(visiblebuffer), Some(indirectbuffer)) = (
+            &self.visiblebuffers[frame_index],
+            &self.indirectbuffers[frame_index],
+        ) else {
+            return Vec::new();
+        };
+        self.draws
+            .iter()
+            .enumerate()
+            .map(|(draw_index, draw)| {
+                let model = &models[draw.model];
+                let (vertex_offset, first_index) = self.mesh_offsets[draw.model];
+                CullTarget {
+                    model,
+                    instancebuffer: model.instancebuffers[frame_index]
+                        .as_ref()
+                        .expect("update only keeps models with an instance buffer"),
+                    visiblebuffer,
+                    indirectbuffer,
+                    draw_index: draw_index as u32,
+                    first_instance: draw.first_instance,
+                    vertex_offset,
+                    first_index,
+                }
+            })
+            .collect()
+    }
+
+    // Draws a run of draw commands, pass 0 and draw_count() for the whole scene
+    pub fn draw(
+        &self,
+        logical_device: &ash::Device,
+        commandbuffer: vk::CommandBuffer,
+        frame_index: usize,
+        first_draw: u32,
+        draw_count: u32,
+    ) {
+        if draw_count == 0 {
+            return;
+        }
+        let buffers = (
+            &self.vertexbuffer,
+            &self.indexbuffer,
+            &self.visiblebuffers[frame_index],
+            &self.indirectbuffers[frame_index],
+        );
+        if let (Some(vertexbuffer), Some(indexbuffer), Some(visiblebuffer), Some(indirectbuffer)) =
+            buffers
+        {
+            unsafe {
+                logical_device.cmd_bind_index_buffer(
+                    commandbuffer,
+                    indexbuffer.buffer,
+                    0,
+                    vk::IndexType::UINT32,
+                );
+                logical_device.cmd_bind_vertex_buffers(commandbuffer, 0, &[vertexbuffer.buffer], &[0]);
+                logical_device.cmd_bind_vertex_buffers(commandbuffer, 1, &[visiblebuffer.buffer], &[0]);
+                let offset = first_draw as u64 * DRAW_COMMAND_SIZE;
+                if self.multi_draw {
+                    logical_device.cmd_draw_indexed_indirect(
+                        commandbuffer,
+                        indirectbuffer.buffer,
+                        offset,
+                        draw_count,
+                        DRAW_COMMAND_SIZE as u32,
+                    );
+                } else {
+                    for draw in 0..draw_count as u64 {
+                        logical_device.cmd_draw_indexed_indirect(
+                            commandbuffer,
+                            indirectbuffer.buffer,
+                            offset + draw * DRAW_COMMAND_SIZE,
+                            1,
+                            DRAW_COMMAND_SIZE as u32,
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn draw_count(&self) -> u32 {
+        self.draws.len() as u32
+    }
+
+    pub unsafe fn cleanup(
+        &mut self,
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let buffers = self
+            .vertexbuffer
+            .iter_mut()
+            .chain(self.indexbuffer.iter_mut())
+            .chain(self.visiblebuffers.iter_mut().flatten())
+            .chain(self.indirectbuffers.iter_mut().flatten());
+        for buffer in buffers {
+            buffer.destroy(logical_device, allocator)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,417 @@
+use ash::vk;
+
+use crate::ceaser::{
+    ibl,
+    post::{HdrTarget, HDR_FORMAT},
+    shaders::ShaderLibrary,
+    texture::Image,
+};
+
+// Upper limit, small targets get fewer levels
+pub const MAX_BLOOM_MIP_LEVELS: u32 = 6;
+pub const SHADERS: [&str; 2] = ["bloom_downsample.comp", "bloom_upsample.comp"];
+
+// Physically based bloom after Jimenez, "Next Generation Post Processing in Call of Duty:
+// Advanced Warfare". The HDR target is downsampled into a mip chain with a 13 tap filter,
+// then upsampled back with a tent filter, adding every level onto the next larger one.
+// The post pass blends level 0 into the scene before exposure and tonemapping.
+pub struct Bloom {
+    // Share of the blurred image in the final radiance, 0 turns bloom off
+    pub intensity: f32,
+    // Radiance below this is left out of the bloom, in scene units. 0 blooms everything
+    pub threshold: f32,
+    // Width of the transition around the threshold
+    pub soft_knee: f32,
+    // Upsampling tent radius in texels of the smaller level
+    pub filter_radius: f32,
+    chain: Image,
+    level_views: Vec<vk::ImageView>,
+    down_sets: Vec<vk::DescriptorSet>,
+    up_sets: Vec<vk::DescriptorSet>,
+    pub sampler: vk::Sampler,
+    descriptor_pool: vk::DescriptorPool,
+    descriptor_set_layout: vk::DescriptorSetLayout,
+    pipeline_layout: vk::PipelineLayout,
+    downsample_pipeline: vk::Pipeline,
+    upsample_pipeline: vk::Pipeline,
+}
+
+impl Bloom {
+    pub fn new(
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+        hdr_target: &HdrTarget,
+        extent: vk::Extent2D,
+        cache: vk::PipelineCache,
+        shaders: &ShaderLibrary,
+    ) -> Result<Bloom, Box<dyn std::error::Error>> {
+        let bindings = [
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(0)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::COMPUTE)
+                .build(),
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(1)
+                .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::COMPUTE)
+                .build(),
+        ];
+        let set_layout_info = vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings);
+        let descriptor_set_layout =
+            unsafe { logical_device.create_descriptor_set_layout(&set_layout_info, None) }?;
+        let set_layouts = [descriptor_set_layout];
+        let push_constant_ranges = [vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::COMPUTE,
+            offset: 0,
+            size: 16,
+        }];
+        let layout_info = vk::PipelineLayoutCreateInfo::builder()
+            .set_layouts(&set_layouts)
+            .push_constant_ranges(&push_constant_ranges);
+        let pipeline_layout = unsafe { logical_device.create_pipeline_layout(&layout_info, None) }?;
+        let pipelines = ibl::create_compute_pipelines(
+            logical_device,
+            pipeline_layout,
+            cache,
+            shaders,
+            &SHADERS,
+        )?;
+
+        let sampler_info = vk::SamplerCreateInfo::builder()
+            .mag_filter(vk::Filter::LINEAR)
+            .min_filter(vk::Filter::LINEAR)
+            .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE);
+        let sampler = unsafe { logical_device.create_sampler(&sampler_info, None) }?;
+
+        // One set per downsample and per upsample dispatch
+        let set_count = 2 * MAX_BLOOM_MIP_LEVELS;
+        let pool_sizes = [
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                descriptor_count: set_count,
+            },
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::STORAGE_IMAGE,
+                descriptor_count: set_count,
+            },
+        ];
+        let pool_info = vk::DescriptorPoolCreateInfo::builder()
+            .max_sets(set_count)
+            .pool_sizes(&pool_sizes);
+        let descriptor_pool = unsafe { logical_device.create_descriptor_pool(&pool_info, None) }?;
+
+        let (chain, level_views) = create_chain(logical_device, allocator, extent)?;
+        let mut bloom = Bloom {
+            intensity: 0.04,
+            threshold: 0.0,
+            soft_knee: 0.5,
+            filter_radius: 1.0,
+            chain,
+            level_views,
+            down_sets: vec![],
+            up_sets: vec![],
+            sampler,
+            descriptor_pool,
+            descriptor_set_layout,
+            pipeline_layout,
+            downsample_pipeline: pipelines[0],
+            upsample_pipeline: pipelines[1],
+        };
+        bloom.write_sets(logical_device, hdr_target)?;
+        Ok(bloom)
+    }
+
+    // Rebuilds the mip chain for a new target size. The caller has to make sure the device
+    // is idle.
+    pub unsafe fn resize(
+        &mut self,
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+        hdr_target: &HdrTarget,
+        extent: vk::Extent2D,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.destroy_chain(logical_device, allocator)?;
+        logical_device
+            .reset_descriptor_pool(self.descriptor_pool, vk::DescriptorPoolResetFlags::empty())?;
+        let (chain, level_views) = create_chain(logical_device, allocator, extent)?;
+        self.chain = chain;
+        self.level_views = level_views;
+        self.write_sets(logical_device, hdr_target)?;
+        Ok(())
+    }
+
+    // Level 0 of the chain, in GENERAL layout once record has run
+    pub fn view(&self) -> vk::ImageView {
+        self.level_views[0]
+    }
+
+    fn write_sets(
+        &mut self,
+        logical_device: &ash::Device,
+        hdr_target: &HdrTarget,
+    ) -> Result<(), vk::Result> {
+        let levels = self.level_views.len();
+        let layouts = vec![self.descriptor_set_layout; 2 * levels - 1];
+        let allocate_info = vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(self.descriptor_pool)
+            .set_layouts(&layouts);
+        let mut sets = unsafe { logical_device.allocate_descriptor_sets(&allocate_info) }?;
+        self.up_sets = sets.split_off(levels);
+        self.down_sets = sets;
+
+        // Downsampling reads the HDR target or the previous level and writes the next one,
+        // upsampling reads level i + 1 and adds onto level i
+        let mut sources = vec![(hdr_target.color.imageview, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)];
+        sources.extend(
+            self.level_views[..levels - 1]
+                .iter()
+                .map(|&view| (view, vk::ImageLayout::GENERAL)),
+        );
+        let mut dispatches: Vec<(vk::DescriptorSet, (vk::ImageView, vk::ImageLayout), vk::ImageView)> =
+            self.down_sets
+                .iter()
+                .zip(sources)
+                .zip(&self.level_views)
+                .map(|((&set, source), &target)| (set, source, target))
+                .collect();
+        for (level, &set) in self.up_sets.iter().enumerate() {
+            dispatches.push((
+                set,
+                (self.level_views[level + 1], vk::ImageLayout::GENERAL),
+                self.level_views[level],
+            ));
+        }
+        for (set, (source, source_layout), target) in dispatches {
+            let source_infos = [vk::DescriptorImageInfo {
+                sampler: self.sampler,
+                image_view: source,
+                image_layout: source_layout,
+            }];
+            let target_infos = [vk::DescriptorImageInfo {
+                sampler: vk::Sampler::null(),
+                image_view: target,
+                image_layout: vk::ImageLayout::GENERAL,
+            }];
+            let writes = [
+                vk::WriteDescriptorSet::builder()
+                    .dst_set(set)
+                    .dst_binding(0)
+                    .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                    .image_info(&source_infos)
+                    .build(),
+                vk::WriteDescriptorSet::builder()
+                    .dst_set(set)
+                    .dst_binding(1)
+                    .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                    .image_info(&target_infos)
+                    .build(),
+            ];
+            unsafe { logical_device.update_descriptor_sets(&writes, &[]) };
+        }
+        Ok(())
+    }
+
+    // Recorded between the scene pass and the post pass
+    pub fn record(&self, logical_device: &ash::Device, commandbuffer: vk::CommandBuffer) {
+        let levels = self.level_views.len() as u32;
+        let extent = self.chain.extent;
+        // The chain is rebuilt from scratch every frame, but the previous frame's post pass
+        // may still be reading it
+        let to_general = [vk::ImageMemoryBarrier::builder()
+            .image(self.chain.image)
+            .src_access_mask(vk::AccessFlags::empty())
+            .dst_access_mask(vk::AccessFlags::SHADER_WRITE)
+            .old_layout(vk::ImageLayout::UNDEFINED)
+            .new_layout(vk::ImageLayout::GENERAL)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .subresource_range(vk::ImageSubresourceRange {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                base_mip_level: 0,
+                level_count: levels,
+                base_array_layer: 0,
+                layer_count: 1,
+            })
+            .build()];
+        let level_done = [vk::MemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE)
+            .build()];
+        let chain_done = [vk::MemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ)
+            .build()];
+        // Laid out like the push constant blocks in shaders/bloom_*.comp
+        let push_constants = |first_pass: bool| {
+            let mut bytes = [0u8; 16];
+            bytes[0..4].copy_from_slice(&self.threshold.to_ne_bytes());
+            bytes[4..8].copy_from_slice(&self.soft_knee.to_ne_bytes());
+            bytes[8..12].copy_from_slice(&(first_pass as u32).to_ne_bytes());
+            bytes[12..16].copy_from_slice(&self.filter_radius.to_ne_bytes());
+            bytes
+        };
+        let dispatch = |set: vk::DescriptorSet, level: u32, first_pass: bool| unsafe {
+            logical_device.cmd_bind_descriptor_sets(
+                commandbuffer,
+                vk::PipelineBindPoint::COMPUTE,
+                self.pipeline_layout,
+                0,
+                &[set],
+                &[],
+            );
+            logical_device.cmd_push_constants(
+                commandbuffer,
+                self.pipeline_layout,
+                vk::ShaderStageFlags::COMPUTE,
+                0,
+                &push_constants(first_pass),
+            );
+            logical_device.cmd_dispatch(
+                commandbuffer,
+                (extent.width >> level).max(1).div_ceil(8),
+                (extent.height >> level).max(1).div_ceil(8),
+                1,
+            );
+            logical_device.cmd_pipeline_barrier(
+                commandbuffer,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::DependencyFlags::empty(),
+                &level_done,
+                &[],
+                &[],
+            );
+        };
+        unsafe {
+            logical_device.cmd_pipeline_barrier(
+                commandbuffer,
+                vk::PipelineStageFlags::FRAGMENT_SHADER,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &to_general,
+            );
+            logical_device.cmd_bind_pipeline(
+                commandbuffer,
+                vk::PipelineBindPoint::COMPUTE,
+                self.downsample_pipeline,
+            );
+        }
+        for (level, &set) in self.down_sets.iter().enumerate() {
+            dispatch(set, level as u32, level == 0);
+        }
+        unsafe {
+            logical_device.cmd_bind_pipeline(
+                commandbuffer,
+                vk::PipelineBindPoint::COMPUTE,
+                self.upsample_pipeline,
+            );
+        }
+        for (level, &set) in self.up_sets.iter().enumerate().rev() {
+            dispatch(set, level as u32, false);
+        }
+        unsafe {
+            logical_device.cmd_pipeline_barrier(
+                commandbuffer,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::FRAGMENT_SHADER,
+                vk::DependencyFlags::empty(),
+                &chain_done,
+                &[],
+                &[],
+            );
+        }
+    }
+
+    unsafe fn destroy_chain(
+        &mut self,
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        for view in self.level_views.drain(..) {
+            logical_device.destroy_image_view(view, None);
+        }
+        self.chain.destroy(logical_device, allocator)
+    }
+
+    // Builds the pipelines again from the shader library, keeping the old ones if that fails.
+    // They must not be in use anymore
+    pub unsafe fn rebuild_pipelines(
+        &mut self,
+        logical_device: &ash::Device,
+        cache: vk::PipelineCache,
+        shaders: &ShaderLibrary,
+    ) -> Result<(), vk::Result> {
+        let pipelines = ibl::create_compute_pipelines(
+            logical_device,
+            self.pipeline_layout,
+            cache,
+            shaders,
+            &SHADERS,
+        )?;
+        logical_device.destroy_pipeline(self.downsample_pipeline, None);
+        logical_device.destroy_pipeline(self.upsample_pipeline, None);
+        self.downsample_pipeline = pipelines[0];
+        self.upsample_pipeline = pipelines[1];
+        Ok(())
+    }
+
+    pub unsafe fn cleanup(
+        &mut self,
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.destroy_chain(logical_device, allocator)?;
+        logical_device.destroy_descriptor_pool(self.descriptor_pool, None);
+        logical_device.destroy_sampler(self.sampler, None);
+        logical_device.destroy_pipeline(self.downsample_pipeline, None);
+        logical_device.destroy_pipeline(self.upsample_pipeline, None);
+        logical_device.destroy_pipeline_layout(self.pipeline_layout, None);
+        logical_device.destroy_descriptor_set_layout(self.descriptor_set_layout, None);
+        Ok(())
+    }
+}
+
+// Level 0 is half the target size, every further level halves again
+fn create_chain(
+    logical_device: &ash::Device,
+    allocator: &mut gpu_allocator::vulkan::Allocator,
+    extent: vk::Extent2D,
+) -> Result<(Image, Vec<vk::ImageView>), Box<dyn std::error::Error>> {
+    let chain_extent = vk::Extent2D {
+        width: (extent.width / 2).max(1),
+        height: (extent.height / 2).max(1),
+    };
+    let levels = (32 - chain_extent.width.min(chain_extent.height).leading_zeros())
+        .min(MAX_BLOOM_MIP_LEVELS);
+    let chain = Image::new(
+        logical_device,
+        allocator,
+        chain_extent,
+        HDR_FORMAT,
+        levels,
+        vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED,
+    )?;
+    let mut level_views = Vec::with_capacity(levels as usize);
+    for level in 0..levels {
+        let subresource_range = vk::ImageSubresourceRange::builder()
+            .aspect_mask(vk::ImageAspectFlags::COLOR)
+            .base_mip_level(level)
+            .level_count(1)
+            .base_array_layer(0)
+            .layer_count(1);
+        let view_info = vk::ImageViewCreateInfo::builder()
+            .image(chain.image)
+            .view_type(vk::ImageViewType::TYPE_2D)
+            .format(HDR_FORMAT)
+            .subresource_range(*subresource_range);
+        level_views.push(unsafe { logical_device.create_image_view(&view_info, None) }?);
+    }
+    Ok((chain, level_views))
+}
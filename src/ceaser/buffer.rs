@@ -1,5 +1,7 @@
 use ash::vk;
 
+use crate::ceaser::deletion_queue::DeletionQueue;
+
 pub struct Buffer {
     pub buffer: vk::Buffer,
     pub allocation: gpu_allocator::vulkan::Allocation,
@@ -45,13 +47,16 @@ impl Buffer {
             memory_usage
         })
     }
+    // Grows the buffer when `data` does not fit. Frames in flight may still read the old one,
+    // so it goes through the deletion queue instead of being destroyed here.
     pub fn fill<T: Sized>(
         &mut self,
         logical_device: &ash::Device,
         allocator: &mut gpu_allocator::vulkan::Allocator,
+        deletion_queue: &mut DeletionQueue,
         data: &[T],
-    ) -> Result<(),  Box<dyn std::error::Error>> {
-        let bytes_to_write = (data.len() * std::mem::size_of::<T>()) as u64;
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let bytes_to_write = std::mem::size_of_val(data) as u64;
         if bytes_to_write > self.size_in_bytes {
             let newbuffer = Buffer::new(
                 logical_device,
@@ -60,10 +65,43 @@ impl Buffer {
                 self.usage,
                 self.memory_usage,
             )?;
-            *self = newbuffer;
+            deletion_queue.retire_buffer(std::mem::replace(self, newbuffer));
+        }
+        self.write(data)
+    }
+
+    // Like `fill`, for buffers whose size never changes
+    pub fn write<T: Sized>(&mut self, data: &[T]) -> Result<(), Box<dyn std::error::Error>> {
+        if std::mem::size_of_val(data) as u64 > self.size_in_bytes {
+            return Err("data does not fit into the buffer".into());
         }
-        let data_ptr: *mut T = self.allocation.mapped_ptr().unwrap().cast().as_ptr();
+        let data_ptr: *mut T = self
+            .allocation
+            .mapped_ptr()
+            .ok_or("buffer is not host visible")?
+            .cast()
+            .as_ptr();
         unsafe { data_ptr.copy_from_nonoverlapping(data.as_ptr(), data.len()) };
         Ok(())
     }
-}
\ No newline at end of file
+
+    pub unsafe fn destroy(
+        &mut self,
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        allocator.free(std::mem::take(&mut self.allocation))?;
+        logical_device.destroy_buffer(self.buffer, None);
+        self.buffer = vk::Buffer::null();
+        Ok(())
+    }
+}
+
+// A Buffer cannot free itself without the device, so dropping a live one is a leak
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        if self.buffer != vk::Buffer::null() {
+            eprintln!("leaked a buffer of {} bytes", self.size_in_bytes);
+        }
+    }
+}
@@ -9,6 +9,11 @@ pub struct CameraBuilder {
     aspect: f32,
     near: f32,
     far: f32,
+    aperture: f32,
+    shutter_speed: f32,
+    iso: f32,
+    exposure_compensation: f32,
+    auto_exposure: bool,
 }
 
 #[allow(dead_code)]
@@ -35,6 +40,11 @@ impl CameraBuilder {
             aspect: self.aspect,
             near: self.near,
             far: self.far,
+            aperture: self.aperture,
+            shutter_speed: self.shutter_speed,
+            iso: self.iso,
+            exposure_compensation: self.exposure_compensation,
+            auto_exposure: self.auto_exposure,
             view_matrix: na::Matrix4::identity(),
             projection_matrix: na::Matrix4::identity(),
         };
@@ -48,7 +58,7 @@ impl CameraBuilder {
         self
     }
     pub fn fovy(mut self, fovy: f32) -> CameraBuilder {
-        self.fovy = fovy.max(0.01).min(std::f32::consts::PI - 0.01);
+        self.fovy = fovy.clamp(0.01, std::f32::consts::PI - 0.01);
         self
     }
     pub fn aspect(mut self, aspect: f32) -> CameraBuilder {
@@ -77,6 +87,30 @@ impl CameraBuilder {
         self.down_direction = na::Unit::new_normalize(direction);
         self
     }
+    // f-number, e.g. 16.0 for f/16
+    pub fn aperture(mut self, aperture: f32) -> CameraBuilder {
+        self.aperture = aperture.max(0.5);
+        self
+    }
+    // In seconds, e.g. 1.0 / 125.0
+    pub fn shutter_speed(mut self, shutter_speed: f32) -> CameraBuilder {
+        self.shutter_speed = shutter_speed.max(1e-6);
+        self
+    }
+    pub fn iso(mut self, iso: f32) -> CameraBuilder {
+        self.iso = iso.max(1.0);
+        self
+    }
+    // In stops, positive brightens. Also applies on top of auto exposure
+    pub fn exposure_compensation(mut self, ev: f32) -> CameraBuilder {
+        self.exposure_compensation = ev;
+        self
+    }
+    // Meters the rendered image instead of using aperture, shutter speed and ISO
+    pub fn auto_exposure(mut self, enabled: bool) -> CameraBuilder {
+        self.auto_exposure = enabled;
+        self
+    }
 }
 
 pub struct Camera {
@@ -89,6 +123,11 @@ pub struct Camera {
     pub near: f32,
     pub far: f32,
     pub projection_matrix: na::Matrix4<f32>,
+    pub aperture: f32,
+    pub shutter_speed: f32,
+    pub iso: f32,
+    pub exposure_compensation: f32,
+    pub auto_exposure: bool,
 }
 
 impl Default for Camera {
@@ -103,6 +142,11 @@ impl Default for Camera {
             near: 0.1,
             far: 100.0,
             projection_matrix: na::Matrix4::identity(), 
+            aperture: 16.0,
+            shutter_speed: 1.0 / 125.0,
+            iso: 100.0,
+            exposure_compensation: 0.0,
+            auto_exposure: false,
         }
     }
 }
@@ -117,22 +161,55 @@ impl Camera {
             aspect: 800.0 / 600.0,
             near: 0.1,
             far: 100.0,
+            // Sunny 16, matches daylight with the lights in lux and lumens
+            aperture: 16.0,
+            shutter_speed: 1.0 / 125.0,
+            iso: 100.0,
+            exposure_compensation: 0.0,
+            auto_exposure: false,
         }
     }
 
 }
 
 impl Camera {
-    pub fn update_buffer(
-        &self,
-        logical_device: &ash::Device,
-        allocator: &mut gpu_allocator::vulkan::Allocator,
-        buffer: &mut Buffer,
-    ) {
+    pub fn set_aspect(&mut self, aspect: f32) {
+        self.aspect = aspect;
+        self.update_projection_matrix();
+    }
+
+    // Exposure value of the aperture and shutter speed, normalized to ISO 100
+    pub fn ev100(&self) -> f32 {
+        (self.aperture * self.aperture / self.shutter_speed * 100.0 / self.iso).log2()
+    }
+
+    // Scale from scene luminance in cd/m² to the [0, 1] range the tonemapper expects, using the
+    // saturation based sensitivity: the luminance that just saturates the sensor maps to 1
+    pub fn exposure(&self) -> f32 {
+        let max_luminance = 1.2 * self.ev100().exp2();
+        self.exposure_compensation.exp2() / max_luminance
+    }
+
+    // Left, right, top, bottom, near and far planes as (normal, distance), with the normals
+    // pointing inwards and normalized, so dot(normal, p) + distance is the signed distance
+    pub fn frustum_planes(&self) -> [na::Vector4<f32>; 6] {
+        let m = self.projection_matrix * self.view_matrix;
+        let row = |i: usize| m.row(i).transpose();
+        let planes = [
+            row(3) + row(0),
+            row(3) - row(0),
+            row(3) + row(1),
+            row(3) - row(1),
+            // Depth ends up in [0, 1]
+            row(2),
+            row(3) - row(2),
+        ];
+        planes.map(|p| p / p.xyz().norm())
+    }
+
+    pub fn update_buffer(&self, buffer: &mut Buffer) {
         let data: [[[f32; 4]; 4]; 2] = [self.view_matrix.into(), self.projection_matrix.into()];
-        buffer
-            .fill(logical_device, allocator, &data)
-            .expect("Error updating camera buffer");
+        buffer.write(&data).expect("Error updating camera buffer");
     }
 
     fn update_view_matrix(&mut self) {
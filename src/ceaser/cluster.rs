@@ -0,0 +1,161 @@
+use ash::vk;
+use nalgebra as na;
+
+use crate::ceaser::{camera::Camera, ibl, shaders::ShaderLibrary};
+
+// The view frustum is split into a grid of clusters, tiles on screen times depth slices.
+// Has to match shaders/light_culling.comp and shaders/lighting.glsl
+pub const CLUSTERS_X: u32 = 16;
+pub const CLUSTERS_Y: u32 = 9;
+pub const CLUSTERS_Z: u32 = 24;
+pub const CLUSTER_COUNT: u32 = CLUSTERS_X * CLUSTERS_Y * CLUSTERS_Z;
+// Lights beyond this in one cluster are dropped
+pub const MAX_LIGHTS_PER_CLUSTER: u32 = 128;
+// A header with the tile size and depth slice mapping, then per cluster the light count and
+// its light indices
+pub const CLUSTER_BUFFER_SIZE: u64 = 16 + 4 * (CLUSTER_COUNT * (MAX_LIGHTS_PER_CLUSTER + 1)) as u64;
+pub const SHADERS: [&str; 1] = ["light_culling.comp"];
+
+// Assigns the point lights to the clusters their influence radius reaches, so fragments only
+// loop over the lights near them. Runs once per frame before the scene is drawn
+pub struct LightCulling {
+    // Binding 0 is the frame's light buffer, binding 1 its cluster buffer
+    pub descriptor_set_layout: vk::DescriptorSetLayout,
+    pipeline_layout: vk::PipelineLayout,
+    pipeline: vk::Pipeline,
+    // Taken from the camera in set_camera
+    view_matrix: na::Matrix4<f32>,
+    projection: [f32; 4],
+}
+
+impl LightCulling {
+    pub fn new(
+        logical_device: &ash::Device,
+        cache: vk::PipelineCache,
+        shaders: &ShaderLibrary,
+    ) -> Result<LightCulling, vk::Result> {
+        let bindings = [0, 1].map(|binding| {
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(binding)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::COMPUTE)
+                .build()
+        });
+        let set_layout_info = vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings);
+        let descriptor_set_layout =
+            unsafe { logical_device.create_descriptor_set_layout(&set_layout_info, None) }?;
+        let set_layouts = [descriptor_set_layout];
+        let push_constant_ranges = [vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::COMPUTE,
+            offset: 0,
+            size: 88,
+        }];
+        let layout_info = vk::PipelineLayoutCreateInfo::builder()
+            .set_layouts(&set_layouts)
+            .push_constant_ranges(&push_constant_ranges);
+        let pipeline_layout = unsafe { logical_device.create_pipeline_layout(&layout_info, None) }?;
+        let pipelines = ibl::create_compute_pipelines(
+            logical_device,
+            pipeline_layout,
+            cache,
+            shaders,
+            &SHADERS,
+        )?;
+        Ok(LightCulling {
+            descriptor_set_layout,
+            pipeline_layout,
+            pipeline: pipelines[0],
+            view_matrix: na::Matrix4::identity(),
+            projection: [1.0, 1.0, 0.1, 100.0],
+        })
+    }
+
+    pub fn set_camera(&mut self, camera: &Camera) {
+        self.view_matrix = camera.view_matrix;
+        self.projection = [
+            camera.projection_matrix[(0, 0)],
+            camera.projection_matrix[(1, 1)],
+            camera.near,
+            camera.far,
+        ];
+    }
+
+    pub fn record(
+        &self,
+        logical_device: &ash::Device,
+        commandbuffer: vk::CommandBuffer,
+        descriptor_set: vk::DescriptorSet,
+        extent: vk::Extent2D,
+    ) {
+        // Laid out like the push constant block in shaders/light_culling.comp
+        let mut values: Vec<f32> = self.view_matrix.as_slice().to_vec();
+        values.extend_from_slice(&self.projection);
+        values.push(extent.width as f32);
+        values.push(extent.height as f32);
+        let push_constants: Vec<u8> = values.iter().flat_map(|v| v.to_ne_bytes()).collect();
+        // The lighting reads the clusters in the fragment shaders of the scene pass
+        let culled = [vk::MemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ)
+            .build()];
+        unsafe {
+            logical_device.cmd_bind_pipeline(
+                commandbuffer,
+                vk::PipelineBindPoint::COMPUTE,
+                self.pipeline,
+            );
+            logical_device.cmd_bind_descriptor_sets(
+                commandbuffer,
+                vk::PipelineBindPoint::COMPUTE,
+                self.pipeline_layout,
+                0,
+                &[descriptor_set],
+                &[],
+            );
+            logical_device.cmd_push_constants(
+                commandbuffer,
+                self.pipeline_layout,
+                vk::ShaderStageFlags::COMPUTE,
+                0,
+                &push_constants,
+            );
+            logical_device.cmd_dispatch(commandbuffer, CLUSTER_COUNT.div_ceil(64), 1, 1);
+            logical_device.cmd_pipeline_barrier(
+                commandbuffer,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::FRAGMENT_SHADER,
+                vk::DependencyFlags::empty(),
+                &culled,
+                &[],
+                &[],
+            );
+        }
+    }
+
+    // Builds the pipeline again from the shader library, keeping the old one if that fails. It
+    // must not be in use anymore
+    pub unsafe fn rebuild_pipeline(
+        &mut self,
+        logical_device: &ash::Device,
+        cache: vk::PipelineCache,
+        shaders: &ShaderLibrary,
+    ) -> Result<(), vk::Result> {
+        let pipelines = ibl::create_compute_pipelines(
+            logical_device,
+            self.pipeline_layout,
+            cache,
+            shaders,
+            &SHADERS,
+        )?;
+        logical_device.destroy_pipeline(self.pipeline, None);
+        self.pipeline = pipelines[0];
+        Ok(())
+    }
+
+    pub unsafe fn cleanup(&self, logical_device: &ash::Device) {
+        logical_device.destroy_pipeline(self.pipeline, None);
+        logical_device.destroy_pipeline_layout(self.pipeline_layout, None);
+        logical_device.destroy_descriptor_set_layout(self.descriptor_set_layout, None);
+    }
+}
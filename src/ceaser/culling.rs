@@ -0,0 +1,303 @@
+use ash::vk;
+
+use crate::ceaser::{buffer::Buffer, camera::Camera, ibl, shaders::ShaderLibrary};
+use crate::hamlet::Model;
+
+pub const SHADERS: [&str; 1] = ["frustum_culling.comp"];
+
+// Where the instances of one model that pass the test end up
+pub struct CullTarget<'a, V, I> {
+    pub model: &'a Model<V, I>,
+    pub instancebuffer: &'a Buffer,
+    pub visiblebuffer: &'a Buffer,
+    pub indirectbuffer: &'a Buffer,
+    // Index of the draw command in the indirect buffer
+    pub draw_index: u32,
+    // Offsets into the visible, vertex and index buffers
+    pub first_instance: u32,
+    pub vertex_offset: i32,
+    pub first_index: u32,
+}
+
+// Each model into its own visible and indirect buffers, as drawn by Model::draw
+pub fn model_targets<V, I>(
+    models: &[Model<V, I>],
+    frame_index: usize,
+) -> Vec<CullTarget<'_, V, I>> {
+    models
+        .iter()
+        .filter(|m| m.first_invisible > 0)
+        .filter_map(|m| {
+            Some(CullTarget {
+                model: m,
+                instancebuffer: m.instancebuffers.get(frame_index)?.as_ref()?,
+                visiblebuffer: m.visiblebuffers.get(frame_index)?.as_ref()?,
+                indirectbuffer: m.indirectbuffers.get(frame_index)?.as_ref()?,
+                draw_index: 0,
+                first_instance: 0,
+                vertex_offset: 0,
+                first_index: 0,
+            })
+        })
+        .collect()
+}
+
+// Tests every visible instance's bounding sphere against the camera frustum and packs the ones
+// inside into a visible buffer, counting them into an indirect draw command. Model::draw or
+// SceneBatches::draw then draws only those
+pub struct FrustumCulling {
+    // Binding 0 is the instance buffer, 1 the visible buffer and 2 the indirect command
+    descriptor_set_layout: vk::DescriptorSetLayout,
+    pipeline_layout: vk::PipelineLayout,
+    pipeline: vk::Pipeline,
+    // One per frame in flight with room for that many sets, reset whenever that frame is
+    // recorded and replaced when there are more models than fit
+    descriptor_pools: Vec<(vk::DescriptorPool, u32)>,
+    // Taken from the camera in set_camera
+    frustum_planes: [[f32; 4]; 6],
+}
+
+impl FrustumCulling {
+    pub fn new(
+        logical_device: &ash::Device,
+        frames_in_flight: usize,
+        cache: vk::PipelineCache,
+        shaders: &ShaderLibrary,
+    ) -> Result<FrustumCulling, vk::Result> {
+        let bindings = [0, 1, 2].map(|binding| {
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(binding)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::COMPUTE)
+                .build()
+        });
+        let set_layout_info = vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings);
+        let descriptor_set_layout =
+            unsafe { logical_device.create_descriptor_set_layout(&set_layout_info, None) }?;
+        let set_layouts = [descriptor_set_layout];
+        let push_constant_ranges = [vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::COMPUTE,
+            offset: 0,
+            size: 128,
+        }];
+        let layout_info = vk::PipelineLayoutCreateInfo::builder()
+            .set_layouts(&set_layouts)
+            .push_constant_ranges(&push_constant_ranges);
+        let pipeline_layout = unsafe { logical_device.create_pipeline_layout(&layout_info, None) }?;
+        let pipelines = ibl::create_compute_pipelines(
+            logical_device,
+            pipeline_layout,
+            cache,
+            shaders,
+            &SHADERS,
+        )?;
+        let descriptor_pools = (0..frames_in_flight)
+            .map(|_| Ok((create_descriptor_pool(logical_device, 64)?, 64)))
+            .collect::<Result<Vec<_>, vk::Result>>()?;
+        Ok(FrustumCulling {
+            descriptor_set_layout,
+            pipeline_layout,
+            pipeline: pipelines[0],
+            descriptor_pools,
+            frustum_planes: [[0.0; 4]; 6],
+        })
+    }
+
+    pub fn set_camera(&mut self, camera: &Camera) {
+        self.frustum_planes = camera.frustum_planes().map(|p| [p.x, p.y, p.z, p.w]);
+    }
+
+    // Has to come before the first pass that draws the targets this frame. The shader reads the
+    // model matrix from the start of every instance, so I has to begin with it
+    pub fn record<V, I>(
+        &mut self,
+        logical_device: &ash::Device,
+        commandbuffer: vk::CommandBuffer,
+        culled: &[CullTarget<V, I>],
+        frame_index: usize,
+    ) -> Result<(), vk::Result> {
+        if culled.is_empty() {
+            return Ok(());
+        }
+
+        // The frame's previous submission is done, so nothing uses its pool anymore
+        let (pool, capacity) = &mut self.descriptor_pools[frame_index];
+        unsafe {
+            if culled.len() as u32 > *capacity {
+                logical_device.destroy_descriptor_pool(*pool, None);
+                *capacity = (culled.len() as u32).next_power_of_two();
+                *pool = create_descriptor_pool(logical_device, *capacity)?;
+            } else {
+                logical_device.reset_descriptor_pool(*pool, vk::DescriptorPoolResetFlags::empty())?;
+            }
+        }
+        let pool = *pool;
+
+        let set_layouts = vec![self.descriptor_set_layout; culled.len()];
+        let allocate_info = vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(pool)
+            .set_layouts(&set_layouts);
+        let descriptor_sets = unsafe { logical_device.allocate_descriptor_sets(&allocate_info) }?;
+        for (target, &set) in culled.iter().zip(&descriptor_sets) {
+            let buffers = [target.instancebuffer, target.visiblebuffer, target.indirectbuffer];
+            let buffer_infos = buffers.map(|b| {
+                [vk::DescriptorBufferInfo {
+                    buffer: b.buffer,
+                    offset: 0,
+                    range: vk::WHOLE_SIZE,
+                }]
+            });
+            let writes: Vec<_> = buffer_infos
+                .iter()
+                .enumerate()
+                .map(|(binding, info)| {
+                    vk::WriteDescriptorSet::builder()
+                        .dst_set(set)
+                        .dst_binding(binding as u32)
+                        .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                        .buffer_info(info)
+                        .build()
+                })
+                .collect();
+            unsafe { logical_device.update_descriptor_sets(&writes, &[]) };
+        }
+
+        let stride = (std::mem::size_of::<I>() / 4) as u32;
+        let reset = [vk::MemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE)
+            .build()];
+        let culled_barrier = [vk::MemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+            .dst_access_mask(
+                vk::AccessFlags::INDIRECT_COMMAND_READ | vk::AccessFlags::VERTEX_ATTRIBUTE_READ,
+            )
+            .build()];
+        unsafe {
+            // The instance count starts at zero, the shader counts up from there
+            for target in culled {
+                let command = vk::DrawIndexedIndirectCommand {
+                    index_count: target.model.indexdata.len() as u32,
+                    instance_count: 0,
+                    first_index: target.first_index,
+                    vertex_offset: target.vertex_offset,
+                    first_instance: target.first_instance,
+                };
+                logical_device.cmd_update_buffer(
+                    commandbuffer,
+                    target.indirectbuffer.buffer,
+                    target.draw_index as u64
+                        * std::mem::size_of::<vk::DrawIndexedIndirectCommand>() as u64,
+                    std::slice::from_raw_parts(
+                        &command as *const _ as *const u8,
+                        std::mem::size_of::<vk::DrawIndexedIndirectCommand>(),
+                    ),
+                );
+            }
+            logical_device.cmd_pipeline_barrier(
+                commandbuffer,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::DependencyFlags::empty(),
+                &reset,
+                &[],
+                &[],
+            );
+            logical_device.cmd_bind_pipeline(
+                commandbuffer,
+                vk::PipelineBindPoint::COMPUTE,
+                self.pipeline,
+            );
+            for (target, &set) in culled.iter().zip(&descriptor_sets) {
+                let m = target.model;
+                // Laid out like the push constant block in shaders/frustum_culling.comp
+                let mut values: Vec<u8> = self
+                    .frustum_planes
+                    .iter()
+                    .flatten()
+                    .chain(&m.bounding_sphere.unwrap_or([0.0, 0.0, 0.0, f32::MAX]))
+                    .flat_map(|v| v.to_ne_bytes())
+                    .collect();
+                values.extend_from_slice(&(m.first_invisible as u32).to_ne_bytes());
+                values.extend_from_slice(&stride.to_ne_bytes());
+                values.extend_from_slice(&target.draw_index.to_ne_bytes());
+                values.extend_from_slice(&target.first_instance.to_ne_bytes());
+                logical_device.cmd_bind_descriptor_sets(
+                    commandbuffer,
+                    vk::PipelineBindPoint::COMPUTE,
+                    self.pipeline_layout,
+                    0,
+                    &[set],
+                    &[],
+                );
+                logical_device.cmd_push_constants(
+                    commandbuffer,
+                    self.pipeline_layout,
+                    vk::ShaderStageFlags::COMPUTE,
+                    0,
+                    &values,
+                );
+                logical_device.cmd_dispatch(
+                    commandbuffer,
+                    (m.first_invisible as u32).div_ceil(64),
+                    1,
+                    1,
+                );
+            }
+            logical_device.cmd_pipeline_barrier(
+                commandbuffer,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::DRAW_INDIRECT | vk::PipelineStageFlags::VERTEX_INPUT,
+                vk::DependencyFlags::empty(),
+                &culled_barrier,
+                &[],
+                &[],
+            );
+        }
+        Ok(())
+    }
+
+    // Builds the pipeline again from the shader library, keeping the old one if that fails. It
+    // must not be in use anymore
+    pub unsafe fn rebuild_pipeline(
+        &mut self,
+        logical_device: &ash::Device,
+        cache: vk::PipelineCache,
+        shaders: &ShaderLibrary,
+    ) -> Result<(), vk::Result> {
+        let pipelines = ibl::create_compute_pipelines(
+            logical_device,
+            self.pipeline_layout,
+            cache,
+            shaders,
+            &SHADERS,
+        )?;
+        logical_device.destroy_pipeline(self.pipeline, None);
+        self.pipeline = pipelines[0];
+        Ok(())
+    }
+
+    pub unsafe fn cleanup(&self, logical_device: &ash::Device) {
+        for &(pool, _) in &self.descriptor_pools {
+            logical_device.destroy_descriptor_pool(pool, None);
+        }
+        logical_device.destroy_pipeline(self.pipeline, None);
+        logical_device.destroy_pipeline_layout(self.pipeline_layout, None);
+        logical_device.destroy_descriptor_set_layout(self.descriptor_set_layout, None);
+    }
+}
+
+fn create_descriptor_pool(
+    logical_device: &ash::Device,
+    max_sets: u32,
+) -> Result<vk::DescriptorPool, vk::Result> {
+    let pool_sizes = [vk::DescriptorPoolSize {
+        ty: vk::DescriptorType::STORAGE_BUFFER,
+        descriptor_count: 3 * max_sets,
+    }];
+    let pool_info = vk::DescriptorPoolCreateInfo::builder()
+        .max_sets(max_sets)
+        .pool_sizes(&pool_sizes);
+    unsafe { logical_device.create_descriptor_pool(&pool_info, None) }
+}
@@ -0,0 +1,499 @@
+use ash::vk;
+use nalgebra as na;
+
+use crate::ceaser::{
+    attachment::Attachment,
+    camera::Camera,
+    pipeline::Pipeline,
+    post::HdrTarget,
+    queue::QueueFamilies,
+    render_pass,
+    shaders::ShaderLibrary,
+};
+
+// Has to match what shaders/gbuffer.frag writes
+pub const ALBEDO_FORMAT: vk::Format = vk::Format::R8G8B8A8_SRGB;
+pub const NORMAL_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+// Metallic, roughness and whether the model receives shadows
+pub const MATERIAL_FORMAT: vk::Format = vk::Format::R8G8B8A8_UNORM;
+pub const LIGHTING_SHADERS: [&str; 2] = ["post.vert", "deferred_lighting.frag"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RenderPath {
+    // Every fragment of every model shades itself, looping over the lights of its cluster
+    Forward,
+    // Models only write their surface into the G-buffer and each pixel is lit once. Blended
+    // models and materials without a G-buffer shader are still drawn forward, after the
+    // lighting
+    Deferred,
+}
+
+// Surface attributes of the closest opaque model per pixel. Always single sampled, the
+// deferred path does not use MSAA
+pub struct GBuffer {
+    pub albedo: Attachment,
+    pub normal: Attachment,
+    pub material: Attachment,
+    pub depth: Attachment,
+    // Also has the HDR target's color image, which the lighting subpass writes
+    pub framebuffer: vk::Framebuffer,
+}
+
+impl GBuffer {
+    pub fn new(
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+        queue_families: &QueueFamilies,
+        hdr_target: &HdrTarget,
+        extent: vk::Extent2D,
+        renderpass: vk::RenderPass,
+    ) -> Result<GBuffer, Box<dyn std::error::Error>> {
+        let queuefamilies = [queue_families.graphics_q_index.unwrap()];
+        let mut create = |format, usage, name| {
+            Attachment::new(
+                logical_device,
+                allocator,
+                &queuefamilies,
+                extent,
+                format,
+                usage | vk::ImageUsageFlags::INPUT_ATTACHMENT,
+                vk::SampleCountFlags::TYPE_1,
+                name,
+            )
+        };
+        let color = vk::ImageUsageFlags::COLOR_ATTACHMENT;
+        let albedo = create(ALBEDO_FORMAT, color, "G-Buffer Albedo")?;
+        let normal = create(NORMAL_FORMAT, color, "G-Buffer Normal")?;
+        let material = create(MATERIAL_FORMAT, color, "G-Buffer Material")?;
+        let depth = create(
+            vk::Format::D32_SFLOAT,
+            vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
+            "G-Buffer Depth",
+        )?;
+        // Attachment order has to match render_pass::init_deferred_render_pass
+        let iview = [
+            hdr_target.color.imageview,
+            depth.imageview,
+            albedo.imageview,
+            normal.imageview,
+            material.imageview,
+        ];
+        let framebuffer_info = vk::FramebufferCreateInfo::builder()
+            .render_pass(renderpass)
+            .attachments(&iview)
+            .width(extent.width)
+            .height(extent.height)
+            .layers(1);
+        let framebuffer = unsafe { logical_device.create_framebuffer(&framebuffer_info, None) }?;
+        Ok(GBuffer {
+            albedo,
+            normal,
+            material,
+            depth,
+            framebuffer,
+        })
+    }
+
+    pub unsafe fn destroy(
+        &mut self,
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+    ) {
+        logical_device.destroy_framebuffer(self.framebuffer, None);
+        self.albedo.destroy(logical_device, allocator);
+        self.normal.destroy(logical_device, allocator);
+        self.material.destroy(logical_device, allocator);
+        self.depth.destroy(logical_device, allocator);
+    }
+}
+
+// The deferred render pass with its lighting pipeline, the materials have their own pipelines
+// for it. The lighting pipeline adds the G-buffer as set 0 in front of the scene pipeline
+// layout's set 1
+pub struct Deferred {
+    pub render_pass: vk::RenderPass,
+    pub gbuffer: GBuffer,
+    pub lighting_pipeline: vk::Pipeline,
+    pub lighting_pipeline_layout: vk::PipelineLayout,
+    input_set_layout: vk::DescriptorSetLayout,
+    descriptor_pool: vk::DescriptorPool,
+    input_set: vk::DescriptorSet,
+    // Taken from the camera in set_camera
+    inverse_view_projection: na::Matrix4<f32>,
+    camera_position: na::Vector3<f32>,
+    near: f32,
+    far: f32,
+}
+
+impl Deferred {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+        queue_families: &QueueFamilies,
+        hdr_target: &HdrTarget,
+        extent: vk::Extent2D,
+        scene_pipeline: &Pipeline,
+        cache: vk::PipelineCache,
+        shaders: &ShaderLibrary,
+    ) -> Result<Deferred, Box<dyn std::error::Error>> {
+        let render_pass = render_pass::init_deferred_render_pass(logical_device)?;
+
+        let bindings: Vec<vk::DescriptorSetLayoutBinding> = (0..4)
+            .map(|binding| {
+                vk::DescriptorSetLayoutBinding::builder()
+                    .binding(binding)
+                    .descriptor_type(vk::DescriptorType::INPUT_ATTACHMENT)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+                    .build()
+            })
+            .collect();
+        let set_layout_info = vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings);
+        let input_set_layout =
+            unsafe { logical_device.create_descriptor_set_layout(&set_layout_info, None) }?;
+        let (lighting_pipeline, lighting_pipeline_layout) = create_lighting_pipeline(
+            logical_device,
+            render_pass,
+            &[input_set_layout, scene_pipeline.descriptor_set_layouts[1]],
+            cache,
+            shaders,
+        )?;
+
+        let pool_sizes = [vk::DescriptorPoolSize {
+            ty: vk::DescriptorType::INPUT_ATTACHMENT,
+            descriptor_count: 4,
+        }];
+        let pool_info = vk::DescriptorPoolCreateInfo::builder()
+            .max_sets(1)
+            .pool_sizes(&pool_sizes);
+        let descriptor_pool = unsafe { logical_device.create_descriptor_pool(&pool_info, None) }?;
+        let layouts = [input_set_layout];
+        let allocate_info = vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(descriptor_pool)
+            .set_layouts(&layouts);
+        let input_set = unsafe { logical_device.allocate_descriptor_sets(&allocate_info) }?[0];
+
+        let gbuffer = GBuffer::new(
+            logical_device,
+            allocator,
+            queue_families,
+            hdr_target,
+            extent,
+            render_pass,
+        )?;
+        let deferred = Deferred {
+            render_pass,
+            gbuffer,
+            lighting_pipeline,
+            lighting_pipeline_layout,
+            input_set_layout,
+            descriptor_pool,
+            input_set,
+            inverse_view_projection: na::Matrix4::identity(),
+            camera_position: na::Vector3::zeros(),
+            near: 0.1,
+            far: 100.0,
+        };
+        deferred.write_inputs(logical_device);
+        Ok(deferred)
+    }
+
+    // Rebuilds the G-buffer around a new HDR target. The caller has to make sure the device
+    // is idle.
+    pub unsafe fn resize(
+        &mut self,
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+        queue_families: &QueueFamilies,
+        hdr_target: &HdrTarget,
+        extent: vk::Extent2D,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.gbuffer.destroy(logical_device, allocator);
+        self.gbuffer = GBuffer::new(
+            logical_device,
+            allocator,
+            queue_families,
+            hdr_target,
+            extent,
+            self.render_pass,
+        )?;
+        self.write_inputs(logical_device);
+        Ok(())
+    }
+
+    pub fn set_camera(&mut self, camera: &Camera) {
+        self.inverse_view_projection = (camera.projection_matrix * camera.view_matrix)
+            .try_inverse()
+            .unwrap_or_else(na::Matrix4::identity);
+        self.camera_position = camera.position;
+        self.near = camera.near;
+        self.far = camera.far;
+    }
+
+    fn write_inputs(&self, logical_device: &ash::Device) {
+        let attachments = [
+            (&self.gbuffer.albedo, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+            (&self.gbuffer.normal, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+            (&self.gbuffer.material, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+            (&self.gbuffer.depth, vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL),
+        ];
+        let image_infos: Vec<[vk::DescriptorImageInfo; 1]> = attachments
+            .iter()
+            .map(|(attachment, image_layout)| {
+                [vk::DescriptorImageInfo {
+                    sampler: vk::Sampler::null(),
+                    image_view: attachment.imageview,
+                    image_layout: *image_layout,
+                }]
+            })
+            .collect();
+        let writes: Vec<vk::WriteDescriptorSet> = image_infos
+            .iter()
+            .enumerate()
+            .map(|(i, info)| {
+                vk::WriteDescriptorSet::builder()
+                    .dst_set(self.input_set)
+                    .dst_binding(i as u32)
+                    .descriptor_type(vk::DescriptorType::INPUT_ATTACHMENT)
+                    .image_info(info)
+                    .build()
+            })
+            .collect();
+        unsafe { logical_device.update_descriptor_sets(&writes, &[]) };
+    }
+
+    // Starts the G-buffer subpass, the caller binds the scene descriptor sets and draws the
+    // models whose material writes the G-buffer
+    pub fn begin(
+        &self,
+        logical_device: &ash::Device,
+        commandbuffer: vk::CommandBuffer,
+        extent: vk::Extent2D,
+        clear_color: [f32; 4],
+    ) {
+        let clearvalues = [
+            vk::ClearValue {
+                color: vk::ClearColorValue {
+                    float32: clear_color,
+                },
+            },
+            vk::ClearValue {
+                depth_stencil: vk::ClearDepthStencilValue {
+                    depth: 1.0,
+                    stencil: 0,
+                },
+            },
+        ];
+        let render_area = vk::Rect2D {
+            offset: vk::Offset2D { x: 0, y: 0 },
+            extent,
+        };
+        let renderpass_begininfo = vk::RenderPassBeginInfo::builder()
+            .render_pass(self.render_pass)
+            .framebuffer(self.gbuffer.framebuffer)
+            .render_area(render_area)
+            .clear_values(&clearvalues);
+        let viewports = [vk::Viewport {
+            x: 0.,
+            y: 0.,
+            width: extent.width as f32,
+            height: extent.height as f32,
+            min_depth: 0.,
+            max_depth: 1.,
+        }];
+        unsafe {
+            logical_device.cmd_begin_render_pass(
+                commandbuffer,
+                &renderpass_begininfo,
+                vk::SubpassContents::INLINE,
+            );
+            logical_device.cmd_set_viewport(commandbuffer, 0, &viewports);
+            logical_device.cmd_set_scissor(commandbuffer, 0, &[render_area]);
+        }
+    }
+
+    // Moves on to the second subpass and lights every covered pixel once. The caller rebinds
+    // the scene descriptor sets, draws the remaining models and ends the render pass
+    pub fn light(
+        &self,
+        logical_device: &ash::Device,
+        commandbuffer: vk::CommandBuffer,
+        descriptor_set_light: vk::DescriptorSet,
+        ambient_occlusion: bool,
+    ) {
+        // Laid out like the push constant block in shaders/deferred_lighting.frag
+        let mut push_constants = [0u8; 96];
+        let matrix: [[f32; 4]; 4] = self.inverse_view_projection.into();
+        for (i, column) in matrix.iter().enumerate() {
+            for (j, value) in column.iter().enumerate() {
+                let offset = 16 * i + 4 * j;
+                push_constants[offset..offset + 4].copy_from_slice(&value.to_ne_bytes());
+            }
+        }
+        for (j, value) in self.camera_position.iter().enumerate() {
+            push_constants[64 + 4 * j..68 + 4 * j].copy_from_slice(&value.to_ne_bytes());
+        }
+        push_constants[80..84].copy_from_slice(&self.near.to_ne_bytes());
+        push_constants[84..88].copy_from_slice(&self.far.to_ne_bytes());
+        push_constants[88..92].copy_from_slice(&(ambient_occlusion as u32).to_ne_bytes());
+        unsafe {
+            logical_device.cmd_next_subpass(commandbuffer, vk::SubpassContents::INLINE);
+            logical_device.cmd_bind_pipeline(
+                commandbuffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                self.lighting_pipeline,
+            );
+            logical_device.cmd_bind_descriptor_sets(
+                commandbuffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                self.lighting_pipeline_layout,
+                0,
+                &[self.input_set, descriptor_set_light],
+                &[],
+            );
+            logical_device.cmd_push_constants(
+                commandbuffer,
+                self.lighting_pipeline_layout,
+                vk::ShaderStageFlags::FRAGMENT,
+                0,
+                &push_constants,
+            );
+            // A single triangle covering the screen, positions come from gl_VertexIndex
+            logical_device.cmd_draw(commandbuffer, 3, 1, 0, 0);
+        }
+    }
+
+    // Builds the lighting pipeline again from the shader library, keeping the old one if that
+    // fails. It must not be in use anymore
+    pub unsafe fn rebuild_lighting_pipeline(
+        &mut self,
+        logical_device: &ash::Device,
+        scene_pipeline: &Pipeline,
+        cache: vk::PipelineCache,
+        shaders: &ShaderLibrary,
+    ) -> Result<(), vk::Result> {
+        let (lighting_pipeline, lighting_pipeline_layout) = create_lighting_pipeline(
+            logical_device,
+            self.render_pass,
+            &[self.input_set_layout, scene_pipeline.descriptor_set_layouts[1]],
+            cache,
+            shaders,
+        )?;
+        logical_device.destroy_pipeline(self.lighting_pipeline, None);
+        logical_device.destroy_pipeline_layout(self.lighting_pipeline_layout, None);
+        self.lighting_pipeline = lighting_pipeline;
+        self.lighting_pipeline_layout = lighting_pipeline_layout;
+        Ok(())
+    }
+
+    pub unsafe fn cleanup(
+        &mut self,
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+    ) {
+        self.gbuffer.destroy(logical_device, allocator);
+        logical_device.destroy_descriptor_pool(self.descriptor_pool, None);
+        logical_device.destroy_pipeline(self.lighting_pipeline, None);
+        logical_device.destroy_pipeline_layout(self.lighting_pipeline_layout, None);
+        logical_device.destroy_descriptor_set_layout(self.input_set_layout, None);
+        logical_device.destroy_render_pass(self.render_pass, None);
+    }
+}
+
+fn create_lighting_pipeline(
+    logical_device: &ash::Device,
+    renderpass: vk::RenderPass,
+    set_layouts: &[vk::DescriptorSetLayout],
+    cache: vk::PipelineCache,
+    shaders: &ShaderLibrary,
+) -> Result<(vk::Pipeline, vk::PipelineLayout), vk::Result> {
+    let vertexshader_createinfo =
+        vk::ShaderModuleCreateInfo::builder().code(shaders.get("post.vert"));
+    let vertexshader_module =
+        unsafe { logical_device.create_shader_module(&vertexshader_createinfo, None)? };
+    let fragmentshader_createinfo =
+        vk::ShaderModuleCreateInfo::builder().code(shaders.get("deferred_lighting.frag"));
+    let fragmentshader_module =
+        unsafe { logical_device.create_shader_module(&fragmentshader_createinfo, None)? };
+    let mainfunctionname = std::ffi::CString::new("main").unwrap();
+    let shader_stages = [
+        vk::PipelineShaderStageCreateInfo::builder()
+            .stage(vk::ShaderStageFlags::VERTEX)
+            .module(vertexshader_module)
+            .name(&mainfunctionname)
+            .build(),
+        vk::PipelineShaderStageCreateInfo::builder()
+            .stage(vk::ShaderStageFlags::FRAGMENT)
+            .module(fragmentshader_module)
+            .name(&mainfunctionname)
+            .build(),
+    ];
+    let vertex_input_info = vk::PipelineVertexInputStateCreateInfo::builder();
+    let input_assembly_info = vk::PipelineInputAssemblyStateCreateInfo::builder()
+        .topology(vk::PrimitiveTopology::TRIANGLE_LIST);
+    let viewport_info = vk::PipelineViewportStateCreateInfo::builder()
+        .viewport_count(1)
+        .scissor_count(1);
+    let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+    let dynamic_state_info =
+        vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&dynamic_states);
+    let rasterizer_info = vk::PipelineRasterizationStateCreateInfo::builder()
+        .line_width(1.0)
+        .front_face(vk::FrontFace::COUNTER_CLOCKWISE)
+        .cull_mode(vk::CullModeFlags::NONE)
+        .polygon_mode(vk::PolygonMode::FILL);
+    let multisampler_info = vk::PipelineMultisampleStateCreateInfo::builder()
+        .rasterization_samples(vk::SampleCountFlags::TYPE_1);
+    // The depth buffer is read as an input attachment instead
+    let depth_stencil_info = vk::PipelineDepthStencilStateCreateInfo::builder()
+        .depth_test_enable(false)
+        .depth_write_enable(false);
+    let colorblend_attachments = [vk::PipelineColorBlendAttachmentState::builder()
+        .blend_enable(false)
+        .color_write_mask(
+            vk::ColorComponentFlags::R
+                | vk::ColorComponentFlags::G
+                | vk::ColorComponentFlags::B
+                | vk::ColorComponentFlags::A,
+        )
+        .build()];
+    let colorblend_info =
+        vk::PipelineColorBlendStateCreateInfo::builder().attachments(&colorblend_attachments);
+    let push_constant_ranges = [vk::PushConstantRange {
+        stage_flags: vk::ShaderStageFlags::FRAGMENT,
+        offset: 0,
+        size: 96,
+    }];
+    let pipelinelayout_info = vk::PipelineLayoutCreateInfo::builder()
+        .set_layouts(set_layouts)
+        .push_constant_ranges(&push_constant_ranges);
+    let pipelinelayout =
+        unsafe { logical_device.create_pipeline_layout(&pipelinelayout_info, None) }?;
+    let pipeline_info = vk::GraphicsPipelineCreateInfo::builder()
+        .stages(&shader_stages)
+        .vertex_input_state(&vertex_input_info)
+        .input_assembly_state(&input_assembly_info)
+        .viewport_state(&viewport_info)
+        .rasterization_state(&rasterizer_info)
+        .multisample_state(&multisampler_info)
+        .depth_stencil_state(&depth_stencil_info)
+        .color_blend_state(&colorblend_info)
+        .dynamic_state(&dynamic_state_info)
+        .layout(pipelinelayout)
+        .render_pass(renderpass)
+        .subpass(1);
+    let pipelines =
+        unsafe { logical_device.create_graphics_pipelines(cache, &[pipeline_info.build()], None) };
+    unsafe {
+        logical_device.destroy_shader_module(fragmentshader_module, None);
+        logical_device.destroy_shader_module(vertexshader_module, None);
+    }
+    match pipelines {
+        Ok(pipelines) => Ok((pipelines[0], pipelinelayout)),
+        Err((_, e)) => {
+            unsafe { logical_device.destroy_pipeline_layout(pipelinelayout, None) };
+            Err(e)
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use crate::ceaser::buffer::Buffer;
+
+// Resources that were replaced or removed while earlier frames may still read them.
+// Every entry is stamped with the number of frames submitted at the time it was retired,
+// and is only destroyed once all of those frames are known to have finished.
+pub struct DeletionQueue {
+    pub frames_in_flight: u64,
+    pub submitted_frames: u64,
+    pub buffers: Vec<(u64, Buffer)>,
+}
+
+impl DeletionQueue {
+    pub fn new(frames_in_flight: usize) -> DeletionQueue {
+        DeletionQueue {
+            frames_in_flight: frames_in_flight as u64,
+            submitted_frames: 0,
+            buffers: vec![],
+        }
+    }
+
+    pub fn retire_buffer(&mut self, buffer: Buffer) {
+        self.buffers.push((self.submitted_frames, buffer));
+    }
+
+    pub fn frame_submitted(&mut self) {
+        self.submitted_frames += 1;
+    }
+
+    // Called right after waiting on the fence of the frame slot about to be reused, at which
+    // point every frame up to `submitted_frames - frames_in_flight` has completed
+    pub unsafe fn collect(
+        &mut self,
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let completed = self.submitted_frames + 1;
+        let frames_in_flight = self.frames_in_flight;
+        let mut i = 0;
+        while i < self.buffers.len() {
+            if self.buffers[i].0 + frames_in_flight <= completed {
+                let (_, mut buffer) = self.buffers.swap_remove(i);
+                buffer.destroy(logical_device, allocator)?;
+            } else {
+                i += 1;
+            }
+        }
+        Ok(())
+    }
+
+    // Only valid once the device is idle
+    pub unsafe fn cleanup(
+        &mut self,
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        for (_, mut buffer) in self.buffers.drain(..) {
+            buffer.destroy(logical_device, allocator)?;
+        }
+        Ok(())
+    }
+}
@@ -1,25 +1,220 @@
 use ash::vk;
 
+use crate::ceaser::surface::Surface;
+
+pub const DEVICE_ENV_VAR: &str = "OBERON_DEVICE";
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum DevicePreference {
+    #[default]
+    Auto,
+    Index(usize),
+    Name(String),
+}
+
+// "1" picks by enumeration index, anything else is matched against the device name
+impl From<&str> for DevicePreference {
+    fn from(value: &str) -> Self {
+        let value = value.trim();
+        match value.parse::<usize>() {
+            Ok(index) => DevicePreference::Index(index),
+            Err(_) if value.is_empty() => DevicePreference::Auto,
+            Err(_) => DevicePreference::Name(value.to_string()),
+        }
+    }
+}
+
+impl DevicePreference {
+    pub fn from_env() -> Option<DevicePreference> {
+        match DevicePreference::from(std::env::var(DEVICE_ENV_VAR).ok()?.as_str()) {
+            DevicePreference::Auto => None,
+            preference => Some(preference),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub index: usize,
+    pub name: String,
+    pub device_type: vk::PhysicalDeviceType,
+    pub rejection: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct DeviceSelectionError {
+    pub preference: DevicePreference,
+    pub candidates: Vec<Candidate>,
+}
+
+impl std::fmt::Display for DeviceSelectionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.preference {
+            DevicePreference::Auto => write!(f, "no suitable physical device found")?,
+            DevicePreference::Index(index) => {
+                write!(f, "no suitable physical device at index {}", index)?
+            }
+            DevicePreference::Name(name) => {
+                write!(f, "no suitable physical device matching \"{}\"", name)?
+            }
+        }
+        if self.candidates.is_empty() {
+            return write!(f, " (the Vulkan instance reports no devices)");
+        }
+        write!(f, "; candidates:")?;
+        for c in &self.candidates {
+            write!(f, "\n  [{}] {} ({:?}): ", c.index, c.name, c.device_type)?;
+            match &c.rejection {
+                Some(reason) => write!(f, "rejected, {}", reason)?,
+                None => write!(f, "suitable, but not selected")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for DeviceSelectionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        None
+    }
+}
+
 pub struct Device {
     pub physical_device: vk::PhysicalDevice,
     pub physical_device_properties: vk::PhysicalDeviceProperties,
 }
 
 impl Device {
-    pub fn new(instance: &ash::Instance) -> Result<Device, vk::Result> {
+    pub fn new(
+        instance: &ash::Instance,
+        surfaces: Option<&Surface>,
+        preference: &DevicePreference,
+    ) -> Result<Device, Box<dyn std::error::Error>> {
         let phys_devs = unsafe { instance.enumerate_physical_devices()? };
-        let (physical_device, physical_device_properties) = {
-            let mut chosen = None;
-            for p in phys_devs {
-                let properties = unsafe { instance.get_physical_device_properties(p) };
-                if properties.device_type == vk::PhysicalDeviceType::DISCRETE_GPU || properties.device_type == vk::PhysicalDeviceType::INTEGRATED_GPU {
-                    chosen = Some((p, properties));
+        let mut candidates = Vec::with_capacity(phys_devs.len());
+        let mut best: Option<(u64, usize)> = None;
+        for (index, &p) in phys_devs.iter().enumerate() {
+            let properties = unsafe { instance.get_physical_device_properties(p) };
+            let name = unsafe { std::ffi::CStr::from_ptr(properties.device_name.as_ptr()) }
+                .to_string_lossy()
+                .into_owned();
+            let rejection = match preference {
+                DevicePreference::Index(wanted) if *wanted != index => {
+                    Some(format!("device index {} was requested", wanted))
+                }
+                DevicePreference::Name(wanted)
+                    if !name.to_lowercase().contains(&wanted.to_lowercase()) =>
+                {
+                    Some(format!("name does not contain \"{}\"", wanted))
+                }
+                // A failed query only rules out this device
+                _ => check_suitability(instance, p, surfaces)
+                    .unwrap_or_else(|e| Some(format!("querying the device failed: {}", e))),
+            };
+            if rejection.is_none() {
+                let score = score(&properties);
+                if best.is_none_or(|(best_score, _)| score > best_score) {
+                    best = Some((score, index));
                 }
             }
-            chosen.unwrap()
-        };
+            candidates.push(Candidate {
+                index,
+                name,
+                device_type: properties.device_type,
+                rejection,
+            });
+        }
 
-        Ok(Self {physical_device, physical_device_properties})
+        match best {
+            Some((_, index)) => {
+                let physical_device = phys_devs[index];
+                let physical_device_properties =
+                    unsafe { instance.get_physical_device_properties(physical_device) };
+                println!(
+                    "Using physical device [{}] {} ({:?})",
+                    index, candidates[index].name, candidates[index].device_type
+                );
+                Ok(Self {
+                    physical_device,
+                    physical_device_properties,
+                })
+            }
+            None => Err(Box::new(DeviceSelectionError {
+                preference: preference.clone(),
+                candidates,
+            })),
+        }
+    }
+
+    // Largest sample count not above `requested` that works for both color and depth attachments
+    pub fn supported_sample_count(&self, requested: vk::SampleCountFlags) -> vk::SampleCountFlags {
+        let limits = &self.physical_device_properties.limits;
+        let supported = limits.framebuffer_color_sample_counts & limits.framebuffer_depth_sample_counts;
+        [
+            vk::SampleCountFlags::TYPE_64,
+            vk::SampleCountFlags::TYPE_32,
+            vk::SampleCountFlags::TYPE_16,
+            vk::SampleCountFlags::TYPE_8,
+            vk::SampleCountFlags::TYPE_4,
+            vk::SampleCountFlags::TYPE_2,
+        ]
+        .into_iter()
+        .find(|&count| count.as_raw() <= requested.as_raw() && supported.contains(count))
+        .unwrap_or(vk::SampleCountFlags::TYPE_1)
+    }
 
+    pub fn max_sample_count(&self) -> vk::SampleCountFlags {
+        self.supported_sample_count(vk::SampleCountFlags::TYPE_64)
     }
-}
\ No newline at end of file
+}
+
+fn check_suitability(
+    instance: &ash::Instance,
+    physical_device: vk::PhysicalDevice,
+    surfaces: Option<&Surface>,
+) -> Result<Option<String>, vk::Result> {
+    let queuefamilyproperties =
+        unsafe { instance.get_physical_device_queue_family_properties(physical_device) };
+    let mut has_graphics = false;
+    let mut can_present = surfaces.is_none();
+    for (index, qfam) in queuefamilyproperties.iter().enumerate() {
+        if qfam.queue_count == 0 || !qfam.queue_flags.contains(vk::QueueFlags::GRAPHICS) {
+            continue;
+        }
+        has_graphics = true;
+        if let Some(surfaces) = surfaces {
+            can_present |= surfaces.get_physical_device_surface_support(physical_device, index)?;
+        }
+    }
+    if !has_graphics {
+        return Ok(Some("no queue family supports graphics".to_string()));
+    }
+    if !can_present {
+        return Ok(Some("no graphics queue family can present to the window surface".to_string()));
+    }
+    if surfaces.is_some() {
+        let extensions =
+            unsafe { instance.enumerate_device_extension_properties(physical_device)? };
+        let swapchain_name = ash::extensions::khr::Swapchain::name();
+        let has_swapchain = extensions.iter().any(|e| {
+            let name = unsafe { std::ffi::CStr::from_ptr(e.extension_name.as_ptr()) };
+            name == swapchain_name
+        });
+        if !has_swapchain {
+            return Ok(Some(format!("missing {:?}", swapchain_name)));
+        }
+    }
+    Ok(None)
+}
+
+// Hardware first, software rasterizers like lavapipe last; resolution limits break ties
+fn score(properties: &vk::PhysicalDeviceProperties) -> u64 {
+    let type_score = match properties.device_type {
+        vk::PhysicalDeviceType::DISCRETE_GPU => 4,
+        vk::PhysicalDeviceType::INTEGRATED_GPU => 3,
+        vk::PhysicalDeviceType::VIRTUAL_GPU => 2,
+        vk::PhysicalDeviceType::CPU => 1,
+        _ => 0,
+    };
+    (type_score << 32) + properties.limits.max_image_dimension2_d as u64
+}
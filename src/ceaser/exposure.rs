@@ -0,0 +1,326 @@
+use ash::vk;
+
+use crate::ceaser::{buffer::Buffer, ibl, post::HdrTarget, shaders::ShaderLibrary};
+
+// Has to match the local size of shaders/exposure_average.comp
+pub const HISTOGRAM_BINS: u64 = 256;
+// The histogram pass, then the average
+pub const SHADERS: [&str; 2] = ["exposure_histogram.comp", "exposure_average.comp"];
+
+// Histogram based auto exposure. Every frame the HDR target is binned by log luminance,
+// the average scene luminance is taken from the histogram and the exposure adapts to it
+// over time. The result stays on the GPU and is read by the post pass.
+pub struct AutoExposure {
+    pub histogram_buffer: Buffer,
+    // Adapted average luminance and the exposure derived from it, two floats
+    pub state_buffer: Buffer,
+    pub descriptor_set_layout: vk::DescriptorSetLayout,
+    pub descriptor_pool: vk::DescriptorPool,
+    pub descriptor_set: vk::DescriptorSet,
+    pub pipeline_layout: vk::PipelineLayout,
+    pub histogram_pipeline: vk::Pipeline,
+    pub average_pipeline: vk::Pipeline,
+    pub sampler: vk::Sampler,
+    // Luminance range covered by the histogram, in stops. Darker pixels are ignored,
+    // brighter ones are counted in the last bin
+    pub min_log_luminance: f32,
+    pub max_log_luminance: f32,
+    // How quickly the exposure follows the scene, higher is faster
+    pub adaptation_rate: f32,
+    last_update: Option<std::time::Instant>,
+}
+
+impl AutoExposure {
+    pub fn new(
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+        hdr_target: &HdrTarget,
+        cache: vk::PipelineCache,
+        shaders: &ShaderLibrary,
+    ) -> Result<AutoExposure, Box<dyn std::error::Error>> {
+        let mut histogram_buffer = Buffer::new(
+            logical_device,
+            allocator,
+            4 * HISTOGRAM_BINS,
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+            gpu_allocator::MemoryLocation::CpuToGpu,
+        )?;
+        histogram_buffer.write(&[0u32; HISTOGRAM_BINS as usize])?;
+        let mut state_buffer = Buffer::new(
+            logical_device,
+            allocator,
+            8,
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+            gpu_allocator::MemoryLocation::CpuToGpu,
+        )?;
+        // A negative luminance makes the first frame take the measured value without adapting
+        state_buffer.write(&[-1.0f32, 1.0])?;
+
+        let bindings = [
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(0)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::COMPUTE)
+                .build(),
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(1)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::COMPUTE)
+                .build(),
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(2)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::COMPUTE)
+                .build(),
+        ];
+        let set_layout_info = vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings);
+        let descriptor_set_layout =
+            unsafe { logical_device.create_descriptor_set_layout(&set_layout_info, None) }?;
+        let set_layouts = [descriptor_set_layout];
+        let push_constant_ranges = [vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::COMPUTE,
+            offset: 0,
+            size: 16,
+        }];
+        let layout_info = vk::PipelineLayoutCreateInfo::builder()
+            .set_layouts(&set_layouts)
+            .push_constant_ranges(&push_constant_ranges);
+        let pipeline_layout = unsafe { logical_device.create_pipeline_layout(&layout_info, None) }?;
+        let pipelines = ibl::create_compute_pipelines(
+            logical_device,
+            pipeline_layout,
+            cache,
+            shaders,
+            &SHADERS,
+        )?;
+
+        let sampler_info = vk::SamplerCreateInfo::builder()
+            .mag_filter(vk::Filter::NEAREST)
+            .min_filter(vk::Filter::NEAREST)
+            .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE);
+        let sampler = unsafe { logical_device.create_sampler(&sampler_info, None) }?;
+
+        let pool_sizes = [
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                descriptor_count: 1,
+            },
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::STORAGE_BUFFER,
+                descriptor_count: 2,
+            },
+        ];
+        let pool_info = vk::DescriptorPoolCreateInfo::builder()
+            .max_sets(1)
+            .pool_sizes(&pool_sizes);
+        let descriptor_pool = unsafe { logical_device.create_descriptor_pool(&pool_info, None) }?;
+        let allocate_info = vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(descriptor_pool)
+            .set_layouts(&set_layouts);
+        let descriptor_set = unsafe { logical_device.allocate_descriptor_sets(&allocate_info) }?[0];
+        let histogram_infos = [vk::DescriptorBufferInfo {
+            buffer: histogram_buffer.buffer,
+            offset: 0,
+            range: vk::WHOLE_SIZE,
+        }];
+        let state_infos = [vk::DescriptorBufferInfo {
+            buffer: state_buffer.buffer,
+            offset: 0,
+            range: vk::WHOLE_SIZE,
+        }];
+        let writes = [
+            vk::WriteDescriptorSet::builder()
+                .dst_set(descriptor_set)
+                .dst_binding(1)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .buffer_info(&histogram_infos)
+                .build(),
+            vk::WriteDescriptorSet::builder()
+                .dst_set(descriptor_set)
+                .dst_binding(2)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .buffer_info(&state_infos)
+                .build(),
+        ];
+        unsafe { logical_device.update_descriptor_sets(&writes, &[]) };
+
+        let auto_exposure = AutoExposure {
+            histogram_buffer,
+            state_buffer,
+            descriptor_set_layout,
+            descriptor_pool,
+            descriptor_set,
+            pipeline_layout,
+            histogram_pipeline: pipelines[0],
+            average_pipeline: pipelines[1],
+            sampler,
+            min_log_luminance: -8.0,
+            max_log_luminance: 16.0,
+            adaptation_rate: 1.5,
+            last_update: None,
+        };
+        auto_exposure.write_input(logical_device, hdr_target);
+        Ok(auto_exposure)
+    }
+
+    // Has to be called again whenever the HDR target is recreated
+    pub fn write_input(&self, logical_device: &ash::Device, hdr_target: &HdrTarget) {
+        let image_infos = [vk::DescriptorImageInfo {
+            sampler: self.sampler,
+            image_view: hdr_target.color.imageview,
+            image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        }];
+        let writes = [vk::WriteDescriptorSet::builder()
+            .dst_set(self.descriptor_set)
+            .dst_binding(0)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .image_info(&image_infos)
+            .build()];
+        unsafe { logical_device.update_descriptor_sets(&writes, &[]) };
+    }
+
+    // Recorded between the scene pass and the post pass. The time since the previous call
+    // decides how far the exposure moves towards the measured luminance.
+    pub fn record(
+        &mut self,
+        logical_device: &ash::Device,
+        commandbuffer: vk::CommandBuffer,
+        extent: vk::Extent2D,
+    ) {
+        let now = std::time::Instant::now();
+        let delta_time = self
+            .last_update
+            .map_or(0.0, |last| now.duration_since(last).as_secs_f32());
+        self.last_update = Some(now);
+
+        // Laid out like the push constant blocks in shaders/exposure_*.comp
+        let mut push_constants = [0u8; 16];
+        push_constants[0..4].copy_from_slice(&self.min_log_luminance.to_ne_bytes());
+        push_constants[4..8].copy_from_slice(
+            &(self.max_log_luminance - self.min_log_luminance)
+                .max(f32::EPSILON)
+                .to_ne_bytes(),
+        );
+        push_constants[8..12].copy_from_slice(&delta_time.to_ne_bytes());
+        push_constants[12..16].copy_from_slice(&self.adaptation_rate.to_ne_bytes());
+
+        // Both buffers are shared by all frames in flight, the previous frame's average pass
+        // and post pass have to be done with them first
+        let previous_frame = [vk::MemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE)
+            .build()];
+        let histogram_done = [vk::MemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE)
+            .build()];
+        let exposure_done = [vk::MemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ)
+            .build()];
+        unsafe {
+            logical_device.cmd_pipeline_barrier(
+                commandbuffer,
+                vk::PipelineStageFlags::COMPUTE_SHADER | vk::PipelineStageFlags::FRAGMENT_SHADER,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::DependencyFlags::empty(),
+                &previous_frame,
+                &[],
+                &[],
+            );
+            logical_device.cmd_bind_descriptor_sets(
+                commandbuffer,
+                vk::PipelineBindPoint::COMPUTE,
+                self.pipeline_layout,
+                0,
+                &[self.descriptor_set],
+                &[],
+            );
+            logical_device.cmd_push_constants(
+                commandbuffer,
+                self.pipeline_layout,
+                vk::ShaderStageFlags::COMPUTE,
+                0,
+                &push_constants,
+            );
+            logical_device.cmd_bind_pipeline(
+                commandbuffer,
+                vk::PipelineBindPoint::COMPUTE,
+                self.histogram_pipeline,
+            );
+            logical_device.cmd_dispatch(
+                commandbuffer,
+                extent.width.div_ceil(16),
+                extent.height.div_ceil(16),
+                1,
+            );
+            logical_device.cmd_pipeline_barrier(
+                commandbuffer,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::DependencyFlags::empty(),
+                &histogram_done,
+                &[],
+                &[],
+            );
+            logical_device.cmd_bind_pipeline(
+                commandbuffer,
+                vk::PipelineBindPoint::COMPUTE,
+                self.average_pipeline,
+            );
+            logical_device.cmd_dispatch(commandbuffer, 1, 1, 1);
+            logical_device.cmd_pipeline_barrier(
+                commandbuffer,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::FRAGMENT_SHADER,
+                vk::DependencyFlags::empty(),
+                &exposure_done,
+                &[],
+                &[],
+            );
+        }
+    }
+
+    // Builds the pipelines again from the shader library, keeping the old ones if that fails.
+    // They must not be in use anymore
+    pub unsafe fn rebuild_pipelines(
+        &mut self,
+        logical_device: &ash::Device,
+        cache: vk::PipelineCache,
+        shaders: &ShaderLibrary,
+    ) -> Result<(), vk::Result> {
+        let pipelines = ibl::create_compute_pipelines(
+            logical_device,
+            self.pipeline_layout,
+            cache,
+            shaders,
+            &SHADERS,
+        )?;
+        logical_device.destroy_pipeline(self.histogram_pipeline, None);
+        logical_device.destroy_pipeline(self.average_pipeline, None);
+        self.histogram_pipeline = pipelines[0];
+        self.average_pipeline = pipelines[1];
+        Ok(())
+    }
+
+    pub unsafe fn cleanup(
+        &mut self,
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        logical_device.destroy_descriptor_pool(self.descriptor_pool, None);
+        logical_device.destroy_sampler(self.sampler, None);
+        logical_device.destroy_pipeline(self.histogram_pipeline, None);
+        logical_device.destroy_pipeline(self.average_pipeline, None);
+        logical_device.destroy_pipeline_layout(self.pipeline_layout, None);
+        logical_device.destroy_descriptor_set_layout(self.descriptor_set_layout, None);
+        self.histogram_buffer.destroy(logical_device, allocator)?;
+        self.state_buffer.destroy(logical_device, allocator)?;
+        Ok(())
+    }
+}
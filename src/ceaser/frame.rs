@@ -0,0 +1,326 @@
+use ash::vk;
+use nalgebra as na;
+
+use crate::ceaser::{
+    buffer::Buffer,
+    cluster::{self, LightCulling},
+    ibl::Environment,
+    pipeline::Pipeline,
+    shadow::{self, ShadowMaps, ShadowViews},
+    ssao::Ssao,
+};
+
+pub const DEFAULT_FRAMES_IN_FLIGHT: usize = 2;
+
+// The view and projection matrix
+const CAMERA_UNIFORMS_SIZE: usize = 128;
+// The light counts in front of the lights, see LightManager::update_buffer, which grows the
+// buffer once there are lights
+const LIGHT_HEADER_SIZE: usize = 16;
+const SHADOW_UNIFORMS_SIZE: usize =
+    64 * (shadow::MAX_SHADOW_MAPS + shadow::MAX_POINT_SHADOW_MAPS) + 16;
+
+// What the buffers hold until the first frame writes them
+const NO_LIGHTS: [f32; 4] = [0.0; 4];
+const NO_SHADOWS: [u8; SHADOW_UNIFORMS_SIZE] = [0; SHADOW_UNIFORMS_SIZE];
+
+fn initial_camera_transforms() -> [[[f32; 4]; 4]; 2] {
+    [
+        na::Matrix4::identity().into(),
+        na::Matrix4::identity().into(),
+    ]
+}
+
+// Everything the CPU writes while recording a frame. A frame slot is only reused once
+// `may_begin_drawing` has been signaled, so the GPU is done reading its buffers by then.
+pub struct FrameContext {
+    pub command_buffer: vk::CommandBuffer,
+    pub image_available: vk::Semaphore,
+    pub rendering_finished: vk::Semaphore,
+    pub may_begin_drawing: vk::Fence,
+    pub uniform_buffer: Buffer,
+    pub light_buffer: Buffer,
+    pub shadow_buffer: Buffer,
+    // Point light indices per cluster, only written and read on the GPU
+    pub cluster_buffer: Buffer,
+    pub shadow_views: ShadowViews,
+    pub descriptor_set_camera: vk::DescriptorSet,
+    pub descriptor_set_light: vk::DescriptorSet,
+    pub descriptor_set_culling: vk::DescriptorSet,
+}
+
+impl FrameContext {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+        command_buffer: vk::CommandBuffer,
+        descriptor_pool: vk::DescriptorPool,
+        pipeline: &Pipeline,
+        light_culling: &LightCulling,
+        shadow_maps: &ShadowMaps,
+        environment: &Environment,
+    ) -> Result<FrameContext, Box<dyn std::error::Error>> {
+        let semaphoreinfo = vk::SemaphoreCreateInfo::builder();
+        let fenceinfo = vk::FenceCreateInfo::builder().flags(vk::FenceCreateFlags::SIGNALED);
+        let image_available = unsafe { logical_device.create_semaphore(&semaphoreinfo, None) }?;
+        let rendering_finished = unsafe { logical_device.create_semaphore(&semaphoreinfo, None) }?;
+        let may_begin_drawing = unsafe { logical_device.create_fence(&fenceinfo, None) }?;
+
+        let mut uniform_buffer = Buffer::new(
+            logical_device,
+            allocator,
+            CAMERA_UNIFORMS_SIZE as u64,
+            vk::BufferUsageFlags::UNIFORM_BUFFER,
+            gpu_allocator::MemoryLocation::CpuToGpu,
+        )?;
+        uniform_buffer.write(&initial_camera_transforms())?;
+
+        let mut light_buffer = Buffer::new(
+            logical_device,
+            allocator,
+            LIGHT_HEADER_SIZE as u64,
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+            gpu_allocator::MemoryLocation::CpuToGpu,
+        )?;
+        light_buffer.write(&NO_LIGHTS)?;
+
+        let mut shadow_buffer = Buffer::new(
+            logical_device,
+            allocator,
+            SHADOW_UNIFORMS_SIZE as u64,
+            vk::BufferUsageFlags::UNIFORM_BUFFER,
+            gpu_allocator::MemoryLocation::CpuToGpu,
+        )?;
+        shadow_buffer.write(&NO_SHADOWS)?;
+
+        let cluster_buffer = Buffer::new(
+            logical_device,
+            allocator,
+            cluster::CLUSTER_BUFFER_SIZE,
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+            gpu_allocator::MemoryLocation::GpuOnly,
+        )?;
+
+        let desc_layouts = [
+            pipeline.descriptor_set_layouts[0],
+            pipeline.descriptor_set_layouts[1],
+            light_culling.descriptor_set_layout,
+        ];
+        let descriptor_set_allocate_info = vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(descriptor_pool)
+            .set_layouts(&desc_layouts);
+        let descriptor_sets =
+            unsafe { logical_device.allocate_descriptor_sets(&descriptor_set_allocate_info) }?;
+
+        let camera_buffer_infos = [vk::DescriptorBufferInfo {
+            buffer: uniform_buffer.buffer,
+            offset: 0,
+            range: uniform_buffer.size_in_bytes,
+        }];
+        let light_buffer_infos = [vk::DescriptorBufferInfo {
+            buffer: light_buffer.buffer,
+            offset: 0,
+            range: light_buffer.size_in_bytes,
+        }];
+        let shadow_buffer_infos = [vk::DescriptorBufferInfo {
+            buffer: shadow_buffer.buffer,
+            offset: 0,
+            range: shadow_buffer.size_in_bytes,
+        }];
+        let cluster_buffer_infos = [vk::DescriptorBufferInfo {
+            buffer: cluster_buffer.buffer,
+            offset: 0,
+            range: cluster_buffer.size_in_bytes,
+        }];
+        let shadow_map_infos = [vk::DescriptorImageInfo {
+            sampler: shadow_maps.sampler,
+            image_view: shadow_maps.directional.array_view,
+            image_layout: vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL,
+        }];
+        let point_shadow_map_infos = [vk::DescriptorImageInfo {
+            sampler: shadow_maps.sampler,
+            image_view: shadow_maps.point.array_view,
+            image_layout: vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL,
+        }];
+        let desc_sets_write = [
+            vk::WriteDescriptorSet::builder()
+                .dst_set(descriptor_sets[0])
+                .dst_binding(0)
+                .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                .buffer_info(&camera_buffer_infos)
+                .build(),
+            vk::WriteDescriptorSet::builder()
+                .dst_set(descriptor_sets[1])
+                .dst_binding(0)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .buffer_info(&light_buffer_infos)
+                .build(),
+            vk::WriteDescriptorSet::builder()
+                .dst_set(descriptor_sets[1])
+                .dst_binding(1)
+                .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                .buffer_info(&shadow_buffer_infos)
+                .build(),
+            vk::WriteDescriptorSet::builder()
+                .dst_set(descriptor_sets[1])
+                .dst_binding(2)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .image_info(&shadow_map_infos)
+                .build(),
+            vk::WriteDescriptorSet::builder()
+                .dst_set(descriptor_sets[1])
+                .dst_binding(3)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .image_info(&point_shadow_map_infos)
+                .build(),
+            vk::WriteDescriptorSet::builder()
+                .dst_set(descriptor_sets[1])
+                .dst_binding(8)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .buffer_info(&cluster_buffer_infos)
+                .build(),
+            vk::WriteDescriptorSet::builder()
+                .dst_set(descriptor_sets[2])
+                .dst_binding(0)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .buffer_info(&light_buffer_infos)
+                .build(),
+            vk::WriteDescriptorSet::builder()
+                .dst_set(descriptor_sets[2])
+                .dst_binding(1)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .buffer_info(&cluster_buffer_infos)
+                .build(),
+        ];
+        unsafe { logical_device.update_descriptor_sets(&desc_sets_write, &[]) };
+
+        let frame = FrameContext {
+            command_buffer,
+            image_available,
+            rendering_finished,
+            may_begin_drawing,
+            uniform_buffer,
+            light_buffer,
+            shadow_buffer,
+            cluster_buffer,
+            shadow_views: ShadowViews::default(),
+            descriptor_set_camera: descriptor_sets[0],
+            descriptor_set_light: descriptor_sets[1],
+            descriptor_set_culling: descriptor_sets[2],
+        };
+        frame.write_environment(logical_device, environment);
+        Ok(frame)
+    }
+
+    // Also needed whenever the environment is replaced
+    pub fn write_environment(&self, logical_device: &ash::Device, environment: &Environment) {
+        let views = [
+            environment.irradiance_view(),
+            environment.specular_view(),
+            environment.brdf_lut_view(),
+        ];
+        let image_infos: Vec<[vk::DescriptorImageInfo; 1]> = views
+            .iter()
+            .map(|&image_view| {
+                [vk::DescriptorImageInfo {
+                    sampler: environment.sampler,
+                    image_view,
+                    image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                }]
+            })
+            .collect();
+        let desc_sets_write: Vec<vk::WriteDescriptorSet> = image_infos
+            .iter()
+            .enumerate()
+            .map(|(i, info)| {
+                vk::WriteDescriptorSet::builder()
+                    .dst_set(self.descriptor_set_light)
+                    .dst_binding(4 + i as u32)
+                    .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                    .image_info(info)
+                    .build()
+            })
+            .collect();
+        unsafe { logical_device.update_descriptor_sets(&desc_sets_write, &[]) };
+    }
+
+    // Also needed whenever the occlusion images are recreated
+    pub fn write_ambient_occlusion(&self, logical_device: &ash::Device, ssao: &Ssao) {
+        let image_infos = [vk::DescriptorImageInfo {
+            sampler: ssao.sampler,
+            image_view: ssao.view(),
+            image_layout: vk::ImageLayout::GENERAL,
+        }];
+        let desc_sets_write = [vk::WriteDescriptorSet::builder()
+            .dst_set(self.descriptor_set_light)
+            .dst_binding(7)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .image_info(&image_infos)
+            .build()];
+        unsafe { logical_device.update_descriptor_sets(&desc_sets_write, &[]) };
+    }
+
+    pub unsafe fn cleanup(
+        &mut self,
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        logical_device.destroy_fence(self.may_begin_drawing, None);
+        logical_device.destroy_semaphore(self.image_available, None);
+        logical_device.destroy_semaphore(self.rendering_finished, None);
+        self.uniform_buffer.destroy(logical_device, allocator)?;
+        self.light_buffer.destroy(logical_device, allocator)?;
+        self.shadow_buffer.destroy(logical_device, allocator)?;
+        self.cluster_buffer.destroy(logical_device, allocator)?;
+        Ok(())
+    }
+}
+
+// Per frame: camera and shadow uniforms, the light and cluster storage buffers, the shadow maps,
+// the environment, the ambient occlusion and the light culling set
+pub fn create_descriptor_pool(
+    logical_device: &ash::Device,
+    frames_in_flight: u32,
+) -> Result<vk::DescriptorPool, vk::Result> {
+    let pool_sizes = [
+        vk::DescriptorPoolSize {
+            ty: vk::DescriptorType::UNIFORM_BUFFER,
+            descriptor_count: 2 * frames_in_flight,
+        },
+        vk::DescriptorPoolSize {
+            ty: vk::DescriptorType::STORAGE_BUFFER,
+            descriptor_count: 4 * frames_in_flight,
+        },
+        vk::DescriptorPoolSize {
+            ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            descriptor_count: 6 * frames_in_flight,
+        },
+    ];
+    let descriptor_pool_info = vk::DescriptorPoolCreateInfo::builder()
+        .max_sets(3 * frames_in_flight)
+        .pool_sizes(&pool_sizes);
+    unsafe { logical_device.create_descriptor_pool(&descriptor_pool_info, None) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Buffer::write refuses anything larger than the buffer, which would fail Ceaser::init
+    #[test]
+    fn initial_writes_fit_their_buffers() {
+        let writes = [
+            (
+                "camera",
+                std::mem::size_of_val(&initial_camera_transforms()),
+                CAMERA_UNIFORMS_SIZE,
+            ),
+            ("light", std::mem::size_of_val(&NO_LIGHTS), LIGHT_HEADER_SIZE),
+            ("shadow", std::mem::size_of_val(&NO_SHADOWS), SHADOW_UNIFORMS_SIZE),
+        ];
+        for (buffer, written, size) in writes {
+            assert!(written <= size, "{} bytes into the {} byte {} buffer", written, size, buffer);
+        }
+    }
+}
@@ -0,0 +1,525 @@
+use ash::vk;
+
+use crate::ceaser::{shaders::ShaderLibrary, texture::Image, transfer::Uploader};
+
+pub const IRRADIANCE_SIZE: u32 = 32;
+pub const SPECULAR_SIZE: u32 = 128;
+// Keep in sync with shader.frag, the last level is for roughness 1
+pub const SPECULAR_MIP_LEVELS: u32 = 5;
+pub const BRDF_LUT_SIZE: u32 = 256;
+const IBL_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+// Only run while an environment is set up, so reloading them takes effect with the next one
+pub const SHADERS: [&str; 3] = ["ibl_irradiance.comp", "ibl_specular.comp", "ibl_brdf.comp"];
+
+// A cube image written by compute shaders, with one storage view per mip level
+struct Cubemap {
+    image: vk::Image,
+    allocation: gpu_allocator::vulkan::Allocation,
+    view: vk::ImageView,
+    level_views: Vec<vk::ImageView>,
+    mip_levels: u32,
+}
+
+// Ambient light from an equirectangular HDR image: diffuse irradiance, specular radiance
+// prefiltered for increasing roughness along the mip chain and the split sum BRDF table
+pub struct Environment {
+    irradiance: Cubemap,
+    specular: Cubemap,
+    brdf_lut: Image,
+    pub sampler: vk::Sampler,
+}
+
+impl Environment {
+    // Radiance .hdr file, up in the image is -y in the world
+    pub fn load<P: AsRef<std::path::Path>>(
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+        uploader: &Uploader,
+        cache: vk::PipelineCache,
+        shaders: &ShaderLibrary,
+        path: P,
+    ) -> Result<Environment, Box<dyn std::error::Error>> {
+        let pixels = ::image::open(path)?.into_rgba32f();
+        let extent = vk::Extent2D {
+            width: pixels.width(),
+            height: pixels.height(),
+        };
+        let texels: Vec<[half::f16; 4]> = pixels
+            .pixels()
+            .map(|p| p.0.map(half::f16::from_f32))
+            .collect();
+        Environment::from_equirectangular(
+            logical_device,
+            allocator,
+            uploader,
+            cache,
+            shaders,
+            extent,
+            &texels,
+        )
+    }
+
+    // No ambient light at all
+    pub fn black(
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+        uploader: &Uploader,
+        cache: vk::PipelineCache,
+        shaders: &ShaderLibrary,
+    ) -> Result<Environment, Box<dyn std::error::Error>> {
+        Environment::from_equirectangular(
+            logical_device,
+            allocator,
+            uploader,
+            cache,
+            shaders,
+            vk::Extent2D {
+                width: 1,
+                height: 1,
+            },
+            &[[half::f16::ZERO; 4]],
+        )
+    }
+
+    fn from_equirectangular(
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+        uploader: &Uploader,
+        cache: vk::PipelineCache,
+        shaders: &ShaderLibrary,
+        extent: vk::Extent2D,
+        texels: &[[half::f16; 4]],
+    ) -> Result<Environment, Box<dyn std::error::Error>> {
+        let mut equirectangular =
+            Image::from_pixels(logical_device, allocator, uploader, extent, texels, IBL_FORMAT)?;
+        let irradiance = Cubemap::new(logical_device, allocator, IRRADIANCE_SIZE, 1)?;
+        let specular =
+            Cubemap::new(logical_device, allocator, SPECULAR_SIZE, SPECULAR_MIP_LEVELS)?;
+        let brdf_lut = Image::new(
+            logical_device,
+            allocator,
+            vk::Extent2D {
+                width: BRDF_LUT_SIZE,
+                height: BRDF_LUT_SIZE,
+            },
+            IBL_FORMAT,
+            1,
+            vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED,
+        )?;
+
+        let sampler_info = vk::SamplerCreateInfo::builder()
+            .mag_filter(vk::Filter::LINEAR)
+            .min_filter(vk::Filter::LINEAR)
+            .mipmap_mode(vk::SamplerMipmapMode::LINEAR)
+            .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .max_lod(vk::LOD_CLAMP_NONE);
+        let sampler = unsafe { logical_device.create_sampler(&sampler_info, None) }?;
+
+        let environment = Environment {
+            irradiance,
+            specular,
+            brdf_lut,
+            sampler,
+        };
+        let prefiltered =
+            environment.prefilter(logical_device, uploader, cache, shaders, &equirectangular);
+        unsafe { equirectangular.destroy(logical_device, allocator) }?;
+        prefiltered?;
+        Ok(environment)
+    }
+
+    // Runs the three compute shaders once, leaving every image ready for sampling
+    fn prefilter(
+        &self,
+        logical_device: &ash::Device,
+        uploader: &Uploader,
+        cache: vk::PipelineCache,
+        shaders: &ShaderLibrary,
+        equirectangular: &Image,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let bindings = [
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(0)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::COMPUTE)
+                .build(),
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(1)
+                .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::COMPUTE)
+                .build(),
+        ];
+        let set_layout_info = vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings);
+        let set_layout =
+            unsafe { logical_device.create_descriptor_set_layout(&set_layout_info, None) }?;
+        let set_layouts = [set_layout];
+        let push_constant_ranges = [vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::COMPUTE,
+            offset: 0,
+            size: 4,
+        }];
+        let layout_info = vk::PipelineLayoutCreateInfo::builder()
+            .set_layouts(&set_layouts)
+            .push_constant_ranges(&push_constant_ranges);
+        let layout = unsafe { logical_device.create_pipeline_layout(&layout_info, None) }?;
+
+        // The equirectangular map wraps around horizontally
+        let sampler_info = vk::SamplerCreateInfo::builder()
+            .mag_filter(vk::Filter::LINEAR)
+            .min_filter(vk::Filter::LINEAR)
+            .mipmap_mode(vk::SamplerMipmapMode::LINEAR)
+            .address_mode_u(vk::SamplerAddressMode::REPEAT)
+            .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .max_lod(vk::LOD_CLAMP_NONE);
+        let equirectangular_sampler = unsafe { logical_device.create_sampler(&sampler_info, None) }?;
+
+        let set_count = 2 + SPECULAR_MIP_LEVELS;
+        let pool_sizes = [
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                descriptor_count: set_count,
+            },
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::STORAGE_IMAGE,
+                descriptor_count: set_count,
+            },
+        ];
+        let pool_info = vk::DescriptorPoolCreateInfo::builder()
+            .max_sets(set_count)
+            .pool_sizes(&pool_sizes);
+        let pool = unsafe { logical_device.create_descriptor_pool(&pool_info, None) }?;
+
+        let pipelines = create_compute_pipelines(logical_device, layout, cache, shaders, &SHADERS)?;
+
+        // (pipeline, target view, target size, layers, roughness)
+        let mut dispatches = vec![(
+            pipelines[0],
+            self.irradiance.level_views[0],
+            IRRADIANCE_SIZE,
+            6,
+            0.0,
+        )];
+        for level in 0..SPECULAR_MIP_LEVELS {
+            dispatches.push((
+                pipelines[1],
+                self.specular.level_views[level as usize],
+                (SPECULAR_SIZE >> level).max(1),
+                6,
+                level as f32 / (SPECULAR_MIP_LEVELS - 1) as f32,
+            ));
+        }
+        dispatches.push((pipelines[2], self.brdf_lut.imageview, BRDF_LUT_SIZE, 1, 0.0));
+
+        let layouts = vec![set_layout; dispatches.len()];
+        let allocate_info = vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(pool)
+            .set_layouts(&layouts);
+        let sets = unsafe { logical_device.allocate_descriptor_sets(&allocate_info) }?;
+        let source_infos = [vk::DescriptorImageInfo {
+            sampler: equirectangular_sampler,
+            image_view: equirectangular.imageview,
+            image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        }];
+        for (set, &(_, view, _, _, _)) in sets.iter().zip(&dispatches) {
+            let target_infos = [vk::DescriptorImageInfo {
+                sampler: vk::Sampler::null(),
+                image_view: view,
+                image_layout: vk::ImageLayout::GENERAL,
+            }];
+            let writes = [
+                vk::WriteDescriptorSet::builder()
+                    .dst_set(*set)
+                    .dst_binding(0)
+                    .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                    .image_info(&source_infos)
+                    .build(),
+                vk::WriteDescriptorSet::builder()
+                    .dst_set(*set)
+                    .dst_binding(1)
+                    .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                    .image_info(&target_infos)
+                    .build(),
+            ];
+            unsafe { logical_device.update_descriptor_sets(&writes, &[]) };
+        }
+
+        let targets = [
+            (self.irradiance.image, self.irradiance.mip_levels, 6),
+            (self.specular.image, self.specular.mip_levels, 6),
+            (self.brdf_lut.image, 1, 1),
+        ];
+        // The graphics queue family of any device that has one also supports compute
+        let recorded = uploader.run_on_graphics_queue(logical_device, |commandbuffer| unsafe {
+            let to_general: Vec<vk::ImageMemoryBarrier> = targets
+                .iter()
+                .map(|&(image, levels, layers)| {
+                    layout_barrier(
+                        image,
+                        levels,
+                        layers,
+                        (vk::ImageLayout::UNDEFINED, vk::ImageLayout::GENERAL),
+                        (vk::AccessFlags::empty(), vk::AccessFlags::SHADER_WRITE),
+                    )
+                })
+                .collect();
+            logical_device.cmd_pipeline_barrier(
+                commandbuffer,
+                vk::PipelineStageFlags::TOP_OF_PIPE,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &to_general,
+            );
+            for (&(pipeline, _, size, layers, roughness), set) in dispatches.iter().zip(&sets) {
+                logical_device.cmd_bind_pipeline(
+                    commandbuffer,
+                    vk::PipelineBindPoint::COMPUTE,
+                    pipeline,
+                );
+                logical_device.cmd_bind_descriptor_sets(
+                    commandbuffer,
+                    vk::PipelineBindPoint::COMPUTE,
+                    layout,
+                    0,
+                    &[*set],
+                    &[],
+                );
+                logical_device.cmd_push_constants(
+                    commandbuffer,
+                    layout,
+                    vk::ShaderStageFlags::COMPUTE,
+                    0,
+                    &f32::to_ne_bytes(roughness),
+                );
+                let groups = size.div_ceil(8);
+                logical_device.cmd_dispatch(commandbuffer, groups, groups, layers);
+            }
+            let to_read_only: Vec<vk::ImageMemoryBarrier> = targets
+                .iter()
+                .map(|&(image, levels, layers)| {
+                    layout_barrier(
+                        image,
+                        levels,
+                        layers,
+                        (
+                            vk::ImageLayout::GENERAL,
+                            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                        ),
+                        (vk::AccessFlags::SHADER_WRITE, vk::AccessFlags::SHADER_READ),
+                    )
+                })
+                .collect();
+            logical_device.cmd_pipeline_barrier(
+                commandbuffer,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::FRAGMENT_SHADER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &to_read_only,
+            );
+        });
+
+        unsafe {
+            for pipeline in pipelines {
+                logical_device.destroy_pipeline(pipeline, None);
+            }
+            logical_device.destroy_descriptor_pool(pool, None);
+            logical_device.destroy_sampler(equirectangular_sampler, None);
+            logical_device.destroy_pipeline_layout(layout, None);
+            logical_device.destroy_descriptor_set_layout(set_layout, None);
+        }
+        recorded?;
+        Ok(())
+    }
+
+    pub fn irradiance_view(&self) -> vk::ImageView {
+        self.irradiance.view
+    }
+
+    pub fn specular_view(&self) -> vk::ImageView {
+        self.specular.view
+    }
+
+    pub fn brdf_lut_view(&self) -> vk::ImageView {
+        self.brdf_lut.imageview
+    }
+
+    pub unsafe fn cleanup(
+        &mut self,
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.irradiance.destroy(logical_device, allocator)?;
+        self.specular.destroy(logical_device, allocator)?;
+        self.brdf_lut.destroy(logical_device, allocator)?;
+        logical_device.destroy_sampler(self.sampler, None);
+        Ok(())
+    }
+}
+
+impl Cubemap {
+    fn new(
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+        size: u32,
+        mip_levels: u32,
+    ) -> Result<Cubemap, Box<dyn std::error::Error>> {
+        let image_info = vk::ImageCreateInfo::builder()
+            .flags(vk::ImageCreateFlags::CUBE_COMPATIBLE)
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(IBL_FORMAT)
+            .extent(vk::Extent3D {
+                width: size,
+                height: size,
+                depth: 1,
+            })
+            .mip_levels(mip_levels)
+            .array_layers(6)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE);
+        let image = unsafe { logical_device.create_image(&image_info, None) }?;
+        let requirements = unsafe { logical_device.get_image_memory_requirements(image) };
+        let allocation = allocator.allocate(&gpu_allocator::vulkan::AllocationCreateDesc {
+            name: "Environment Cubemap",
+            requirements,
+            location: gpu_allocator::MemoryLocation::GpuOnly,
+            linear: false,
+        })?;
+        unsafe { logical_device.bind_image_memory(image, allocation.memory(), allocation.offset()) }?;
+
+        let view = create_view(logical_device, image, vk::ImageViewType::CUBE, 0, mip_levels)?;
+        let mut level_views = Vec::with_capacity(mip_levels as usize);
+        for level in 0..mip_levels {
+            level_views.push(create_view(
+                logical_device,
+                image,
+                vk::ImageViewType::TYPE_2D_ARRAY,
+                level,
+                1,
+            )?);
+        }
+        Ok(Cubemap {
+            image,
+            allocation,
+            view,
+            level_views,
+            mip_levels,
+        })
+    }
+
+    unsafe fn destroy(
+        &mut self,
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        allocator.free(std::mem::take(&mut self.allocation))?;
+        for view in self.level_views.drain(..) {
+            logical_device.destroy_image_view(view, None);
+        }
+        logical_device.destroy_image_view(self.view, None);
+        logical_device.destroy_image(self.image, None);
+        Ok(())
+    }
+}
+
+fn create_view(
+    logical_device: &ash::Device,
+    image: vk::Image,
+    view_type: vk::ImageViewType,
+    base_mip_level: u32,
+    level_count: u32,
+) -> Result<vk::ImageView, vk::Result> {
+    let subresource_range = vk::ImageSubresourceRange::builder()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .base_mip_level(base_mip_level)
+        .level_count(level_count)
+        .base_array_layer(0)
+        .layer_count(6);
+    let view_info = vk::ImageViewCreateInfo::builder()
+        .image(image)
+        .view_type(view_type)
+        .format(IBL_FORMAT)
+        .subresource_range(*subresource_range);
+    unsafe { logical_device.create_image_view(&view_info, None) }
+}
+
+fn layout_barrier(
+    image: vk::Image,
+    level_count: u32,
+    layer_count: u32,
+    layouts: (vk::ImageLayout, vk::ImageLayout),
+    access: (vk::AccessFlags, vk::AccessFlags),
+) -> vk::ImageMemoryBarrier {
+    vk::ImageMemoryBarrier::builder()
+        .image(image)
+        .src_access_mask(access.0)
+        .dst_access_mask(access.1)
+        .old_layout(layouts.0)
+        .new_layout(layouts.1)
+        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .subresource_range(vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_mip_level: 0,
+            level_count,
+            base_array_layer: 0,
+            layer_count,
+        })
+        .build()
+}
+
+pub fn create_compute_pipeline(
+    logical_device: &ash::Device,
+    layout: vk::PipelineLayout,
+    cache: vk::PipelineCache,
+    code: &[u32],
+) -> Result<vk::Pipeline, vk::Result> {
+    let shader_info = vk::ShaderModuleCreateInfo::builder().code(code);
+    let shader_module = unsafe { logical_device.create_shader_module(&shader_info, None) }?;
+    let mainfunctionname = std::ffi::CString::new("main").unwrap();
+    let stage = vk::PipelineShaderStageCreateInfo::builder()
+        .stage(vk::ShaderStageFlags::COMPUTE)
+        .module(shader_module)
+        .name(&mainfunctionname);
+    let pipeline_info = [vk::ComputePipelineCreateInfo::builder()
+        .stage(*stage)
+        .layout(layout)
+        .build()];
+    let pipelines = unsafe {
+        logical_device.create_compute_pipelines(cache, &pipeline_info, None)
+    };
+    unsafe { logical_device.destroy_shader_module(shader_module, None) };
+    pipelines.map(|p| p[0]).map_err(|(_, e)| e)
+}
+
+// A pipeline per named shader in one layout, none of them if one fails
+pub fn create_compute_pipelines(
+    logical_device: &ash::Device,
+    layout: vk::PipelineLayout,
+    cache: vk::PipelineCache,
+    shaders: &ShaderLibrary,
+    names: &[&str],
+) -> Result<Vec<vk::Pipeline>, vk::Result> {
+    let mut pipelines = Vec::with_capacity(names.len());
+    for name in names {
+        match create_compute_pipeline(logical_device, layout, cache, shaders.get(name)) {
+            Ok(pipeline) => pipelines.push(pipeline),
+            Err(e) => {
+                for pipeline in pipelines {
+                    unsafe { logical_device.destroy_pipeline(pipeline, None) };
+                }
+                return Err(e);
+            }
+        }
+    }
+    Ok(pipelines)
+}
@@ -6,6 +6,8 @@ pub fn init_device_and_queues(
     physical_device: vk::PhysicalDevice,
     queue_families: &QueueFamilies,
     layer_names: &[&str],
+    device_extension_names: &[&std::ffi::CStr],
+    features: &vk::PhysicalDeviceFeatures,
 ) -> Result<(ash::Device, Queues), vk::Result> {
     let layer_names_c: Vec<std::ffi::CString> = layer_names
         .iter()
@@ -17,28 +19,34 @@ pub fn init_device_and_queues(
         .collect();
 
     let priorities = [1.0f32];
-    let queue_infos = [
-        vk::DeviceQueueCreateInfo::builder()
-            .queue_family_index(queue_families.graphics_q_index.unwrap())
-            .queue_priorities(&priorities)
-            .build(),
-        vk::DeviceQueueCreateInfo::builder()
-            .queue_family_index(queue_families.transfer_q_index.unwrap())
-            .queue_priorities(&priorities)
-            .build(),
-    ];
-    let device_extension_name_pointers: Vec<*const i8> =
-        vec![ash::extensions::khr::Swapchain::name().as_ptr()];
+    let graphics_q_index = queue_families.graphics_q_index.unwrap();
+    let transfer_q_index = queue_families.transfer_q_index.unwrap();
+    let mut queue_infos = vec![vk::DeviceQueueCreateInfo::builder()
+        .queue_family_index(graphics_q_index)
+        .queue_priorities(&priorities)
+        .build()];
+    // Software implementations often expose a single family for everything
+    if transfer_q_index != graphics_q_index {
+        queue_infos.push(
+            vk::DeviceQueueCreateInfo::builder()
+                .queue_family_index(transfer_q_index)
+                .queue_priorities(&priorities)
+                .build(),
+        );
+    }
+    let device_extension_name_pointers: Vec<*const i8> = device_extension_names
+        .iter()
+        .map(|extension_name| extension_name.as_ptr())
+        .collect();
     let device_create_info = vk::DeviceCreateInfo::builder()
         .queue_create_infos(&queue_infos)
         .enabled_extension_names(&device_extension_name_pointers)
+        .enabled_features(features)
         .enabled_layer_names(&layer_name_pointers);
     let logical_device =
         unsafe { instance.create_device(physical_device, &device_create_info, None)? };
-    let graphics_queue =
-        unsafe { logical_device.get_device_queue(queue_families.graphics_q_index.unwrap(), 0) };
-    let transfer_queue =
-        unsafe { logical_device.get_device_queue(queue_families.transfer_q_index.unwrap(), 0) };
+    let graphics_queue = unsafe { logical_device.get_device_queue(graphics_q_index, 0) };
+    let transfer_queue = unsafe { logical_device.get_device_queue(transfer_q_index, 0) };
     Ok((
         logical_device,
         Queues {
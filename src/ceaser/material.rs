@@ -0,0 +1,378 @@
+use ash::vk;
+
+use super::deferred::RenderPath;
+use super::pipeline::{self, Pipeline, ScenePass};
+use super::reflect::ShaderReflection;
+use super::shaders::ShaderLibrary;
+
+// Every scene pipeline has the push constant range of Pipeline, reflected from the built-in
+// materials' shaders. The per draw flags come first, see Ceaser::draw_models, then the
+// material's parameter block
+pub const PARAMETER_OFFSET: u32 = 16;
+
+// Added by the renderer in this order, so these are their indices
+pub const PBR: usize = 0;
+pub const TRANSPARENT: usize = 1;
+pub const UNLIT: usize = 2;
+pub const WIREFRAME: usize = 3;
+
+// The fixed function part of a material's pipelines
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RasterState {
+    pub cull_mode: vk::CullModeFlags,
+    // Anything but FILL needs fillModeNonSolid, devices without it draw filled
+    pub polygon_mode: vk::PolygonMode,
+    // Alpha blended and drawn after every opaque model
+    pub blended: bool,
+    pub depth_test: bool,
+    // Ignored after the deferred lighting subpass, which only reads depth
+    pub depth_write: bool,
+}
+
+impl Default for RasterState {
+    fn default() -> Self {
+        RasterState {
+            cull_mode: vk::CullModeFlags::BACK,
+            polygon_mode: vk::PolygonMode::FILL,
+            blended: false,
+            depth_test: true,
+            depth_write: true,
+        }
+    }
+}
+
+// Shaders, fixed function state and the values pushed for them. Shaders are names in the
+// renderer's ShaderLibrary and have to fit the scene pipeline layout, which is reflected from
+// the built-in materials' shaders
+#[derive(Clone, Debug)]
+pub struct Material {
+    pub name: String,
+    pub vertex_shader: &'static str,
+    pub fragment_shader: &'static str,
+    // Writes the G-buffer on the deferred path. Materials without one are drawn forward after
+    // the lighting subpass, like blended ones
+    pub gbuffer_shader: Option<&'static str>,
+    pub state: RasterState,
+    // Pushed at PARAMETER_OFFSET, so the shaders see a vec4 per entry after the flags
+    pub parameters: Vec<[f32; 4]>,
+}
+
+impl Material {
+    pub fn pbr() -> Material {
+        Material {
+            name: "pbr".to_string(),
+            vertex_shader: "shader.vert",
+            fragment_shader: "shader.frag",
+            gbuffer_shader: Some("gbuffer.frag"),
+            state: RasterState::default(),
+            parameters: Vec::new(),
+        }
+    }
+
+    // Blends with the albedo texture's alpha
+    pub fn transparent() -> Material {
+        Material {
+            name: "transparent".to_string(),
+            gbuffer_shader: None,
+            state: RasterState {
+                blended: true,
+                depth_write: false,
+                ..RasterState::default()
+            },
+            ..Material::pbr()
+        }
+    }
+
+    // Instance color times albedo times the tint, without any lighting
+    pub fn unlit(tint: [f32; 4]) -> Material {
+        Material {
+            name: "unlit".to_string(),
+            vertex_shader: "shader.vert",
+            fragment_shader: "unlit.frag",
+            gbuffer_shader: None,
+            state: RasterState::default(),
+            parameters: vec![tint],
+        }
+    }
+
+    pub fn wireframe(tint: [f32; 4]) -> Material {
+        Material {
+            name: "wireframe".to_string(),
+            state: RasterState {
+                cull_mode: vk::CullModeFlags::NONE,
+                polygon_mode: vk::PolygonMode::LINE,
+                ..RasterState::default()
+            },
+            ..Material::unlit(tint)
+        }
+    }
+
+    pub fn shaders(&self) -> impl Iterator<Item = &'static str> {
+        [
+            Some(self.vertex_shader),
+            Some(self.fragment_shader),
+            self.gbuffer_shader,
+        ]
+        .into_iter()
+        .flatten()
+    }
+
+    // Where its models are drawn on the deferred path
+    pub fn deferred_pass(&self) -> ScenePass {
+        if self.gbuffer_shader.is_some() && !self.state.blended {
+            ScenePass::GBuffer
+        } else {
+            ScenePass::AfterLighting
+        }
+    }
+
+    pub fn parameter_bytes(&self) -> Vec<u8> {
+        self.parameters
+            .iter()
+            .flatten()
+            .flat_map(|v| v.to_ne_bytes())
+            .collect()
+    }
+
+    // Checks the shaders against the scene pipeline layout and the parameter block, so a
+    // mismatch is an error here and not a validation message or garbage on screen
+    fn validate(&self, scene: &Pipeline, shaders: &ShaderLibrary) -> Result<(), String> {
+        let range = scene.push_constant_range.unwrap_or_default();
+        let parameter_end = PARAMETER_OFFSET + 16 * self.parameters.len() as u32;
+        if !self.parameters.is_empty() && parameter_end > range.size {
+            return Err(format!(
+                "{} parameters need {} bytes of push constants but the scene pipeline layout \
+                 has {}",
+                self.parameters.len(),
+                parameter_end,
+                range.size
+            ));
+        }
+        for name in self.shaders() {
+            let code = shaders
+                .try_get(name)
+                .ok_or_else(|| format!("there is no shader `{}`", name))?;
+            let reflection = ShaderReflection::new(code).map_err(|e| format!("{}: {}", name, e))?;
+            for b in &reflection.bindings {
+                let declared = scene
+                    .set_bindings
+                    .get(b.set as usize)
+                    .and_then(|set| set.iter().find(|d| d.binding == b.binding));
+                let fits = declared.is_some_and(|d| {
+                    d.descriptor_type == b.descriptor_type
+                        && d.descriptor_count == b.count
+                        && d.stage_flags.contains(reflection.stage)
+                });
+                if !fits {
+                    return Err(format!(
+                        "{} declares `{}` as {} x {:?} at set {} binding {}, which the scene \
+                         pipeline layout does not have for the {:?} stage",
+                        name,
+                        b.name,
+                        b.count,
+                        b.descriptor_type,
+                        b.set,
+                        b.binding,
+                        reflection.stage
+                    ));
+                }
+            }
+            if let Some(size) = reflection.push_constant_size {
+                if size > range.size || !range.stage_flags.contains(reflection.stage) {
+                    return Err(format!(
+                        "{} reads {} bytes of push constants in the {:?} stage but the scene \
+                         pipeline layout has {} bytes for {:?}",
+                        name, size, reflection.stage, range.size, range.stage_flags
+                    ));
+                }
+                if size > parameter_end {
+                    return Err(format!(
+                        "{} reads {} bytes of push constants but the parameter block ends at {}",
+                        name, size, parameter_end
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Every material the models can refer to, with a pipeline per material for the forward render
+// pass and one for the deferred render pass
+pub struct Materials {
+    materials: Vec<Material>,
+    forward_pipelines: Vec<vk::Pipeline>,
+    deferred_pipelines: Vec<vk::Pipeline>,
+    forward_pass: vk::RenderPass,
+    samples: vk::SampleCountFlags,
+    deferred_pass: vk::RenderPass,
+    // Without fillModeNonSolid every material is drawn filled
+    non_solid_fill: bool,
+}
+
+impl Materials {
+    pub fn new(
+        forward_pass: vk::RenderPass,
+        samples: vk::SampleCountFlags,
+        deferred_pass: vk::RenderPass,
+        features: &vk::PhysicalDeviceFeatures,
+    ) -> Materials {
+        Materials {
+            materials: Vec::new(),
+            forward_pipelines: Vec::new(),
+            deferred_pipelines: Vec::new(),
+            forward_pass,
+            samples,
+            deferred_pass,
+            non_solid_fill: features.fill_mode_non_solid == vk::TRUE,
+        }
+    }
+
+    // Returns the index to put into Model::material
+    pub fn add(
+        &mut self,
+        logical_device: &ash::Device,
+        scene: &Pipeline,
+        cache: vk::PipelineCache,
+        shaders: &ShaderLibrary,
+        mut material: Material,
+    ) -> Result<usize, Box<dyn std::error::Error>> {
+        if !self.non_solid_fill {
+            material.state.polygon_mode = vk::PolygonMode::FILL;
+        }
+        let (forward, deferred) = self
+            .create_pipelines(logical_device, scene, cache, shaders, &material)
+            .map_err(|e| format!("material `{}`: {}", material.name, e))?;
+        self.materials.push(material);
+        self.forward_pipelines.push(forward);
+        self.deferred_pipelines.push(deferred);
+        Ok(self.materials.len() - 1)
+    }
+
+    fn create_pipelines(
+        &self,
+        logical_device: &ash::Device,
+        scene: &Pipeline,
+        cache: vk::PipelineCache,
+        shaders: &ShaderLibrary,
+        material: &Material,
+    ) -> Result<(vk::Pipeline, vk::Pipeline), Box<dyn std::error::Error>> {
+        material.validate(scene, shaders)?;
+        let forward = pipeline::create_graphics_pipeline(
+            logical_device,
+            scene,
+            self.forward_pass,
+            self.samples,
+            ScenePass::Forward,
+            material,
+            cache,
+            shaders,
+        )?;
+        let deferred = pipeline::create_graphics_pipeline(
+            logical_device,
+            scene,
+            self.deferred_pass,
+            vk::SampleCountFlags::TYPE_1,
+            material.deferred_pass(),
+            material,
+            cache,
+            shaders,
+        );
+        match deferred {
+            Ok(deferred) => Ok((forward, deferred)),
+            Err(e) => {
+                unsafe { logical_device.destroy_pipeline(forward, None) };
+                Err(e)
+            }
+        }
+    }
+
+    pub fn get(&self, index: usize) -> &Material {
+        &self.materials[index]
+    }
+
+    pub fn count(&self) -> usize {
+        self.materials.len()
+    }
+
+    pub fn pipeline(&self, index: usize, render_path: RenderPath) -> vk::Pipeline {
+        match render_path {
+            RenderPath::Forward => self.forward_pipelines[index],
+            RenderPath::Deferred => self.deferred_pipelines[index],
+        }
+    }
+
+    // Builds a material's pipelines again from the shader library, for instance after its
+    // shaders were reloaded. On failure the old ones stay, otherwise they must not be in use
+    // anymore
+    pub unsafe fn rebuild(
+        &mut self,
+        logical_device: &ash::Device,
+        scene: &Pipeline,
+        cache: vk::PipelineCache,
+        shaders: &ShaderLibrary,
+        index: usize,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let material = &self.materials[index];
+        let (forward, deferred) = self
+            .create_pipelines(logical_device, scene, cache, shaders, material)
+            .map_err(|e| format!("material `{}`: {}", material.name, e))?;
+        logical_device.destroy_pipeline(self.forward_pipelines[index], None);
+        logical_device.destroy_pipeline(self.deferred_pipelines[index], None);
+        self.forward_pipelines[index] = forward;
+        self.deferred_pipelines[index] = deferred;
+        Ok(())
+    }
+
+    // Swaps every forward pipeline for one matching a new forward render pass. None of them may
+    // be in use anymore, on failure all of them are kept
+    pub unsafe fn set_forward_pass(
+        &mut self,
+        logical_device: &ash::Device,
+        scene: &Pipeline,
+        cache: vk::PipelineCache,
+        shaders: &ShaderLibrary,
+        forward_pass: vk::RenderPass,
+        samples: vk::SampleCountFlags,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut pipelines = Vec::with_capacity(self.materials.len());
+        for material in &self.materials {
+            let created = pipeline::create_graphics_pipeline(
+                logical_device,
+                scene,
+                forward_pass,
+                samples,
+                ScenePass::Forward,
+                material,
+                cache,
+                shaders,
+            );
+            match created {
+                Ok(p) => pipelines.push(p),
+                Err(e) => {
+                    for p in pipelines {
+                        logical_device.destroy_pipeline(p, None);
+                    }
+                    return Err(format!("material `{}`: {}", material.name, e).into());
+                }
+            }
+        }
+        for &p in &self.forward_pipelines {
+            logical_device.destroy_pipeline(p, None);
+        }
+        self.forward_pipelines = pipelines;
+        self.forward_pass = forward_pass;
+        self.samples = samples;
+        Ok(())
+    }
+
+    pub unsafe fn cleanup(&self, logical_device: &ash::Device) {
+        for &p in self
+            .forward_pipelines
+            .iter()
+            .chain(&self.deferred_pipelines)
+        {
+            logical_device.destroy_pipeline(p, None);
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use ash::vk;
+
+use crate::ceaser::{attachment::Attachment, buffer::Buffer, queue::QueueFamilies};
+
+pub const OFFSCREEN_FORMAT: vk::Format = vk::Format::R8G8B8A8_UNORM;
+
+pub struct Offscreen {
+    pub color: Attachment,
+    // Target of the post pass, the scene itself is rendered into post::HdrTarget
+    pub framebuffer: vk::Framebuffer,
+    pub readback_buffer: Buffer,
+    pub extent: vk::Extent2D,
+}
+
+impl Offscreen {
+    pub fn new(
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+        queue_families: &QueueFamilies,
+        extent: vk::Extent2D,
+    ) -> Result<Offscreen, Box<dyn std::error::Error>> {
+        let queuefamilies = [queue_families.graphics_q_index.unwrap()];
+        let color = Attachment::new(
+            logical_device,
+            allocator,
+            &queuefamilies,
+            extent,
+            OFFSCREEN_FORMAT,
+            vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_SRC,
+            vk::SampleCountFlags::TYPE_1,
+            "Offscreen Color Image",
+        )?;
+
+        let readback_buffer = Buffer::new(
+            logical_device,
+            allocator,
+            4 * extent.width as u64 * extent.height as u64,
+            vk::BufferUsageFlags::TRANSFER_DST,
+            gpu_allocator::MemoryLocation::GpuToCpu,
+        )?;
+
+        Ok(Offscreen {
+            color,
+            framebuffer: vk::Framebuffer::null(),
+            readback_buffer,
+            extent,
+        })
+    }
+
+    pub fn create_framebuffer(
+        &mut self,
+        logical_device: &ash::Device,
+        renderpass: vk::RenderPass,
+    ) -> Result<(), vk::Result> {
+        let iview = [self.color.imageview];
+        let framebuffer_info = vk::FramebufferCreateInfo::builder()
+            .render_pass(renderpass)
+            .attachments(&iview)
+            .width(self.extent.width)
+            .height(self.extent.height)
+            .layers(1);
+        self.framebuffer = unsafe { logical_device.create_framebuffer(&framebuffer_info, None) }?;
+        Ok(())
+    }
+
+    // The post pass leaves the color image in TRANSFER_SRC_OPTIMAL, so the copy can follow directly
+    pub fn record_readback(&self, logical_device: &ash::Device, commandbuffer: vk::CommandBuffer) {
+        let regions = [vk::BufferImageCopy::builder()
+            .buffer_offset(0)
+            .buffer_row_length(0)
+            .buffer_image_height(0)
+            .image_subresource(vk::ImageSubresourceLayers {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                mip_level: 0,
+                base_array_layer: 0,
+                layer_count: 1,
+            })
+            .image_offset(vk::Offset3D { x: 0, y: 0, z: 0 })
+            .image_extent(vk::Extent3D {
+                width: self.extent.width,
+                height: self.extent.height,
+                depth: 1,
+            })
+            .build()];
+        let buffer_barriers = [vk::BufferMemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::HOST_READ)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .buffer(self.readback_buffer.buffer)
+            .offset(0)
+            .size(vk::WHOLE_SIZE)
+            .build()];
+        unsafe {
+            logical_device.cmd_copy_image_to_buffer(
+                commandbuffer,
+                self.color.image,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                self.readback_buffer.buffer,
+                &regions,
+            );
+            logical_device.cmd_pipeline_barrier(
+                commandbuffer,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::HOST,
+                vk::DependencyFlags::empty(),
+                &[],
+                &buffer_barriers,
+                &[],
+            );
+        }
+    }
+
+    // Tightly packed RGBA8 rows, top row first
+    pub fn read_pixels(&self) -> Vec<u8> {
+        let bytes = 4 * self.extent.width as usize * self.extent.height as usize;
+        self.readback_buffer
+            .allocation
+            .mapped_slice()
+            .map(|slice| slice[..bytes].to_vec())
+            .unwrap_or_default()
+    }
+
+    pub unsafe fn cleanup(
+        &mut self,
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+    ) {
+        logical_device.destroy_framebuffer(self.framebuffer, None);
+        self.color.destroy(logical_device, allocator);
+        self.readback_buffer
+            .destroy(logical_device, allocator)
+            .expect("freeing offscreen readback buffer");
+    }
+}
@@ -1,237 +1,91 @@
-use crate::ceaser::swap_chain::Swapchain;
 use ash::vk;
 
+use super::material::Material;
+use super::reflect::{self, ShaderReflection, VertexBinding, VertexLayout};
+use super::shaders::ShaderLibrary;
+
+// Where a scene pipeline draws, which decides its attachments and subpass
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScenePass {
+    // The forward render pass
+    Forward,
+    // The first subpass of the deferred render pass
+    GBuffer,
+    // After the deferred lighting subpass, depth tested against the G-buffer but without
+    // writing depth
+    AfterLighting,
+}
+
+// The layout every material's pipelines share, so the scene descriptor sets stay bound when
+// the pipeline changes between draws
 pub struct Pipeline {
-    pub pipeline: vk::Pipeline,
     pub layout: vk::PipelineLayout,
     pub descriptor_set_layouts: Vec<vk::DescriptorSetLayout>,
+    // Per set, what the layout was built from
+    pub set_bindings: Vec<Vec<vk::DescriptorSetLayoutBinding>>,
+    // The vertex type in binding 0 and the instance type in binding 1
+    pub vertex_bindings: Vec<VertexBinding>,
+    // None when no scene shader has push constants
+    pub push_constant_range: Option<vk::PushConstantRange>,
 }
 
 impl Pipeline {
-    pub fn new(
+    pub fn new<V: VertexLayout, I: VertexLayout>(
         logical_device: &ash::Device,
-        swapchain: &Swapchain,
-        renderpass: &vk::RenderPass,
-    ) -> Result<Pipeline, vk::Result> {
-        let vertexshader_createinfo = vk::ShaderModuleCreateInfo::builder().code(
-            vk_shader_macros::include_glsl!("./shaders/shader.vert", kind: vert),
-        );
-        let vertexshader_module =
-            unsafe { logical_device.create_shader_module(&vertexshader_createinfo, None)? };
-        let fragmentshader_createinfo = vk::ShaderModuleCreateInfo::builder()
-            .code(vk_shader_macros::include_glsl!("./shaders/shader.frag"));
-        let fragmentshader_module =
-            unsafe { logical_device.create_shader_module(&fragmentshader_createinfo, None)? };
-        let mainfunctionname = std::ffi::CString::new("main").unwrap();
-        let vertexshader_stage = vk::PipelineShaderStageCreateInfo::builder()
-            .stage(vk::ShaderStageFlags::VERTEX)
-            .module(vertexshader_module)
-            .name(&mainfunctionname);
-        let fragmentshader_stage = vk::PipelineShaderStageCreateInfo::builder()
-            .stage(vk::ShaderStageFlags::FRAGMENT)
-            .module(fragmentshader_module)
-            .name(&mainfunctionname);
-        let shader_stages = vec![vertexshader_stage.build(), fragmentshader_stage.build()];
-        let vertex_attrib_descs = [
-            vk::VertexInputAttributeDescription {
-                binding: 0,
-                location: 0,
-                offset: 0,
-                format: vk::Format::R32G32B32_SFLOAT,
-            },
-            vk::VertexInputAttributeDescription {
-                binding: 0,
-                location: 1,
-                offset: 12,
-                format: vk::Format::R32G32B32_SFLOAT,
-            },
-            vk::VertexInputAttributeDescription {
-                binding: 1,
-                location: 2,
-                offset: 0,
-                format: vk::Format::R32G32B32A32_SFLOAT,
-            },
-            vk::VertexInputAttributeDescription {
-                binding: 1,
-                location: 3,
-                offset: 16,
-                format: vk::Format::R32G32B32A32_SFLOAT,
-            },
-            vk::VertexInputAttributeDescription {
-                binding: 1,
-                location: 4,
-                offset: 32,
-                format: vk::Format::R32G32B32A32_SFLOAT,
-            },
-            vk::VertexInputAttributeDescription {
-                binding: 1,
-                location: 5,
-                offset: 48,
-                format: vk::Format::R32G32B32A32_SFLOAT,
-            },
-            vk::VertexInputAttributeDescription {
-                binding: 1,
-                location: 6,
-                offset: 64,
-                format: vk::Format::R32G32B32A32_SFLOAT,
-            },
-            vk::VertexInputAttributeDescription {
-                binding: 1,
-                location: 7,
-                offset: 80,
-                format: vk::Format::R32G32B32A32_SFLOAT,
-            },
-            vk::VertexInputAttributeDescription {
-                binding: 1,
-                location: 8,
-                offset: 96,
-                format: vk::Format::R32G32B32A32_SFLOAT,
-            },
-            vk::VertexInputAttributeDescription {
-                binding: 1,
-                location: 9,
-                offset: 112,
-                format: vk::Format::R32G32B32A32_SFLOAT,
-            },
-            vk::VertexInputAttributeDescription {
-                binding: 1,
-                location: 10,
-                offset: 128,
-                format: vk::Format::R32G32B32_SFLOAT,
-            },
-            vk::VertexInputAttributeDescription {
-                binding: 1,
-                location: 11,
-                offset: 140,
-                format: vk::Format::R32_SFLOAT,
-            },
-             vk::VertexInputAttributeDescription {
-                binding: 1,
-                location: 12,
-                offset: 144,
-                format: vk::Format::R32_SFLOAT,
-            },
-        ];
-        let vertex_binding_descs = [
-            vk::VertexInputBindingDescription {
-                binding: 0,
-                stride: 24,
-                input_rate: vk::VertexInputRate::VERTEX,
-            },
-            vk::VertexInputBindingDescription {
-                binding: 1,
-                stride: 148,
-                input_rate: vk::VertexInputRate::INSTANCE,
-            },
-        ];
-
-        let descriptorset_layout_binding_descs0 =
-            [vk::DescriptorSetLayoutBinding::builder() 
-                .binding(0)
-                .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
-                .descriptor_count(1)
-                .stage_flags(vk::ShaderStageFlags::VERTEX)
-                .build()];
-        let descriptorset_layout_info0 = vk::DescriptorSetLayoutCreateInfo::builder()
-            .bindings(&descriptorset_layout_binding_descs0);
-        let descriptorsetlayout0 = unsafe {
-            logical_device.create_descriptor_set_layout(&descriptorset_layout_info0, None)
-        }?;
-        let descriptorset_layout_binding_descs1 = [vk::DescriptorSetLayoutBinding::builder()
-            .binding(0)
-            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
-            .descriptor_count(1)
-            .stage_flags(vk::ShaderStageFlags::FRAGMENT)
-            .build()];
-        let descriptorset_layout_info1 = vk::DescriptorSetLayoutCreateInfo::builder()
-            .bindings(&descriptorset_layout_binding_descs1);
-        let descriptorsetlayout1 = unsafe {
-            logical_device.create_descriptor_set_layout(&descriptorset_layout_info1, None)
-        }?;
+        shaders: &ShaderLibrary,
+    ) -> Result<Pipeline, Box<dyn std::error::Error>> {
+        // Other materials use a subset of what the lit forward shaders declare
+        let vertex = ShaderReflection::new(shaders.get("shader.vert"))?;
+        let fragment = ShaderReflection::new(shaders.get("shader.frag"))?;
+        let set_bindings = reflect::descriptor_set_bindings(&[&vertex, &fragment])?;
+        // Push constants are not, the range has to hold the largest block of any built-in
+        // material. Materials added later are checked against it
+        let gbuffer = ShaderReflection::new(shaders.get("gbuffer.frag"))?;
+        let unlit = ShaderReflection::new(shaders.get("unlit.frag"))?;
+        let push_constant_ranges =
+            reflect::push_constant_ranges(&[&vertex, &fragment, &gbuffer, &unlit]);
 
-        let desclayouts = vec![descriptorsetlayout0, descriptorsetlayout1]; 
-
-        let vertex_input_info = vk::PipelineVertexInputStateCreateInfo::builder()
-            .vertex_attribute_descriptions(&vertex_attrib_descs)
-            .vertex_binding_descriptions(&vertex_binding_descs);
-        let input_assembly_info = vk::PipelineInputAssemblyStateCreateInfo::builder()
-            .topology(vk::PrimitiveTopology::TRIANGLE_LIST);
-        let viewports = [vk::Viewport {
-            x: 0.,
-            y: 0.,
-            width: swapchain.extent.width as f32,
-            height: swapchain.extent.height as f32,
-            min_depth: 0.,
-            max_depth: 1.,
-        }];
-        let scissors = [vk::Rect2D {
-            offset: vk::Offset2D { x: 0, y: 0 },
-            extent: swapchain.extent,
-        }];
-
-        let viewport_info = vk::PipelineViewportStateCreateInfo::builder()
-            .viewports(&viewports)
-            .scissors(&scissors);
-        let rasterizer_info = vk::PipelineRasterizationStateCreateInfo::builder()
-            .line_width(1.0)
-            .front_face(vk::FrontFace::COUNTER_CLOCKWISE)
-            .cull_mode(vk::CullModeFlags::BACK)
-            .polygon_mode(vk::PolygonMode::FILL);
-        let multisampler_info = vk::PipelineMultisampleStateCreateInfo::builder()
-            .rasterization_samples(vk::SampleCountFlags::TYPE_1);
-        let depth_stencil_info = vk::PipelineDepthStencilStateCreateInfo::builder()
-            .depth_test_enable(true)
-            .depth_write_enable(true)
-            .depth_compare_op(vk::CompareOp::LESS_OR_EQUAL);
-        let colorblend_attachments = [vk::PipelineColorBlendAttachmentState::builder()
-            .blend_enable(true)
-            .src_color_blend_factor(vk::BlendFactor::SRC_ALPHA)
-            .dst_color_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
-            .color_blend_op(vk::BlendOp::ADD)
-            .src_alpha_blend_factor(vk::BlendFactor::SRC_ALPHA)
-            .dst_alpha_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
-            .alpha_blend_op(vk::BlendOp::ADD)
-            .color_write_mask(
-                vk::ColorComponentFlags::R
-                    | vk::ColorComponentFlags::G
-                    | vk::ColorComponentFlags::B
-                    | vk::ColorComponentFlags::A,
-            )
-            .build()];
-        let colorblend_info =
-            vk::PipelineColorBlendStateCreateInfo::builder().attachments(&colorblend_attachments);
-        let pipelinelayout_info = vk::PipelineLayoutCreateInfo::builder().set_layouts(&desclayouts);
+        // Sets declared identically share a layout, like the albedo and normal map in sets 2
+        // and 3. descriptor_set_layouts keeps each layout once, in order of the first set using it
+        let key = |bindings: &[vk::DescriptorSetLayoutBinding]| {
+            bindings
+                .iter()
+                .map(|b| (b.binding, b.descriptor_type, b.descriptor_count, b.stage_flags))
+                .collect::<Vec<_>>()
+        };
+        let mut desclayouts: Vec<vk::DescriptorSetLayout> = Vec::new();
+        let mut set_layouts: Vec<vk::DescriptorSetLayout> = Vec::new();
+        for (set, bindings) in set_bindings.iter().enumerate() {
+            let first = set_bindings
+                .iter()
+                .position(|other| key(other) == key(bindings))
+                .unwrap_or(set);
+            if first < set {
+                set_layouts.push(set_layouts[first]);
+                continue;
+            }
+            let descriptorset_layout_info =
+                vk::DescriptorSetLayoutCreateInfo::builder().bindings(bindings);
+            let descriptorsetlayout = unsafe {
+                logical_device.create_descriptor_set_layout(&descriptorset_layout_info, None)
+            }?;
+            desclayouts.push(descriptorsetlayout);
+            set_layouts.push(descriptorsetlayout);
+        }
+        let pipelinelayout_info = vk::PipelineLayoutCreateInfo::builder()
+            .set_layouts(&set_layouts)
+            .push_constant_ranges(&push_constant_ranges);
         let pipelinelayout =
             unsafe { logical_device.create_pipeline_layout(&pipelinelayout_info, None) }?;
-        let pipeline_info = vk::GraphicsPipelineCreateInfo::builder()
-            .stages(&shader_stages)
-            .vertex_input_state(&vertex_input_info)
-            .input_assembly_state(&input_assembly_info)
-            .viewport_state(&viewport_info)
-            .rasterization_state(&rasterizer_info)
-            .multisample_state(&multisampler_info)
-            .depth_stencil_state(&depth_stencil_info)
-            .color_blend_state(&colorblend_info)
-            .layout(pipelinelayout)
-            .render_pass(*renderpass)
-            .subpass(0);
-        let graphicspipeline = unsafe {
-            logical_device
-                .create_graphics_pipelines(
-                    vk::PipelineCache::null(),
-                    &[pipeline_info.build()],
-                    None,
-                )
-                .expect("A problem with the pipeline creation")
-        }[0];
-        unsafe {
-            logical_device.destroy_shader_module(fragmentshader_module, None);
-            logical_device.destroy_shader_module(vertexshader_module, None);
-        }
         Ok(Pipeline {
-            pipeline: graphicspipeline,
             layout: pipelinelayout,
             descriptor_set_layouts: desclayouts,
+            set_bindings,
+            vertex_bindings: vec![
+                VertexBinding::of::<V>(vk::VertexInputRate::VERTEX),
+                VertexBinding::of::<I>(vk::VertexInputRate::INSTANCE),
+            ],
+            push_constant_range: push_constant_ranges.first().copied(),
         })
     }
 
@@ -240,8 +94,119 @@ impl Pipeline {
             for dsl in &self.descriptor_set_layouts {
                 logical_device.destroy_descriptor_set_layout(*dsl, None);
             }
-            logical_device.destroy_pipeline(self.pipeline, None);
             logical_device.destroy_pipeline_layout(self.layout, None);
         }
     }
 }
+
+// One of a material's pipelines, in the scene pipeline layout
+#[allow(clippy::too_many_arguments)]
+pub fn create_graphics_pipeline(
+    logical_device: &ash::Device,
+    scene: &Pipeline,
+    renderpass: vk::RenderPass,
+    samples: vk::SampleCountFlags,
+    pass: ScenePass,
+    material: &Material,
+    cache: vk::PipelineCache,
+    shaders: &ShaderLibrary,
+) -> Result<vk::Pipeline, Box<dyn std::error::Error>> {
+    let vertex_code = shaders.get(material.vertex_shader);
+    let (vertex_attrib_descs, vertex_binding_descs) =
+        ShaderReflection::new(vertex_code)?.vertex_input(&scene.vertex_bindings)?;
+    let fragment_code = match pass {
+        ScenePass::GBuffer => shaders.get(
+            material
+                .gbuffer_shader
+                .ok_or("the material has no G-buffer shader")?,
+        ),
+        ScenePass::Forward | ScenePass::AfterLighting => shaders.get(material.fragment_shader),
+    };
+    let vertexshader_createinfo = vk::ShaderModuleCreateInfo::builder().code(vertex_code);
+    let vertexshader_module =
+        unsafe { logical_device.create_shader_module(&vertexshader_createinfo, None)? };
+    let fragmentshader_createinfo = vk::ShaderModuleCreateInfo::builder().code(fragment_code);
+    let fragmentshader_module =
+        unsafe { logical_device.create_shader_module(&fragmentshader_createinfo, None)? };
+    let mainfunctionname = std::ffi::CString::new("main").unwrap();
+    let vertexshader_stage = vk::PipelineShaderStageCreateInfo::builder()
+        .stage(vk::ShaderStageFlags::VERTEX)
+        .module(vertexshader_module)
+        .name(&mainfunctionname);
+    let fragmentshader_stage = vk::PipelineShaderStageCreateInfo::builder()
+        .stage(vk::ShaderStageFlags::FRAGMENT)
+        .module(fragmentshader_module)
+        .name(&mainfunctionname);
+    let shader_stages = vec![vertexshader_stage.build(), fragmentshader_stage.build()];
+    let vertex_input_info = vk::PipelineVertexInputStateCreateInfo::builder()
+        .vertex_attribute_descriptions(&vertex_attrib_descs)
+        .vertex_binding_descriptions(&vertex_binding_descs);
+    let input_assembly_info = vk::PipelineInputAssemblyStateCreateInfo::builder()
+        .topology(vk::PrimitiveTopology::TRIANGLE_LIST);
+    // Viewport and scissor are set per frame so a resized swapchain can reuse the pipeline
+    let viewport_info = vk::PipelineViewportStateCreateInfo::builder()
+        .viewport_count(1)
+        .scissor_count(1);
+    let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+    let dynamic_state_info =
+        vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&dynamic_states);
+    let rasterizer_info = vk::PipelineRasterizationStateCreateInfo::builder()
+        .line_width(1.0)
+        .front_face(vk::FrontFace::COUNTER_CLOCKWISE)
+        .cull_mode(material.state.cull_mode)
+        .polygon_mode(material.state.polygon_mode);
+    let multisampler_info = vk::PipelineMultisampleStateCreateInfo::builder()
+        .rasterization_samples(samples);
+    let depth_stencil_info = vk::PipelineDepthStencilStateCreateInfo::builder()
+        .depth_test_enable(material.state.depth_test)
+        .depth_write_enable(material.state.depth_write && pass != ScenePass::AfterLighting)
+        .depth_compare_op(vk::CompareOp::LESS_OR_EQUAL);
+    let blend_attachment = vk::PipelineColorBlendAttachmentState::builder()
+        .blend_enable(material.state.blended)
+        .src_color_blend_factor(vk::BlendFactor::SRC_ALPHA)
+        .dst_color_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
+        .color_blend_op(vk::BlendOp::ADD)
+        .src_alpha_blend_factor(vk::BlendFactor::SRC_ALPHA)
+        .dst_alpha_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
+        .alpha_blend_op(vk::BlendOp::ADD)
+        .color_write_mask(
+            vk::ColorComponentFlags::R
+                | vk::ColorComponentFlags::G
+                | vk::ColorComponentFlags::B
+                | vk::ColorComponentFlags::A,
+        )
+        .build();
+    // Albedo, normal and material, see deferred.rs
+    let gbuffer_attachment = vk::PipelineColorBlendAttachmentState {
+        blend_enable: vk::FALSE,
+        ..blend_attachment
+    };
+    let colorblend_attachments = match pass {
+        ScenePass::GBuffer => vec![gbuffer_attachment; 3],
+        ScenePass::Forward | ScenePass::AfterLighting => vec![blend_attachment],
+    };
+    let colorblend_info =
+        vk::PipelineColorBlendStateCreateInfo::builder().attachments(&colorblend_attachments);
+    let subpass = if pass == ScenePass::AfterLighting { 1 } else { 0 };
+    let pipeline_info = vk::GraphicsPipelineCreateInfo::builder()
+        .stages(&shader_stages)
+        .vertex_input_state(&vertex_input_info)
+        .input_assembly_state(&input_assembly_info)
+        .viewport_state(&viewport_info)
+        .rasterization_state(&rasterizer_info)
+        .multisample_state(&multisampler_info)
+        .depth_stencil_state(&depth_stencil_info)
+        .color_blend_state(&colorblend_info)
+        .dynamic_state(&dynamic_state_info)
+        .layout(scene.layout)
+        .render_pass(renderpass)
+        .subpass(subpass);
+    let graphicspipelines = unsafe {
+        logical_device.create_graphics_pipelines(cache, &[pipeline_info.build()], None)
+    };
+    unsafe {
+        logical_device.destroy_shader_module(fragmentshader_module, None);
+        logical_device.destroy_shader_module(vertexshader_module, None);
+    }
+    Ok(graphicspipelines.map_err(|(_, e)| e)?[0])
+}
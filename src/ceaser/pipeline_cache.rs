@@ -0,0 +1,254 @@
+use ash::vk;
+
+// Overrides where the cache files go, an empty value keeps the cache in memory only
+pub const PIPELINE_CACHE_ENV_VAR: &str = "OBERON_PIPELINE_CACHE";
+
+// Our own header in front of the driver's data, so truncated or damaged files are never handed
+// to the driver
+const MAGIC: &[u8; 4] = b"OBPC";
+const HEADER_SIZE: usize = 24;
+// VkPipelineCacheHeaderVersionOne: length, version, vendor, device and the cache UUID
+const VULKAN_HEADER_SIZE: usize = 32;
+
+// The platform's cache directory, unless PIPELINE_CACHE_ENV_VAR says otherwise
+pub fn default_directory() -> Option<std::path::PathBuf> {
+    if let Ok(dir) = std::env::var(PIPELINE_CACHE_ENV_VAR) {
+        return if dir.is_empty() {
+            None
+        } else {
+            Some(dir.into())
+        };
+    }
+    let base = if cfg!(windows) {
+        std::env::var_os("LOCALAPPDATA").map(std::path::PathBuf::from)
+    } else if let Some(dir) = std::env::var_os("XDG_CACHE_HOME") {
+        Some(dir.into())
+    } else {
+        std::env::var_os("HOME").map(|home| std::path::Path::new(&home).join(".cache"))
+    };
+    base.map(|dir| dir.join("oberon"))
+}
+
+// A VkPipelineCache loaded from and saved to one file per device and driver
+pub struct PipelineCache {
+    pub cache: vk::PipelineCache,
+    // None when the cache lives in memory only
+    pub path: Option<std::path::PathBuf>,
+    vendor_id: u32,
+    device_id: u32,
+    driver_version: u32,
+    uuid: [u8; vk::UUID_SIZE],
+}
+
+impl PipelineCache {
+    // Starts empty when there is no file yet or it belongs to another device or driver or
+    // does not check out
+    pub fn new(
+        logical_device: &ash::Device,
+        properties: &vk::PhysicalDeviceProperties,
+        directory: Option<&std::path::Path>,
+    ) -> Result<PipelineCache, vk::Result> {
+        let uuid_hex: String = properties
+            .pipeline_cache_uuid
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        let path = directory.map(|dir| {
+            dir.join(format!(
+                "{:04x}-{:04x}-{:08x}-{}.bin",
+                properties.vendor_id, properties.device_id, properties.driver_version, uuid_hex
+            ))
+        });
+        let mut pipeline_cache = PipelineCache {
+            cache: vk::PipelineCache::null(),
+            path,
+            vendor_id: properties.vendor_id,
+            device_id: properties.device_id,
+            driver_version: properties.driver_version,
+            uuid: properties.pipeline_cache_uuid,
+        };
+        let initial_data = pipeline_cache
+            .path
+            .as_ref()
+            .and_then(|path| std::fs::read(path).ok())
+            .and_then(|file| pipeline_cache.validate(&file).map(<[u8]>::to_vec))
+            .unwrap_or_default();
+        let cache_info = vk::PipelineCacheCreateInfo::builder().initial_data(&initial_data);
+        pipeline_cache.cache =
+            match unsafe { logical_device.create_pipeline_cache(&cache_info, None) } {
+                Ok(cache) => cache,
+                // The driver may still refuse data it wrote itself, start over then
+                Err(_) if !initial_data.is_empty() => unsafe {
+                    logical_device
+                        .create_pipeline_cache(&vk::PipelineCacheCreateInfo::builder(), None)?
+                },
+                Err(e) => return Err(e),
+            };
+        Ok(pipeline_cache)
+    }
+
+    // The driver's part of a cache file, if the file was written by this device and driver
+    fn validate<'a>(&self, file: &'a [u8]) -> Option<&'a [u8]> {
+        let u32_at = |bytes: &[u8], offset: usize| {
+            Some(u32::from_le_bytes(
+                bytes.get(offset..offset + 4)?.try_into().ok()?,
+            ))
+        };
+        let u64_at = |bytes: &[u8], offset: usize| {
+            Some(u64::from_le_bytes(
+                bytes.get(offset..offset + 8)?.try_into().ok()?,
+            ))
+        };
+        if file.get(0..4)? != MAGIC || u32_at(file, 4)? != self.driver_version {
+            return None;
+        }
+        let (length, sum) = (u64_at(file, 8)?, u64_at(file, 16)?);
+        let data = &file[HEADER_SIZE..];
+        if length != data.len() as u64 || sum != checksum(data) {
+            return None;
+        }
+        let header_length = u32_at(data, 0)? as usize;
+        let valid = header_length >= VULKAN_HEADER_SIZE
+            && header_length <= data.len()
+            && u32_at(data, 4)? == vk::PipelineCacheHeaderVersion::ONE.as_raw() as u32
+            && u32_at(data, 8)? == self.vendor_id
+            && u32_at(data, 12)? == self.device_id
+            && data[16..32] == self.uuid;
+        valid.then_some(data)
+    }
+
+    // Writes through a temporary file, so a crash halfway leaves the previous cache intact
+    pub fn save(&self, logical_device: &ash::Device) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let data = unsafe { logical_device.get_pipeline_cache_data(self.cache) }?;
+        let file = self.encode(&data);
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let temporary = path.with_extension("tmp");
+        std::fs::write(&temporary, &file)?;
+        std::fs::rename(&temporary, path)?;
+        Ok(())
+    }
+
+    // What validate reads back
+    fn encode(&self, data: &[u8]) -> Vec<u8> {
+        let mut file = Vec::with_capacity(HEADER_SIZE + data.len());
+        file.extend_from_slice(MAGIC);
+        file.extend_from_slice(&self.driver_version.to_le_bytes());
+        file.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        file.extend_from_slice(&checksum(data).to_le_bytes());
+        file.extend_from_slice(data);
+        file
+    }
+
+    pub unsafe fn cleanup(&self, logical_device: &ash::Device) {
+        logical_device.destroy_pipeline_cache(self.cache, None);
+    }
+}
+
+// FNV-1a
+fn checksum(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache() -> PipelineCache {
+        PipelineCache {
+            cache: vk::PipelineCache::null(),
+            path: None,
+            vendor_id: 0x10de,
+            device_id: 0x2484,
+            driver_version: 7,
+            uuid: [0xab; vk::UUID_SIZE],
+        }
+    }
+
+    // What a driver returns from vkGetPipelineCacheData, with some payload after the header
+    fn driver_data(vendor_id: u32, device_id: u32, uuid: [u8; vk::UUID_SIZE]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&(VULKAN_HEADER_SIZE as u32).to_le_bytes());
+        data.extend_from_slice(&(vk::PipelineCacheHeaderVersion::ONE.as_raw() as u32).to_le_bytes());
+        data.extend_from_slice(&vendor_id.to_le_bytes());
+        data.extend_from_slice(&device_id.to_le_bytes());
+        data.extend_from_slice(&uuid);
+        data.extend_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        data
+    }
+
+    #[test]
+    fn accepts_its_own_file() {
+        let cache = cache();
+        let data = driver_data(cache.vendor_id, cache.device_id, cache.uuid);
+        let file = cache.encode(&data);
+        assert_eq!(cache.validate(&file), Some(&data[..]));
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let cache = cache();
+        let file = cache.encode(&driver_data(cache.vendor_id, cache.device_id, cache.uuid));
+        for length in 0..file.len() {
+            assert_eq!(cache.validate(&file[..length]), None, "{} bytes", length);
+        }
+    }
+
+    #[test]
+    fn rejects_damaged_files() {
+        let cache = cache();
+        let file = cache.encode(&driver_data(cache.vendor_id, cache.device_id, cache.uuid));
+        for index in 0..file.len() {
+            let mut damaged = file.clone();
+            damaged[index] ^= 0x40;
+            assert_eq!(cache.validate(&damaged), None, "byte {}", index);
+        }
+    }
+
+    #[test]
+    fn rejects_other_drivers() {
+        let other = PipelineCache {
+            driver_version: 8,
+            ..cache()
+        };
+        let cache = cache();
+        let file = cache.encode(&driver_data(cache.vendor_id, cache.device_id, cache.uuid));
+        assert_eq!(other.validate(&file), None);
+    }
+
+    #[test]
+    fn rejects_other_devices() {
+        let cache = cache();
+        let mut uuid = cache.uuid;
+        uuid[15] = 0;
+        let foreign = [
+            driver_data(0x1002, cache.device_id, cache.uuid),
+            driver_data(cache.vendor_id, 0x73bf, cache.uuid),
+            driver_data(cache.vendor_id, cache.device_id, uuid),
+        ];
+        for data in foreign {
+            assert_eq!(cache.validate(&cache.encode(&data)), None);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_vulkan_headers() {
+        let cache = cache();
+        let data = driver_data(cache.vendor_id, cache.device_id, cache.uuid);
+        let mut version = data.clone();
+        version[4..8].copy_from_slice(&2u32.to_le_bytes());
+        let mut short = data.clone();
+        short[0..4].copy_from_slice(&16u32.to_le_bytes());
+        let mut long = data.clone();
+        long[0..4].copy_from_slice(&(data.len() as u32 + 1).to_le_bytes());
+        for data in [version, short, long] {
+            assert_eq!(cache.validate(&cache.encode(&data)), None);
+        }
+    }
+}
@@ -0,0 +1,484 @@
+use ash::vk;
+
+use crate::ceaser::{
+    attachment::Attachment, bloom::Bloom, camera::Camera, exposure::AutoExposure,
+    queue::QueueFamilies, render_pass, shaders::ShaderLibrary,
+};
+
+// Scene radiance is kept linear and unclamped until the post pass tonemaps it
+pub const HDR_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+pub const SHADERS: [&str; 2] = ["post.vert", "post.frag"];
+
+// Values have to match the constants in shaders/post.frag
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tonemapper {
+    Reinhard = 0,
+    Aces = 1,
+    AgX = 2,
+    Uncharted2 = 3,
+}
+
+impl Tonemapper {
+    pub fn next(self) -> Tonemapper {
+        match self {
+            Tonemapper::Reinhard => Tonemapper::Aces,
+            Tonemapper::Aces => Tonemapper::AgX,
+            Tonemapper::AgX => Tonemapper::Uncharted2,
+            Tonemapper::Uncharted2 => Tonemapper::Reinhard,
+        }
+    }
+}
+
+// What the main render pass draws into, sized like the swapchain or offscreen image
+pub struct HdrTarget {
+    // Single sampled, this is what the post pass reads
+    pub color: Attachment,
+    pub depth: Attachment,
+    // Only present when rendering with more than one sample per pixel, resolves into `color`
+    pub msaa_color: Option<Attachment>,
+    pub framebuffer: vk::Framebuffer,
+}
+
+impl HdrTarget {
+    pub fn new(
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+        queue_families: &QueueFamilies,
+        extent: vk::Extent2D,
+        samples: vk::SampleCountFlags,
+        renderpass: vk::RenderPass,
+    ) -> Result<HdrTarget, Box<dyn std::error::Error>> {
+        let queuefamilies = [queue_families.graphics_q_index.unwrap()];
+        let color = Attachment::new(
+            logical_device,
+            allocator,
+            &queuefamilies,
+            extent,
+            HDR_FORMAT,
+            vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::SAMPLED,
+            vk::SampleCountFlags::TYPE_1,
+            "HDR Color Image",
+        )?;
+        let depth = Attachment::new(
+            logical_device,
+            allocator,
+            &queuefamilies,
+            extent,
+            vk::Format::D32_SFLOAT,
+            vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
+            samples,
+            "Z_Buffer Image",
+        )?;
+        let msaa_color = if samples != vk::SampleCountFlags::TYPE_1 {
+            Some(Attachment::new(
+                logical_device,
+                allocator,
+                &queuefamilies,
+                extent,
+                HDR_FORMAT,
+                vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSIENT_ATTACHMENT,
+                samples,
+                "Multisampled HDR Color Image",
+            )?)
+        } else {
+            None
+        };
+        // Attachment order has to match render_pass::init_render_pass
+        let iview = match &msaa_color {
+            Some(msaa_color) => vec![msaa_color.imageview, depth.imageview, color.imageview],
+            None => vec![color.imageview, depth.imageview],
+        };
+        let framebuffer_info = vk::FramebufferCreateInfo::builder()
+            .render_pass(renderpass)
+            .attachments(&iview)
+            .width(extent.width)
+            .height(extent.height)
+            .layers(1);
+        let framebuffer = unsafe { logical_device.create_framebuffer(&framebuffer_info, None) }?;
+        Ok(HdrTarget {
+            color,
+            depth,
+            msaa_color,
+            framebuffer,
+        })
+    }
+
+    pub unsafe fn destroy(
+        &mut self,
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+    ) {
+        logical_device.destroy_framebuffer(self.framebuffer, None);
+        self.color.destroy(logical_device, allocator);
+        self.depth.destroy(logical_device, allocator);
+        if let Some(mut msaa_color) = self.msaa_color.take() {
+            msaa_color.destroy(logical_device, allocator);
+        }
+    }
+}
+
+// Full-screen pass from the HDR target into the presentable or offscreen image
+pub struct PostProcess {
+    pub render_pass: vk::RenderPass,
+    pub pipeline: vk::Pipeline,
+    pub pipeline_layout: vk::PipelineLayout,
+    pub descriptor_set_layout: vk::DescriptorSetLayout,
+    pub descriptor_pool: vk::DescriptorPool,
+    pub descriptor_set: vk::DescriptorSet,
+    pub sampler: vk::Sampler,
+    pub tonemapper: Tonemapper,
+    // Linear scale applied to the radiance before tonemapping, on top of the metered
+    // exposure when auto_exposure is set. Both are taken from the camera in set_exposure
+    exposure: f32,
+    auto_exposure: bool,
+    // UNORM targets get the sRGB transfer function in the shader, _SRGB ones in hardware
+    encode_srgb: bool,
+}
+
+impl PostProcess {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        logical_device: &ash::Device,
+        format: vk::Format,
+        final_layout: vk::ImageLayout,
+        hdr_target: &HdrTarget,
+        auto_exposure: &AutoExposure,
+        bloom: &Bloom,
+        cache: vk::PipelineCache,
+        shaders: &ShaderLibrary,
+    ) -> Result<PostProcess, vk::Result> {
+        let render_pass =
+            render_pass::init_post_render_pass(logical_device, format, final_layout)?;
+
+        let bindings = [
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(0)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+                .build(),
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(1)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+                .build(),
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(2)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+                .build(),
+        ];
+        let set_layout_info = vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings);
+        let descriptor_set_layout =
+            unsafe { logical_device.create_descriptor_set_layout(&set_layout_info, None) }?;
+        let (pipeline, pipeline_layout) = create_pipeline(
+            logical_device,
+            render_pass,
+            descriptor_set_layout,
+            cache,
+            shaders,
+        )?;
+
+        // Source and target have the same size, so every fragment reads exactly one texel
+        let sampler_info = vk::SamplerCreateInfo::builder()
+            .mag_filter(vk::Filter::NEAREST)
+            .min_filter(vk::Filter::NEAREST)
+            .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE);
+        let sampler = unsafe { logical_device.create_sampler(&sampler_info, None) }?;
+
+        let pool_sizes = [
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                descriptor_count: 2,
+            },
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::STORAGE_BUFFER,
+                descriptor_count: 1,
+            },
+        ];
+        let pool_info = vk::DescriptorPoolCreateInfo::builder()
+            .max_sets(1)
+            .pool_sizes(&pool_sizes);
+        let descriptor_pool = unsafe { logical_device.create_descriptor_pool(&pool_info, None) }?;
+        let layouts = [descriptor_set_layout];
+        let allocate_info = vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(descriptor_pool)
+            .set_layouts(&layouts);
+        let descriptor_set = unsafe { logical_device.allocate_descriptor_sets(&allocate_info) }?[0];
+        let state_infos = [vk::DescriptorBufferInfo {
+            buffer: auto_exposure.state_buffer.buffer,
+            offset: 0,
+            range: vk::WHOLE_SIZE,
+        }];
+        let writes = [vk::WriteDescriptorSet::builder()
+            .dst_set(descriptor_set)
+            .dst_binding(1)
+            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+            .buffer_info(&state_infos)
+            .build()];
+        unsafe { logical_device.update_descriptor_sets(&writes, &[]) };
+
+        let post = PostProcess {
+            render_pass,
+            pipeline,
+            pipeline_layout,
+            descriptor_set_layout,
+            descriptor_pool,
+            descriptor_set,
+            sampler,
+            tonemapper: Tonemapper::Reinhard,
+            exposure: 1.0,
+            auto_exposure: false,
+            encode_srgb: !is_srgb(format),
+        };
+        post.write_input(logical_device, hdr_target, bloom);
+        Ok(post)
+    }
+
+    pub fn auto_exposure(&self) -> bool {
+        self.auto_exposure
+    }
+
+    pub fn set_exposure(&mut self, camera: &Camera) {
+        self.auto_exposure = camera.auto_exposure;
+        self.exposure = if camera.auto_exposure {
+            camera.exposure_compensation.exp2()
+        } else {
+            camera.exposure()
+        };
+    }
+
+    // Has to be called again whenever the HDR target and the bloom chain are recreated
+    pub fn write_input(&self, logical_device: &ash::Device, hdr_target: &HdrTarget, bloom: &Bloom) {
+        let image_infos = [vk::DescriptorImageInfo {
+            sampler: self.sampler,
+            image_view: hdr_target.color.imageview,
+            image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        }];
+        // Half resolution, so it needs the bilinear sampler of the bloom stage
+        let bloom_infos = [vk::DescriptorImageInfo {
+            sampler: bloom.sampler,
+            image_view: bloom.view(),
+            image_layout: vk::ImageLayout::GENERAL,
+        }];
+        let writes = [
+            vk::WriteDescriptorSet::builder()
+                .dst_set(self.descriptor_set)
+                .dst_binding(0)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .image_info(&image_infos)
+                .build(),
+            vk::WriteDescriptorSet::builder()
+                .dst_set(self.descriptor_set)
+                .dst_binding(2)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .image_info(&bloom_infos)
+                .build(),
+        ];
+        unsafe { logical_device.update_descriptor_sets(&writes, &[]) };
+    }
+
+    pub fn draw(
+        &self,
+        logical_device: &ash::Device,
+        commandbuffer: vk::CommandBuffer,
+        framebuffer: vk::Framebuffer,
+        extent: vk::Extent2D,
+        bloom_intensity: f32,
+    ) {
+        let render_area = vk::Rect2D {
+            offset: vk::Offset2D { x: 0, y: 0 },
+            extent,
+        };
+        let renderpass_begininfo = vk::RenderPassBeginInfo::builder()
+            .render_pass(self.render_pass)
+            .framebuffer(framebuffer)
+            .render_area(render_area);
+        let viewports = [vk::Viewport {
+            x: 0.,
+            y: 0.,
+            width: extent.width as f32,
+            height: extent.height as f32,
+            min_depth: 0.,
+            max_depth: 1.,
+        }];
+        // Laid out like the push constant block in shaders/post.frag
+        let mut push_constants = [0u8; 20];
+        push_constants[0..4].copy_from_slice(&self.exposure.to_ne_bytes());
+        push_constants[4..8].copy_from_slice(&(self.tonemapper as u32).to_ne_bytes());
+        push_constants[8..12].copy_from_slice(&(self.encode_srgb as u32).to_ne_bytes());
+        push_constants[12..16].copy_from_slice(&(self.auto_exposure as u32).to_ne_bytes());
+        push_constants[16..20].copy_from_slice(&bloom_intensity.to_ne_bytes());
+        unsafe {
+            logical_device.cmd_begin_render_pass(
+                commandbuffer,
+                &renderpass_begininfo,
+                vk::SubpassContents::INLINE,
+            );
+            logical_device.cmd_bind_pipeline(
+                commandbuffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                self.pipeline,
+            );
+            logical_device.cmd_set_viewport(commandbuffer, 0, &viewports);
+            logical_device.cmd_set_scissor(commandbuffer, 0, &[render_area]);
+            logical_device.cmd_bind_descriptor_sets(
+                commandbuffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                self.pipeline_layout,
+                0,
+                &[self.descriptor_set],
+                &[],
+            );
+            logical_device.cmd_push_constants(
+                commandbuffer,
+                self.pipeline_layout,
+                vk::ShaderStageFlags::FRAGMENT,
+                0,
+                &push_constants,
+            );
+            // A single triangle covering the screen, positions come from gl_VertexIndex
+            logical_device.cmd_draw(commandbuffer, 3, 1, 0, 0);
+            logical_device.cmd_end_render_pass(commandbuffer);
+        }
+    }
+
+    // Builds the pipeline again from the shader library, keeping the old one if that fails. It
+    // must not be in use anymore
+    pub unsafe fn rebuild_pipeline(
+        &mut self,
+        logical_device: &ash::Device,
+        cache: vk::PipelineCache,
+        shaders: &ShaderLibrary,
+    ) -> Result<(), vk::Result> {
+        let (pipeline, pipeline_layout) = create_pipeline(
+            logical_device,
+            self.render_pass,
+            self.descriptor_set_layout,
+            cache,
+            shaders,
+        )?;
+        logical_device.destroy_pipeline(self.pipeline, None);
+        logical_device.destroy_pipeline_layout(self.pipeline_layout, None);
+        self.pipeline = pipeline;
+        self.pipeline_layout = pipeline_layout;
+        Ok(())
+    }
+
+    pub unsafe fn cleanup(&self, logical_device: &ash::Device) {
+        logical_device.destroy_descriptor_pool(self.descriptor_pool, None);
+        logical_device.destroy_sampler(self.sampler, None);
+        logical_device.destroy_pipeline(self.pipeline, None);
+        logical_device.destroy_pipeline_layout(self.pipeline_layout, None);
+        logical_device.destroy_descriptor_set_layout(self.descriptor_set_layout, None);
+        logical_device.destroy_render_pass(self.render_pass, None);
+    }
+}
+
+fn is_srgb(format: vk::Format) -> bool {
+    matches!(
+        format,
+        vk::Format::B8G8R8A8_SRGB
+            | vk::Format::R8G8B8A8_SRGB
+            | vk::Format::A8B8G8R8_SRGB_PACK32
+            | vk::Format::R8G8B8_SRGB
+            | vk::Format::B8G8R8_SRGB
+    )
+}
+
+fn create_pipeline(
+    logical_device: &ash::Device,
+    renderpass: vk::RenderPass,
+    descriptor_set_layout: vk::DescriptorSetLayout,
+    cache: vk::PipelineCache,
+    shaders: &ShaderLibrary,
+) -> Result<(vk::Pipeline, vk::PipelineLayout), vk::Result> {
+    let vertexshader_createinfo =
+        vk::ShaderModuleCreateInfo::builder().code(shaders.get("post.vert"));
+    let vertexshader_module =
+        unsafe { logical_device.create_shader_module(&vertexshader_createinfo, None)? };
+    let fragmentshader_createinfo =
+        vk::ShaderModuleCreateInfo::builder().code(shaders.get("post.frag"));
+    let fragmentshader_module =
+        unsafe { logical_device.create_shader_module(&fragmentshader_createinfo, None)? };
+    let mainfunctionname = std::ffi::CString::new("main").unwrap();
+    let shader_stages = [
+        vk::PipelineShaderStageCreateInfo::builder()
+            .stage(vk::ShaderStageFlags::VERTEX)
+            .module(vertexshader_module)
+            .name(&mainfunctionname)
+            .build(),
+        vk::PipelineShaderStageCreateInfo::builder()
+            .stage(vk::ShaderStageFlags::FRAGMENT)
+            .module(fragmentshader_module)
+            .name(&mainfunctionname)
+            .build(),
+    ];
+    let vertex_input_info = vk::PipelineVertexInputStateCreateInfo::builder();
+    let input_assembly_info = vk::PipelineInputAssemblyStateCreateInfo::builder()
+        .topology(vk::PrimitiveTopology::TRIANGLE_LIST);
+    let viewport_info = vk::PipelineViewportStateCreateInfo::builder()
+        .viewport_count(1)
+        .scissor_count(1);
+    let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+    let dynamic_state_info =
+        vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&dynamic_states);
+    let rasterizer_info = vk::PipelineRasterizationStateCreateInfo::builder()
+        .line_width(1.0)
+        .front_face(vk::FrontFace::COUNTER_CLOCKWISE)
+        .cull_mode(vk::CullModeFlags::NONE)
+        .polygon_mode(vk::PolygonMode::FILL);
+    let multisampler_info = vk::PipelineMultisampleStateCreateInfo::builder()
+        .rasterization_samples(vk::SampleCountFlags::TYPE_1);
+    let colorblend_attachments = [vk::PipelineColorBlendAttachmentState::builder()
+        .blend_enable(false)
+        .color_write_mask(
+            vk::ColorComponentFlags::R
+                | vk::ColorComponentFlags::G
+                | vk::ColorComponentFlags::B
+                | vk::ColorComponentFlags::A,
+        )
+        .build()];
+    let colorblend_info =
+        vk::PipelineColorBlendStateCreateInfo::builder().attachments(&colorblend_attachments);
+    let set_layouts = [descriptor_set_layout];
+    let push_constant_ranges = [vk::PushConstantRange {
+        stage_flags: vk::ShaderStageFlags::FRAGMENT,
+        offset: 0,
+        size: 20,
+    }];
+    let pipelinelayout_info = vk::PipelineLayoutCreateInfo::builder()
+        .set_layouts(&set_layouts)
+        .push_constant_ranges(&push_constant_ranges);
+    let pipelinelayout =
+        unsafe { logical_device.create_pipeline_layout(&pipelinelayout_info, None) }?;
+    let pipeline_info = vk::GraphicsPipelineCreateInfo::builder()
+        .stages(&shader_stages)
+        .vertex_input_state(&vertex_input_info)
+        .input_assembly_state(&input_assembly_info)
+        .viewport_state(&viewport_info)
+        .rasterization_state(&rasterizer_info)
+        .multisample_state(&multisampler_info)
+        .color_blend_state(&colorblend_info)
+        .dynamic_state(&dynamic_state_info)
+        .layout(pipelinelayout)
+        .render_pass(renderpass)
+        .subpass(0);
+    let pipelines =
+        unsafe { logical_device.create_graphics_pipelines(cache, &[pipeline_info.build()], None) };
+    unsafe {
+        logical_device.destroy_shader_module(fragmentshader_module, None);
+        logical_device.destroy_shader_module(vertexshader_module, None);
+    }
+    match pipelines {
+        Ok(pipelines) => Ok((pipelines[0], pipelinelayout)),
+        Err((_, e)) => {
+            unsafe { logical_device.destroy_pipeline_layout(pipelinelayout, None) };
+            Err(e)
+        }
+    }
+}
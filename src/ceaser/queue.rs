@@ -10,16 +10,20 @@ impl QueueFamilies {
     pub fn new(
         instance: &ash::Instance,
         physical_device: vk::PhysicalDevice,
-        surfaces: &Surface,
+        surfaces: Option<&Surface>,
     ) -> Result<QueueFamilies, vk::Result> {
         let queuefamilyproperties =
             unsafe { instance.get_physical_device_queue_family_properties(physical_device) };
         let mut found_graphics_q_index = None;
         let mut found_transfer_q_index = None;
         for (index, qfam) in queuefamilyproperties.iter().enumerate() {
+            let can_present = match surfaces {
+                Some(surfaces) => surfaces.get_physical_device_surface_support(physical_device, index)?,
+                None => true,
+            };
             if qfam.queue_count > 0
                 && qfam.queue_flags.contains(vk::QueueFlags::GRAPHICS)
-                && surfaces.get_physical_device_surface_support(physical_device, index)?
+                && can_present
             {
                 found_graphics_q_index = Some(index as u32);
             }
@@ -0,0 +1,1003 @@
+use std::collections::{BTreeMap, HashMap};
+
+use ash::vk;
+
+// Just enough of the SPIR-V spec to find a module's inputs, descriptors and push constants
+const MAGIC: u32 = 0x0723_0203;
+const OP_NAME: u32 = 5;
+const OP_ENTRY_POINT: u32 = 15;
+const OP_TYPE_BOOL: u32 = 20;
+const OP_TYPE_INT: u32 = 21;
+const OP_TYPE_FLOAT: u32 = 22;
+const OP_TYPE_VECTOR: u32 = 23;
+const OP_TYPE_MATRIX: u32 = 24;
+const OP_TYPE_IMAGE: u32 = 25;
+const OP_TYPE_SAMPLER: u32 = 26;
+const OP_TYPE_SAMPLED_IMAGE: u32 = 27;
+const OP_TYPE_ARRAY: u32 = 28;
+const OP_TYPE_RUNTIME_ARRAY: u32 = 29;
+const OP_TYPE_STRUCT: u32 = 30;
+const OP_TYPE_POINTER: u32 = 32;
+const OP_CONSTANT: u32 = 43;
+const OP_VARIABLE: u32 = 59;
+const OP_DECORATE: u32 = 71;
+const OP_MEMBER_DECORATE: u32 = 72;
+
+const DECORATION_BUFFER_BLOCK: u32 = 3;
+const DECORATION_ARRAY_STRIDE: u32 = 6;
+const DECORATION_MATRIX_STRIDE: u32 = 7;
+const DECORATION_BUILTIN: u32 = 11;
+const DECORATION_LOCATION: u32 = 30;
+const DECORATION_BINDING: u32 = 33;
+const DECORATION_DESCRIPTOR_SET: u32 = 34;
+const DECORATION_OFFSET: u32 = 35;
+
+const STORAGE_UNIFORM_CONSTANT: u32 = 0;
+const STORAGE_INPUT: u32 = 1;
+const STORAGE_UNIFORM: u32 = 2;
+const STORAGE_PUSH_CONSTANT: u32 = 9;
+const STORAGE_STORAGE_BUFFER: u32 = 12;
+
+const DIM_BUFFER: u32 = 5;
+const DIM_SUBPASS_DATA: u32 = 6;
+
+#[derive(Clone, Debug)]
+enum Type {
+    Bool,
+    Int { width: u32, signed: bool },
+    Float { width: u32 },
+    Vector { component: u32, count: u32 },
+    Matrix { column: u32, count: u32 },
+    Image { dim: u32, sampled: u32 },
+    Sampler,
+    SampledImage,
+    Array { element: u32, length: u32 },
+    RuntimeArray,
+    Struct { members: Vec<u32> },
+    Pointer { pointee: u32 },
+}
+
+// A struct the vertex input reads from a buffer, usually through #[derive(VertexLayout)]
+pub trait VertexLayout {
+    const TYPE_NAME: &'static str;
+
+    fn fields() -> Vec<VertexField>;
+}
+
+// One field of a vertex or instance struct as the vertex input reads it. Matrices take one
+// location per column. The location only counts for shaders without debug names, otherwise
+// fields are matched by name
+#[derive(Clone, Copy, Debug)]
+pub struct VertexField {
+    pub name: &'static str,
+    pub location: u32,
+    pub offset: u32,
+    pub format: vk::Format,
+    pub columns: u32,
+}
+
+// A struct bound as one vertex buffer
+#[derive(Clone, Debug)]
+pub struct VertexBinding {
+    pub type_name: &'static str,
+    pub fields: Vec<VertexField>,
+    pub stride: u32,
+    pub input_rate: vk::VertexInputRate,
+}
+
+impl VertexBinding {
+    pub fn of<T: VertexLayout>(input_rate: vk::VertexInputRate) -> VertexBinding {
+        VertexBinding {
+            type_name: T::TYPE_NAME,
+            fields: T::fields(),
+            stride: std::mem::size_of::<T>() as u32,
+            input_rate,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ShaderInput {
+    pub name: String,
+    pub location: u32,
+    pub format: vk::Format,
+    pub columns: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct DescriptorBinding {
+    pub name: String,
+    pub set: u32,
+    pub binding: u32,
+    pub descriptor_type: vk::DescriptorType,
+    pub count: u32,
+}
+
+// What a compiled shader expects from the pipeline around it
+pub struct ShaderReflection {
+    pub stage: vk::ShaderStageFlags,
+    // Only those with a location, builtins are left out
+    pub inputs: Vec<ShaderInput>,
+    pub bindings: Vec<DescriptorBinding>,
+    pub push_constant_size: Option<u32>,
+}
+
+impl ShaderReflection {
+    pub fn new(code: &[u32]) -> Result<ShaderReflection, Box<dyn std::error::Error>> {
+        if code.len() < 5 || code[0] != MAGIC {
+            return Err("not a SPIR-V module".into());
+        }
+        let mut stage = None;
+        let mut names: HashMap<u32, String> = HashMap::new();
+        let mut decorations: HashMap<(u32, u32), u32> = HashMap::new();
+        let mut member_decorations: HashMap<(u32, u32, u32), u32> = HashMap::new();
+        let mut types: HashMap<u32, Type> = HashMap::new();
+        let mut constants: HashMap<u32, u32> = HashMap::new();
+        let mut variables: Vec<(u32, u32, u32)> = Vec::new();
+
+        let mut position = 5;
+        while position < code.len() {
+            let word_count = (code[position] >> 16) as usize;
+            let opcode = code[position] & 0xffff;
+            if word_count == 0 || position + word_count > code.len() {
+                return Err("truncated SPIR-V module".into());
+            }
+            let operands = &code[position + 1..position + word_count];
+            let operand = |i: usize| operands.get(i).copied().unwrap_or(0);
+            match opcode {
+                OP_NAME => {
+                    names.insert(operand(0), literal_string(&operands[1..]));
+                }
+                OP_ENTRY_POINT if stage.is_none() => stage = Some(stage_flags(operand(0))?),
+                OP_DECORATE => {
+                    decorations.insert((operand(0), operand(1)), operand(2));
+                }
+                OP_MEMBER_DECORATE => {
+                    member_decorations.insert((operand(0), operand(1), operand(2)), operand(3));
+                }
+                OP_TYPE_BOOL => {
+                    types.insert(operand(0), Type::Bool);
+                }
+                OP_TYPE_INT => {
+                    types.insert(
+                        operand(0),
+                        Type::Int {
+                            width: operand(1),
+                            signed: operand(2) == 1,
+                        },
+                    );
+                }
+                OP_TYPE_FLOAT => {
+                    types.insert(operand(0), Type::Float { width: operand(1) });
+                }
+                OP_TYPE_VECTOR => {
+                    types.insert(
+                        operand(0),
+                        Type::Vector {
+                            component: operand(1),
+                            count: operand(2),
+                        },
+                    );
+                }
+                OP_TYPE_MATRIX => {
+                    types.insert(
+                        operand(0),
+                        Type::Matrix {
+                            column: operand(1),
+                            count: operand(2),
+                        },
+                    );
+                }
+                OP_TYPE_IMAGE => {
+                    types.insert(
+                        operand(0),
+                        Type::Image {
+                            dim: operand(2),
+                            sampled: operand(6),
+                        },
+                    );
+                }
+                OP_TYPE_SAMPLER => {
+                    types.insert(operand(0), Type::Sampler);
+                }
+                OP_TYPE_SAMPLED_IMAGE => {
+                    types.insert(operand(0), Type::SampledImage);
+                }
+                OP_TYPE_ARRAY => {
+                    types.insert(
+                        operand(0),
+                        Type::Array {
+                            element: operand(1),
+                            length: operand(2),
+                        },
+                    );
+                }
+                OP_TYPE_RUNTIME_ARRAY => {
+                    types.insert(operand(0), Type::RuntimeArray);
+                }
+                OP_TYPE_STRUCT => {
+                    types.insert(
+                        operand(0),
+                        Type::Struct {
+                            members: operands[1..].to_vec(),
+                        },
+                    );
+                }
+                OP_TYPE_POINTER => {
+                    types.insert(
+                        operand(0),
+                        Type::Pointer {
+                            pointee: operand(2),
+                        },
+                    );
+                }
+                // Only 32 bit constants are used as array lengths here
+                OP_CONSTANT => {
+                    constants.insert(operand(1), operand(2));
+                }
+                OP_VARIABLE => variables.push((operand(0), operand(1), operand(2))),
+                _ => {}
+            }
+            position += word_count;
+        }
+
+        let module = Module {
+            names,
+            decorations,
+            member_decorations,
+            types,
+            constants,
+        };
+        let mut reflection = ShaderReflection {
+            stage: stage.ok_or("SPIR-V module without an entry point")?,
+            inputs: Vec::new(),
+            bindings: Vec::new(),
+            push_constant_size: None,
+        };
+        for (pointer_type, id, storage) in variables {
+            let Some(Type::Pointer { pointee, .. }) = module.types.get(&pointer_type) else {
+                continue;
+            };
+            let pointee = *pointee;
+            match storage {
+                STORAGE_INPUT => {
+                    if module.decorations.contains_key(&(id, DECORATION_BUILTIN)) {
+                        continue;
+                    }
+                    let Some(&location) = module.decorations.get(&(id, DECORATION_LOCATION)) else {
+                        continue;
+                    };
+                    let name = module.name(id);
+                    let (format, columns) = module.input_format(pointee).ok_or_else(|| {
+                        format!(
+                            "shader input `{}` at location {} has an unsupported type",
+                            name, location
+                        )
+                    })?;
+                    reflection.inputs.push(ShaderInput {
+                        name,
+                        location,
+                        format,
+                        columns,
+                    });
+                }
+                STORAGE_UNIFORM_CONSTANT | STORAGE_UNIFORM | STORAGE_STORAGE_BUFFER => {
+                    let (descriptor_type, count) =
+                        module.descriptor(pointee, storage).ok_or_else(|| {
+                            format!("resource `{}` has an unsupported type", module.name(id))
+                        })?;
+                    reflection.bindings.push(DescriptorBinding {
+                        name: module.name(id),
+                        set: module
+                            .decorations
+                            .get(&(id, DECORATION_DESCRIPTOR_SET))
+                            .copied()
+                            .unwrap_or(0),
+                        binding: module
+                            .decorations
+                            .get(&(id, DECORATION_BINDING))
+                            .copied()
+                            .unwrap_or(0),
+                        descriptor_type,
+                        count,
+                    });
+                }
+                STORAGE_PUSH_CONSTANT => {
+                    reflection.push_constant_size =
+                        Some(module.size(pointee).ok_or_else(|| {
+                            format!(
+                                "push constant block `{}` has an unsupported type",
+                                module.name(id)
+                            )
+                        })?);
+                }
+                _ => {}
+            }
+        }
+        reflection.inputs.sort_by_key(|input| input.location);
+        Ok(reflection)
+    }
+
+    // Pairs every input with the field of the same name, where a trailing `_in` on the input
+    // is ignored since GLSL does not allow it to share the name of an output. Inputs without
+    // a name go to the field at their location
+    pub fn vertex_input(
+        &self,
+        vertex_bindings: &[VertexBinding],
+    ) -> Result<
+        (
+            Vec<vk::VertexInputAttributeDescription>,
+            Vec<vk::VertexInputBindingDescription>,
+        ),
+        Box<dyn std::error::Error>,
+    > {
+        let mut attributes = Vec::new();
+        for input in &self.inputs {
+            let field_name = input.name.strip_suffix("_in").unwrap_or(&input.name);
+            let found = vertex_bindings.iter().enumerate().find_map(|(binding, b)| {
+                let field = b.fields.iter().find(|f| {
+                    if input.name.is_empty() {
+                        f.location == input.location
+                    } else {
+                        f.name == field_name
+                    }
+                })?;
+                Some((binding as u32, b.type_name, field))
+            });
+            let Some((binding, type_name, field)) = found else {
+                let types: Vec<&str> = vertex_bindings.iter().map(|b| b.type_name).collect();
+                return Err(if input.name.is_empty() {
+                    format!(
+                        "vertex shader input at location {} matches no field of {}",
+                        input.location,
+                        types.join(" or ")
+                    )
+                } else {
+                    format!(
+                        "vertex shader input `{}` at location {} matches no field `{}` of {}",
+                        input.name,
+                        input.location,
+                        field_name,
+                        types.join(" or ")
+                    )
+                }
+                .into());
+            };
+            if field.format != input.format || field.columns != input.columns {
+                return Err(format!(
+                    "vertex shader input `{}` at location {} is {} but {}::{} is {}",
+                    input.name,
+                    input.location,
+                    describe(input.format, input.columns),
+                    type_name,
+                    field.name,
+                    describe(field.format, field.columns)
+                )
+                .into());
+            }
+            let column_size = format_size(field.format);
+            for column in 0..field.columns {
+                attributes.push(vk::VertexInputAttributeDescription {
+                    binding,
+                    location: input.location + column,
+                    offset: field.offset + column * column_size,
+                    format: field.format,
+                });
+            }
+        }
+        let bindings = vertex_bindings
+            .iter()
+            .enumerate()
+            .map(|(binding, b)| vk::VertexInputBindingDescription {
+                binding: binding as u32,
+                stride: b.stride,
+                input_rate: b.input_rate,
+            })
+            .collect();
+        Ok((attributes, bindings))
+    }
+}
+
+// The descriptor set layouts of a pipeline made of these stages, one list of bindings per set
+// from 0 to the highest one used. A binding used by several stages has to agree between them
+pub fn descriptor_set_bindings(
+    stages: &[&ShaderReflection],
+) -> Result<Vec<Vec<vk::DescriptorSetLayoutBinding>>, Box<dyn std::error::Error>> {
+    let mut sets: BTreeMap<u32, BTreeMap<u32, vk::DescriptorSetLayoutBinding>> = BTreeMap::new();
+    for stage in stages {
+        for b in &stage.bindings {
+            let set = sets.entry(b.set).or_default();
+            match set.get_mut(&b.binding) {
+                Some(existing) => {
+                    if existing.descriptor_type != b.descriptor_type
+                        || existing.descriptor_count != b.count
+                    {
+                        return Err(format!(
+                            "`{}` at set {} binding {} is {} x {:?} in the {:?} stage but {} x \
+                             {:?} in the {:?} stage",
+                            b.name,
+                            b.set,
+                            b.binding,
+                            existing.descriptor_count,
+                            existing.descriptor_type,
+                            existing.stage_flags,
+                            b.count,
+                            b.descriptor_type,
+                            stage.stage
+                        )
+                        .into());
+                    }
+                    existing.stage_flags |= stage.stage;
+                }
+                None => {
+                    set.insert(
+                        b.binding,
+                        vk::DescriptorSetLayoutBinding::builder()
+                            .binding(b.binding)
+                            .descriptor_type(b.descriptor_type)
+                            .descriptor_count(b.count)
+                            .stage_flags(stage.stage)
+                            .build(),
+                    );
+                }
+            }
+        }
+    }
+    let set_count = sets.keys().next_back().map_or(0, |&last| last + 1);
+    Ok((0..set_count)
+        .map(|set| {
+            sets.get(&set)
+                .map(|bindings| bindings.values().copied().collect())
+                .unwrap_or_default()
+        })
+        .collect())
+}
+
+// One range over the largest push constant block, visible to every stage that has one
+pub fn push_constant_ranges(stages: &[&ShaderReflection]) -> Vec<vk::PushConstantRange> {
+    let mut range = vk::PushConstantRange::default();
+    for stage in stages {
+        if let Some(size) = stage.push_constant_size {
+            range.stage_flags |= stage.stage;
+            range.size = range.size.max(size);
+        }
+    }
+    if range.size > 0 {
+        vec![range]
+    } else {
+        Vec::new()
+    }
+}
+
+struct Module {
+    names: HashMap<u32, String>,
+    decorations: HashMap<(u32, u32), u32>,
+    member_decorations: HashMap<(u32, u32, u32), u32>,
+    types: HashMap<u32, Type>,
+    constants: HashMap<u32, u32>,
+}
+
+impl Module {
+    fn name(&self, id: u32) -> String {
+        self.names.get(&id).cloned().unwrap_or_default()
+    }
+
+    fn input_format(&self, type_id: u32) -> Option<(vk::Format, u32)> {
+        match self.types.get(&type_id)? {
+            Type::Matrix { column, count } => {
+                let (format, 1) = self.input_format(*column)? else {
+                    return None;
+                };
+                Some((format, *count))
+            }
+            Type::Vector { component, count } => {
+                let formats = match self.types.get(component)? {
+                    Type::Float { width: 32 } => [
+                        vk::Format::R32_SFLOAT,
+                        vk::Format::R32G32_SFLOAT,
+                        vk::Format::R32G32B32_SFLOAT,
+                        vk::Format::R32G32B32A32_SFLOAT,
+                    ],
+                    Type::Int {
+                        width: 32,
+                        signed: true,
+                    } => [
+                        vk::Format::R32_SINT,
+                        vk::Format::R32G32_SINT,
+                        vk::Format::R32G32B32_SINT,
+                        vk::Format::R32G32B32A32_SINT,
+                    ],
+                    Type::Int {
+                        width: 32,
+                        signed: false,
+                    } => [
+                        vk::Format::R32_UINT,
+                        vk::Format::R32G32_UINT,
+                        vk::Format::R32G32B32_UINT,
+                        vk::Format::R32G32B32A32_UINT,
+                    ],
+                    _ => return None,
+                };
+                Some((*formats.get(count.checked_sub(1)? as usize)?, 1))
+            }
+            Type::Float { width: 32 } => Some((vk::Format::R32_SFLOAT, 1)),
+            Type::Int {
+                width: 32,
+                signed: true,
+            } => Some((vk::Format::R32_SINT, 1)),
+            Type::Int {
+                width: 32,
+                signed: false,
+            } => Some((vk::Format::R32_UINT, 1)),
+            _ => None,
+        }
+    }
+
+    fn descriptor(&self, type_id: u32, storage: u32) -> Option<(vk::DescriptorType, u32)> {
+        match self.types.get(&type_id)? {
+            Type::Array { element, length } => {
+                let (descriptor_type, count) = self.descriptor(*element, storage)?;
+                Some((descriptor_type, count * self.constants.get(length)?))
+            }
+            Type::SampledImage => Some((vk::DescriptorType::COMBINED_IMAGE_SAMPLER, 1)),
+            Type::Sampler => Some((vk::DescriptorType::SAMPLER, 1)),
+            Type::Image { dim, sampled } => Some((
+                match (*dim, *sampled) {
+                    (DIM_SUBPASS_DATA, _) => vk::DescriptorType::INPUT_ATTACHMENT,
+                    (DIM_BUFFER, 2) => vk::DescriptorType::STORAGE_TEXEL_BUFFER,
+                    (DIM_BUFFER, _) => vk::DescriptorType::UNIFORM_TEXEL_BUFFER,
+                    (_, 2) => vk::DescriptorType::STORAGE_IMAGE,
+                    _ => vk::DescriptorType::SAMPLED_IMAGE,
+                },
+                1,
+            )),
+            Type::Struct { .. } if storage == STORAGE_STORAGE_BUFFER => {
+                Some((vk::DescriptorType::STORAGE_BUFFER, 1))
+            }
+            Type::Struct { .. }
+                if self
+                    .decorations
+                    .contains_key(&(type_id, DECORATION_BUFFER_BLOCK)) =>
+            {
+                Some((vk::DescriptorType::STORAGE_BUFFER, 1))
+            }
+            Type::Struct { .. } => Some((vk::DescriptorType::UNIFORM_BUFFER, 1)),
+            _ => None,
+        }
+    }
+
+    // Size in bytes with the offsets and strides the module was laid out with
+    fn size(&self, type_id: u32) -> Option<u32> {
+        match self.types.get(&type_id)? {
+            Type::Bool => Some(4),
+            Type::Int { width, .. } | Type::Float { width } => Some(width / 8),
+            Type::Vector { component, count } => Some(self.size(*component)? * count),
+            Type::Matrix { column, count } => Some(self.size(*column)? * count),
+            Type::Array { element, length } => {
+                let stride = match self.decorations.get(&(type_id, DECORATION_ARRAY_STRIDE)) {
+                    Some(&stride) => stride,
+                    None => self.size(*element)?,
+                };
+                Some(stride * self.constants.get(length)?)
+            }
+            Type::Struct { members } => members
+                .iter()
+                .enumerate()
+                .map(|(index, &member)| {
+                    let index = index as u32;
+                    let offset = self
+                        .member_decorations
+                        .get(&(type_id, index, DECORATION_OFFSET))
+                        .copied()
+                        .unwrap_or(0);
+                    let size = match (
+                        self.types.get(&member)?,
+                        self.member_decorations
+                            .get(&(type_id, index, DECORATION_MATRIX_STRIDE)),
+                    ) {
+                        (Type::Matrix { count, .. }, Some(&stride)) => stride * count,
+                        _ => self.size(member)?,
+                    };
+                    Some(offset + size)
+                })
+                .try_fold(0, |end, member_end: Option<u32>| Some(end.max(member_end?))),
+            _ => None,
+        }
+    }
+}
+
+fn literal_string(words: &[u32]) -> String {
+    let bytes: Vec<u8> = words
+        .iter()
+        .flat_map(|word| word.to_le_bytes())
+        .take_while(|&b| b != 0)
+        .collect();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+fn stage_flags(execution_model: u32) -> Result<vk::ShaderStageFlags, Box<dyn std::error::Error>> {
+    Ok(match execution_model {
+        0 => vk::ShaderStageFlags::VERTEX,
+        1 => vk::ShaderStageFlags::TESSELLATION_CONTROL,
+        2 => vk::ShaderStageFlags::TESSELLATION_EVALUATION,
+        3 => vk::ShaderStageFlags::GEOMETRY,
+        4 => vk::ShaderStageFlags::FRAGMENT,
+        5 => vk::ShaderStageFlags::COMPUTE,
+        model => return Err(format!("unsupported execution model {}", model).into()),
+    })
+}
+
+pub fn format_size(format: vk::Format) -> u32 {
+    match format {
+        vk::Format::R32_SFLOAT | vk::Format::R32_SINT | vk::Format::R32_UINT => 4,
+        vk::Format::R32G32_SFLOAT | vk::Format::R32G32_SINT | vk::Format::R32G32_UINT => 8,
+        vk::Format::R32G32B32_SFLOAT | vk::Format::R32G32B32_SINT | vk::Format::R32G32B32_UINT => {
+            12
+        }
+        _ => 16,
+    }
+}
+
+fn describe(format: vk::Format, columns: u32) -> String {
+    if columns > 1 {
+        format!("{} columns of {:?}", columns, format)
+    } else {
+        format!("{:?}", format)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ceaser::shaders::ShaderLibrary;
+    use crate::hamlet::{InstanceData, VertexData};
+
+    const VERTEX: u32 = 0;
+    const FRAGMENT: u32 = 4;
+
+    // Writes a module one instruction at a time. The ids are the test's business, nothing
+    // checks that they are defined before use
+    struct Assembler {
+        code: Vec<u32>,
+    }
+
+    impl Assembler {
+        fn new(execution_model: u32) -> Assembler {
+            Assembler {
+                code: vec![MAGIC, 0x0001_0000, 0, 100, 0],
+            }
+            .op(
+                OP_ENTRY_POINT,
+                &[&[execution_model, 1][..], &string("main")].concat(),
+            )
+        }
+
+        fn op(mut self, opcode: u32, operands: &[u32]) -> Assembler {
+            self.code.push(((operands.len() as u32 + 1) << 16) | opcode);
+            self.code.extend_from_slice(operands);
+            self
+        }
+
+        fn name(self, id: u32, name: &str) -> Assembler {
+            self.op(OP_NAME, &[&[id][..], &string(name)].concat())
+        }
+
+        fn input(self, id: u32, pointer_type: u32, location: u32) -> Assembler {
+            self.op(OP_DECORATE, &[id, DECORATION_LOCATION, location])
+                .op(OP_VARIABLE, &[pointer_type, id, STORAGE_INPUT])
+        }
+
+        // Ids 2 to 8: float, vec3, vec4, mat4 and pointers to the input vectors and matrix
+        fn vertex_types(self) -> Assembler {
+            self.op(OP_TYPE_FLOAT, &[2, 32])
+                .op(OP_TYPE_VECTOR, &[3, 2, 3])
+                .op(OP_TYPE_VECTOR, &[4, 2, 4])
+                .op(OP_TYPE_MATRIX, &[5, 4, 4])
+                .op(OP_TYPE_POINTER, &[6, STORAGE_INPUT, 3])
+                .op(OP_TYPE_POINTER, &[7, STORAGE_INPUT, 4])
+                .op(OP_TYPE_POINTER, &[8, STORAGE_INPUT, 5])
+        }
+
+        // A uniform block with one float in it, as variable 20
+        fn uniform_block(self, set: u32, binding: u32) -> Assembler {
+            self.op(OP_TYPE_FLOAT, &[2, 32])
+                .op(OP_TYPE_STRUCT, &[16, 2])
+                .op(OP_TYPE_POINTER, &[17, STORAGE_UNIFORM, 16])
+                .name(20, "block")
+                .op(OP_DECORATE, &[20, DECORATION_DESCRIPTOR_SET, set])
+                .op(OP_DECORATE, &[20, DECORATION_BINDING, binding])
+                .op(OP_VARIABLE, &[17, 20, STORAGE_UNIFORM])
+        }
+
+        // A sampler2D, as variable 21
+        fn sampler(self, set: u32, binding: u32) -> Assembler {
+            self.op(OP_TYPE_FLOAT, &[2, 32])
+                .op(OP_TYPE_IMAGE, &[18, 2, 1, 0, 0, 0, 1, 0])
+                .op(OP_TYPE_SAMPLED_IMAGE, &[19, 18])
+                .op(OP_TYPE_POINTER, &[22, STORAGE_UNIFORM_CONSTANT, 19])
+                .name(21, "albedo")
+                .op(OP_DECORATE, &[21, DECORATION_DESCRIPTOR_SET, set])
+                .op(OP_DECORATE, &[21, DECORATION_BINDING, binding])
+                .op(OP_VARIABLE, &[22, 21, STORAGE_UNIFORM_CONSTANT])
+        }
+
+        fn reflect(&self) -> Result<ShaderReflection, Box<dyn std::error::Error>> {
+            ShaderReflection::new(&self.code)
+        }
+    }
+
+    fn string(s: &str) -> Vec<u32> {
+        let mut bytes = s.as_bytes().to_vec();
+        bytes.resize(s.len() / 4 * 4 + 4, 0);
+        bytes
+            .chunks_exact(4)
+            .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+            .collect()
+    }
+
+    fn field(name: &'static str, location: u32, offset: u32, format: vk::Format) -> VertexField {
+        VertexField {
+            name,
+            location,
+            offset,
+            format,
+            columns: 1,
+        }
+    }
+
+    fn vertex_bindings() -> Vec<VertexBinding> {
+        vec![
+            VertexBinding {
+                type_name: "Vertex",
+                fields: vec![
+                    field("position", 0, 0, vk::Format::R32G32B32_SFLOAT),
+                    field("normal", 1, 12, vk::Format::R32G32B32_SFLOAT),
+                ],
+                stride: 24,
+                input_rate: vk::VertexInputRate::VERTEX,
+            },
+            VertexBinding {
+                type_name: "Instance",
+                fields: vec![
+                    VertexField {
+                        columns: 4,
+                        ..field("model_matrix", 2, 0, vk::Format::R32G32B32A32_SFLOAT)
+                    },
+                    field("color", 6, 64, vk::Format::R32G32B32_SFLOAT),
+                ],
+                stride: 76,
+                input_rate: vk::VertexInputRate::INSTANCE,
+            },
+        ]
+    }
+
+    fn error_of<T>(result: Result<T, Box<dyn std::error::Error>>) -> String {
+        match result {
+            Ok(_) => panic!("expected an error"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn matches_inputs_by_name() {
+        let reflection = Assembler::new(VERTEX)
+            .vertex_types()
+            .name(10, "position_in")
+            .name(11, "model_matrix")
+            .input(10, 6, 0)
+            .input(11, 8, 2)
+            .reflect()
+            .unwrap();
+        assert_eq!(reflection.stage, vk::ShaderStageFlags::VERTEX);
+        let (attributes, bindings) = reflection.vertex_input(&vertex_bindings()).unwrap();
+        let attributes: Vec<_> = attributes
+            .iter()
+            .map(|a| (a.binding, a.location, a.offset, a.format))
+            .collect();
+        assert_eq!(
+            attributes,
+            [
+                (0, 0, 0, vk::Format::R32G32B32_SFLOAT),
+                (1, 2, 0, vk::Format::R32G32B32A32_SFLOAT),
+                (1, 3, 16, vk::Format::R32G32B32A32_SFLOAT),
+                (1, 4, 32, vk::Format::R32G32B32A32_SFLOAT),
+                (1, 5, 48, vk::Format::R32G32B32A32_SFLOAT),
+            ]
+        );
+        let bindings: Vec<_> = bindings
+            .iter()
+            .map(|b| (b.binding, b.stride, b.input_rate))
+            .collect();
+        assert_eq!(
+            bindings,
+            [
+                (0, 24, vk::VertexInputRate::VERTEX),
+                (1, 76, vk::VertexInputRate::INSTANCE)
+            ]
+        );
+    }
+
+    #[test]
+    fn matches_unnamed_inputs_by_location() {
+        let reflection = Assembler::new(VERTEX)
+            .vertex_types()
+            .input(10, 6, 1)
+            .reflect()
+            .unwrap();
+        let (attributes, _) = reflection.vertex_input(&vertex_bindings()).unwrap();
+        assert_eq!(attributes.len(), 1);
+        assert_eq!((attributes[0].binding, attributes[0].offset), (0, 12));
+    }
+
+    #[test]
+    fn leaves_out_builtins() {
+        let reflection = Assembler::new(VERTEX)
+            .op(OP_TYPE_INT, &[12, 32, 1])
+            .op(OP_TYPE_POINTER, &[13, STORAGE_INPUT, 12])
+            .op(OP_DECORATE, &[14, DECORATION_BUILTIN, 42])
+            .op(OP_VARIABLE, &[13, 14, STORAGE_INPUT])
+            .reflect()
+            .unwrap();
+        assert!(reflection.inputs.is_empty());
+    }
+
+    #[test]
+    fn rejects_missing_inputs() {
+        let reflection = Assembler::new(VERTEX)
+            .vertex_types()
+            .name(10, "tangent_in")
+            .input(10, 7, 3)
+            .reflect()
+            .unwrap();
+        let error = error_of(reflection.vertex_input(&vertex_bindings()));
+        assert!(error.contains("`tangent_in`"), "{}", error);
+        assert!(
+            error.contains("no field `tangent` of Vertex or Instance"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn rejects_mistyped_inputs() {
+        let reflection = Assembler::new(VERTEX)
+            .vertex_types()
+            .name(10, "normal")
+            .input(10, 7, 1)
+            .reflect()
+            .unwrap();
+        let error = error_of(reflection.vertex_input(&vertex_bindings()));
+        assert!(
+            error.contains("is R32G32B32A32_SFLOAT but Vertex::normal is R32G32B32_SFLOAT"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn merges_bindings_shared_by_stages() {
+        let vertex = Assembler::new(VERTEX)
+            .uniform_block(1, 3)
+            .reflect()
+            .unwrap();
+        let fragment = Assembler::new(FRAGMENT)
+            .uniform_block(1, 3)
+            .sampler(0, 0)
+            .reflect()
+            .unwrap();
+        let sets = descriptor_set_bindings(&[&vertex, &fragment]).unwrap();
+        assert_eq!(sets.len(), 2);
+        assert_eq!(
+            sets[0][0].descriptor_type,
+            vk::DescriptorType::COMBINED_IMAGE_SAMPLER
+        );
+        assert_eq!(sets[0][0].stage_flags, vk::ShaderStageFlags::FRAGMENT);
+        assert_eq!(sets[1][0].binding, 3);
+        assert_eq!(
+            sets[1][0].descriptor_type,
+            vk::DescriptorType::UNIFORM_BUFFER
+        );
+        assert_eq!(
+            sets[1][0].stage_flags,
+            vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT
+        );
+    }
+
+    #[test]
+    fn rejects_conflicting_bindings() {
+        let vertex = Assembler::new(VERTEX)
+            .uniform_block(0, 1)
+            .reflect()
+            .unwrap();
+        let fragment = Assembler::new(FRAGMENT).sampler(0, 1).reflect().unwrap();
+        let error = error_of(descriptor_set_bindings(&[&vertex, &fragment]));
+        assert!(error.contains("set 0 binding 1"), "{}", error);
+    }
+
+    #[test]
+    fn measures_push_constant_blocks() {
+        let reflection = Assembler::new(FRAGMENT)
+            .op(OP_TYPE_INT, &[12, 32, 0])
+            .op(OP_TYPE_FLOAT, &[2, 32])
+            .op(OP_TYPE_VECTOR, &[4, 2, 4])
+            .op(OP_TYPE_STRUCT, &[16, 12, 12, 4])
+            .op(OP_MEMBER_DECORATE, &[16, 0, DECORATION_OFFSET, 0])
+            .op(OP_MEMBER_DECORATE, &[16, 1, DECORATION_OFFSET, 4])
+            .op(OP_MEMBER_DECORATE, &[16, 2, DECORATION_OFFSET, 16])
+            .op(OP_TYPE_POINTER, &[17, STORAGE_PUSH_CONSTANT, 16])
+            .op(OP_VARIABLE, &[17, 20, STORAGE_PUSH_CONSTANT])
+            .reflect()
+            .unwrap();
+        assert_eq!(reflection.push_constant_size, Some(32));
+    }
+
+    #[test]
+    fn unites_push_constant_blocks() {
+        let block = |execution_model, size| ShaderReflection {
+            push_constant_size: size,
+            ..Assembler::new(execution_model).reflect().unwrap()
+        };
+        let vertex = block(VERTEX, None);
+        assert!(push_constant_ranges(&[&vertex]).is_empty());
+        let lit = block(FRAGMENT, Some(8));
+        let tinted = block(FRAGMENT, Some(32));
+        let ranges = push_constant_ranges(&[&vertex, &lit, &tinted]);
+        assert_eq!(ranges.len(), 1);
+        assert_eq!(
+            (ranges[0].stage_flags, ranges[0].offset, ranges[0].size),
+            (vk::ShaderStageFlags::FRAGMENT, 0, 32)
+        );
+        let skinned = block(VERTEX, Some(64));
+        let ranges = push_constant_ranges(&[&skinned, &lit]);
+        assert_eq!(
+            (ranges[0].stage_flags, ranges[0].size),
+            (
+                vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
+                64
+            )
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_modules() {
+        let module = Assembler::new(VERTEX).vertex_types().code;
+        let mut bad_magic = module.clone();
+        bad_magic[0] = MAGIC.swap_bytes();
+        let error = error_of(ShaderReflection::new(&bad_magic));
+        assert!(error.contains("not a SPIR-V module"), "{}", error);
+        assert!(ShaderReflection::new(&module[..4]).is_err());
+        // Ending in the middle of an instruction
+        for length in [module.len() - 1, module.len() - 2] {
+            let error = error_of(ShaderReflection::new(&module[..length]));
+            assert!(error.contains("truncated"), "{}", error);
+        }
+        let mut zero_words = module.clone();
+        zero_words.push(OP_NAME);
+        let error = error_of(ShaderReflection::new(&zero_words));
+        assert!(error.contains("truncated"), "{}", error);
+        let no_entry_point = [MAGIC, 0x0001_0000, 0, 100, 0];
+        assert!(ShaderReflection::new(&no_entry_point).is_err());
+    }
+
+    // Needs the shaders compiled into the binary by include_glsl!
+    #[test]
+    fn reflects_the_embedded_shaders() {
+        let shaders = ShaderLibrary::new();
+        let scene = [
+            VertexBinding::of::<VertexData>(vk::VertexInputRate::VERTEX),
+            VertexBinding::of::<InstanceData>(vk::VertexInputRate::INSTANCE),
+        ];
+        for name in ["shader.vert", "shadow.vert"] {
+            let reflection = ShaderReflection::new(shaders.get(name)).unwrap();
+            assert_eq!(reflection.stage, vk::ShaderStageFlags::VERTEX, "{}", name);
+            reflection
+                .vertex_input(&scene)
+                .unwrap_or_else(|e| panic!("{}: {}", name, e));
+        }
+        let stages: Vec<_> = ["shader.vert", "shader.frag"]
+            .iter()
+            .map(|name| ShaderReflection::new(shaders.get(name)).unwrap())
+            .collect();
+        descriptor_set_bindings(&stages.iter().collect::<Vec<_>>()).unwrap();
+    }
+}
@@ -1,19 +1,34 @@
 use ash::vk;
 
+use super::{deferred, post};
+
 pub fn init_render_pass(
     logical_device: &ash::Device,
     format: vk::Format,
+    final_layout: vk::ImageLayout,
+    samples: vk::SampleCountFlags,
 ) -> Result<vk::RenderPass, vk::Result> {
-    let attachments = [
+    // With multisampling, attachment 0 is the multisampled color image that only lives
+    // for the pass and attachment 2 is the single sampled target it gets resolved into
+    let multisampled = samples != vk::SampleCountFlags::TYPE_1;
+    let mut attachments = vec![
         vk::AttachmentDescription::builder()
             .format(format)
             .load_op(vk::AttachmentLoadOp::CLEAR)
-            .store_op(vk::AttachmentStoreOp::STORE)
+            .store_op(if multisampled {
+                vk::AttachmentStoreOp::DONT_CARE
+            } else {
+                vk::AttachmentStoreOp::STORE
+            })
             .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
             .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
             .initial_layout(vk::ImageLayout::UNDEFINED)
-            .final_layout(vk::ImageLayout::PRESENT_SRC_KHR)
-            .samples(vk::SampleCountFlags::TYPE_1)
+            .final_layout(if multisampled {
+                vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL
+            } else {
+                final_layout
+            })
+            .samples(samples)
             .build(),
         vk::AttachmentDescription::builder()
             .format(vk::Format::D32_SFLOAT)
@@ -23,9 +38,23 @@ pub fn init_render_pass(
             .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
             .initial_layout(vk::ImageLayout::UNDEFINED)
             .final_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
-            .samples(vk::SampleCountFlags::TYPE_1)
+            .samples(samples)
             .build(),
     ];
+    if multisampled {
+        attachments.push(
+            vk::AttachmentDescription::builder()
+                .format(format)
+                .load_op(vk::AttachmentLoadOp::DONT_CARE)
+                .store_op(vk::AttachmentStoreOp::STORE)
+                .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+                .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+                .initial_layout(vk::ImageLayout::UNDEFINED)
+                .final_layout(final_layout)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .build(),
+        );
+    }
     let color_attachment_references = [vk::AttachmentReference {
         attachment: 0,
         layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
@@ -34,22 +63,232 @@ pub fn init_render_pass(
         attachment: 1,
         layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
     };
-    let subpasses = [vk::SubpassDescription::builder()
+    let resolve_attachment_references = [vk::AttachmentReference {
+        attachment: 2,
+        layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+    }];
+    let mut subpass = vk::SubpassDescription::builder()
         .color_attachments(&color_attachment_references)
         .depth_stencil_attachment(&depth_attachment_reference)
-        .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
-        .build()];
-    let subpass_dependencies = [vk::SubpassDependency::builder()
+        .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS);
+    if multisampled {
+        subpass = subpass.resolve_attachments(&resolve_attachment_references);
+    }
+    let subpasses = [subpass.build()];
+    // The attachments are shared by all frames in flight, so the previous frame's writes to
+    // them and the post pass reading the HDR image have to finish before this pass touches them
+    let mut subpass_dependencies = vec![vk::SubpassDependency::builder()
         .src_subpass(vk::SUBPASS_EXTERNAL)
-        .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+        .src_stage_mask(
+            vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+                | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS
+                | vk::PipelineStageFlags::FRAGMENT_SHADER,
+        )
+        .src_access_mask(
+            vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+                | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+        )
         .dst_subpass(0)
         .dst_stage_mask(
             vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+                | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS,
         )
         .dst_access_mask(
-            vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+            vk::AccessFlags::COLOR_ATTACHMENT_READ
+                | vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+                | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
         )
         .build()];
+    subpass_dependencies.extend(read_after_pass(0, final_layout));
+    let renderpass_info = vk::RenderPassCreateInfo::builder()
+        .attachments(&attachments)
+        .subpasses(&subpasses)
+        .dependencies(&subpass_dependencies);
+    let renderpass = unsafe { logical_device.create_render_pass(&renderpass_info, None)? };
+    Ok(renderpass)
+}
+
+// Single color attachment that is overwritten completely by a full-screen triangle
+pub fn init_post_render_pass(
+    logical_device: &ash::Device,
+    format: vk::Format,
+    final_layout: vk::ImageLayout,
+) -> Result<vk::RenderPass, vk::Result> {
+    let attachments = [vk::AttachmentDescription::builder()
+        .format(format)
+        .load_op(vk::AttachmentLoadOp::DONT_CARE)
+        .store_op(vk::AttachmentStoreOp::STORE)
+        .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+        .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+        .initial_layout(vk::ImageLayout::UNDEFINED)
+        .final_layout(final_layout)
+        .samples(vk::SampleCountFlags::TYPE_1)
+        .build()];
+    let color_attachment_references = [vk::AttachmentReference {
+        attachment: 0,
+        layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+    }];
+    let subpasses = [vk::SubpassDescription::builder()
+        .color_attachments(&color_attachment_references)
+        .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+        .build()];
+    // Swapchain images are only available once the acquire semaphore, waited on at the
+    // color output stage, is signalled
+    let mut subpass_dependencies = vec![vk::SubpassDependency::builder()
+        .src_subpass(vk::SUBPASS_EXTERNAL)
+        .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+        .src_access_mask(vk::AccessFlags::empty())
+        .dst_subpass(0)
+        .dst_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+        .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+        .build()];
+    subpass_dependencies.extend(read_after_pass(0, final_layout));
+    let renderpass_info = vk::RenderPassCreateInfo::builder()
+        .attachments(&attachments)
+        .subpasses(&subpasses)
+        .dependencies(&subpass_dependencies);
+    let renderpass = unsafe { logical_device.create_render_pass(&renderpass_info, None)? };
+    Ok(renderpass)
+}
+
+// Subpass 0 fills the G-buffer, subpass 1 lights it into the HDR target and then draws blended
+// geometry on top, depth tested but not written. Attachments are the HDR color, depth, and the
+// albedo, normal and material images, in that order
+pub fn init_deferred_render_pass(logical_device: &ash::Device) -> Result<vk::RenderPass, vk::Result> {
+    let attachment = |format, load_op, store_op, final_layout| {
+        vk::AttachmentDescription::builder()
+            .format(format)
+            .load_op(load_op)
+            .store_op(store_op)
+            .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+            .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+            .initial_layout(vk::ImageLayout::UNDEFINED)
+            .final_layout(final_layout)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .build()
+    };
+    let gbuffer = |format| {
+        attachment(
+            format,
+            vk::AttachmentLoadOp::DONT_CARE,
+            vk::AttachmentStoreOp::DONT_CARE,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        )
+    };
+    let attachments = [
+        attachment(
+            post::HDR_FORMAT,
+            vk::AttachmentLoadOp::CLEAR,
+            vk::AttachmentStoreOp::STORE,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        ),
+        attachment(
+            vk::Format::D32_SFLOAT,
+            vk::AttachmentLoadOp::CLEAR,
+            vk::AttachmentStoreOp::DONT_CARE,
+            vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL,
+        ),
+        gbuffer(deferred::ALBEDO_FORMAT),
+        gbuffer(deferred::NORMAL_FORMAT),
+        gbuffer(deferred::MATERIAL_FORMAT),
+    ];
+    let gbuffer_references = [2, 3, 4].map(|index| vk::AttachmentReference {
+        attachment: index,
+        layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+    });
+    let depth_attachment_reference = vk::AttachmentReference {
+        attachment: 1,
+        layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+    };
+    // Has to match the input attachment indices in shaders/deferred_lighting.frag
+    let input_references = [
+        vk::AttachmentReference {
+            attachment: 2,
+            layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        },
+        vk::AttachmentReference {
+            attachment: 3,
+            layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        },
+        vk::AttachmentReference {
+            attachment: 4,
+            layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        },
+        vk::AttachmentReference {
+            attachment: 1,
+            layout: vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL,
+        },
+    ];
+    let color_attachment_references = [vk::AttachmentReference {
+        attachment: 0,
+        layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+    }];
+    let read_only_depth_reference = vk::AttachmentReference {
+        attachment: 1,
+        layout: vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL,
+    };
+    let subpasses = [
+        vk::SubpassDescription::builder()
+            .color_attachments(&gbuffer_references)
+            .depth_stencil_attachment(&depth_attachment_reference)
+            .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+            .build(),
+        vk::SubpassDescription::builder()
+            .color_attachments(&color_attachment_references)
+            .input_attachments(&input_references)
+            .depth_stencil_attachment(&read_only_depth_reference)
+            .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+            .build(),
+    ];
+    // Same as the forward pass for the previous frame, plus the G-buffer written in subpass 0
+    // being read per pixel in subpass 1
+    let mut subpass_dependencies = vec![
+        vk::SubpassDependency::builder()
+            .src_subpass(vk::SUBPASS_EXTERNAL)
+            .src_stage_mask(
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+                    | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS
+                    | vk::PipelineStageFlags::FRAGMENT_SHADER,
+            )
+            .src_access_mask(
+                vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+                    | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+            )
+            .dst_subpass(0)
+            .dst_stage_mask(
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+                    | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS,
+            )
+            .dst_access_mask(
+                vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+                    | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+            )
+            .build(),
+        vk::SubpassDependency::builder()
+            .src_subpass(0)
+            .src_stage_mask(
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+                    | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+            )
+            .src_access_mask(
+                vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+                    | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+            )
+            .dst_subpass(1)
+            .dst_stage_mask(
+                vk::PipelineStageFlags::FRAGMENT_SHADER
+                    | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS
+                    | vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+            )
+            .dst_access_mask(
+                vk::AccessFlags::INPUT_ATTACHMENT_READ
+                    | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
+                    | vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+            )
+            .dependency_flags(vk::DependencyFlags::BY_REGION)
+            .build(),
+    ];
+    subpass_dependencies.extend(read_after_pass(1, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL));
     let renderpass_info = vk::RenderPassCreateInfo::builder()
         .attachments(&attachments)
         .subpasses(&subpasses)
@@ -57,3 +296,33 @@ pub fn init_render_pass(
     let renderpass = unsafe { logical_device.create_render_pass(&renderpass_info, None)? };
     Ok(renderpass)
 }
+
+// Makes the color writes of `subpass` visible to whatever reads the target right after the
+// pass ends
+fn read_after_pass(
+    subpass: u32,
+    final_layout: vk::ImageLayout,
+) -> Option<vk::SubpassDependency> {
+    let (dst_stage_mask, dst_access_mask) = match final_layout {
+        // Offscreen targets are copied out
+        vk::ImageLayout::TRANSFER_SRC_OPTIMAL => {
+            (vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::TRANSFER_READ)
+        }
+        // The HDR target is metered for auto exposure and sampled by the post pass
+        vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL => (
+            vk::PipelineStageFlags::COMPUTE_SHADER | vk::PipelineStageFlags::FRAGMENT_SHADER,
+            vk::AccessFlags::SHADER_READ,
+        ),
+        _ => return None,
+    };
+    Some(
+        vk::SubpassDependency::builder()
+            .src_subpass(subpass)
+            .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+            .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+            .dst_subpass(vk::SUBPASS_EXTERNAL)
+            .dst_stage_mask(dst_stage_mask)
+            .dst_access_mask(dst_access_mask)
+            .build(),
+    )
+}
@@ -0,0 +1,216 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+// The compiler used when reloading, glslc from the Vulkan SDK unless this names another one
+pub const GLSLC_ENV_VAR: &str = "OBERON_GLSLC";
+
+// How often the watched directory is checked for changes
+const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(250);
+
+// SPIR-V of every shader in shaders/, by file name. Starts out with the code compiled into the
+// binary, reload swaps in code compiled at runtime
+pub struct ShaderLibrary {
+    code: HashMap<&'static str, Vec<u32>>,
+}
+
+impl Default for ShaderLibrary {
+    fn default() -> Self {
+        ShaderLibrary::new()
+    }
+}
+
+impl ShaderLibrary {
+    pub fn new() -> ShaderLibrary {
+        let embedded: [(&'static str, &[u32]); 19] = [
+            ("shader.vert", vk_shader_macros::include_glsl!("./shaders/shader.vert", kind: vert)),
+            ("shader.frag", vk_shader_macros::include_glsl!("./shaders/shader.frag")),
+            ("gbuffer.frag", vk_shader_macros::include_glsl!("./shaders/gbuffer.frag")),
+            ("unlit.frag", vk_shader_macros::include_glsl!("./shaders/unlit.frag")),
+            (
+                "deferred_lighting.frag",
+                vk_shader_macros::include_glsl!("./shaders/deferred_lighting.frag"),
+            ),
+            ("post.vert", vk_shader_macros::include_glsl!("./shaders/post.vert", kind: vert)),
+            ("post.frag", vk_shader_macros::include_glsl!("./shaders/post.frag")),
+            ("shadow.vert", vk_shader_macros::include_glsl!("./shaders/shadow.vert", kind: vert)),
+            ("ssao.comp", vk_shader_macros::include_glsl!("./shaders/ssao.comp", kind: comp)),
+            (
+                "ssao_blur.comp",
+                vk_shader_macros::include_glsl!("./shaders/ssao_blur.comp", kind: comp),
+            ),
+            (
+                "bloom_downsample.comp",
+                vk_shader_macros::include_glsl!("./shaders/bloom_downsample.comp", kind: comp),
+            ),
+            (
+                "bloom_upsample.comp",
+                vk_shader_macros::include_glsl!("./shaders/bloom_upsample.comp", kind: comp),
+            ),
+            (
+                "exposure_histogram.comp",
+                vk_shader_macros::include_glsl!("./shaders/exposure_histogram.comp", kind: comp),
+            ),
+            (
+                "exposure_average.comp",
+                vk_shader_macros::include_glsl!("./shaders/exposure_average.comp", kind: comp),
+            ),
+            (
+                "frustum_culling.comp",
+                vk_shader_macros::include_glsl!("./shaders/frustum_culling.comp", kind: comp),
+            ),
+            (
+                "light_culling.comp",
+                vk_shader_macros::include_glsl!("./shaders/light_culling.comp", kind: comp),
+            ),
+            (
+                "ibl_irradiance.comp",
+                vk_shader_macros::include_glsl!("./shaders/ibl_irradiance.comp", kind: comp),
+            ),
+            (
+                "ibl_specular.comp",
+                vk_shader_macros::include_glsl!("./shaders/ibl_specular.comp", kind: comp),
+            ),
+            (
+                "ibl_brdf.comp",
+                vk_shader_macros::include_glsl!("./shaders/ibl_brdf.comp", kind: comp),
+            ),
+        ];
+        ShaderLibrary {
+            code: embedded
+                .into_iter()
+                .map(|(name, code)| (name, code.to_vec()))
+                .collect(),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> &[u32] {
+        &self.code[name]
+    }
+
+    pub fn try_get(&self, name: &str) -> Option<&[u32]> {
+        self.code.get(name).map(Vec::as_slice)
+    }
+
+    // Recompiles every shader that is one of the changed files or includes one. Shaders that
+    // do not compile keep their old code
+    pub fn reload(&mut self, directory: &Path, changed: &[String]) -> ShaderReload {
+        let mut reload = ShaderReload::default();
+        for (&name, code) in self.code.iter_mut() {
+            let path = directory.join(name);
+            let affected = changed.iter().any(|file| file == name)
+                || includes(&path)
+                    .iter()
+                    .any(|include| changed.contains(include));
+            if !affected {
+                continue;
+            }
+            match compile(&path) {
+                Ok(spirv) => {
+                    *code = spirv;
+                    reload.reloaded.push(name);
+                }
+                Err(e) => reload.errors.push(format!("{}: {}", path.display(), e)),
+            }
+        }
+        reload
+    }
+}
+
+// What ShaderLibrary::reload did: the shaders that compiled and the compiler's complaints
+// about the others
+#[derive(Default)]
+pub struct ShaderReload {
+    pub reloaded: Vec<&'static str>,
+    pub errors: Vec<String>,
+}
+
+// File names from the #include lines of a shader, followed into the included files
+fn includes(path: &Path) -> Vec<String> {
+    let mut found: Vec<String> = Vec::new();
+    let mut pending = vec![path.to_path_buf()];
+    while let Some(file) = pending.pop() {
+        let Ok(source) = std::fs::read_to_string(&file) else {
+            continue;
+        };
+        for line in source.lines() {
+            let Some(rest) = line.trim().strip_prefix("#include") else {
+                continue;
+            };
+            let name = rest.trim().trim_matches(|c| c == '"' || c == '<' || c == '>');
+            if !found.iter().any(|f| f == name) {
+                found.push(name.to_string());
+                pending.push(file.with_file_name(name));
+            }
+        }
+    }
+    found
+}
+
+// Runs glslc, which picks the stage from the extension and resolves includes next to the file
+pub fn compile(path: &Path) -> Result<Vec<u32>, String> {
+    let compiler = std::env::var(GLSLC_ENV_VAR).unwrap_or_else(|_| "glslc".to_string());
+    let output = std::process::Command::new(&compiler)
+        .arg(path)
+        .args(["--target-env=vulkan1.0", "-o", "-"])
+        .output()
+        .map_err(|e| format!("could not run {}: {}", compiler, e))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).into_owned());
+    }
+    if output.stdout.len() % 4 != 0 {
+        return Err("compiler output is not SPIR-V".to_string());
+    }
+    Ok(output
+        .stdout
+        .chunks_exact(4)
+        .map(|word| u32::from_ne_bytes([word[0], word[1], word[2], word[3]]))
+        .collect())
+}
+
+// Polls the modification times of the files in a directory
+pub struct ShaderWatcher {
+    pub directory: PathBuf,
+    modified: HashMap<String, std::time::SystemTime>,
+    last_poll: std::time::Instant,
+}
+
+impl ShaderWatcher {
+    pub fn new(directory: PathBuf) -> ShaderWatcher {
+        let mut watcher = ShaderWatcher {
+            directory,
+            modified: HashMap::new(),
+            last_poll: std::time::Instant::now(),
+        };
+        watcher.modified = watcher.scan();
+        watcher
+    }
+
+    fn scan(&self) -> HashMap<String, std::time::SystemTime> {
+        let Ok(entries) = std::fs::read_dir(&self.directory) else {
+            return HashMap::new();
+        };
+        entries
+            .flatten()
+            .filter_map(|entry| {
+                let modified = entry.metadata().ok()?.modified().ok()?;
+                Some((entry.file_name().into_string().ok()?, modified))
+            })
+            .collect()
+    }
+
+    // Files written since the last call, empty while the last check was too recent
+    pub fn changed_files(&mut self) -> Vec<String> {
+        if self.last_poll.elapsed() < POLL_INTERVAL {
+            return Vec::new();
+        }
+        self.last_poll = std::time::Instant::now();
+        let current = self.scan();
+        let changed = current
+            .iter()
+            .filter(|(name, time)| self.modified.get(*name) != Some(time))
+            .map(|(name, _)| name.clone())
+            .collect();
+        self.modified = current;
+        changed
+    }
+}
@@ -0,0 +1,641 @@
+use ash::vk;
+use nalgebra as na;
+
+use crate::ceaser::{
+    camera::Camera,
+    queue::QueueFamilies,
+    reflect::{ShaderReflection, VertexBinding},
+    shaders::ShaderLibrary,
+    transfer::Uploader,
+};
+
+// Keep in sync with shader.frag
+pub const CASCADE_COUNT: usize = 4;
+pub const MAX_SHADOWED_DIRECTIONAL_LIGHTS: usize = 2;
+pub const MAX_SHADOW_MAPS: usize = CASCADE_COUNT * MAX_SHADOWED_DIRECTIONAL_LIGHTS;
+pub const SHADOW_MAP_SIZE: u32 = 2048;
+// Point lights render all six cube faces into consecutive layers of their own array
+pub const MAX_SHADOWED_POINT_LIGHTS: usize = 4;
+pub const MAX_POINT_SHADOW_MAPS: usize = 6 * MAX_SHADOWED_POINT_LIGHTS;
+pub const POINT_SHADOW_MAP_SIZE: u32 = 512;
+pub const SHADOW_FORMAT: vk::Format = vk::Format::D32_SFLOAT;
+// Shadows end here even if the camera sees further
+pub const MAX_SHADOW_DISTANCE: f32 = 50.0;
+// How far behind a cascade casters are still caught, in m
+const CASTER_EXTRUSION: f32 = 30.0;
+// Blend between logarithmic (1) and uniform (0) cascade splits
+const SPLIT_LAMBDA: f32 = 0.75;
+// Also the SSAO depth prepass
+pub const SHADERS: [&str; 1] = ["shadow.vert"];
+
+// A depth array image with a framebuffer per layer for rendering and one view over all
+// layers for sampling
+pub struct ShadowArray {
+    pub image: vk::Image,
+    pub allocation: gpu_allocator::vulkan::Allocation,
+    pub array_view: vk::ImageView,
+    pub layer_views: Vec<vk::ImageView>,
+    pub framebuffers: Vec<vk::Framebuffer>,
+    pub size: u32,
+}
+
+// Light matrices of the layers that get rendered this frame, in layer order
+#[derive(Default)]
+pub struct ShadowViews {
+    pub directional: Vec<na::Matrix4<f32>>,
+    pub point: Vec<na::Matrix4<f32>>,
+}
+
+// One layer per cascade of every shadowed directional light and one per cube face of every
+// shadowed point light, rendered with a depth-only pass before the main pass samples them
+pub struct ShadowMaps {
+    pub directional: ShadowArray,
+    pub point: ShadowArray,
+    pub render_pass: vk::RenderPass,
+    pub sampler: vk::Sampler,
+    pub pipeline: vk::Pipeline,
+    pub pipeline_layout: vk::PipelineLayout,
+}
+
+impl ShadowMaps {
+    pub fn new(
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+        queue_families: &QueueFamilies,
+        uploader: &Uploader,
+        cache: vk::PipelineCache,
+        shaders: &ShaderLibrary,
+        vertex_bindings: &[VertexBinding],
+    ) -> Result<ShadowMaps, Box<dyn std::error::Error>> {
+        let render_pass =
+            create_depth_render_pass(logical_device, vk::PipelineStageFlags::FRAGMENT_SHADER)?;
+        let directional = ShadowArray::new(
+            logical_device,
+            allocator,
+            queue_families,
+            uploader,
+            render_pass,
+            SHADOW_MAP_SIZE,
+            MAX_SHADOW_MAPS as u32,
+            "Directional Shadow Maps",
+        )?;
+        let point = ShadowArray::new(
+            logical_device,
+            allocator,
+            queue_families,
+            uploader,
+            render_pass,
+            POINT_SHADOW_MAP_SIZE,
+            MAX_POINT_SHADOW_MAPS as u32,
+            "Point Shadow Maps",
+        )?;
+
+        // Depth comparison happens in the sampler, outside the map everything is lit
+        let sampler_info = vk::SamplerCreateInfo::builder()
+            .mag_filter(vk::Filter::LINEAR)
+            .min_filter(vk::Filter::LINEAR)
+            .mipmap_mode(vk::SamplerMipmapMode::NEAREST)
+            .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_BORDER)
+            .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_BORDER)
+            .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_BORDER)
+            .border_color(vk::BorderColor::FLOAT_OPAQUE_WHITE)
+            .compare_enable(true)
+            .compare_op(vk::CompareOp::LESS_OR_EQUAL)
+            .max_lod(0.0);
+        let sampler = unsafe { logical_device.create_sampler(&sampler_info, None) }?;
+
+        let (pipeline, pipeline_layout) = create_depth_pipeline(
+            logical_device,
+            render_pass,
+            true,
+            cache,
+            shaders,
+            vertex_bindings,
+        )?;
+
+        Ok(ShadowMaps {
+            directional,
+            point,
+            render_pass,
+            sampler,
+            pipeline,
+            pipeline_layout,
+        })
+    }
+
+    // Starts rendering the casters into one layer of `target`, the caller draws and calls `end`
+    pub fn begin(
+        &self,
+        logical_device: &ash::Device,
+        commandbuffer: vk::CommandBuffer,
+        target: &ShadowArray,
+        layer: usize,
+        light_matrix: &na::Matrix4<f32>,
+    ) {
+        let extent = vk::Extent2D {
+            width: target.size,
+            height: target.size,
+        };
+        let clearvalues = [vk::ClearValue {
+            depth_stencil: vk::ClearDepthStencilValue {
+                depth: 1.0,
+                stencil: 0,
+            },
+        }];
+        let renderpass_begininfo = vk::RenderPassBeginInfo::builder()
+            .render_pass(self.render_pass)
+            .framebuffer(target.framebuffers[layer])
+            .render_area(vk::Rect2D {
+                offset: vk::Offset2D { x: 0, y: 0 },
+                extent,
+            })
+            .clear_values(&clearvalues);
+        let viewports = [vk::Viewport {
+            x: 0.,
+            y: 0.,
+            width: target.size as f32,
+            height: target.size as f32,
+            min_depth: 0.,
+            max_depth: 1.,
+        }];
+        let scissors = [vk::Rect2D {
+            offset: vk::Offset2D { x: 0, y: 0 },
+            extent,
+        }];
+        let matrix: [[f32; 4]; 4] = (*light_matrix).into();
+        unsafe {
+            logical_device.cmd_begin_render_pass(
+                commandbuffer,
+                &renderpass_begininfo,
+                vk::SubpassContents::INLINE,
+            );
+            logical_device.cmd_set_viewport(commandbuffer, 0, &viewports);
+            logical_device.cmd_set_scissor(commandbuffer, 0, &scissors);
+            logical_device.cmd_bind_pipeline(
+                commandbuffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                self.pipeline,
+            );
+            logical_device.cmd_push_constants(
+                commandbuffer,
+                self.pipeline_layout,
+                vk::ShaderStageFlags::VERTEX,
+                0,
+                std::slice::from_raw_parts(matrix.as_ptr() as *const u8, 64),
+            );
+        }
+    }
+
+    pub fn end(&self, logical_device: &ash::Device, commandbuffer: vk::CommandBuffer) {
+        unsafe { logical_device.cmd_end_render_pass(commandbuffer) };
+    }
+
+    // Builds the pipeline again from the shader library, keeping the old one if that fails. It
+    // must not be in use anymore
+    pub unsafe fn rebuild_pipeline(
+        &mut self,
+        logical_device: &ash::Device,
+        cache: vk::PipelineCache,
+        shaders: &ShaderLibrary,
+        vertex_bindings: &[VertexBinding],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (pipeline, pipeline_layout) = create_depth_pipeline(
+            logical_device,
+            self.render_pass,
+            true,
+            cache,
+            shaders,
+            vertex_bindings,
+        )?;
+        logical_device.destroy_pipeline(self.pipeline, None);
+        logical_device.destroy_pipeline_layout(self.pipeline_layout, None);
+        self.pipeline = pipeline;
+        self.pipeline_layout = pipeline_layout;
+        Ok(())
+    }
+
+    pub unsafe fn cleanup(
+        &mut self,
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+    ) {
+        logical_device.destroy_pipeline(self.pipeline, None);
+        logical_device.destroy_pipeline_layout(self.pipeline_layout, None);
+        logical_device.destroy_sampler(self.sampler, None);
+        self.directional.cleanup(logical_device, allocator);
+        self.point.cleanup(logical_device, allocator);
+        logical_device.destroy_render_pass(self.render_pass, None);
+    }
+}
+
+impl ShadowArray {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+        queue_families: &QueueFamilies,
+        uploader: &Uploader,
+        render_pass: vk::RenderPass,
+        size: u32,
+        layers: u32,
+        name: &str,
+    ) -> Result<ShadowArray, Box<dyn std::error::Error>> {
+        let queuefamilies = [queue_families.graphics_q_index.unwrap()];
+        let image_info = vk::ImageCreateInfo::builder()
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(SHADOW_FORMAT)
+            .extent(vk::Extent3D {
+                width: size,
+                height: size,
+                depth: 1,
+            })
+            .mip_levels(1)
+            .array_layers(layers)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT | vk::ImageUsageFlags::SAMPLED)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .queue_family_indices(&queuefamilies);
+        let image = unsafe { logical_device.create_image(&image_info, None) }?;
+        let requirements = unsafe { logical_device.get_image_memory_requirements(image) };
+        let allocation = allocator.allocate(&gpu_allocator::vulkan::AllocationCreateDesc {
+            name,
+            requirements,
+            location: gpu_allocator::MemoryLocation::GpuOnly,
+            linear: false,
+        })?;
+        unsafe { logical_device.bind_image_memory(image, allocation.memory(), allocation.offset()) }?;
+
+        let array_view = create_view(
+            logical_device,
+            image,
+            vk::ImageViewType::TYPE_2D_ARRAY,
+            0,
+            layers,
+        )?;
+        let mut layer_views = Vec::with_capacity(layers as usize);
+        let mut framebuffers = Vec::with_capacity(layers as usize);
+        for layer in 0..layers {
+            let view = create_view(logical_device, image, vk::ImageViewType::TYPE_2D, layer, 1)?;
+            let iview = [view];
+            let framebuffer_info = vk::FramebufferCreateInfo::builder()
+                .render_pass(render_pass)
+                .attachments(&iview)
+                .width(size)
+                .height(size)
+                .layers(1);
+            framebuffers.push(unsafe { logical_device.create_framebuffer(&framebuffer_info, None) }?);
+            layer_views.push(view);
+        }
+
+        // Layers of lights that never cast a shadow are still bound for sampling
+        uploader.run_on_graphics_queue(logical_device, |commandbuffer| {
+            let barrier = [vk::ImageMemoryBarrier::builder()
+                .image(image)
+                .src_access_mask(vk::AccessFlags::empty())
+                .dst_access_mask(vk::AccessFlags::SHADER_READ)
+                .old_layout(vk::ImageLayout::UNDEFINED)
+                .new_layout(vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .subresource_range(vk::ImageSubresourceRange {
+                    aspect_mask: vk::ImageAspectFlags::DEPTH,
+                    base_mip_level: 0,
+                    level_count: 1,
+                    base_array_layer: 0,
+                    layer_count: layers,
+                })
+                .build()];
+            unsafe {
+                logical_device.cmd_pipeline_barrier(
+                    commandbuffer,
+                    vk::PipelineStageFlags::TOP_OF_PIPE,
+                    vk::PipelineStageFlags::FRAGMENT_SHADER,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    &[],
+                    &barrier,
+                )
+            };
+        })?;
+
+        Ok(ShadowArray {
+            image,
+            allocation,
+            array_view,
+            layer_views,
+            framebuffers,
+            size,
+        })
+    }
+
+    unsafe fn cleanup(
+        &mut self,
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+    ) {
+        for fb in self.framebuffers.drain(..) {
+            logical_device.destroy_framebuffer(fb, None);
+        }
+        for iv in self.layer_views.drain(..) {
+            logical_device.destroy_image_view(iv, None);
+        }
+        logical_device.destroy_image_view(self.array_view, None);
+        logical_device.destroy_image(self.image, None);
+        allocator
+            .free(std::mem::take(&mut self.allocation))
+            .expect("freeing shadow maps");
+    }
+}
+
+// View distances at which each cascade ends
+pub fn cascade_splits(camera: &Camera) -> [f32; CASCADE_COUNT] {
+    let near = camera.near;
+    let far = camera.far.min(MAX_SHADOW_DISTANCE);
+    let mut splits = [far; CASCADE_COUNT];
+    for (i, split) in splits.iter_mut().enumerate() {
+        let p = (i + 1) as f32 / CASCADE_COUNT as f32;
+        let logarithmic = near * (far / near).powf(p);
+        let uniform = near + (far - near) * p;
+        *split = SPLIT_LAMBDA * logarithmic + (1.0 - SPLIT_LAMBDA) * uniform;
+    }
+    splits
+}
+
+// Orthographic projection from the light onto the part of the camera frustum between
+// `near` and `far`. The frustum slice is enclosed in a sphere and the projection is moved
+// in whole texels, so the map does not shimmer when the camera turns or moves.
+pub fn cascade_matrix(
+    direction_to_light: &na::Vector3<f32>,
+    camera: &Camera,
+    near: f32,
+    far: f32,
+) -> na::Matrix4<f32> {
+    let forward = -direction_to_light.normalize();
+    let helper = if forward.y.abs() < 0.99 {
+        na::Vector3::y()
+    } else {
+        na::Vector3::x()
+    };
+    let down = (helper - forward * helper.dot(&forward)).normalize();
+    let right = down.cross(&forward);
+
+    let camera_right = camera.down_direction.cross(&camera.view_direction).normalize();
+    let tan_half_fovy = (0.5 * camera.fovy).tan();
+    let center = camera.position + camera.view_direction.as_ref() * (0.5 * (near + far));
+    let mut radius: f32 = 0.0;
+    for d in [near, far] {
+        let half_height = d * tan_half_fovy;
+        let half_width = half_height * camera.aspect;
+        let slice_center = camera.position + camera.view_direction.as_ref() * d;
+        for (sx, sy) in [(-1.0, -1.0), (-1.0, 1.0), (1.0, -1.0), (1.0, 1.0)] {
+            let corner = slice_center
+                + camera_right * (sx * half_width)
+                + camera.down_direction.as_ref() * (sy * half_height);
+            radius = radius.max((corner - center).norm());
+        }
+    }
+    radius = (radius * 16.0).ceil() / 16.0;
+
+    let texel = 2.0 * radius / SHADOW_MAP_SIZE as f32;
+    let cx = (right.dot(&center) / texel).floor() * texel;
+    let cy = (down.dot(&center) / texel).floor() * texel;
+    let depth_start = forward.dot(&center) - radius - CASTER_EXTRUSION;
+    let depth_range = 2.0 * radius + CASTER_EXTRUSION;
+    na::Matrix4::new(
+        right.x / radius,
+        right.y / radius,
+        right.z / radius,
+        -cx / radius,
+        down.x / radius,
+        down.y / radius,
+        down.z / radius,
+        -cy / radius,
+        forward.x / depth_range,
+        forward.y / depth_range,
+        forward.z / depth_range,
+        -depth_start / depth_range,
+        0.0,
+        0.0,
+        0.0,
+        1.0,
+    )
+}
+
+// Perspective projections onto the six cube faces around a point light, in the order
+// +x, -x, +y, -y, +z, -z. shader.frag picks the face by the same major axis rule.
+pub fn cube_face_matrices(position: &na::Point3<f32>, near: f32, far: f32) -> [na::Matrix4<f32>; 6] {
+    let faces = [
+        (na::Vector3::x(), na::Vector3::y()),
+        (-na::Vector3::x(), na::Vector3::y()),
+        (na::Vector3::y(), na::Vector3::z()),
+        (-na::Vector3::y(), -na::Vector3::z()),
+        (na::Vector3::z(), na::Vector3::y()),
+        (-na::Vector3::z(), na::Vector3::y()),
+    ];
+    // Same conventions as Camera: x right, y down, depth from 0 at `near` to 1 at `far`
+    let projection = na::Matrix4::new(
+        1.0,
+        0.0,
+        0.0,
+        0.0,
+        0.0,
+        1.0,
+        0.0,
+        0.0,
+        0.0,
+        0.0,
+        far / (far - near),
+        -near * far / (far - near),
+        0.0,
+        0.0,
+        1.0,
+        0.0,
+    );
+    faces.map(|(forward, down)| {
+        let right = down.cross(&forward);
+        let p = position.coords;
+        let view = na::Matrix4::new(
+            right.x,
+            right.y,
+            right.z,
+            -right.dot(&p),
+            down.x,
+            down.y,
+            down.z,
+            -down.dot(&p),
+            forward.x,
+            forward.y,
+            forward.z,
+            -forward.dot(&p),
+            0.0,
+            0.0,
+            0.0,
+            1.0,
+        );
+        projection * view
+    })
+}
+
+fn create_view(
+    logical_device: &ash::Device,
+    image: vk::Image,
+    view_type: vk::ImageViewType,
+    base_array_layer: u32,
+    layer_count: u32,
+) -> Result<vk::ImageView, vk::Result> {
+    let subresource_range = vk::ImageSubresourceRange::builder()
+        .aspect_mask(vk::ImageAspectFlags::DEPTH)
+        .base_mip_level(0)
+        .level_count(1)
+        .base_array_layer(base_array_layer)
+        .layer_count(layer_count);
+    let imageview_create_info = vk::ImageViewCreateInfo::builder()
+        .image(image)
+        .view_type(view_type)
+        .format(SHADOW_FORMAT)
+        .subresource_range(*subresource_range);
+    unsafe { logical_device.create_image_view(&imageview_create_info, None) }
+}
+
+// Single depth attachment that `read_stage` samples once the pass is done
+pub fn create_depth_render_pass(
+    logical_device: &ash::Device,
+    read_stage: vk::PipelineStageFlags,
+) -> Result<vk::RenderPass, vk::Result> {
+    let attachments = [vk::AttachmentDescription::builder()
+        .format(SHADOW_FORMAT)
+        .load_op(vk::AttachmentLoadOp::CLEAR)
+        .store_op(vk::AttachmentStoreOp::STORE)
+        .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+        .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+        .initial_layout(vk::ImageLayout::UNDEFINED)
+        .final_layout(vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL)
+        .samples(vk::SampleCountFlags::TYPE_1)
+        .build()];
+    let depth_attachment_reference = vk::AttachmentReference {
+        attachment: 0,
+        layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+    };
+    let subpasses = [vk::SubpassDescription::builder()
+        .depth_stencil_attachment(&depth_attachment_reference)
+        .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+        .build()];
+    // The previous frame may still be sampling the map, and this frame samples it right after
+    let subpass_dependencies = [
+        vk::SubpassDependency::builder()
+            .src_subpass(vk::SUBPASS_EXTERNAL)
+            .src_stage_mask(read_stage)
+            .src_access_mask(vk::AccessFlags::SHADER_READ)
+            .dst_subpass(0)
+            .dst_stage_mask(
+                vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS
+                    | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+            )
+            .dst_access_mask(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
+            .build(),
+        vk::SubpassDependency::builder()
+            .src_subpass(0)
+            .src_stage_mask(vk::PipelineStageFlags::LATE_FRAGMENT_TESTS)
+            .src_access_mask(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
+            .dst_subpass(vk::SUBPASS_EXTERNAL)
+            .dst_stage_mask(read_stage)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ)
+            .build(),
+    ];
+    let renderpass_info = vk::RenderPassCreateInfo::builder()
+        .attachments(&attachments)
+        .subpasses(&subpasses)
+        .dependencies(&subpass_dependencies);
+    unsafe { logical_device.create_render_pass(&renderpass_info, None) }
+}
+
+// Depth-only pipeline for a render pass from create_depth_render_pass, the push constant is
+// the view projection matrix
+pub fn create_depth_pipeline(
+    logical_device: &ash::Device,
+    renderpass: vk::RenderPass,
+    depth_bias: bool,
+    cache: vk::PipelineCache,
+    shaders: &ShaderLibrary,
+    vertex_bindings: &[VertexBinding],
+) -> Result<(vk::Pipeline, vk::PipelineLayout), Box<dyn std::error::Error>> {
+    let vertex_code = shaders.get("shadow.vert");
+    // Same buffers as the main pass, only the position and the model matrix are read
+    let (vertex_attrib_descs, vertex_binding_descs) =
+        ShaderReflection::new(vertex_code)?.vertex_input(vertex_bindings)?;
+    let vertexshader_createinfo = vk::ShaderModuleCreateInfo::builder().code(vertex_code);
+    let vertexshader_module =
+        unsafe { logical_device.create_shader_module(&vertexshader_createinfo, None)? };
+    let mainfunctionname = std::ffi::CString::new("main").unwrap();
+    let shader_stages = [vk::PipelineShaderStageCreateInfo::builder()
+        .stage(vk::ShaderStageFlags::VERTEX)
+        .module(vertexshader_module)
+        .name(&mainfunctionname)
+        .build()];
+    let vertex_input_info = vk::PipelineVertexInputStateCreateInfo::builder()
+        .vertex_attribute_descriptions(&vertex_attrib_descs)
+        .vertex_binding_descriptions(&vertex_binding_descs);
+    let input_assembly_info = vk::PipelineInputAssemblyStateCreateInfo::builder()
+        .topology(vk::PrimitiveTopology::TRIANGLE_LIST);
+    let viewport_info = vk::PipelineViewportStateCreateInfo::builder()
+        .viewport_count(1)
+        .scissor_count(1);
+    let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+    let dynamic_state_info =
+        vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&dynamic_states);
+    // Slope scaled bias keeps surfaces facing away from the light from shadowing themselves
+    let rasterizer_info = vk::PipelineRasterizationStateCreateInfo::builder()
+        .line_width(1.0)
+        .front_face(vk::FrontFace::COUNTER_CLOCKWISE)
+        .cull_mode(vk::CullModeFlags::BACK)
+        .polygon_mode(vk::PolygonMode::FILL)
+        .depth_bias_enable(depth_bias)
+        .depth_bias_constant_factor(1.25)
+        .depth_bias_slope_factor(1.75);
+    let multisampler_info = vk::PipelineMultisampleStateCreateInfo::builder()
+        .rasterization_samples(vk::SampleCountFlags::TYPE_1);
+    let depth_stencil_info = vk::PipelineDepthStencilStateCreateInfo::builder()
+        .depth_test_enable(true)
+        .depth_write_enable(true)
+        .depth_compare_op(vk::CompareOp::LESS_OR_EQUAL);
+    let colorblend_info = vk::PipelineColorBlendStateCreateInfo::builder();
+    let push_constant_ranges = [vk::PushConstantRange {
+        stage_flags: vk::ShaderStageFlags::VERTEX,
+        offset: 0,
+        size: 64,
+    }];
+    let pipelinelayout_info =
+        vk::PipelineLayoutCreateInfo::builder().push_constant_ranges(&push_constant_ranges);
+    let pipelinelayout =
+        match unsafe { logical_device.create_pipeline_layout(&pipelinelayout_info, None) } {
+            Ok(layout) => layout,
+            Err(e) => {
+                unsafe { logical_device.destroy_shader_module(vertexshader_module, None) };
+                return Err(e.into());
+            }
+        };
+    let pipeline_info = vk::GraphicsPipelineCreateInfo::builder()
+        .stages(&shader_stages)
+        .vertex_input_state(&vertex_input_info)
+        .input_assembly_state(&input_assembly_info)
+        .viewport_state(&viewport_info)
+        .rasterization_state(&rasterizer_info)
+        .multisample_state(&multisampler_info)
+        .depth_stencil_state(&depth_stencil_info)
+        .color_blend_state(&colorblend_info)
+        .dynamic_state(&dynamic_state_info)
+        .layout(pipelinelayout)
+        .render_pass(renderpass)
+        .subpass(0);
+    let pipelines =
+        unsafe { logical_device.create_graphics_pipelines(cache, &[pipeline_info.build()], None) };
+    unsafe { logical_device.destroy_shader_module(vertexshader_module, None) };
+    match pipelines {
+        Ok(pipelines) => Ok((pipelines[0], pipelinelayout)),
+        Err((_, e)) => {
+            unsafe { logical_device.destroy_pipeline_layout(pipelinelayout, None) };
+            Err(e.into())
+        }
+    }
+}
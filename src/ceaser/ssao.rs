@@ -0,0 +1,529 @@
+use ash::vk;
+use nalgebra as na;
+
+use crate::ceaser::{
+    attachment::Attachment, camera::Camera, ibl, queue::QueueFamilies, reflect::VertexBinding,
+    shaders::ShaderLibrary, shadow, texture::Image, transfer::Uploader,
+};
+
+// Has to match shaders/ssao.comp
+pub const MAX_SSAO_SAMPLES: u32 = 64;
+const AO_FORMAT: vk::Format = vk::Format::R32_SFLOAT;
+// The occlusion pass, then the blur. The depth prepass uses shadow::SHADERS
+pub const SHADERS: [&str; 2] = ["ssao.comp", "ssao_blur.comp"];
+
+// Screen-space ambient occlusion. The main pass needs the result while it shades, so the
+// camera depth is rendered by its own single sampled depth prepass first. Normals are
+// reconstructed from that depth, a hemisphere around each pixel is tested against it and a
+// depth aware blur removes the noise. shader.frag multiplies the result into the ambient term.
+pub struct Ssao {
+    pub enabled: bool,
+    // Hemisphere radius in world units
+    pub radius: f32,
+    pub sample_count: u32,
+    // Depth difference below which a sample does not count as occluded, against self-shadowing
+    pub bias: f32,
+    // Exponent on the visibility, higher darkens the occluded areas more
+    pub intensity: f32,
+    render_pass: vk::RenderPass,
+    depth_pipeline: vk::Pipeline,
+    depth_pipeline_layout: vk::PipelineLayout,
+    depth: Attachment,
+    framebuffer: vk::Framebuffer,
+    raw: Image,
+    blurred: Image,
+    extent: vk::Extent2D,
+    pub sampler: vk::Sampler,
+    descriptor_set_layout: vk::DescriptorSetLayout,
+    descriptor_pool: vk::DescriptorPool,
+    ssao_set: vk::DescriptorSet,
+    blur_set: vk::DescriptorSet,
+    pipeline_layout: vk::PipelineLayout,
+    ssao_pipeline: vk::Pipeline,
+    blur_pipeline: vk::Pipeline,
+    // Taken from the camera in set_camera
+    view_projection: na::Matrix4<f32>,
+    projection: [f32; 4],
+}
+
+impl Ssao {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+        queue_families: &QueueFamilies,
+        uploader: &Uploader,
+        extent: vk::Extent2D,
+        cache: vk::PipelineCache,
+        shaders: &ShaderLibrary,
+        vertex_bindings: &[VertexBinding],
+    ) -> Result<Ssao, Box<dyn std::error::Error>> {
+        let render_pass = shadow::create_depth_render_pass(
+            logical_device,
+            vk::PipelineStageFlags::COMPUTE_SHADER,
+        )?;
+        let (depth_pipeline, depth_pipeline_layout) = shadow::create_depth_pipeline(
+            logical_device,
+            render_pass,
+            false,
+            cache,
+            shaders,
+            vertex_bindings,
+        )?;
+
+        let bindings = [
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(0)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::COMPUTE)
+                .build(),
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(1)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::COMPUTE)
+                .build(),
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(2)
+                .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::COMPUTE)
+                .build(),
+        ];
+        let set_layout_info = vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings);
+        let descriptor_set_layout =
+            unsafe { logical_device.create_descriptor_set_layout(&set_layout_info, None) }?;
+        let set_layouts = [descriptor_set_layout];
+        let push_constant_ranges = [vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::COMPUTE,
+            offset: 0,
+            size: 32,
+        }];
+        let layout_info = vk::PipelineLayoutCreateInfo::builder()
+            .set_layouts(&set_layouts)
+            .push_constant_ranges(&push_constant_ranges);
+        let pipeline_layout = unsafe { logical_device.create_pipeline_layout(&layout_info, None) }?;
+        let pipelines = ibl::create_compute_pipelines(
+            logical_device,
+            pipeline_layout,
+            cache,
+            shaders,
+            &SHADERS,
+        )?;
+
+        // Depth must not be filtered across edges
+        let sampler_info = vk::SamplerCreateInfo::builder()
+            .mag_filter(vk::Filter::NEAREST)
+            .min_filter(vk::Filter::NEAREST)
+            .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE);
+        let sampler = unsafe { logical_device.create_sampler(&sampler_info, None) }?;
+
+        let pool_sizes = [
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                descriptor_count: 4,
+            },
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::STORAGE_IMAGE,
+                descriptor_count: 2,
+            },
+        ];
+        let pool_info = vk::DescriptorPoolCreateInfo::builder()
+            .max_sets(2)
+            .pool_sizes(&pool_sizes);
+        let descriptor_pool = unsafe { logical_device.create_descriptor_pool(&pool_info, None) }?;
+        let layouts = [descriptor_set_layout; 2];
+        let allocate_info = vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(descriptor_pool)
+            .set_layouts(&layouts);
+        let sets = unsafe { logical_device.allocate_descriptor_sets(&allocate_info) }?;
+
+        let (depth, framebuffer, raw, blurred) = create_targets(
+            logical_device,
+            allocator,
+            queue_families,
+            uploader,
+            render_pass,
+            extent,
+        )?;
+        let ssao = Ssao {
+            enabled: true,
+            radius: 0.5,
+            sample_count: 16,
+            bias: 0.025,
+            intensity: 1.0,
+            render_pass,
+            depth_pipeline,
+            depth_pipeline_layout,
+            depth,
+            framebuffer,
+            raw,
+            blurred,
+            extent,
+            sampler,
+            descriptor_set_layout,
+            descriptor_pool,
+            ssao_set: sets[0],
+            blur_set: sets[1],
+            pipeline_layout,
+            ssao_pipeline: pipelines[0],
+            blur_pipeline: pipelines[1],
+            view_projection: na::Matrix4::identity(),
+            projection: [1.0, 1.0, 0.1, 100.0],
+        };
+        ssao.write_sets(logical_device);
+        Ok(ssao)
+    }
+
+    // Rebuilds the depth and occlusion images for a new target size. The caller has to make
+    // sure the device is idle.
+    pub unsafe fn resize(
+        &mut self,
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+        queue_families: &QueueFamilies,
+        uploader: &Uploader,
+        extent: vk::Extent2D,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.destroy_targets(logical_device, allocator)?;
+        let (depth, framebuffer, raw, blurred) = create_targets(
+            logical_device,
+            allocator,
+            queue_families,
+            uploader,
+            self.render_pass,
+            extent,
+        )?;
+        self.depth = depth;
+        self.framebuffer = framebuffer;
+        self.raw = raw;
+        self.blurred = blurred;
+        self.extent = extent;
+        self.write_sets(logical_device);
+        Ok(())
+    }
+
+    // The blurred occlusion, always in GENERAL layout. Read by shader.frag
+    pub fn view(&self) -> vk::ImageView {
+        self.blurred.imageview
+    }
+
+    pub fn set_camera(&mut self, camera: &Camera) {
+        self.view_projection = camera.projection_matrix * camera.view_matrix;
+        self.projection = [
+            camera.projection_matrix[(0, 0)],
+            camera.projection_matrix[(1, 1)],
+            camera.near,
+            camera.far,
+        ];
+    }
+
+    fn write_sets(&self, logical_device: &ash::Device) {
+        let depth_infos = [vk::DescriptorImageInfo {
+            sampler: self.sampler,
+            image_view: self.depth.imageview,
+            image_layout: vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL,
+        }];
+        let raw_sampled_infos = [vk::DescriptorImageInfo {
+            sampler: self.sampler,
+            image_view: self.raw.imageview,
+            image_layout: vk::ImageLayout::GENERAL,
+        }];
+        let raw_storage_infos = [vk::DescriptorImageInfo {
+            sampler: vk::Sampler::null(),
+            image_view: self.raw.imageview,
+            image_layout: vk::ImageLayout::GENERAL,
+        }];
+        let blurred_storage_infos = [vk::DescriptorImageInfo {
+            sampler: vk::Sampler::null(),
+            image_view: self.blurred.imageview,
+            image_layout: vk::ImageLayout::GENERAL,
+        }];
+        // The occlusion pass leaves binding 1 unused
+        let bindings = [
+            (self.ssao_set, 0, &depth_infos),
+            (self.ssao_set, 2, &raw_storage_infos),
+            (self.blur_set, 0, &depth_infos),
+            (self.blur_set, 1, &raw_sampled_infos),
+            (self.blur_set, 2, &blurred_storage_infos),
+        ];
+        let writes: Vec<vk::WriteDescriptorSet> = bindings
+            .iter()
+            .map(|&(set, binding, info)| {
+                let descriptor_type = if binding == 2 {
+                    vk::DescriptorType::STORAGE_IMAGE
+                } else {
+                    vk::DescriptorType::COMBINED_IMAGE_SAMPLER
+                };
+                vk::WriteDescriptorSet::builder()
+                    .dst_set(set)
+                    .dst_binding(binding)
+                    .descriptor_type(descriptor_type)
+                    .image_info(info)
+                    .build()
+            })
+            .collect();
+        unsafe { logical_device.update_descriptor_sets(&writes, &[]) };
+    }
+
+    // Depth prepass of every model, then occlusion and blur. Recorded before the main pass
+    pub fn record(
+        &self,
+        logical_device: &ash::Device,
+        commandbuffer: vk::CommandBuffer,
+        draw_scene: impl Fn(vk::CommandBuffer),
+    ) {
+        let render_area = vk::Rect2D {
+            offset: vk::Offset2D { x: 0, y: 0 },
+            extent: self.extent,
+        };
+        let clearvalues = [vk::ClearValue {
+            depth_stencil: vk::ClearDepthStencilValue {
+                depth: 1.0,
+                stencil: 0,
+            },
+        }];
+        let renderpass_begininfo = vk::RenderPassBeginInfo::builder()
+            .render_pass(self.render_pass)
+            .framebuffer(self.framebuffer)
+            .render_area(render_area)
+            .clear_values(&clearvalues);
+        let viewports = [vk::Viewport {
+            x: 0.,
+            y: 0.,
+            width: self.extent.width as f32,
+            height: self.extent.height as f32,
+            min_depth: 0.,
+            max_depth: 1.,
+        }];
+        let matrix: [[f32; 4]; 4] = self.view_projection.into();
+
+        // Laid out like the push constant blocks in shaders/ssao*.comp
+        let mut push_constants = [0u8; 32];
+        let values = [
+            self.projection[0],
+            self.projection[1],
+            self.projection[2],
+            self.projection[3],
+            self.radius,
+            self.bias,
+            self.intensity,
+        ];
+        for (i, value) in values.iter().enumerate() {
+            push_constants[4 * i..4 * i + 4].copy_from_slice(&value.to_ne_bytes());
+        }
+        let sample_count = self.sample_count.clamp(1, MAX_SSAO_SAMPLES);
+        push_constants[28..32].copy_from_slice(&sample_count.to_ne_bytes());
+
+        // The previous frame's main pass may still be reading the occlusion
+        let previous_frame = [vk::MemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::SHADER_READ)
+            .dst_access_mask(vk::AccessFlags::SHADER_WRITE)
+            .build()];
+        let pass_done = [vk::MemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ)
+            .build()];
+        let group_count = (self.extent.width.div_ceil(8), self.extent.height.div_ceil(8));
+        unsafe {
+            logical_device.cmd_begin_render_pass(
+                commandbuffer,
+                &renderpass_begininfo,
+                vk::SubpassContents::INLINE,
+            );
+            logical_device.cmd_bind_pipeline(
+                commandbuffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                self.depth_pipeline,
+            );
+            logical_device.cmd_set_viewport(commandbuffer, 0, &viewports);
+            logical_device.cmd_set_scissor(commandbuffer, 0, &[render_area]);
+            logical_device.cmd_push_constants(
+                commandbuffer,
+                self.depth_pipeline_layout,
+                vk::ShaderStageFlags::VERTEX,
+                0,
+                std::slice::from_raw_parts(matrix.as_ptr() as *const u8, 64),
+            );
+            draw_scene(commandbuffer);
+            logical_device.cmd_end_render_pass(commandbuffer);
+
+            logical_device.cmd_pipeline_barrier(
+                commandbuffer,
+                vk::PipelineStageFlags::FRAGMENT_SHADER,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::DependencyFlags::empty(),
+                &previous_frame,
+                &[],
+                &[],
+            );
+            logical_device.cmd_push_constants(
+                commandbuffer,
+                self.pipeline_layout,
+                vk::ShaderStageFlags::COMPUTE,
+                0,
+                &push_constants,
+            );
+            for (pipeline, set) in [
+                (self.ssao_pipeline, self.ssao_set),
+                (self.blur_pipeline, self.blur_set),
+            ] {
+                logical_device.cmd_bind_pipeline(
+                    commandbuffer,
+                    vk::PipelineBindPoint::COMPUTE,
+                    pipeline,
+                );
+                logical_device.cmd_bind_descriptor_sets(
+                    commandbuffer,
+                    vk::PipelineBindPoint::COMPUTE,
+                    self.pipeline_layout,
+                    0,
+                    &[set],
+                    &[],
+                );
+                logical_device.cmd_dispatch(commandbuffer, group_count.0, group_count.1, 1);
+                logical_device.cmd_pipeline_barrier(
+                    commandbuffer,
+                    vk::PipelineStageFlags::COMPUTE_SHADER,
+                    vk::PipelineStageFlags::COMPUTE_SHADER | vk::PipelineStageFlags::FRAGMENT_SHADER,
+                    vk::DependencyFlags::empty(),
+                    &pass_done,
+                    &[],
+                    &[],
+                );
+            }
+        }
+    }
+
+    unsafe fn destroy_targets(
+        &mut self,
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        logical_device.destroy_framebuffer(self.framebuffer, None);
+        self.depth.destroy(logical_device, allocator);
+        self.raw.destroy(logical_device, allocator)?;
+        self.blurred.destroy(logical_device, allocator)
+    }
+
+    // Builds the occlusion and blur pipelines again from the shader library, keeping the old ones
+    // if that fails. They must not be in use anymore
+    pub unsafe fn rebuild_pipelines(
+        &mut self,
+        logical_device: &ash::Device,
+        cache: vk::PipelineCache,
+        shaders: &ShaderLibrary,
+    ) -> Result<(), vk::Result> {
+        let pipelines = ibl::create_compute_pipelines(
+            logical_device,
+            self.pipeline_layout,
+            cache,
+            shaders,
+            &SHADERS,
+        )?;
+        logical_device.destroy_pipeline(self.ssao_pipeline, None);
+        logical_device.destroy_pipeline(self.blur_pipeline, None);
+        self.ssao_pipeline = pipelines[0];
+        self.blur_pipeline = pipelines[1];
+        Ok(())
+    }
+
+    // Same for the depth prepass pipeline
+    pub unsafe fn rebuild_depth_pipeline(
+        &mut self,
+        logical_device: &ash::Device,
+        cache: vk::PipelineCache,
+        shaders: &ShaderLibrary,
+        vertex_bindings: &[VertexBinding],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (depth_pipeline, depth_pipeline_layout) = shadow::create_depth_pipeline(
+            logical_device,
+            self.render_pass,
+            false,
+            cache,
+            shaders,
+            vertex_bindings,
+        )?;
+        logical_device.destroy_pipeline(self.depth_pipeline, None);
+        logical_device.destroy_pipeline_layout(self.depth_pipeline_layout, None);
+        self.depth_pipeline = depth_pipeline;
+        self.depth_pipeline_layout = depth_pipeline_layout;
+        Ok(())
+    }
+
+    pub unsafe fn cleanup(
+        &mut self,
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.destroy_targets(logical_device, allocator)?;
+        logical_device.destroy_descriptor_pool(self.descriptor_pool, None);
+        logical_device.destroy_sampler(self.sampler, None);
+        logical_device.destroy_pipeline(self.ssao_pipeline, None);
+        logical_device.destroy_pipeline(self.blur_pipeline, None);
+        logical_device.destroy_pipeline_layout(self.pipeline_layout, None);
+        logical_device.destroy_descriptor_set_layout(self.descriptor_set_layout, None);
+        logical_device.destroy_pipeline(self.depth_pipeline, None);
+        logical_device.destroy_pipeline_layout(self.depth_pipeline_layout, None);
+        logical_device.destroy_render_pass(self.render_pass, None);
+        Ok(())
+    }
+}
+
+// The occlusion images go to GENERAL once and stay there, compute writes and fragment
+// shaders read them in that layout
+fn create_targets(
+    logical_device: &ash::Device,
+    allocator: &mut gpu_allocator::vulkan::Allocator,
+    queue_families: &QueueFamilies,
+    uploader: &Uploader,
+    render_pass: vk::RenderPass,
+    extent: vk::Extent2D,
+) -> Result<(Attachment, vk::Framebuffer, Image, Image), Box<dyn std::error::Error>> {
+    let queuefamilies = [queue_families.graphics_q_index.unwrap()];
+    let depth = Attachment::new(
+        logical_device,
+        allocator,
+        &queuefamilies,
+        extent,
+        shadow::SHADOW_FORMAT,
+        vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT | vk::ImageUsageFlags::SAMPLED,
+        vk::SampleCountFlags::TYPE_1,
+        "SSAO Depth Image",
+    )?;
+    let iview = [depth.imageview];
+    let framebuffer_info = vk::FramebufferCreateInfo::builder()
+        .render_pass(render_pass)
+        .attachments(&iview)
+        .width(extent.width)
+        .height(extent.height)
+        .layers(1);
+    let framebuffer = unsafe { logical_device.create_framebuffer(&framebuffer_info, None) }?;
+
+    let usage = vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED;
+    let raw = Image::new(logical_device, allocator, extent, AO_FORMAT, 1, usage)?;
+    let blurred = Image::new(logical_device, allocator, extent, AO_FORMAT, 1, usage)?;
+    uploader.run_on_graphics_queue(logical_device, |commandbuffer| unsafe {
+        for image in [&raw, &blurred] {
+            image.barrier(
+                logical_device,
+                commandbuffer,
+                (0, 1),
+                (vk::ImageLayout::UNDEFINED, vk::ImageLayout::GENERAL),
+                (
+                    vk::AccessFlags::empty(),
+                    vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE,
+                ),
+                (
+                    vk::PipelineStageFlags::TOP_OF_PIPE,
+                    vk::PipelineStageFlags::COMPUTE_SHADER
+                        | vk::PipelineStageFlags::FRAGMENT_SHADER,
+                ),
+            );
+        }
+    })?;
+    Ok((depth, framebuffer, raw, blurred))
+}
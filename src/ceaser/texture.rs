@@ -0,0 +1,467 @@
+use ash::vk;
+
+use crate::ceaser::{buffer::Buffer, transfer::Uploader};
+
+// Descriptor sets are allocated once per texture and live as long as the storage
+pub const MAX_TEXTURES: u32 = 256;
+// Always present, for models without textures of their own
+pub const WHITE: usize = 0;
+pub const FLAT_NORMAL: usize = 1;
+
+// A sampled device-local image with a full mip chain
+pub struct Image {
+    pub image: vk::Image,
+    pub allocation: gpu_allocator::vulkan::Allocation,
+    pub imageview: vk::ImageView,
+    pub extent: vk::Extent2D,
+}
+
+impl Image {
+    pub fn new(
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+        extent: vk::Extent2D,
+        format: vk::Format,
+        mip_levels: u32,
+        usage: vk::ImageUsageFlags,
+    ) -> Result<Image, Box<dyn std::error::Error>> {
+        let image_info = vk::ImageCreateInfo::builder()
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(format)
+            .extent(vk::Extent3D {
+                width: extent.width,
+                height: extent.height,
+                depth: 1,
+            })
+            .mip_levels(mip_levels)
+            .array_layers(1)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(usage)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE);
+        let image = unsafe { logical_device.create_image(&image_info, None) }?;
+        let requirements = unsafe { logical_device.get_image_memory_requirements(image) };
+        let allocation = allocator.allocate(&gpu_allocator::vulkan::AllocationCreateDesc {
+            name: "Texture",
+            requirements,
+            location: gpu_allocator::MemoryLocation::GpuOnly,
+            linear: false,
+        })?;
+        unsafe { logical_device.bind_image_memory(image, allocation.memory(), allocation.offset()) }?;
+
+        let subresource_range = vk::ImageSubresourceRange::builder()
+            .aspect_mask(vk::ImageAspectFlags::COLOR)
+            .base_mip_level(0)
+            .level_count(mip_levels)
+            .base_array_layer(0)
+            .layer_count(1);
+        let imageview_create_info = vk::ImageViewCreateInfo::builder()
+            .image(image)
+            .view_type(vk::ImageViewType::TYPE_2D)
+            .format(format)
+            .subresource_range(*subresource_range);
+        let imageview = unsafe { logical_device.create_image_view(&imageview_create_info, None) }?;
+        Ok(Image {
+            image,
+            allocation,
+            imageview,
+            extent,
+        })
+    }
+
+    // PNG or JPEG, converted to RGBA. Colour textures want an _SRGB format, data like normal
+    // maps an _UNORM one.
+    pub fn load<P: AsRef<std::path::Path>>(
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+        uploader: &Uploader,
+        path: P,
+        format: vk::Format,
+    ) -> Result<Image, Box<dyn std::error::Error>> {
+        let pixels = ::image::open(path)?.into_rgba8();
+        Image::from_rgba8(
+            logical_device,
+            allocator,
+            uploader,
+            vk::Extent2D {
+                width: pixels.width(),
+                height: pixels.height(),
+            },
+            pixels.as_raw(),
+            format,
+        )
+    }
+
+    pub fn from_rgba8(
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+        uploader: &Uploader,
+        extent: vk::Extent2D,
+        pixels: &[u8],
+        format: vk::Format,
+    ) -> Result<Image, Box<dyn std::error::Error>> {
+        let texels: Vec<[u8; 4]> = pixels
+            .chunks_exact(4)
+            .map(|c| [c[0], c[1], c[2], c[3]])
+            .collect();
+        Image::from_pixels(logical_device, allocator, uploader, extent, &texels, format)
+    }
+
+    // Uploads the top level and fills the rest of the mip chain by blitting. RGBA8 and
+    // RGBA16F are required to support linear blits, so no format check. `T` is one texel.
+    pub fn from_pixels<T: Copy>(
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+        uploader: &Uploader,
+        extent: vk::Extent2D,
+        pixels: &[T],
+        format: vk::Format,
+    ) -> Result<Image, Box<dyn std::error::Error>> {
+        if pixels.len() as u64 != extent.width as u64 * extent.height as u64 {
+            return Err("pixel data does not match the image size".into());
+        }
+        let mip_levels = 32 - extent.width.max(extent.height).leading_zeros();
+        let image = Image::new(
+            logical_device,
+            allocator,
+            extent,
+            format,
+            mip_levels,
+            vk::ImageUsageFlags::TRANSFER_SRC
+                | vk::ImageUsageFlags::TRANSFER_DST
+                | vk::ImageUsageFlags::SAMPLED,
+        )?;
+
+        let mut staging = Buffer::new(
+            logical_device,
+            allocator,
+            std::mem::size_of_val(pixels) as u64,
+            vk::BufferUsageFlags::TRANSFER_SRC,
+            gpu_allocator::MemoryLocation::CpuToGpu,
+        )?;
+        staging.write(pixels)?;
+
+        let recorded = uploader.run_on_graphics_queue(logical_device, |commandbuffer| unsafe {
+            image.barrier(
+                logical_device,
+                commandbuffer,
+                (0, mip_levels),
+                (vk::ImageLayout::UNDEFINED, vk::ImageLayout::TRANSFER_DST_OPTIMAL),
+                (vk::AccessFlags::empty(), vk::AccessFlags::TRANSFER_WRITE),
+                (vk::PipelineStageFlags::TOP_OF_PIPE, vk::PipelineStageFlags::TRANSFER),
+            );
+            let region = [vk::BufferImageCopy::builder()
+                .image_subresource(vk::ImageSubresourceLayers {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    mip_level: 0,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                })
+                .image_extent(vk::Extent3D {
+                    width: extent.width,
+                    height: extent.height,
+                    depth: 1,
+                })
+                .build()];
+            logical_device.cmd_copy_buffer_to_image(
+                commandbuffer,
+                staging.buffer,
+                image.image,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                &region,
+            );
+
+            let (mut width, mut height) = (extent.width as i32, extent.height as i32);
+            for level in 1..mip_levels {
+                image.barrier(
+                    logical_device,
+                    commandbuffer,
+                    (level - 1, 1),
+                    (
+                        vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                        vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                    ),
+                    (vk::AccessFlags::TRANSFER_WRITE, vk::AccessFlags::TRANSFER_READ),
+                    (vk::PipelineStageFlags::TRANSFER, vk::PipelineStageFlags::TRANSFER),
+                );
+                let (next_width, next_height) = ((width / 2).max(1), (height / 2).max(1));
+                let blit = [vk::ImageBlit::builder()
+                    .src_subresource(vk::ImageSubresourceLayers {
+                        aspect_mask: vk::ImageAspectFlags::COLOR,
+                        mip_level: level - 1,
+                        base_array_layer: 0,
+                        layer_count: 1,
+                    })
+                    .src_offsets([
+                        vk::Offset3D { x: 0, y: 0, z: 0 },
+                        vk::Offset3D {
+                            x: width,
+                            y: height,
+                            z: 1,
+                        },
+                    ])
+                    .dst_subresource(vk::ImageSubresourceLayers {
+                        aspect_mask: vk::ImageAspectFlags::COLOR,
+                        mip_level: level,
+                        base_array_layer: 0,
+                        layer_count: 1,
+                    })
+                    .dst_offsets([
+                        vk::Offset3D { x: 0, y: 0, z: 0 },
+                        vk::Offset3D {
+                            x: next_width,
+                            y: next_height,
+                            z: 1,
+                        },
+                    ])
+                    .build()];
+                logical_device.cmd_blit_image(
+                    commandbuffer,
+                    image.image,
+                    vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                    image.image,
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    &blit,
+                    vk::Filter::LINEAR,
+                );
+                width = next_width;
+                height = next_height;
+            }
+
+            // Every level but the last was a blit source
+            if mip_levels > 1 {
+                image.barrier(
+                    logical_device,
+                    commandbuffer,
+                    (0, mip_levels - 1),
+                    (
+                        vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                        vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                    ),
+                    (vk::AccessFlags::TRANSFER_READ, vk::AccessFlags::SHADER_READ),
+                    (
+                        vk::PipelineStageFlags::TRANSFER,
+                        vk::PipelineStageFlags::FRAGMENT_SHADER,
+                    ),
+                );
+            }
+            image.barrier(
+                logical_device,
+                commandbuffer,
+                (mip_levels - 1, 1),
+                (
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                ),
+                (vk::AccessFlags::TRANSFER_WRITE, vk::AccessFlags::SHADER_READ),
+                (
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::PipelineStageFlags::FRAGMENT_SHADER,
+                ),
+            );
+        });
+        unsafe { staging.destroy(logical_device, allocator) }?;
+        recorded?;
+        Ok(image)
+    }
+
+    // (first level, level count), (old, new layout), (src, dst access), (src, dst stage)
+    pub unsafe fn barrier(
+        &self,
+        logical_device: &ash::Device,
+        commandbuffer: vk::CommandBuffer,
+        levels: (u32, u32),
+        layouts: (vk::ImageLayout, vk::ImageLayout),
+        access: (vk::AccessFlags, vk::AccessFlags),
+        stages: (vk::PipelineStageFlags, vk::PipelineStageFlags),
+    ) {
+        let barrier = [vk::ImageMemoryBarrier::builder()
+            .image(self.image)
+            .src_access_mask(access.0)
+            .dst_access_mask(access.1)
+            .old_layout(layouts.0)
+            .new_layout(layouts.1)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .subresource_range(vk::ImageSubresourceRange {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                base_mip_level: levels.0,
+                level_count: levels.1,
+                base_array_layer: 0,
+                layer_count: 1,
+            })
+            .build()];
+        logical_device.cmd_pipeline_barrier(
+            commandbuffer,
+            stages.0,
+            stages.1,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[],
+            &barrier,
+        );
+    }
+
+    pub unsafe fn destroy(
+        &mut self,
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        allocator.free(std::mem::take(&mut self.allocation))?;
+        logical_device.destroy_image_view(self.imageview, None);
+        logical_device.destroy_image(self.image, None);
+        self.image = vk::Image::null();
+        Ok(())
+    }
+}
+
+// Same as for Buffer, only `destroy` can free an image
+impl Drop for Image {
+    fn drop(&mut self) {
+        if self.image != vk::Image::null() {
+            eprintln!(
+                "leaked a {}x{} image",
+                self.extent.width, self.extent.height
+            );
+        }
+    }
+}
+
+pub struct Texture {
+    pub image: Image,
+    pub descriptor_set: vk::DescriptorSet,
+}
+
+// Every texture of the scene, each with its own descriptor set for set 2 or 3 of the main
+// pipeline
+pub struct TextureStorage {
+    pub textures: Vec<Texture>,
+    pub sampler: vk::Sampler,
+    pub descriptor_pool: vk::DescriptorPool,
+    pub descriptor_set_layout: vk::DescriptorSetLayout,
+}
+
+impl TextureStorage {
+    pub fn new(
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+        uploader: &Uploader,
+        descriptor_set_layout: vk::DescriptorSetLayout,
+    ) -> Result<TextureStorage, Box<dyn std::error::Error>> {
+        let sampler_info = vk::SamplerCreateInfo::builder()
+            .mag_filter(vk::Filter::LINEAR)
+            .min_filter(vk::Filter::LINEAR)
+            .mipmap_mode(vk::SamplerMipmapMode::LINEAR)
+            .address_mode_u(vk::SamplerAddressMode::REPEAT)
+            .address_mode_v(vk::SamplerAddressMode::REPEAT)
+            .address_mode_w(vk::SamplerAddressMode::REPEAT)
+            .max_lod(vk::LOD_CLAMP_NONE);
+        let sampler = unsafe { logical_device.create_sampler(&sampler_info, None) }?;
+
+        let pool_sizes = [vk::DescriptorPoolSize {
+            ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            descriptor_count: MAX_TEXTURES,
+        }];
+        let descriptor_pool_info = vk::DescriptorPoolCreateInfo::builder()
+            .max_sets(MAX_TEXTURES)
+            .pool_sizes(&pool_sizes);
+        let descriptor_pool =
+            unsafe { logical_device.create_descriptor_pool(&descriptor_pool_info, None) }?;
+
+        let mut storage = TextureStorage {
+            textures: vec![],
+            sampler,
+            descriptor_pool,
+            descriptor_set_layout,
+        };
+        let white = Image::from_rgba8(
+            logical_device,
+            allocator,
+            uploader,
+            vk::Extent2D {
+                width: 1,
+                height: 1,
+            },
+            &[255, 255, 255, 255],
+            vk::Format::R8G8B8A8_SRGB,
+        )?;
+        storage.add(logical_device, white)?;
+        let flat_normal = Image::from_rgba8(
+            logical_device,
+            allocator,
+            uploader,
+            vk::Extent2D {
+                width: 1,
+                height: 1,
+            },
+            &[128, 128, 255, 255],
+            vk::Format::R8G8B8A8_UNORM,
+        )?;
+        storage.add(logical_device, flat_normal)?;
+        Ok(storage)
+    }
+
+    // Returns the index models refer to the texture by
+    pub fn add(
+        &mut self,
+        logical_device: &ash::Device,
+        image: Image,
+    ) -> Result<usize, Box<dyn std::error::Error>> {
+        if self.textures.len() as u32 >= MAX_TEXTURES {
+            return Err("too many textures".into());
+        }
+        let desc_layouts = [self.descriptor_set_layout];
+        let descriptor_set_allocate_info = vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(self.descriptor_pool)
+            .set_layouts(&desc_layouts);
+        let descriptor_set =
+            unsafe { logical_device.allocate_descriptor_sets(&descriptor_set_allocate_info) }?[0];
+        let image_infos = [vk::DescriptorImageInfo {
+            sampler: self.sampler,
+            image_view: image.imageview,
+            image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        }];
+        let desc_sets_write = [vk::WriteDescriptorSet::builder()
+            .dst_set(descriptor_set)
+            .dst_binding(0)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .image_info(&image_infos)
+            .build()];
+        unsafe { logical_device.update_descriptor_sets(&desc_sets_write, &[]) };
+        self.textures.push(Texture {
+            image,
+            descriptor_set,
+        });
+        Ok(self.textures.len() - 1)
+    }
+
+    pub fn load<P: AsRef<std::path::Path>>(
+        &mut self,
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+        uploader: &Uploader,
+        path: P,
+        format: vk::Format,
+    ) -> Result<usize, Box<dyn std::error::Error>> {
+        let image = Image::load(logical_device, allocator, uploader, path, format)?;
+        self.add(logical_device, image)
+    }
+
+    // Unknown indices fall back to the white texture
+    pub fn get(&self, index: usize) -> &Texture {
+        self.textures.get(index).unwrap_or(&self.textures[0])
+    }
+
+    pub unsafe fn cleanup(
+        &mut self,
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        for mut texture in self.textures.drain(..) {
+            texture.image.destroy(logical_device, allocator)?;
+        }
+        logical_device.destroy_descriptor_pool(self.descriptor_pool, None);
+        logical_device.destroy_sampler(self.sampler, None);
+        Ok(())
+    }
+}
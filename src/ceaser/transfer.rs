@@ -0,0 +1,297 @@
+use ash::vk;
+
+use crate::ceaser::{
+    buffer::Buffer,
+    queue::{Pools, QueueFamilies, Queues},
+};
+
+// Copies static data into device-local memory through a host-visible staging buffer.
+// The copy runs on the dedicated transfer queue when there is one, in which case the
+// destination buffer is released by the transfer family and acquired by the graphics family.
+pub struct Uploader {
+    pub transfer_queue: vk::Queue,
+    pub graphics_queue: vk::Queue,
+    pub transfer_q_index: u32,
+    pub graphics_q_index: u32,
+    pub commandpool_transfer: vk::CommandPool,
+    pub commandpool_graphics: vk::CommandPool,
+    pub transfer_finished: vk::Semaphore,
+    pub upload_finished: vk::Fence,
+}
+
+impl Uploader {
+    pub fn new(
+        logical_device: &ash::Device,
+        queue_families: &QueueFamilies,
+        queues: &Queues,
+        pools: &Pools,
+    ) -> Result<Uploader, vk::Result> {
+        let semaphoreinfo = vk::SemaphoreCreateInfo::builder();
+        let fenceinfo = vk::FenceCreateInfo::builder();
+        let transfer_finished = unsafe { logical_device.create_semaphore(&semaphoreinfo, None) }?;
+        let upload_finished = unsafe { logical_device.create_fence(&fenceinfo, None) }?;
+        Ok(Uploader {
+            transfer_queue: queues.transfer_queue,
+            graphics_queue: queues.graphics_queue,
+            transfer_q_index: queue_families.transfer_q_index.unwrap(),
+            graphics_q_index: queue_families.graphics_q_index.unwrap(),
+            commandpool_transfer: pools.commandpool_transfer,
+            commandpool_graphics: pools.commandpool_graphics,
+            transfer_finished,
+            upload_finished,
+        })
+    }
+
+    fn ownership_transfer(&self) -> bool {
+        self.transfer_q_index != self.graphics_q_index
+    }
+
+    // Blocks until the data is visible to the graphics queue
+    pub fn upload<T: Sized>(
+        &self,
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+        data: &[T],
+        usage: vk::BufferUsageFlags,
+    ) -> Result<Buffer, Box<dyn std::error::Error>> {
+        let size_in_bytes = std::mem::size_of_val(data) as u64;
+        if size_in_bytes == 0 {
+            return Err("cannot upload an empty buffer".into());
+        }
+        let mut staging = Buffer::new(
+            logical_device,
+            allocator,
+            size_in_bytes,
+            vk::BufferUsageFlags::TRANSFER_SRC,
+            gpu_allocator::MemoryLocation::CpuToGpu,
+        )?;
+        staging.write(data)?;
+        let buffer = Buffer::new(
+            logical_device,
+            allocator,
+            size_in_bytes,
+            usage | vk::BufferUsageFlags::TRANSFER_DST,
+            gpu_allocator::MemoryLocation::GpuOnly,
+        )?;
+
+        let (dst_stage, dst_access) = consumer_of(usage);
+        let regions = [vk::BufferCopy {
+            src_offset: 0,
+            dst_offset: 0,
+            size: size_in_bytes,
+        }];
+
+        let copy_pool = if self.ownership_transfer() {
+            self.commandpool_transfer
+        } else {
+            self.commandpool_graphics
+        };
+        let copy_commandbuffer = allocate_one(logical_device, copy_pool)?;
+        unsafe {
+            let begininfo = vk::CommandBufferBeginInfo::builder()
+                .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+            logical_device.begin_command_buffer(copy_commandbuffer, &begininfo)?;
+            logical_device.cmd_copy_buffer(
+                copy_commandbuffer,
+                staging.buffer,
+                buffer.buffer,
+                &regions,
+            );
+            if self.ownership_transfer() {
+                // Release half: the access mask on the receiving side is ignored here
+                let release = [self.barrier(
+                    buffer.buffer,
+                    vk::AccessFlags::TRANSFER_WRITE,
+                    vk::AccessFlags::empty(),
+                )];
+                logical_device.cmd_pipeline_barrier(
+                    copy_commandbuffer,
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    &release,
+                    &[],
+                );
+            } else {
+                let barrier = [vk::BufferMemoryBarrier::builder()
+                    .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                    .dst_access_mask(dst_access)
+                    .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .buffer(buffer.buffer)
+                    .offset(0)
+                    .size(vk::WHOLE_SIZE)
+                    .build()];
+                logical_device.cmd_pipeline_barrier(
+                    copy_commandbuffer,
+                    vk::PipelineStageFlags::TRANSFER,
+                    dst_stage,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    &barrier,
+                    &[],
+                );
+            }
+            logical_device.end_command_buffer(copy_commandbuffer)?;
+        }
+
+        let copy_commandbuffers = [copy_commandbuffer];
+        if self.ownership_transfer() {
+            let acquire_commandbuffer = allocate_one(logical_device, self.commandpool_graphics)?;
+            unsafe {
+                let begininfo = vk::CommandBufferBeginInfo::builder()
+                    .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+                logical_device.begin_command_buffer(acquire_commandbuffer, &begininfo)?;
+                // Acquire half: matches the release above, the source access mask is ignored
+                let acquire = [self.barrier(buffer.buffer, vk::AccessFlags::empty(), dst_access)];
+                logical_device.cmd_pipeline_barrier(
+                    acquire_commandbuffer,
+                    // Chains with the semaphore wait below, which blocks the same stages
+                    dst_stage,
+                    dst_stage,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    &acquire,
+                    &[],
+                );
+                logical_device.end_command_buffer(acquire_commandbuffer)?;
+
+                let signal_semaphores = [self.transfer_finished];
+                let transfer_submit = [vk::SubmitInfo::builder()
+                    .command_buffers(&copy_commandbuffers)
+                    .signal_semaphores(&signal_semaphores)
+                    .build()];
+                logical_device.queue_submit(
+                    self.transfer_queue,
+                    &transfer_submit,
+                    vk::Fence::null(),
+                )?;
+                let acquire_commandbuffers = [acquire_commandbuffer];
+                let wait_stages = [dst_stage];
+                let acquire_submit = [vk::SubmitInfo::builder()
+                    .wait_semaphores(&signal_semaphores)
+                    .wait_dst_stage_mask(&wait_stages)
+                    .command_buffers(&acquire_commandbuffers)
+                    .build()];
+                logical_device.queue_submit(
+                    self.graphics_queue,
+                    &acquire_submit,
+                    self.upload_finished,
+                )?;
+                self.wait(logical_device)?;
+                logical_device
+                    .free_command_buffers(self.commandpool_graphics, &acquire_commandbuffers);
+            }
+        } else {
+            unsafe {
+                let submit = [vk::SubmitInfo::builder()
+                    .command_buffers(&copy_commandbuffers)
+                    .build()];
+                logical_device.queue_submit(self.graphics_queue, &submit, self.upload_finished)?;
+                self.wait(logical_device)?;
+            }
+        }
+        unsafe {
+            logical_device.free_command_buffers(copy_pool, &copy_commandbuffers);
+            staging.destroy(logical_device, allocator)?;
+        }
+        Ok(buffer)
+    }
+
+    // Records and runs one-off graphics work, e.g. initial layout transitions, and blocks
+    // until it has finished
+    pub fn run_on_graphics_queue<F: FnOnce(vk::CommandBuffer)>(
+        &self,
+        logical_device: &ash::Device,
+        record: F,
+    ) -> Result<(), vk::Result> {
+        let commandbuffer = allocate_one(logical_device, self.commandpool_graphics)?;
+        let commandbuffers = [commandbuffer];
+        unsafe {
+            let begininfo = vk::CommandBufferBeginInfo::builder()
+                .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+            logical_device.begin_command_buffer(commandbuffer, &begininfo)?;
+            record(commandbuffer);
+            logical_device.end_command_buffer(commandbuffer)?;
+            let submit = [vk::SubmitInfo::builder()
+                .command_buffers(&commandbuffers)
+                .build()];
+            logical_device.queue_submit(self.graphics_queue, &submit, self.upload_finished)?;
+            self.wait(logical_device)?;
+            logical_device.free_command_buffers(self.commandpool_graphics, &commandbuffers);
+        }
+        Ok(())
+    }
+
+    fn barrier(
+        &self,
+        buffer: vk::Buffer,
+        src_access: vk::AccessFlags,
+        dst_access: vk::AccessFlags,
+    ) -> vk::BufferMemoryBarrier {
+        vk::BufferMemoryBarrier::builder()
+            .src_access_mask(src_access)
+            .dst_access_mask(dst_access)
+            .src_queue_family_index(self.transfer_q_index)
+            .dst_queue_family_index(self.graphics_q_index)
+            .buffer(buffer)
+            .offset(0)
+            .size(vk::WHOLE_SIZE)
+            .build()
+    }
+
+    unsafe fn wait(&self, logical_device: &ash::Device) -> Result<(), vk::Result> {
+        logical_device.wait_for_fences(&[self.upload_finished], true, u64::MAX)?;
+        logical_device.reset_fences(&[self.upload_finished])
+    }
+
+    pub unsafe fn cleanup(&self, logical_device: &ash::Device) {
+        logical_device.destroy_semaphore(self.transfer_finished, None);
+        logical_device.destroy_fence(self.upload_finished, None);
+    }
+}
+
+fn allocate_one(
+    logical_device: &ash::Device,
+    pool: vk::CommandPool,
+) -> Result<vk::CommandBuffer, vk::Result> {
+    let commandbuf_allocate_info = vk::CommandBufferAllocateInfo::builder()
+        .command_pool(pool)
+        .level(vk::CommandBufferLevel::PRIMARY)
+        .command_buffer_count(1);
+    Ok(unsafe { logical_device.allocate_command_buffers(&commandbuf_allocate_info) }?[0])
+}
+
+// Where the graphics queue first touches a buffer with this usage
+fn consumer_of(usage: vk::BufferUsageFlags) -> (vk::PipelineStageFlags, vk::AccessFlags) {
+    let mut stages = vk::PipelineStageFlags::empty();
+    let mut access = vk::AccessFlags::empty();
+    if usage.contains(vk::BufferUsageFlags::VERTEX_BUFFER) {
+        stages |= vk::PipelineStageFlags::VERTEX_INPUT;
+        access |= vk::AccessFlags::VERTEX_ATTRIBUTE_READ;
+    }
+    if usage.contains(vk::BufferUsageFlags::INDEX_BUFFER) {
+        stages |= vk::PipelineStageFlags::VERTEX_INPUT;
+        access |= vk::AccessFlags::INDEX_READ;
+    }
+    if usage.contains(vk::BufferUsageFlags::UNIFORM_BUFFER) {
+        stages |= vk::PipelineStageFlags::VERTEX_SHADER | vk::PipelineStageFlags::FRAGMENT_SHADER;
+        access |= vk::AccessFlags::UNIFORM_READ;
+    }
+    if usage.contains(vk::BufferUsageFlags::STORAGE_BUFFER) {
+        stages |= vk::PipelineStageFlags::VERTEX_SHADER
+            | vk::PipelineStageFlags::FRAGMENT_SHADER
+            | vk::PipelineStageFlags::COMPUTE_SHADER;
+        access |= vk::AccessFlags::SHADER_READ;
+    }
+    if usage.contains(vk::BufferUsageFlags::INDIRECT_BUFFER) {
+        stages |= vk::PipelineStageFlags::DRAW_INDIRECT;
+        access |= vk::AccessFlags::INDIRECT_COMMAND_READ;
+    }
+    if stages.is_empty() {
+        stages = vk::PipelineStageFlags::ALL_COMMANDS;
+        access = vk::AccessFlags::MEMORY_READ;
+    }
+    (stages, access)
+}
@@ -1,8 +1,12 @@
 use crate::ceaser::buffer::Buffer;
+use crate::ceaser::deletion_queue::DeletionQueue;
+use crate::ceaser::transfer::Uploader;
 use ash::vk;
 use nalgebra as na;
+use oberon_derive::VertexLayout;
 
 pub mod light;
+pub mod tangents;
 
 #[derive(Debug, Clone)]
 pub struct InvalidHandle;
@@ -17,10 +21,13 @@ impl std::error::Error for InvalidHandle {
     }
 }
 
+// Matched by name against the inputs of shaders/shader.vert, the locations are those it declares
+#[derive(VertexLayout)]
 #[repr(C)]
 pub struct InstanceData {
+    #[vertex(location = 2)]
     pub model_matrix: [[f32; 4]; 4],
-    pub inverse_modelmatrix: [[f32; 4]; 4],
+    pub inverse_model_matrix: [[f32; 4]; 4],
     pub color: [f32; 3],
     pub metallic: f32,
     pub roughness: f32,
@@ -35,7 +42,7 @@ impl InstanceData {
     ) -> InstanceData {
         InstanceData {
             model_matrix: model_matrix.into(),
-            inverse_modelmatrix: model_matrix.try_inverse().unwrap().into(),
+            inverse_model_matrix: model_matrix.try_inverse().unwrap().into(),
             color,
             metallic,
             roughness,
@@ -43,11 +50,26 @@ impl InstanceData {
     }
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, VertexLayout)]
 #[repr(C)]
 pub struct VertexData {
     pub position: [f32; 3],
     pub normal: [f32; 3],
+    #[vertex(location = 13)]
+    pub uv: [f32; 2],
+    // xyz along increasing u, w is the sign of the bitangent
+    pub tangent: [f32; 4],
+}
+
+// What the bounding sphere of a model is computed from, see Model::update_bounding_sphere
+pub trait Position {
+    fn position(&self) -> [f32; 3];
+}
+
+impl Position for VertexData {
+    fn position(&self) -> [f32; 3] {
+        self.position
+    }
 }
 
 impl VertexData {
@@ -63,6 +85,13 @@ impl VertexData {
                 0.5 * (a.normal[1] + b.normal[1]),
                 0.5 * (a.normal[2] + b.normal[2]),
             ]),
+            uv: [0.5 * (a.uv[0] + b.uv[0]), 0.5 * (a.uv[1] + b.uv[1])],
+            tangent: [
+                0.5 * (a.tangent[0] + b.tangent[0]),
+                0.5 * (a.tangent[1] + b.tangent[1]),
+                0.5 * (a.tangent[2] + b.tangent[2]),
+                a.tangent[3],
+            ],
         }
     }
 }
@@ -82,7 +111,20 @@ pub struct Model<V, I> {
     pub next_handle: usize,
     pub vertexbuffer: Option<Buffer>,
     pub indexbuffer: Option<Buffer>,
-    pub instancebuffer: Option<Buffer>,
+    pub instancebuffers: Vec<Option<Buffer>>,
+    // Written by the frustum culling pass each frame: the instances on screen and the
+    // indirect draw command that draws them
+    pub visiblebuffers: Vec<Option<Buffer>>,
+    pub indirectbuffers: Vec<Option<Buffer>>,
+    // Model space centre and radius, None until computed from the vertex data
+    pub bounding_sphere: Option<[f32; 4]>,
+    pub casts_shadows: bool,
+    pub receives_shadows: bool,
+    // Index into the renderer's materials, see Ceaser::add_material
+    pub material: usize,
+    // Indices into the renderer's TextureStorage
+    pub albedo_texture: usize,
+    pub normal_texture: usize,
 }
 
 #[allow(dead_code)]
@@ -202,9 +244,11 @@ impl<V, I> Model<V, I> {
         &mut self,
         logical_device: &ash::Device,
         allocator: &mut gpu_allocator::vulkan::Allocator,
+        deletion_queue: &mut DeletionQueue,
     ) -> Result<(), Box<dyn std::error::Error>> {
+        self.bounding_sphere = None;
         if let Some(buffer) = &mut self.vertexbuffer {
-            buffer.fill(logical_device, allocator, &self.vertexdata)?;
+            buffer.fill(logical_device, allocator, deletion_queue, &self.vertexdata)?;
             Ok(())
         } else {
             let bytes = (self.vertexdata.len() * std::mem::size_of::<V>()) as u64;
@@ -215,7 +259,7 @@ impl<V, I> Model<V, I> {
                 vk::BufferUsageFlags::VERTEX_BUFFER,
                 gpu_allocator::MemoryLocation::CpuToGpu,
             )?;
-            buffer.fill(logical_device, allocator, &self.vertexdata)?;
+            buffer.write(&self.vertexdata)?;
             self.vertexbuffer = Some(buffer);
             Ok(())
         }
@@ -225,12 +269,13 @@ impl<V, I> Model<V, I> {
         &mut self,
         logical_device: &ash::Device,
         allocator: &mut gpu_allocator::vulkan::Allocator,
+        deletion_queue: &mut DeletionQueue,
     ) -> Result<(), Box<dyn std::error::Error>> {
         if let Some(buffer) = &mut self.indexbuffer {
-            buffer.fill(logical_device, allocator, &self.indexdata)?;
+            buffer.fill(logical_device, allocator, deletion_queue, &self.indexdata)?;
             Ok(())
         } else {
-            let bytes = (self.indexdata.len() * std::mem::size_of::<V>()) as u64;
+            let bytes = std::mem::size_of_val(self.indexdata.as_slice()) as u64;
             let mut buffer = Buffer::new(
                 logical_device,
                 allocator,
@@ -238,49 +283,202 @@ impl<V, I> Model<V, I> {
                 vk::BufferUsageFlags::INDEX_BUFFER,
                 gpu_allocator::MemoryLocation::CpuToGpu,
             )?;
-            buffer.fill(logical_device, allocator, &self.indexdata)?;
+            buffer.write(&self.indexdata)?;
             self.indexbuffer = Some(buffer);
             Ok(())
         }
     }
 
+    // Static geometry: copied once into device-local memory through the transfer queue
+    pub fn upload_vertexbuffer(
+        &mut self,
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+        uploader: &Uploader,
+        deletion_queue: &mut DeletionQueue,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.bounding_sphere = None;
+        let buffer = uploader.upload(
+            logical_device,
+            allocator,
+            &self.vertexdata,
+            vk::BufferUsageFlags::VERTEX_BUFFER,
+        )?;
+        if let Some(old) = self.vertexbuffer.replace(buffer) {
+            deletion_queue.retire_buffer(old);
+        }
+        Ok(())
+    }
+
+    pub fn upload_indexbuffer(
+        &mut self,
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+        uploader: &Uploader,
+        deletion_queue: &mut DeletionQueue,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let buffer = uploader.upload(
+            logical_device,
+            allocator,
+            &self.indexdata,
+            vk::BufferUsageFlags::INDEX_BUFFER,
+        )?;
+        if let Some(old) = self.indexbuffer.replace(buffer) {
+            deletion_queue.retire_buffer(old);
+        }
+        Ok(())
+    }
+
+    // Instance data changes every frame, so each frame in flight gets its own buffer
     pub fn update_instancebuffer(
         &mut self,
         logical_device: &ash::Device,
         allocator: &mut gpu_allocator::vulkan::Allocator,
+        deletion_queue: &mut DeletionQueue,
+        frame: usize,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        if let Some(buffer) = &mut self.instancebuffer {
+        if self.instancebuffers.len() <= frame {
+            self.instancebuffers.resize_with(frame + 1, || None);
+            self.visiblebuffers.resize_with(frame + 1, || None);
+            self.indirectbuffers.resize_with(frame + 1, || None);
+        }
+        let bytes = (self.first_invisible * std::mem::size_of::<I>()) as u64;
+        if let Some(buffer) = &mut self.instancebuffers[frame] {
             buffer.fill(
                 logical_device,
                 allocator,
+                deletion_queue,
                 &self.instances[0..self.first_invisible],
             )?;
-            Ok(())
-        } else {
-            let bytes = (self.first_invisible * std::mem::size_of::<I>()) as u64;
-            if bytes > 0 {
-                let mut buffer = Buffer::new(
-                    logical_device,
-                    allocator,
-                    bytes,
-                    vk::BufferUsageFlags::VERTEX_BUFFER,
-                    gpu_allocator::MemoryLocation::CpuToGpu,
-                )?;
-                buffer.fill(
-                    logical_device,
-                    allocator,
-                    &self.instances[0..self.first_invisible],
-                )?;
-                self.instancebuffer = Some(buffer);
+        } else if bytes > 0 {
+            // Also read by the culling pass
+            let mut buffer = Buffer::new(
+                logical_device,
+                allocator,
+                bytes,
+                vk::BufferUsageFlags::VERTEX_BUFFER | vk::BufferUsageFlags::STORAGE_BUFFER,
+                gpu_allocator::MemoryLocation::CpuToGpu,
+            )?;
+            buffer.write(&self.instances[0..self.first_invisible])?;
+            self.instancebuffers[frame] = Some(buffer);
+        }
+        if bytes == 0 {
+            return Ok(());
+        }
+        // Only the GPU writes these, so they are grown without copying anything over
+        let visible_size = self.visiblebuffers[frame]
+            .as_ref()
+            .map_or(0, |buffer| buffer.size_in_bytes);
+        if visible_size < bytes {
+            let buffer = Buffer::new(
+                logical_device,
+                allocator,
+                bytes,
+                vk::BufferUsageFlags::VERTEX_BUFFER | vk::BufferUsageFlags::STORAGE_BUFFER,
+                gpu_allocator::MemoryLocation::GpuOnly,
+            )?;
+            if let Some(old) = self.visiblebuffers[frame].replace(buffer) {
+                deletion_queue.retire_buffer(old);
+            }
+        }
+        if self.indirectbuffers[frame].is_none() {
+            let buffer = Buffer::new(
+                logical_device,
+                allocator,
+                std::mem::size_of::<vk::DrawIndexedIndirectCommand>() as u64,
+                vk::BufferUsageFlags::INDIRECT_BUFFER
+                    | vk::BufferUsageFlags::STORAGE_BUFFER
+                    | vk::BufferUsageFlags::TRANSFER_DST,
+                gpu_allocator::MemoryLocation::GpuOnly,
+            )?;
+            self.indirectbuffers[frame] = Some(buffer);
+        }
+        Ok(())
+    }
+
+    // Hands every buffer to the deletion queue, for models removed while frames are in flight
+    pub fn retire(&mut self, deletion_queue: &mut DeletionQueue) {
+        let buffers = self
+            .vertexbuffer
+            .take()
+            .into_iter()
+            .chain(self.indexbuffer.take())
+            .chain(self.instancebuffers.drain(..).flatten())
+            .chain(self.visiblebuffers.drain(..).flatten())
+            .chain(self.indirectbuffers.drain(..).flatten());
+        for buffer in buffers {
+            deletion_queue.retire_buffer(buffer);
+        }
+    }
+
+    // Only valid once the GPU is done with every frame that drew this model
+    pub unsafe fn cleanup(
+        &mut self,
+        logical_device: &ash::Device,
+        allocator: &mut gpu_allocator::vulkan::Allocator,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let buffers = self
+            .vertexbuffer
+            .iter_mut()
+            .chain(self.indexbuffer.iter_mut())
+            .chain(self.instancebuffers.iter_mut().flatten())
+            .chain(self.visiblebuffers.iter_mut().flatten())
+            .chain(self.indirectbuffers.iter_mut().flatten());
+        for buffer in buffers {
+            buffer.destroy(logical_device, allocator)?;
+        }
+        self.vertexbuffer = None;
+        self.indexbuffer = None;
+        self.instancebuffers.clear();
+        self.visiblebuffers.clear();
+        self.indirectbuffers.clear();
+        Ok(())
+    }
+
+    // Draws the instances the culling pass found inside the frustum this frame
+    pub fn draw(&self, logical_device: &ash::Device, commandbuffer: vk::CommandBuffer, frame: usize) {
+        if self.first_invisible == 0 {
+            return;
+        }
+        let buffers = (
+            &self.vertexbuffer,
+            &self.indexbuffer,
+            self.visiblebuffers.get(frame),
+            self.indirectbuffers.get(frame),
+        );
+        if let (Some(vertexbuffer), Some(indexbuffer), Some(Some(visiblebuffer)), Some(Some(indirectbuffer))) =
+            buffers
+        {
+            unsafe {
+                logical_device.cmd_bind_index_buffer(
+                    commandbuffer,
+                    indexbuffer.buffer,
+                    0,
+                    vk::IndexType::UINT32,
+                );
+                logical_device.cmd_bind_vertex_buffers(commandbuffer, 0, &[vertexbuffer.buffer], &[0]);
+                logical_device.cmd_bind_vertex_buffers(commandbuffer, 1, &[visiblebuffer.buffer], &[0]);
+                logical_device.cmd_draw_indexed_indirect(
+                    commandbuffer,
+                    indirectbuffer.buffer,
+                    0,
+                    1,
+                    std::mem::size_of::<vk::DrawIndexedIndirectCommand>() as u32,
+                );
             }
-            Ok(())
         }
     }
 
-    pub fn draw(&self, logical_device: &ash::Device, commandbuffer: vk::CommandBuffer) {
+    // Every visible instance, culled or not. Shadow casters off screen still cast into it
+    pub fn draw_all_instances(
+        &self,
+        logical_device: &ash::Device,
+        commandbuffer: vk::CommandBuffer,
+        frame: usize,
+    ) {
         if let Some(vertexbuffer) = &self.vertexbuffer {
             if let Some(indexbuffer) = &self.indexbuffer {
-                if let Some(instancebuffer) = &self.instancebuffer {
+                if let Some(Some(instancebuffer)) = self.instancebuffers.get(frame) {
                     if self.first_invisible > 0 {
                         unsafe {
                             logical_device.cmd_bind_index_buffer(
@@ -317,4 +515,29 @@ impl<V, I> Model<V, I> {
     }
 }
 
+impl<V: Position, I> Model<V, I> {
+    // Centred on the bounding box, which is close enough for culling
+    pub fn update_bounding_sphere(&mut self) {
+        let mut min = [f32::MAX; 3];
+        let mut max = [f32::MIN; 3];
+        for v in &self.vertexdata {
+            for i in 0..3 {
+                min[i] = min[i].min(v.position()[i]);
+                max[i] = max[i].max(v.position()[i]);
+            }
+        }
+        if self.vertexdata.is_empty() {
+            self.bounding_sphere = Some([0.0; 4]);
+            return;
+        }
+        let centre = na::Vector3::from(min).lerp(&na::Vector3::from(max), 0.5);
+        let radius = self
+            .vertexdata
+            .iter()
+            .map(|v| (na::Vector3::from(v.position()) - centre).norm())
+            .fold(0.0, f32::max);
+        self.bounding_sphere = Some([centre.x, centre.y, centre.z, radius]);
+    }
+}
+
 mod primitives;
@@ -1,16 +1,40 @@
 use ash::vk;
 use nalgebra as na;
 
-use crate::ceaser::buffer::Buffer;
+use crate::ceaser::{
+    buffer::Buffer,
+    camera::Camera,
+    deletion_queue::DeletionQueue,
+    shadow,
+};
 
 pub struct DirectionalLight {
     pub direction: na::Vector3<f32>,
     pub illuminance: [f32; 3], //in lx = lm/m^2
+    // Only the first shadow::MAX_SHADOWED_DIRECTIONAL_LIGHTS that cast shadows get a shadow map
+    pub casts_shadows: bool,
 }
 
 pub struct PointLight {
     pub position: na::Point3<f32>, //in m
     pub luminous_flux: [f32; 3],   //in lm
+    // Whether the light competes for one of LightManager's point shadow slots
+    pub casts_shadows: bool,
+    // Range covered by the cube shadow map, in m. Nothing beyond `shadow_far` is shadowed.
+    pub shadow_near: f32,
+    pub shadow_far: f32,
+}
+
+// Illuminance in lx below which a point light no longer counts, this sets its influence radius
+pub const POINT_LIGHT_CUTOFF: f32 = 0.01;
+
+impl PointLight {
+    // Distance at which the brightest channel falls to POINT_LIGHT_CUTOFF. The light is faded
+    // out towards it and only assigned to the clusters it reaches
+    pub fn influence_radius(&self) -> f32 {
+        let flux = self.luminous_flux.iter().cloned().fold(0.0, f32::max);
+        (flux / (4.0 * std::f32::consts::PI * POINT_LIGHT_CUTOFF)).sqrt()
+    }
 }
 
 pub enum Light {
@@ -33,6 +57,8 @@ impl From<DirectionalLight> for Light {
 pub struct LightManager {
     directional_lights: Vec<DirectionalLight>,
     point_lights: Vec<PointLight>,
+    // How many point lights get a cube shadow map each frame
+    point_shadow_budget: usize,
 }
 
 impl Default for LightManager {
@@ -40,6 +66,7 @@ impl Default for LightManager {
         LightManager {
             directional_lights: vec![],
             point_lights: vec![],
+            point_shadow_budget: shadow::MAX_SHADOWED_POINT_LIGHTS,
         }
     }
 }
@@ -57,39 +84,88 @@ impl LightManager {
         }
     }
 
+    pub fn point_shadow_budget(&self) -> usize {
+        self.point_shadow_budget
+    }
+
+    // Clamped to shadow::MAX_SHADOWED_POINT_LIGHTS, 0 turns point light shadows off
+    pub fn set_point_shadow_budget(&mut self, budget: usize) {
+        self.point_shadow_budget = budget.min(shadow::MAX_SHADOWED_POINT_LIGHTS);
+    }
+
+    // Shadow slot of every point light. When more lights cast shadows than the budget allows,
+    // the ones that look brightest from the camera win.
+    fn point_shadow_slots(&self, camera: &Camera) -> Vec<Option<usize>> {
+        let mut candidates: Vec<(usize, f32)> = self
+            .point_lights
+            .iter()
+            .enumerate()
+            .filter(|(_, pl)| pl.casts_shadows)
+            .map(|(i, pl)| {
+                let distance_squared = (pl.position.coords - camera.position)
+                    .norm_squared()
+                    .max(camera.near * camera.near);
+                let flux = pl.luminous_flux.iter().cloned().fold(0.0, f32::max);
+                (i, flux / distance_squared)
+            })
+            .collect();
+        candidates.sort_by(|a, b| b.1.total_cmp(&a.1));
+        let mut slots = vec![None; self.point_lights.len()];
+        for (slot, (i, _)) in candidates.into_iter().take(self.point_shadow_budget).enumerate() {
+            slots[i] = Some(slot);
+        }
+        slots
+    }
+
+    // First shadow map layer of every directional light, if it has any
+    fn shadow_layers(&self) -> impl Iterator<Item = Option<usize>> + '_ {
+        let mut next = 0;
+        self.directional_lights.iter().map(move |dl| {
+            if dl.casts_shadows && next < shadow::MAX_SHADOWED_DIRECTIONAL_LIGHTS {
+                next += 1;
+                Some((next - 1) * shadow::CASCADE_COUNT)
+            } else {
+                None
+            }
+        })
+    }
+
     pub fn update_buffer(
         &self,
         logical_device: &ash::Device,
         allocator: &mut gpu_allocator::vulkan::Allocator,
+        deletion_queue: &mut DeletionQueue,
+        camera: &Camera,
         buffer: &mut Buffer,
         descriptor_sets_light: &mut [vk::DescriptorSet],
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let mut data: Vec<f32> = vec![];
-        data.push(self.directional_lights.len() as f32);
-        data.push(self.point_lights.len() as f32);
-        data.push(0.0);
-        data.push(0.0);
-        for dl in &self.directional_lights {
+        let mut data: Vec<f32> = vec![
+            self.directional_lights.len() as f32,
+            self.point_lights.len() as f32,
+            0.0,
+            0.0,
+        ];
+        for (dl, layer) in self.directional_lights.iter().zip(self.shadow_layers()) {
             data.push(dl.direction.x);
             data.push(dl.direction.y);
             data.push(dl.direction.z);
-            data.push(0.0);
+            data.push(layer.map_or(-1.0, |layer| layer as f32));
             data.push(dl.illuminance[0]);
             data.push(dl.illuminance[1]);
             data.push(dl.illuminance[2]);
             data.push(0.0);
         }
-        for pl in &self.point_lights {
+        for (pl, slot) in self.point_lights.iter().zip(self.point_shadow_slots(camera)) {
             data.push(pl.position.x);
             data.push(pl.position.y);
             data.push(pl.position.z);
-            data.push(0.0);
+            data.push(slot.map_or(-1.0, |slot| (6 * slot) as f32));
             data.push(pl.luminous_flux[0]);
             data.push(pl.luminous_flux[1]);
             data.push(pl.luminous_flux[2]);
-            data.push(0.0);
+            data.push(pl.influence_radius());
         }
-        buffer.fill(logical_device, allocator, &data)?;
+        buffer.fill(logical_device, allocator, deletion_queue, &data)?;
         for descset in descriptor_sets_light {
             let buffer_infos = [vk::DescriptorBufferInfo {
                 buffer: buffer.buffer,
@@ -106,4 +182,54 @@ impl LightManager {
         }
         Ok(())
     }
-}
\ No newline at end of file
+
+    // Fills the shadow uniforms for the camera and returns the matrices of the shadow maps
+    // that need rendering, in layer order
+    pub fn update_shadow_buffer(
+        &self,
+        camera: &Camera,
+        buffer: &mut Buffer,
+    ) -> Result<shadow::ShadowViews, Box<dyn std::error::Error>> {
+        let splits = shadow::cascade_splits(camera);
+        let mut matrices = vec![];
+        for (dl, layer) in self.directional_lights.iter().zip(self.shadow_layers()) {
+            if layer.is_some() {
+                let mut near = camera.near;
+                for far in splits {
+                    matrices.push(shadow::cascade_matrix(&dl.direction, camera, near, far));
+                    near = far;
+                }
+            }
+        }
+        let mut point_matrices = vec![None; self.point_shadow_budget];
+        for (pl, slot) in self.point_lights.iter().zip(self.point_shadow_slots(camera)) {
+            if let Some(slot) = slot {
+                point_matrices[slot] = Some(shadow::cube_face_matrices(
+                    &pl.position,
+                    pl.shadow_near,
+                    pl.shadow_far,
+                ));
+            }
+        }
+        let point_matrices: Vec<na::Matrix4<f32>> =
+            point_matrices.into_iter().flatten().flatten().collect();
+
+        let mut data: Vec<f32> = Vec::with_capacity(
+            16 * (shadow::MAX_SHADOW_MAPS + shadow::MAX_POINT_SHADOW_MAPS) + 4,
+        );
+        for m in &matrices {
+            data.extend_from_slice(m.as_slice());
+        }
+        data.resize(16 * shadow::MAX_SHADOW_MAPS, 0.0);
+        for m in &point_matrices {
+            data.extend_from_slice(m.as_slice());
+        }
+        data.resize(16 * (shadow::MAX_SHADOW_MAPS + shadow::MAX_POINT_SHADOW_MAPS), 0.0);
+        data.extend_from_slice(&splits);
+        buffer.write(&data)?;
+        Ok(shadow::ShadowViews {
+            directional: matrices,
+            point: point_matrices,
+        })
+    }
+}
@@ -1,26 +1,48 @@
-use super::{InstanceData, Model, VertexData, normalize};
+use super::{Model, VertexData, normalize};
+use crate::ceaser::{material, texture};
 
-impl Model<VertexData, InstanceData> {
+impl<I> Model<VertexData, I> {
     #[allow(dead_code)]
-    pub fn cube() -> Model<[f32; 3], InstanceData> {
-        let lbf = [-1.0, 1.0, -1.0]; //lbf: left-bottom-front
-        let lbb = [-1.0, 1.0, 1.0];
-        let ltf = [-1.0, -1.0, -1.0];
-        let ltb = [-1.0, -1.0, 1.0];
-        let rbf = [1.0, 1.0, -1.0];
-        let rbb = [1.0, 1.0, 1.0];
-        let rtf = [1.0, -1.0, -1.0];
-        let rtb = [1.0, -1.0, 1.0];
-        Model {
-            vertexdata: vec![lbf, lbb, ltf, ltb, rbf, rbb, rtf, rtb],
-            indexdata: vec![
-                0, 1, 5, 0, 5, 4, //bottom
-                2, 7, 3, 2, 6, 7, //top
-                0, 6, 2, 0, 4, 6, //front
-                1, 3, 7, 1, 7, 5, //back
-                0, 2, 1, 1, 2, 3, //left
-                4, 5, 6, 5, 7, 6, //right
-            ],
+    pub fn cube() -> Model<VertexData, I> {
+        // Every face gets its own four vertices, so normals and UVs stay flat per face.
+        // (normal, right, down) with the UV origin in the top left corner of the face.
+        let faces: [([f32; 3], [f32; 3], [f32; 3]); 6] = [
+            ([1.0, 0.0, 0.0], [0.0, 0.0, 1.0], [0.0, 1.0, 0.0]),
+            ([-1.0, 0.0, 0.0], [0.0, 0.0, -1.0], [0.0, 1.0, 0.0]),
+            ([0.0, 1.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, 1.0]),
+            ([0.0, -1.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, -1.0]),
+            ([0.0, 0.0, 1.0], [-1.0, 0.0, 0.0], [0.0, 1.0, 0.0]),
+            ([0.0, 0.0, -1.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]),
+        ];
+        let mut vertexdata = Vec::with_capacity(24);
+        let mut indexdata = Vec::with_capacity(36);
+        for (normal, right, down) in faces {
+            let first = vertexdata.len() as u32;
+            for (u, v) in [(0.0, 0.0), (1.0, 0.0), (0.0, 1.0), (1.0, 1.0)] {
+                let (x, y) = (2.0 * u - 1.0, 2.0 * v - 1.0);
+                vertexdata.push(VertexData {
+                    position: [
+                        normal[0] + x * right[0] + y * down[0],
+                        normal[1] + x * right[1] + y * down[1],
+                        normal[2] + x * right[2] + y * down[2],
+                    ],
+                    normal,
+                    uv: [u, v],
+                    tangent: [0.0; 4],
+                });
+            }
+            indexdata.extend_from_slice(&[
+                first,
+                first + 2,
+                first + 1,
+                first + 1,
+                first + 2,
+                first + 3,
+            ]);
+        }
+        let mut model = Model {
+            vertexdata,
+            indexdata,
             handle_to_index: std::collections::HashMap::new(),
             handles: Vec::new(),
             instances: Vec::new(),
@@ -28,11 +50,23 @@ impl Model<VertexData, InstanceData> {
             next_handle: 0,
             vertexbuffer: None,
             indexbuffer: None,
-            instancebuffer: None,
-        }
+            instancebuffers: Vec::new(),
+            visiblebuffers: Vec::new(),
+            indirectbuffers: Vec::new(),
+            bounding_sphere: None,
+            casts_shadows: true,
+            receives_shadows: true,
+            material: material::PBR,
+            albedo_texture: texture::WHITE,
+            normal_texture: texture::FLAT_NORMAL,
+        };
+        model
+            .generate_tangents()
+            .expect("the cube has a valid UV layout");
+        model
     }
 
-    pub fn sphere(refinements: u32) -> Model<VertexData, InstanceData> {
+    pub fn sphere(refinements: u32) -> Model<VertexData, I> {
         let mut model = Model::icosahedron();
         for _ in 0..refinements {
             model.ico_refine();
@@ -40,59 +74,122 @@ impl Model<VertexData, InstanceData> {
 
         for v in &mut model.vertexdata {
             v.position = normalize(v.position);
+            // Equirectangular, v runs from the top (y = -1) to the bottom
+            v.uv = [
+                0.5 + v.position[2].atan2(v.position[0]) / (2.0 * std::f32::consts::PI),
+                (-v.position[1]).clamp(-1.0, 1.0).acos() / std::f32::consts::PI,
+            ];
         }
+        model.split_uv_seam();
+        model
+            .generate_tangents()
+            .expect("the sphere has a valid UV layout");
         model
     }
 
-    pub fn icosahedron() -> Model<VertexData, InstanceData> {
+    // Triangles crossing the u = 0/1 seam would interpolate across the whole texture, so
+    // their vertices on the low side get copies shifted by one
+    fn split_uv_seam(&mut self) {
+        let mut copies = std::collections::HashMap::<u32, u32>::new();
+        for t in 0..self.indexdata.len() / 3 {
+            let triangle = &self.indexdata[3 * t..3 * t + 3];
+            let us: Vec<f32> = triangle
+                .iter()
+                .map(|&i| self.vertexdata[i as usize].uv[0])
+                .collect();
+            let max = us.iter().cloned().fold(f32::MIN, f32::max);
+            let min = us.iter().cloned().fold(f32::MAX, f32::min);
+            if max - min <= 0.5 {
+                continue;
+            }
+            for k in 0..3 {
+                let index = self.indexdata[3 * t + k];
+                if self.vertexdata[index as usize].uv[0] < 0.5 {
+                    let copy = *copies.entry(index).or_insert_with(|| {
+                        let mut v = self.vertexdata[index as usize];
+                        v.uv[0] += 1.0;
+                        self.vertexdata.push(v);
+                        self.vertexdata.len() as u32 - 1
+                    });
+                    self.indexdata[3 * t + k] = copy;
+                }
+            }
+        }
+    }
+
+    pub fn icosahedron() -> Model<VertexData, I> {
         let phi = (1.0 + 5.0_f32.sqrt()) / 2.0;
         let darkgreen_front_top = VertexData {
             position: [phi, -1.0, 0.0],
             normal: normalize([phi, -1.0, 0.0]),
+            uv: [0.0, 0.0],
+            tangent: [0.0; 4],
         }; //0
         let darkgreen_front_bottom = VertexData {
             position: [phi, 1.0, 0.0],
             normal: normalize([phi, 1.0, 0.0]),
+            uv: [0.0, 0.0],
+            tangent: [0.0; 4],
         }; //1
         let darkgreen_back_top = VertexData {
             position: [-phi, -1.0, 0.0],
             normal: normalize([-phi, -1.0, 0.0]),
+            uv: [0.0, 0.0],
+            tangent: [0.0; 4],
         }; //2
         let darkgreen_back_bottom = VertexData {
             position: [-phi, 1.0, 0.0],
             normal: normalize([-phi, 1.0, 0.0]),
+            uv: [0.0, 0.0],
+            tangent: [0.0; 4],
         }; //3
         let lightgreen_front_right = VertexData {
             position: [1.0, 0.0, -phi],
             normal: normalize([1.0, 0.0, -phi]),
+            uv: [0.0, 0.0],
+            tangent: [0.0; 4],
         }; //4
         let lightgreen_front_left = VertexData {
             position: [-1.0, 0.0, -phi],
             normal: normalize([-1.0, 0.0, -phi]),
+            uv: [0.0, 0.0],
+            tangent: [0.0; 4],
         }; //5
         let lightgreen_back_right = VertexData {
             position: [1.0, 0.0, phi],
             normal: normalize([1.0, 0.0, phi]),
+            uv: [0.0, 0.0],
+            tangent: [0.0; 4],
         }; //6
         let lightgreen_back_left = VertexData {
             position: [-1.0, 0.0, phi],
             normal: normalize([-1.0, 0.0, phi]),
+            uv: [0.0, 0.0],
+            tangent: [0.0; 4],
         }; //7
         let purple_top_left = VertexData {
             position: [0.0, -phi, -1.0],
             normal: normalize([0.0, -phi, -1.0]),
+            uv: [0.0, 0.0],
+            tangent: [0.0; 4],
         }; //8
         let purple_top_right = VertexData {
             position: [0.0, -phi, 1.0],
             normal: normalize([0.0, -phi, 1.0]),
+            uv: [0.0, 0.0],
+            tangent: [0.0; 4],
         }; //9
         let purple_bottom_left = VertexData {
             position: [0.0, phi, -1.0],
             normal: normalize([0.0, phi, -1.0]),
+            uv: [0.0, 0.0],
+            tangent: [0.0; 4],
         }; //10
         let purple_bottom_right = VertexData {
             position: [0.0, phi, 1.0],
             normal: normalize([0.0, phi, 1.0]),
+            uv: [0.0, 0.0],
+            tangent: [0.0; 4],
         }; //11
         Model {
             vertexdata: vec![
@@ -138,7 +235,15 @@ impl Model<VertexData, InstanceData> {
             next_handle: 0,
             vertexbuffer: None,
             indexbuffer: None,
-            instancebuffer: None,
+            instancebuffers: Vec::new(),
+            visiblebuffers: Vec::new(),
+            indirectbuffers: Vec::new(),
+            bounding_sphere: None,
+            casts_shadows: true,
+            receives_shadows: true,
+            material: material::PBR,
+            albedo_texture: texture::WHITE,
+            normal_texture: texture::FLAT_NORMAL,
         }
     }
 
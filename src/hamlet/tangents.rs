@@ -0,0 +1,84 @@
+use super::{Model, VertexData};
+
+// Feeds an indexed triangle list to MikkTSpace corner by corner
+struct Corners<'a> {
+    vertexdata: &'a [VertexData],
+    indexdata: &'a [u32],
+    tangents: Vec<[f32; 4]>,
+}
+
+impl Corners<'_> {
+    fn vertex(&self, face: usize, vert: usize) -> &VertexData {
+        &self.vertexdata[self.indexdata[3 * face + vert] as usize]
+    }
+}
+
+impl bevy_mikktspace::Geometry for Corners<'_> {
+    fn num_faces(&self) -> usize {
+        self.indexdata.len() / 3
+    }
+
+    fn num_vertices_of_face(&self, _face: usize) -> usize {
+        3
+    }
+
+    fn position(&self, face: usize, vert: usize) -> [f32; 3] {
+        self.vertex(face, vert).position
+    }
+
+    fn normal(&self, face: usize, vert: usize) -> [f32; 3] {
+        self.vertex(face, vert).normal
+    }
+
+    // Our v runs down the image. Bakers put v up, so their bitangent and the green channel
+    // of +y up normal maps point up the image.
+    fn tex_coord(&self, face: usize, vert: usize) -> [f32; 2] {
+        let uv = self.vertex(face, vert).uv;
+        [uv[0], 1.0 - uv[1]]
+    }
+
+    fn set_tangent_encoded(&mut self, tangent: [f32; 4], face: usize, vert: usize) {
+        self.tangents[3 * face + vert] = tangent;
+    }
+}
+
+impl<I> Model<VertexData, I> {
+    // MikkTSpace tangents with the handedness in w, the same ones baking tools use. Where
+    // the corners of a shared vertex disagree, the vertex is split.
+    pub fn generate_tangents(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut corners = Corners {
+            vertexdata: &self.vertexdata,
+            indexdata: &self.indexdata,
+            tangents: vec![[0.0; 4]; self.indexdata.len()],
+        };
+        if !bevy_mikktspace::generate_tangents(&mut corners) {
+            return Err("could not generate tangents".into());
+        }
+        let tangents = corners.tangents;
+
+        let mut assigned = vec![None; self.vertexdata.len()];
+        let mut copies = std::collections::HashMap::<(u32, [u32; 4]), u32>::new();
+        for (corner, tangent) in tangents.into_iter().enumerate() {
+            let index = self.indexdata[corner];
+            match assigned[index as usize] {
+                None => {
+                    assigned[index as usize] = Some(tangent);
+                    self.vertexdata[index as usize].tangent = tangent;
+                }
+                Some(existing) if existing == tangent => {}
+                Some(_) => {
+                    let key = (index, tangent.map(f32::to_bits));
+                    let vertexdata = &mut self.vertexdata;
+                    let copy = *copies.entry(key).or_insert_with(|| {
+                        let mut v = vertexdata[index as usize];
+                        v.tangent = tangent;
+                        vertexdata.push(v);
+                        vertexdata.len() as u32 - 1
+                    });
+                    self.indexdata[corner] = copy;
+                }
+            }
+        }
+        Ok(())
+    }
+}
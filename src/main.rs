@@ -1,5 +1,5 @@
 use ash::vk;
-use ceaser::{camera::Camera};
+use ceaser::{camera::Camera, deferred::RenderPath, device::DevicePreference};
 use hamlet::{InstanceData, Model, light::{LightManager, DirectionalLight, PointLight}};
 use nalgebra as na;
 use winit::event::{Event, WindowEvent};
@@ -7,12 +7,48 @@ use winit::event::{Event, WindowEvent};
 mod ceaser;
 mod hamlet;
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let eventloop = winit::event_loop::EventLoop::new();
-    let window = winit::window::Window::new(&eventloop)?;
-    let mut ceaser = ceaser::Ceaser::new(window)?;
+// Files given on the command line
+#[derive(Clone, Copy)]
+struct SceneAssets<'a> {
+    // Images to put on the sphere
+    albedo: Option<&'a str>,
+    normal_map: Option<&'a str>,
+    // Equirectangular .hdr for ambient light
+    environment: Option<&'a str>,
+}
+
+impl<'a> SceneAssets<'a> {
+    fn from_args(args: &'a [String]) -> Result<SceneAssets<'a>, Box<dyn std::error::Error>> {
+        let value = |flag: &str| match args.iter().position(|arg| arg == flag) {
+            Some(position) => match args.get(position + 1) {
+                Some(value) => Ok(Some(value.as_str())),
+                None => Err(format!("{} needs a file", flag)),
+            },
+            None => Ok(None),
+        };
+        Ok(SceneAssets {
+            albedo: value("--texture")?,
+            normal_map: value("--normal-map")?,
+            environment: value("--environment")?,
+        })
+    }
+}
+
+fn populate_scene(
+    ceaser: &mut ceaser::Ceaser,
+    assets: SceneAssets,
+) -> Result<(), Box<dyn std::error::Error>> {
     let mut sphere = Model::sphere(3);
-    
+    if let Some(path) = assets.albedo {
+        sphere.albedo_texture = ceaser.load_texture(path)?;
+    }
+    if let Some(path) = assets.normal_map {
+        sphere.normal_texture = ceaser.load_normal_map(path)?;
+    }
+    if let Some(path) = assets.environment {
+        ceaser.set_environment(path)?;
+    }
+
     sphere.insert_visibly(InstanceData::from_matrix_and_color(
         na::Matrix4::new_scaling(0.5),
         [0.955, 0.638, 0.538],
@@ -33,33 +69,141 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
     */
 
-    sphere.update_vertexbuffer(&ceaser.logical_device, &mut ceaser.allocator)?;
-    sphere.update_indexbuffer(&ceaser.logical_device, &mut ceaser.allocator)?;
-    sphere.update_instancebuffer(&ceaser.logical_device, &mut ceaser.allocator)?;
+    sphere.upload_vertexbuffer(
+        &ceaser.logical_device,
+        &mut ceaser.allocator,
+        &ceaser.uploader,
+        &mut ceaser.deletion_queue,
+    )?;
+    sphere.upload_indexbuffer(
+        &ceaser.logical_device,
+        &mut ceaser.allocator,
+        &ceaser.uploader,
+        &mut ceaser.deletion_queue,
+    )?;
+
+    // A flattened sphere as the ground, so there is something to catch the shadows
+    let mut ground = Model::sphere(3);
+    ground.insert_visibly(InstanceData::from_matrix_and_color(
+        na::Matrix4::new_translation(&na::Vector3::new(0.0, 0.6, 0.0))
+            * na::Matrix4::new_nonuniform_scaling(&na::Vector3::new(10.0, 0.05, 10.0)),
+        [0.5, 0.5, 0.5],
+        0.,
+        0.8,
+    ));
+    ground.casts_shadows = false;
+    ground.upload_vertexbuffer(
+        &ceaser.logical_device,
+        &mut ceaser.allocator,
+        &ceaser.uploader,
+        &mut ceaser.deletion_queue,
+    )?;
+    ground.upload_indexbuffer(
+        &ceaser.logical_device,
+        &mut ceaser.allocator,
+        &ceaser.uploader,
+        &mut ceaser.deletion_queue,
+    )?;
 
-    ceaser.models = vec![sphere];
+    ceaser.set_models(vec![sphere, ground]);
 
     let mut lights = LightManager::default();
     lights.add_light(DirectionalLight {
         direction: na::Vector3::new(-1., -1., 0.),
         illuminance: [10.1, 10.1, 10.1],
+        casts_shadows: true,
     });
     lights.add_light(PointLight {
         position: na::Point3::new(0.1, -3.0, -3.0),
         luminous_flux: [100.0, 100.0, 100.0],
+        casts_shadows: true,
+        shadow_near: 0.05,
+        shadow_far: 20.0,
     });
     lights.add_light(PointLight {
         position: na::Point3::new(0.1, -3.0, -3.0),
         luminous_flux: [100.0, 100.0, 100.0],
+        casts_shadows: true,
+        shadow_near: 0.05,
+        shadow_far: 20.0,
     });
     lights.add_light(PointLight {
         position: na::Point3::new(0.1, -3.0, -3.0),
         luminous_flux: [100.0, 100.0, 100.0],
+        casts_shadows: true,
+        shadow_near: 0.05,
+        shadow_far: 20.0,
     });
 
-    lights.update_buffer(&ceaser.logical_device, &mut ceaser.allocator, &mut ceaser.light_buffer, &mut ceaser.descriptor_sets_light)?;
+    ceaser.lights = lights;
+    Ok(())
+}
+
+// Renders a single frame without a window and writes it out as a binary PPM
+fn render_headless(
+    path: &str,
+    device_preference: DevicePreference,
+    assets: SceneAssets,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (width, height) = (800, 600);
+    let mut ceaser = ceaser::Ceaser::builder()
+        .device(device_preference)
+        .build_headless(width, height)?;
+    populate_scene(&mut ceaser, assets)?;
 
-    let mut camera = Camera::builder().build();
+    // The demo lights are far dimmer than daylight, so the camera meters the scene
+    let camera = Camera::builder()
+        .aspect(width as f32 / height as f32)
+        .auto_exposure(true)
+        .build();
+
+    let pixels = ceaser.render_offscreen(&camera)?;
+    let mut ppm = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+    for rgba in pixels.chunks(4) {
+        ppm.extend_from_slice(&rgba[0..3]);
+    }
+    std::fs::write(path, ppm)?;
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().collect();
+    let device_preference = match args.iter().position(|arg| arg == "--device") {
+        Some(position) => match args.get(position + 1) {
+            Some(value) => DevicePreference::from(value.as_str()),
+            None => return Err("--device needs a device name or index".into()),
+        },
+        None => DevicePreference::Auto,
+    };
+    let assets = SceneAssets::from_args(&args)?;
+    if let Some(position) = args.iter().position(|arg| arg == "--headless") {
+        let path = args
+            .get(position + 1)
+            .map(String::as_str)
+            .filter(|arg| !arg.starts_with("--"))
+            .unwrap_or("frame.ppm");
+        return render_headless(path, device_preference, assets);
+    }
+
+    // Recompiles the scene shaders from the source tree whenever they are saved
+    let shader_dir = args
+        .iter()
+        .any(|arg| arg == "--hot-reload")
+        .then(|| std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("shaders"));
+
+    let eventloop = winit::event_loop::EventLoop::new();
+    let window = winit::window::Window::new(&eventloop)?;
+    let mut ceaser = ceaser::Ceaser::builder()
+        .device(device_preference)
+        .hot_reload(shader_dir)
+        .build(window)?;
+    populate_scene(&mut ceaser, assets)?;
+
+    let extent = ceaser.extent();
+    let mut camera = Camera::builder()
+        .aspect(extent.width as f32 / extent.height as f32)
+        .auto_exposure(true)
+        .build();
 
     eventloop.run(move |event, _, controlflow| match event {
         Event::WindowEvent {
@@ -69,128 +213,132 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             *controlflow = winit::event_loop::ControlFlow::Exit;
         }
         Event::MainEventsCleared => {
-            ceaser.window.request_redraw();
+            if let Some(window) = &ceaser.window {
+                window.request_redraw();
+            }
+        }
+        Event::WindowEvent {
+            event: WindowEvent::Resized(_),
+            ..
+        } => {
+            ceaser.framebuffer_resized = true;
         }
         Event::RedrawRequested(_) => {
-            ceaser.swapchain.current_image =
-                (ceaser.swapchain.current_image + 1) % ceaser.swapchain.amount_of_images as usize;
-            let (image_index, _) = unsafe {
-                ceaser
-                    .swapchain
-                    .swapchain_loader
-                    .acquire_next_image(
-                        ceaser.swapchain.swapchain,
-                        std::u64::MAX,
-                        ceaser.swapchain.image_available[ceaser.swapchain.current_image],
-                        vk::Fence::null(),
-                    )
-                    .expect("image acquisition trouble")
-            };
-
-            unsafe {
-                ceaser
-                    .logical_device
-                    .wait_for_fences(
-                        &[ceaser.swapchain.may_begin_drawing[ceaser.swapchain.current_image]],
-                        true,
-                        std::u64::MAX,
-                    )
-                    .expect("fence-waiting");
-                ceaser
-                    .logical_device
-                    .reset_fences(&[
-                        ceaser.swapchain.may_begin_drawing[ceaser.swapchain.current_image]
-                    ])
-                    .expect("resetting fences");
-            }
-
-            camera.update_buffer(
-                &ceaser.logical_device,
-                &mut ceaser.allocator,
-                &mut ceaser.uniform_buffer,
-            );
-            for m in &mut ceaser.models {
-                m.update_instancebuffer(&ceaser.logical_device, &mut ceaser.allocator)
-                    .expect("Error updating instance buffers");
+            // A shader that does not compile is for the user to fix, the old one keeps running
+            match ceaser.reload_shaders() {
+                Ok(reloaded) if !reloaded.is_empty() => println!("Reloaded {}", reloaded.join(", ")),
+                Ok(_) => {}
+                Err(e) => eprintln!("{}", e),
             }
-
             ceaser
-                .update_commandbuffer(image_index as usize)
-                .expect("updating the command buffer");
-
-            let semaphores_available =
-                [ceaser.swapchain.image_available[ceaser.swapchain.current_image]];
-            let waiting_stages = [vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT];
-            let semaphores_finished =
-                [ceaser.swapchain.rendering_finished[ceaser.swapchain.current_image]];
-            let command_buffers = [ceaser.command_buffers[image_index as usize]];
-            let submit_info = [vk::SubmitInfo::builder()
-                .wait_semaphores(&semaphores_available)
-                .wait_dst_stage_mask(&waiting_stages)
-                .command_buffers(&command_buffers)
-                .signal_semaphores(&semaphores_finished)
-                .build()];
-
-            unsafe {
-                ceaser
-                    .logical_device
-                    .queue_submit(
-                        ceaser.queues.graphics_queue,
-                        &submit_info,
-                        ceaser.swapchain.may_begin_drawing[ceaser.swapchain.current_image],
-                    )
-                    .expect("queue submission");
-            }
-
-            let swapchains = [ceaser.swapchain.swapchain];
-            let indices = [image_index];
-            let present_info = vk::PresentInfoKHR::builder()
-                .wait_semaphores(&semaphores_finished)
-                .swapchains(&swapchains)
-                .image_indices(&indices);
-            unsafe {
-                ceaser
-                    .swapchain
-                    .swapchain_loader
-                    .queue_present(ceaser.queues.graphics_queue, &present_info)
-                    .expect("queue presentation");
-            };
+                .render_frame(&mut camera)
+                .expect("rendering a frame");
         }
         Event::WindowEvent {
-            event: WindowEvent::KeyboardInput { input, .. },
+            event:
+                WindowEvent::KeyboardInput {
+                    input:
+                        winit::event::KeyboardInput {
+                            state: winit::event::ElementState::Pressed,
+                            virtual_keycode: Some(keycode),
+                            ..
+                        },
+                    ..
+                },
             ..
-        } => match input {
-            winit::event::KeyboardInput {
-                state: winit::event::ElementState::Pressed,
-                virtual_keycode: Some(keycode),
-                ..
-            } => match keycode {
-                winit::event::VirtualKeyCode::Right | winit::event::VirtualKeyCode::D => {
-                    camera.turn_right(0.1);
-                }
-                winit::event::VirtualKeyCode::Left | winit::event::VirtualKeyCode::A => {
-                    camera.turn_left(0.1);
-                }
-                winit::event::VirtualKeyCode::Q => {
-                    camera.strafe_right(0.1);
-                }
-                winit::event::VirtualKeyCode::E => {
-                    camera.strafe_left(0.1);
-                }
-                winit::event::VirtualKeyCode::Up | winit::event::VirtualKeyCode::W => {
-                    camera.move_forward(0.05);
-                }
-                winit::event::VirtualKeyCode::Down | winit::event::VirtualKeyCode::S => {
-                    camera.move_backward(0.05);
+        } => match keycode {
+            winit::event::VirtualKeyCode::Right | winit::event::VirtualKeyCode::D => {
+                camera.turn_right(0.1);
+            }
+            winit::event::VirtualKeyCode::Left | winit::event::VirtualKeyCode::A => {
+                camera.turn_left(0.1);
+            }
+            winit::event::VirtualKeyCode::Q => {
+                camera.strafe_right(0.1);
+            }
+            winit::event::VirtualKeyCode::E => {
+                camera.strafe_left(0.1);
+            }
+            winit::event::VirtualKeyCode::Up | winit::event::VirtualKeyCode::W => {
+                camera.move_forward(0.05);
+            }
+            winit::event::VirtualKeyCode::Down | winit::event::VirtualKeyCode::S => {
+                camera.move_backward(0.05);
+            }
+            winit::event::VirtualKeyCode::PageUp => {
+                camera.turn_up(0.02);
+            }
+            winit::event::VirtualKeyCode::PageDown => {
+                camera.turn_down(0.02);
+            }
+            winit::event::VirtualKeyCode::M => {
+                // Cycles 1x, 2x, 4x, ... up to what the device supports
+                let samples = if ceaser.msaa_samples == ceaser.device.max_sample_count() {
+                    vk::SampleCountFlags::TYPE_1
+                } else {
+                    vk::SampleCountFlags::from_raw(ceaser.msaa_samples.as_raw() * 2)
+                };
+                let samples = ceaser.set_msaa(samples).expect("switching msaa");
+                println!("MSAA: {}x", samples.as_raw());
+            }
+            winit::event::VirtualKeyCode::P => {
+                let budget = (ceaser.lights.point_shadow_budget() + 1)
+                    % (ceaser::shadow::MAX_SHADOWED_POINT_LIGHTS + 1);
+                ceaser.lights.set_point_shadow_budget(budget);
+                println!("Shadowed point lights: {}", budget);
+            }
+            winit::event::VirtualKeyCode::T => {
+                ceaser.post.tonemapper = ceaser.post.tonemapper.next();
+                println!("Tonemapper: {:?}", ceaser.post.tonemapper);
+            }
+            // One stop brighter or darker per press
+            winit::event::VirtualKeyCode::Equals => {
+                camera.exposure_compensation += 1.0;
+                println!("Exposure compensation: {:+} EV", camera.exposure_compensation);
+            }
+            winit::event::VirtualKeyCode::Minus => {
+                camera.exposure_compensation -= 1.0;
+                println!("Exposure compensation: {:+} EV", camera.exposure_compensation);
+            }
+            winit::event::VirtualKeyCode::B => {
+                ceaser.bloom.intensity = if ceaser.bloom.intensity > 0.0 { 0.0 } else { 0.04 };
+                println!("Bloom intensity: {}", ceaser.bloom.intensity);
+            }
+            winit::event::VirtualKeyCode::O => {
+                ceaser.ssao.enabled = !ceaser.ssao.enabled;
+                println!("Ambient occlusion: {}", ceaser.ssao.enabled);
+            }
+            winit::event::VirtualKeyCode::G => {
+                ceaser.render_path = match ceaser.render_path {
+                    RenderPath::Forward => RenderPath::Deferred,
+                    RenderPath::Deferred => RenderPath::Forward,
+                };
+                println!("Render path: {:?}", ceaser.render_path);
+            }
+            winit::event::VirtualKeyCode::I => {
+                ceaser.batches.enabled = !ceaser.batches.enabled;
+                if ceaser.batches.enabled && !ceaser.batches.supported {
+                    println!("Batched drawing is not supported on this device");
+                } else {
+                    println!("Batched drawing: {}", ceaser.batches.enabled);
                 }
-                winit::event::VirtualKeyCode::PageUp => {
-                    camera.turn_up(0.02);
+            }
+            // The sphere goes through the built in materials and any added ones
+            winit::event::VirtualKeyCode::L => {
+                let count = ceaser.materials.count();
+                if let Some(sphere) = ceaser.models.first_mut() {
+                    sphere.material = (sphere.material + 1) % count;
+                    println!("Material: {}", ceaser.materials.get(sphere.material).name);
                 }
-                winit::event::VirtualKeyCode::PageDown => {
-                    camera.turn_down(0.02);
+            }
+            winit::event::VirtualKeyCode::X => {
+                camera.auto_exposure = !camera.auto_exposure;
+                if camera.auto_exposure {
+                    println!("Auto exposure");
+                } else {
+                    println!("Manual exposure: EV100 {:.1}", camera.ev100());
                 }
-                _ => {}
-            },
+            }
             _ => {}
         },
         _ => {}